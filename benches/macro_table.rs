@@ -0,0 +1,52 @@
+/*
+    Times whole documents that look up definitions tens of thousands of times, run with
+    cargo bench --bench macro_table, and with --features siphash to compare the definition tables
+    with the default SipHash instead of FxHash
+*/
+
+use std::fmt::Write;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use proj3::Processor;
+
+const MACROS: usize = 5_000;
+
+// A library of macros defined in terms of each other, each called a few times
+fn library() -> String {
+    let mut text = String::new();
+    for i in 0..MACROS {
+        let _ = writeln!(text, "\\def{{term{}}}{{#1 is term {}}}", i, i);
+    }
+    for i in 0..MACROS {
+        let _ = writeln!(text, "\\term{}{{x}} \\term{}{{y}}", i, (i * 7919) % MACROS);
+    }
+    text
+}
+
+// A few macros called far more than the rest, as in a filled-in template
+fn template() -> String {
+    let mut text = String::from("\\def{sep}{, }\\def{item}{<#1>}\n");
+    for i in 0..64 {
+        let _ = writeln!(text, "\\def{{field{}}}{{value {}}}", i, i);
+    }
+    for i in 0..MACROS * 4 {
+        let _ = writeln!(text, "\\item{{\\field{}{{}}}}\\sep{{}}\\item{{\\field7{{}}}}", i % 64);
+    }
+    text
+}
+
+fn bench(text: &str) -> Duration {
+    let mut processor = Processor::new(&["--max-expansions", "0"]).unwrap();
+    let start = Instant::now();
+    black_box(processor.process("doc.txt", text).unwrap());
+    start.elapsed()
+}
+
+fn main() {
+    let hasher = if cfg!(feature = "siphash") { "siphash" } else { "fxhash" };
+    for (name, text) in [("library", library()), ("template", template())] {
+        let fastest = (0..20).map(|_| bench(&text)).min().unwrap();
+        println!("{:<9} {}: {:>8.2} ms", name, hasher, fastest.as_secs_f64() * 1000.0);
+    }
+}
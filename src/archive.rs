@@ -0,0 +1,318 @@
+/*
+    Reading files out of archives for \include{bundle.zip!path/inside.mac}, with the archives feature.
+    Zip files with stored or deflated members, tar files, and gzipped tar files are supported. An
+    archive is read and indexed once per run, see Context::archives.
+*/
+
+use std::collections::HashMap;
+
+use super::crc32;
+
+/*
+    Splits bundle.zip!path/inside.mac into the archive and the member, if the part before the first
+    ! names an archive
+*/
+pub(crate) fn split(path: &str) -> Option<(&str, &str)> {
+    let (archive, member) = path.split_once('!')?;
+    [".zip", ".tar", ".tar.gz", ".tgz"].iter().any(|ext| archive.ends_with(ext)).then_some((archive, member))
+}
+
+enum Member {
+    Zip { offset: usize, compressed_size: usize, size: usize, method: u16, crc: u32 },
+    Tar { offset: usize, size: usize } // Into the tar data, decompressed for .tar.gz
+}
+
+pub(crate) struct Archive {
+    data: Vec<u8>,
+    members: HashMap<String, Member>
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16, String> {
+    match data.get(at..at + 2) {
+        Some(bytes) => Ok(u16::from_le_bytes([bytes[0], bytes[1]])),
+        None => Err("Include error.".to_string())
+    }
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32, String> {
+    match data.get(at..at + 4) {
+        Some(bytes) => Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+        None => Err("Include error.".to_string())
+    }
+}
+
+// Whether size bytes are more than a --max-memory of max_memory allows, 0 for no limit
+fn too_large(size: usize, max_memory: usize) -> bool {
+    max_memory != 0 && size > max_memory
+}
+
+impl Archive {
+    pub(crate) fn parse(path: &str, data: Vec<u8>, max_memory: usize) -> Result<Archive, String> {
+        if path.ends_with(".zip") {
+            let members = zip_index(&data)?;
+            Ok(Archive { data, members })
+        } else {
+            let data = if path.ends_with(".tar") { data } else { gunzip(path, &data, max_memory)? };
+            let members = tar_index(&data)?;
+            Ok(Archive { data, members })
+        }
+    }
+
+    pub(crate) fn read(&self, name: &str, max_memory: usize) -> Result<Vec<u8>, String> {
+        match self.members.get(name) {
+            Some(&Member::Zip { offset, compressed_size, size, method, crc }) => {
+                if too_large(size, max_memory) { return Err(format!("Memory limit exceeded by archive member {}.", name)) }
+                let Some(compressed) = self.data.get(offset..offset + compressed_size) else { return Err("Include error.".to_string()) };
+                let contents = match method {
+                    0 => compressed.to_vec(),
+                    8 => inflate(compressed, size)?,
+                    _ => return Err("Include error.".to_string())
+                };
+                if contents.len() != size || crc32::checksum(&contents) != crc { return Err("Include error.".to_string()) }
+                Ok(contents)
+            },
+            Some(&Member::Tar { offset, size }) => Ok(self.data[offset..offset + size].to_vec()),
+            None => Err("Include error.".to_string())
+        }
+    }
+}
+
+/*
+    Index of a zip file from its central directory, which the end of central directory record at
+    the end of the file points to
+*/
+fn zip_index(data: &[u8]) -> Result<HashMap<String, Member>, String> {
+    let end = (0..data.len().saturating_sub(21)).rev().take(65536 + 22)
+        .find(|&at| data[at..].starts_with(&[0x50, 0x4b, 0x05, 0x06]))
+        .ok_or("Include error.")?;
+    let count = u16_at(data, end + 10)? as usize;
+    let mut at = u32_at(data, end + 16)? as usize;
+    let mut members = HashMap::new();
+    for _ in 0..count {
+        if u32_at(data, at)? != 0x02014b50 { return Err("Include error.".to_string()) }
+        let method = u16_at(data, at + 10)?;
+        let crc = u32_at(data, at + 16)?;
+        let compressed_size = u32_at(data, at + 20)? as usize;
+        let size = u32_at(data, at + 24)? as usize;
+        let name_len = u16_at(data, at + 28)? as usize;
+        let extra_len = u16_at(data, at + 30)? as usize;
+        let comment_len = u16_at(data, at + 32)? as usize;
+        let local = u32_at(data, at + 42)? as usize;
+        let name = data.get(at + 46..at + 46 + name_len).ok_or("Include error.")?;
+        // The local header repeats the name but may have a different extra field
+        let offset = local + 30 + u16_at(data, local + 26)? as usize + u16_at(data, local + 28)? as usize;
+        members.insert(String::from_utf8_lossy(name).into_owned(), Member::Zip { offset, compressed_size, size, method, crc });
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(members)
+}
+
+/*
+    Index of the regular files in a tar file, named with the ustar prefix if there is one
+*/
+fn tar_index(data: &[u8]) -> Result<HashMap<String, Member>, String> {
+    let field = |header: &[u8], range: std::ops::Range<usize>| {
+        let bytes = &header[range];
+        String::from_utf8_lossy(&bytes[..bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len())]).into_owned()
+    };
+    let mut members = HashMap::new();
+    let mut at = 0;
+    while let Some(header) = data.get(at..at + 512) {
+        if header.iter().all(|&b| b == 0) { break }
+        let size = usize::from_str_radix(field(header, 124..136).trim(), 8).map_err(|_| "Include error.")?;
+        let offset = at + 512;
+        if size > data.len() - offset { return Err("Include error.".to_string()) }
+        if header[156] == b'0' || header[156] == 0 {
+            let (prefix, name) = (field(header, 345..500), field(header, 0..100));
+            let name = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+            members.insert(name, Member::Tar { offset, size });
+        }
+        at = offset + size.div_ceil(512) * 512;
+    }
+    Ok(members)
+}
+
+/*
+    Contents of a gzip file, skipping the optional header fields. The size in the trailer is only
+    that modulo 2^32, so larger contents cannot be read.
+*/
+fn gunzip(path: &str, data: &[u8], max_memory: usize) -> Result<Vec<u8>, String> {
+    if !data.starts_with(&[0x1f, 0x8b, 8]) || data.len() < 18 { return Err("Include error.".to_string()) }
+    let flags = data[3];
+    let mut at = 10;
+    if flags & 4 != 0 { at += 2 + u16_at(data, at)? as usize }
+    for flag in [8, 16] {
+        if flags & flag != 0 {
+            at += data.get(at..).and_then(|rest| rest.iter().position(|&b| b == 0)).ok_or("Include error.")? + 1;
+        }
+    }
+    if flags & 2 != 0 { at += 2 }
+    let trailer = data.len() - 8;
+    let size = u32_at(data, trailer + 4)? as usize;
+    if too_large(size, max_memory) { return Err(format!("Memory limit exceeded by {}.", path)) }
+    let contents = inflate(data.get(at..).ok_or("Include error.")?, size)?;
+    if crc32::checksum(&contents) != u32_at(data, trailer)? || contents.len() != size {
+        return Err("Include error.".to_string());
+    }
+    Ok(contents)
+}
+
+struct Bits<'a> {
+    data: &'a [u8],
+    at: usize, // Next byte
+    buffer: u32,
+    count: u32 // Bits in buffer
+}
+
+impl Bits<'_> {
+    fn take(&mut self, n: u32) -> Result<u32, String> {
+        while self.count < n {
+            let byte = *self.data.get(self.at).ok_or("Include error.")?;
+            self.at += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << n) - 1) as u32;
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+}
+
+/*
+    Canonical Huffman code, decoded one bit at a time: counts[len] codes of each length, with their
+    symbols in code order
+*/
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &len in lengths { counts[len as usize] += 1 }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for len in 1..16 { offsets[len] = offsets[len - 1] + counts[len - 1] }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= bits.take(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return self.symbols.get((index + code - first) as usize).copied().ok_or("Include error.".to_string());
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("Include error.".to_string())
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073,
+                              4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/*
+    Decompresses a raw deflate stream (RFC 1951) of at most size bytes, failing as soon as it would
+    go past them
+*/
+fn inflate(data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let mut bits = Bits { data, at: 0, buffer: 0, count: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => {
+                // Stored: the rest of the current byte is skipped
+                bits.buffer = 0;
+                bits.count = 0;
+                let len = u16_at(data, bits.at)?;
+                if u16_at(data, bits.at + 2)? != !len { return Err("Include error.".to_string()) }
+                let start = bits.at + 4;
+                if out.len() + len as usize > size { return Err("Include error.".to_string()) }
+                out.extend_from_slice(data.get(start..start + len as usize).ok_or("Include error.")?);
+                bits.at = start + len as usize;
+            },
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                inflate_block(&mut bits, &mut out, size, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            },
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, size, &literals, &distances)?;
+            },
+            _ => return Err("Include error.".to_string())
+        }
+        if last { return Ok(out) }
+    }
+}
+
+/*
+    Reads the code lengths of a dynamic block, themselves Huffman coded
+*/
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), String> {
+    let literal_count = bits.take(5)? as usize + 257;
+    let distance_count = bits.take(5)? as usize + 1;
+    let code_length_count = bits.take(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[i] = bits.take(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_lengths.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or("Include error.")?, 3 + bits.take(2)?),
+            17 => (0, 3 + bits.take(3)?),
+            _ => (0, 11 + bits.take(7)?)
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() != literal_count + distance_count { return Err("Include error.".to_string()) }
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+fn inflate_block(bits: &mut Bits, out: &mut Vec<u8>, size: usize, literals: &Huffman, distances: &Huffman) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        if symbol < 256 {
+            if out.len() == size { return Err("Include error.".to_string()) }
+            out.push(symbol as u8);
+        } else if symbol == 256 {
+            return Ok(());
+        } else {
+            let i = symbol - 257;
+            if i >= LENGTH_BASE.len() { return Err("Include error.".to_string()) }
+            let len = LENGTH_BASE[i] as usize + bits.take(LENGTH_EXTRA[i] as u32)? as usize;
+            let d = distances.decode(bits)? as usize;
+            if d >= DIST_BASE.len() { return Err("Include error.".to_string()) }
+            let distance = DIST_BASE[d] as usize + bits.take(DIST_EXTRA[d] as u32)? as usize;
+            if distance > out.len() || out.len() + len > size { return Err("Include error.".to_string()) }
+            let start = out.len() - distance;
+            for k in 0..len {
+                out.push(out[start + k]); // May overlap the bytes being written
+            }
+        }
+    }
+}
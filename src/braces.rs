@@ -0,0 +1,90 @@
+/*
+    The mustache-like dialect of --dialect braces, translated into the native syntax before a file
+    is preprocessed. Text outside tags is taken as it is. {{name}} is the expansion of the macro,
+    HTML-escaped, {{{name}}} the same unescaped, and both are empty if it is not defined.
+    {{#if name}}..{{else}}..{{/if}} and {{#unless name}}..{{/unless}} test whether it is defined,
+    {{#def name}}..{{/def}} defines it, {{> file}} includes a file, and {{! ..}} is a comment.
+*/
+
+use super::{literal_body, push_literal};
+
+/*
+    The native form of a file written with tags
+*/
+pub(crate) fn translate(text: &str) -> Result<String, String> {
+    let mut translated = String::with_capacity(text.len());
+    let mut sections: Vec<(&str, bool)> = Vec::new(); // Open sections and whether past their {{else}}, innermost last
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        for c in rest[..start].chars() { push_literal(&mut translated, c) }
+        let line = text[..text.len() - rest.len() + start].matches('\n').count() + 1;
+        let triple = rest[start..].starts_with("{{{");
+        let (open, close) = if triple { ("{{{", "}}}") } else { ("{{", "}}") };
+        let inside = &rest[start + open.len()..];
+        let Some(end) = inside.find(close) else {
+            return Err(format!("Unterminated tag on line {}.", line));
+        };
+        let tag = inside[..end].trim();
+        rest = &inside[end + close.len()..];
+        if triple {
+            let name = tag_name(tag, line)?;
+            translated.push_str(&format!("\\ifdef{{{0}}}{{\\{0}{{}}}}{{}}", name));
+            continue;
+        }
+        if let Some(comment) = tag.strip_prefix('!') {
+            // Its line breaks stay in the text so that lines keep their numbers
+            translated.push_str(&format!("\\if{{}}{{{}}}{{}}", "\n".repeat(comment.matches('\n').count())));
+            continue;
+        }
+        if let Some(path) = tag.strip_prefix('>') {
+            translated.push_str(&format!("\\include{{{}}}", literal_body(path.trim())));
+            continue;
+        }
+        if let Some(section) = tag.strip_prefix('#') {
+            let (kind, name) = section.split_once(char::is_whitespace).unwrap_or((section, ""));
+            let name = tag_name(name.trim(), line)?;
+            let kind = match kind {
+                "if" => { translated.push_str(&format!("\\ifdef{{{}}}{{", name)); "if" },
+                "unless" => { translated.push_str(&format!("\\ifndef{{{}}}{{", name)); "unless" },
+                "def" => { translated.push_str(&format!("\\def{{{}}}{{", name)); "def" },
+                _ => return Err(format!("Unknown section {{{{#{}}}}} on line {}.", kind, line))
+            };
+            sections.push((kind, false));
+            continue;
+        }
+        if tag == "else" {
+            match sections.last_mut() {
+                Some((kind, past_else @ false)) if *kind != "def" => {
+                    *past_else = true;
+                    translated.push_str("}{");
+                },
+                _ => return Err(format!("Unexpected {{{{else}}}} on line {}.", line))
+            }
+            continue;
+        }
+        if let Some(kind) = tag.strip_prefix('/') {
+            match sections.pop() {
+                Some((open, past_else)) if open == kind.trim() => {
+                    translated.push_str(if past_else || open == "def" { "}" } else { "}{}" });
+                },
+                _ => return Err(format!("Unexpected {{{{/{}}}}} on line {}.", kind.trim(), line))
+            }
+            continue;
+        }
+        let name = tag_name(tag, line)?;
+        translated.push_str(&format!("\\ifdef{{{0}}}{{\\htmlescape{{\\{0}{{}}}}}}{{}}", name));
+    }
+    for c in rest.chars() { push_literal(&mut translated, c) }
+    if let Some((kind, _)) = sections.last() {
+        return Err(format!("Unterminated {{{{#{}}}}} section.", kind));
+    }
+    Ok(translated)
+}
+
+// The macro a tag names, which may be imported as prefix:name
+fn tag_name(name: &str, line: usize) -> Result<&str, String> {
+    if name.is_empty() || !name.starts_with(|c: char| c.is_alphanumeric()) || !name.chars().all(|c| c.is_alphanumeric() || c == ':') {
+        return Err(format!("Invalid name {:?} in tag on line {}.", name, line));
+    }
+    Ok(name)
+}
@@ -0,0 +1,1310 @@
+/*
+    The builtin registry. Each builtin declares how many argument groups it takes, so the evaluator
+    reads them before calling the handler, and handlers only see the call. New builtins are added by
+    registering them, with no change to the evaluator.
+*/
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+use super::{Context, Macro, Options, ScopedInclude, Segment, Symbol, calls_builtin, compile_body, literal_body, preproc_include, read_included};
+use super::crc32;
+use super::csv;
+use super::encoding;
+use super::eval::{check_output, chomp_newline, count_expansion, expand_arg, process_str, push_taken_branch, record_error};
+use super::format;
+use super::fxhash::FxHashMap;
+#[cfg(feature = "json")]
+use super::json;
+use super::lexer::{Input, read_arg, read_default, read_group, read_name, read_number_group, read_trailing_args};
+use super::sha1;
+use super::sha256;
+use super::unicode;
+
+#[derive(Copy, Clone)]
+pub(crate) enum Arity {
+    Exact(usize), // That many groups
+    AtLeast(usize), // That many groups and every group immediately following them
+    Raw // Only the opening brace is consumed, the handler reads the rest itself
+}
+
+/*
+    A call of a builtin. The name is passed so that related builtins can share a handler.
+*/
+pub(crate) struct Call<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) args: Vec<String>
+}
+
+/*
+    Handlers get the call, the input to push text back onto, and the output to write to
+*/
+pub(crate) type Handler = fn(&mut Context, &Options, Call, &mut Input, &mut String) -> Result<(), String>;
+
+#[derive(Copy, Clone)]
+pub(crate) struct Builtin {
+    pub(crate) arity: Arity,
+    pub(crate) handler: Handler
+}
+
+pub(crate) struct Registry {
+    builtins: HashMap<String, Builtin> // Keyed by names from the input, so not FxHash
+}
+
+impl Registry {
+    /*
+        Adds a builtin, replacing any builtin of the same name
+    */
+    pub(crate) fn register(&mut self, name: &str, arity: Arity, handler: Handler) {
+        self.builtins.insert(name.to_string(), Builtin { arity, handler });
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<Builtin> {
+        self.builtins.get(name).copied()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Registry { builtins: HashMap::new() };
+        for name in ["def", "defp", "gdef", "pushdef", "redef"] {
+            registry.register(name, Arity::Raw, builtin_def);
+        }
+        registry.register("capture", Arity::Exact(2), builtin_capture);
+        registry.register("undef", Arity::Exact(1), builtin_undef);
+        registry.register("popdef", Arity::Exact(1), builtin_undef);
+        registry.register("include", Arity::Exact(1), builtin_include);
+        registry.register("includewith", Arity::Exact(2), builtin_includewith);
+        registry.register("includescoped", Arity::Exact(1), builtin_includescoped);
+        registry.register("import", Arity::Exact(2), builtin_import);
+        registry.register("fileexists", Arity::Exact(3), builtin_fileexists);
+        registry.register("basename", Arity::Exact(1), builtin_path);
+        registry.register("dirname", Arity::Exact(1), builtin_path);
+        registry.register("joinpath", Arity::AtLeast(1), builtin_path);
+        registry.register("currentfile", Arity::Exact(1), builtin_current);
+        registry.register("currentline", Arity::Exact(1), builtin_current);
+        registry.register("sha256", Arity::Exact(1), builtin_digest);
+        registry.register("crc32", Arity::Exact(1), builtin_digest);
+        for name in ["b64encode", "b64decode", "hexencode", "hexdecode", "urlencode", "urldecode"] {
+            registry.register(name, Arity::Exact(1), builtin_encode);
+        }
+        for name in ["htmlescape", "jsonescape", "shellquote"] {
+            registry.register(name, Arity::Exact(1), builtin_escape);
+        }
+        registry.register("uppercase", Arity::AtLeast(1), builtin_case);
+        registry.register("lowercase", Arity::AtLeast(1), builtin_case);
+        registry.register("ascii", Arity::Exact(1), builtin_ascii);
+        registry.register("slugify", Arity::Exact(1), builtin_ascii);
+        #[cfg(feature = "json")]
+        registry.register("jsonget", Arity::Exact(2), builtin_jsonget);
+        #[cfg(feature = "json")]
+        registry.register("jsonfile", Arity::Exact(2), builtin_jsonget);
+        registry.register("format", Arity::Exact(2), builtin_format);
+        for name in ["padleft", "padright", "center"] {
+            registry.register(name, Arity::Exact(3), builtin_pad);
+        }
+        registry.register("wordcount", Arity::Exact(1), builtin_count);
+        registry.register("linecount", Arity::Exact(1), builtin_count);
+        for name in ["hostname", "username", "os", "arch"] {
+            registry.register(name, Arity::Exact(1), builtin_host);
+        }
+        registry.register("version", Arity::Exact(1), builtin_version);
+        registry.register("gitdescribe", Arity::Exact(1), builtin_git);
+        registry.register("gitsha", Arity::Exact(1), builtin_git);
+        registry.register("random", Arity::Exact(2), builtin_random);
+        registry.register("uuid", Arity::Exact(1), builtin_uuid);
+        registry.register("export", Arity::Exact(1), builtin_export);
+        registry.register("deprecate", Arity::Exact(2), builtin_deprecate);
+        registry.register("show", Arity::Exact(1), builtin_show);
+        registry.register("error", Arity::Exact(2), builtin_error);
+        registry.register("stderr", Arity::AtLeast(1), builtin_stderr);
+        registry.register("tostream", Arity::Exact(2), builtin_tostream);
+        registry.register("atend", Arity::Exact(1), builtin_atend);
+        registry.register("label", Arity::Exact(1), builtin_label);
+        registry.register("ref", Arity::Exact(1), builtin_ref);
+        registry.register("cite", Arity::Exact(1), builtin_cite);
+        registry.register("indexentry", Arity::Exact(1), builtin_indexentry);
+        registry.register("printindex", Arity::Exact(1), builtin_printindex);
+        registry.register("writefile", Arity::Exact(2), builtin_writefile);
+        registry.register("appendfile", Arity::Exact(2), builtin_writefile);
+        registry.register("expandafter", Arity::Raw, builtin_expandafter);
+        registry.register("expand", Arity::Exact(1), builtin_expand);
+        registry.register("noexpand", Arity::Exact(1), builtin_noexpand);
+        registry.register("if", Arity::Exact(3), builtin_if);
+        registry.register("ifdef", Arity::Exact(3), builtin_ifdef);
+        registry.register("ifndef", Arity::Exact(3), builtin_ifdef);
+        registry.register("ifflag", Arity::Exact(3), builtin_ifflag);
+        registry.register("ifcase", Arity::AtLeast(2), builtin_ifcase);
+        registry.register("and", Arity::AtLeast(1), builtin_bool);
+        registry.register("or", Arity::AtLeast(1), builtin_bool);
+        registry.register("not", Arity::Exact(1), builtin_bool);
+        registry.register("while", Arity::Exact(2), builtin_while);
+        registry.register("foreachrow", Arity::Exact(2), builtin_foreachrow);
+        registry.register("arg", Arity::Exact(1), builtin_variadic_arg);
+        registry.register("argcount", Arity::Exact(1), builtin_variadic_arg);
+        registry.register("begingroup", Arity::Exact(1), builtin_group);
+        registry.register("endgroup", Arity::Exact(1), builtin_group);
+        registry
+    }
+}
+
+/*
+    Reads the argument groups of a builtin call, whose first opening brace is already consumed
+*/
+pub(crate) fn read_args(arity: Arity, input: &mut Input) -> Result<Vec<String>, String> {
+    let (count, trailing) = match arity {
+        Arity::Exact(count) => (count, false),
+        Arity::AtLeast(count) => (count, true),
+        Arity::Raw => return Ok(Vec::new())
+    };
+    let mut args = Vec::with_capacity(count);
+    if count > 0 { args.push(read_arg(input)?) }
+    while args.len() < count { args.push(read_group(input)?) }
+    if trailing { args.append(&mut read_trailing_args(input)?) }
+    Ok(args)
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum DefKind {
+    Local, // \def
+    Global, // \gdef
+    Push, // \pushdef
+    Redefine // \redef
+}
+
+/*
+    \def{name}{body}, \defp, \gdef, \pushdef, and \redef, with an optional [default] after the name
+*/
+fn builtin_def(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let def_kind = match call.name {
+        "gdef" => DefKind::Global,
+        "pushdef" => DefKind::Push,
+        "redef" => DefKind::Redefine,
+        _ => DefKind::Local
+    };
+    let macro_name = read_name(input, false, "Non-alphanumeric while defining macro name.")?;
+    // Calls are looked up among the builtins first, so a macro with a builtin's name could only be
+    // called as prefix:name after an \import
+    if !opts.allow_shadow_builtins && ctx.builtins.get(&macro_name).is_some() {
+        return Err("Macro name is taken by a builtin.".to_string());
+    }
+    let default = read_default(input)?;
+    let body = read_group(input)?;
+    let variadic = calls_builtin(&body, "arg") || calls_builtin(&body, "argcount");
+    let name = ctx.qualify(&macro_name);
+    let defined_at = ctx.location();
+    let site = ctx.coverage_site(name, &defined_at);
+    let macro_val = Macro {
+        segments: compile_body(&body), body, default, variadic, previous: None, private: call.name == "defp",
+        defined_at, site, carried: false
+    };
+    debug!(opts, "{}: \\{} defines \\{}", ctx.location(), call.name, ctx.symbols.name(name));
+    match def_kind {
+        DefKind::Local => ctx.define(name, macro_val)?,
+        DefKind::Global => ctx.define_global(name, macro_val),
+        DefKind::Push => ctx.push_definition(name, macro_val),
+        DefKind::Redefine => ctx.redefine(name, macro_val)?
+    }
+    chomp_newline(opts, input);
+    Ok(())
+}
+
+/*
+    \capture{name}{body}
+    Defines the macro to expand to what the body expands to now, as it is, like \def would
+*/
+fn builtin_capture(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let macro_name = &call.args[0];
+    if macro_name.is_empty() || !macro_name.chars().all(|c| c.is_alphanumeric()) {
+        return Err("Non-alphanumeric while defining macro name.".to_string());
+    }
+    if !opts.allow_shadow_builtins && ctx.builtins.get(macro_name).is_some() {
+        return Err("Macro name is taken by a builtin.".to_string());
+    }
+    let body = literal_body(&expand_arg(ctx, opts, &call.args[1])?);
+    let name = ctx.qualify(macro_name);
+    let defined_at = ctx.location();
+    let site = ctx.coverage_site(name, &defined_at);
+    let macro_val = Macro {
+        segments: compile_body(&body), body, default: None, variadic: false, previous: None, private: false,
+        defined_at, site, carried: false
+    };
+    debug!(opts, "{}: \\capture defines \\{}", ctx.location(), ctx.symbols.name(name));
+    ctx.define(name, macro_val)?;
+    chomp_newline(opts, input);
+    Ok(())
+}
+
+/*
+    \undef{name} and \popdef{name}
+    Removing a name that is not defined is an error unless --lenient-undef is given, and one that
+    --recover goes on after
+*/
+fn builtin_undef(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, output: &mut String) -> Result<(), String> {
+    if !call.args[0].chars().all(|c| c.is_alphanumeric() || c == ':') {
+        return Err("Non-alphanumeric in un-define.".to_string());
+    }
+    let name = ctx.resolve(&call.args[0]);
+    debug!(opts, "{}: \\{} removes \\{}", ctx.location(), call.name, ctx.symbols.name(name));
+    let removed = if call.name == "popdef" { ctx.pop_definition(name) } else { ctx.undefine(name) };
+    if !removed && opts.recover && !opts.lenient_undef {
+        record_error(ctx, opts, output, "Macro not defined.")?;
+    } else if !removed && !opts.lenient_undef {
+        return Err("Macro not defined.".to_string());
+    }
+    chomp_newline(opts, input);
+    Ok(())
+}
+
+/*
+    A builtin of an embedder, found by its name among those the options hold, see Processor
+*/
+pub(crate) fn builtin_embedded(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let Some((_, _, handler)) = opts.embedded.iter().find(|(name, _, _)| name == call.name) else {
+        return Err("Macro not defined.".to_string());
+    };
+    let mut args = Vec::with_capacity(call.args.len());
+    for arg in &call.args {
+        args.push(expand_arg(ctx, opts, arg)?);
+    }
+    output.push_str(&handler(&args)?);
+    Ok(())
+}
+
+/*
+    \include{file}
+    If macros named beforeinclude or afterinclude are defined, calls to them with the path as the
+    argument are processed right before and after the included text.
+*/
+fn builtin_include(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let path = ctx.input_path(opts, &call.args[0]);
+    let (preprocessed, marks) = preproc_include(ctx, opts, &path)?;
+    let start = ctx.add_source(&path, preprocessed.len(), marks);
+    let hook = |ctx: &mut Context, name: &str| {
+        let symbol = ctx.resolve(name);
+        ctx.is_defined(symbol).then(|| format!("\\{}{{{}}}", name, literal_body(&path)))
+    };
+    let (before, after) = (hook(ctx, "beforeinclude"), hook(ctx, "afterinclude"));
+    if let Some(after) = after { input.push_text(&after) }
+    let reversed: String = preprocessed.chars().rev().collect();
+    input.push_source(&reversed, start);
+    if let Some(before) = before { input.push_text(&before) }
+    Ok(())
+}
+
+/*
+    \includewith{file}{name=body,name=body}
+    \include with the macros pushed over any definitions of them for the included text and the
+    include hooks, and popped after them. Bodies are taken as written, like those of \pushdef, and
+    may hold commas inside braces; a body that is all in one pair of braces loses them.
+*/
+fn builtin_includewith(ctx: &mut Context, opts: &Options, mut call: Call, input: &mut Input, output: &mut String) -> Result<(), String> {
+    let mut restore = String::new();
+    let mut definitions = Vec::new();
+    for pair in split_top_level(&call.args[1]) {
+        if pair.trim().is_empty() { continue }
+        let Some((macro_name, body)) = pair.split_once('=') else {
+            return Err("includewith expects name=body pairs separated by commas.".to_string());
+        };
+        let macro_name = macro_name.trim();
+        if macro_name.is_empty() || !macro_name.chars().all(|c| c.is_alphanumeric()) {
+            return Err("Non-alphanumeric while defining macro name.".to_string());
+        }
+        if !opts.allow_shadow_builtins && ctx.builtins.get(macro_name).is_some() {
+            return Err("Macro name is taken by a builtin.".to_string());
+        }
+        let body = body.trim();
+        // Only if the first brace is closed by the last one, unlike in {a}{b}
+        let whole_group = read_group(&mut Input::new(body.chars().rev().collect()))
+            .is_ok_and(|group| group.len() + 2 == body.len());
+        let body = if whole_group { &body[1..body.len() - 1] } else { body };
+        restore.push_str(&format!("\\popdef{{{}}}", macro_name));
+        definitions.push((macro_name.to_string(), body.to_string()));
+    }
+    // The last \popdef takes this newline with --chomp instead of the one after the call
+    if opts.chomp && !restore.is_empty() { restore.push('\n') }
+    input.push_text(&restore);
+    for (macro_name, body) in definitions {
+        let variadic = calls_builtin(&body, "arg") || calls_builtin(&body, "argcount");
+        let name = ctx.qualify(&macro_name);
+        let defined_at = ctx.location();
+        let site = ctx.coverage_site(name, &defined_at);
+        let macro_val = Macro {
+            segments: compile_body(&body), body, default: None, variadic, previous: None, private: false,
+            defined_at, site, carried: false
+        };
+        debug!(opts, "{}: \\includewith defines \\{}", ctx.location(), ctx.symbols.name(name));
+        ctx.push_definition(name, macro_val);
+    }
+    call.args.truncate(1);
+    builtin_include(ctx, opts, call, input, output)
+}
+
+/*
+    \includescoped{file}
+    \include in a group of its own, around the include hooks too, whose \endgroup{} moves the
+    macros the file lists with \export{a,b} to the scope outside, see end_scoped_include
+*/
+fn builtin_includescoped(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, output: &mut String) -> Result<(), String> {
+    input.push_text("\\endgroup{}");
+    builtin_include(ctx, opts, call, input, output)?;
+    ctx.generation += 1;
+    ctx.scopes.push(FxHashMap::default());
+    let outer_exports = ctx.exports.take();
+    ctx.scoped_includes.push(ScopedInclude { depth: ctx.scopes.len(), id: ctx.generation, outer_exports });
+    Ok(())
+}
+
+/*
+    Moves the definitions of the scope of an \includescoped file to the scope outside it once it is
+    processed. Those it exported keep their names, as do those it imported, which have a prefix of
+    their own. The others are renamed to 0:id:name, with the calls to them in the moved bodies
+    rewritten, so that the exported macros can still use them but nothing else reaches them.
+*/
+fn end_scoped_include(ctx: &mut Context, opts: &Options, scoped: ScopedInclude, mut scope: FxHashMap<Symbol, Macro>) -> Result<(), String> {
+    let exports = std::mem::replace(&mut ctx.exports, scoped.outer_exports).unwrap_or_default();
+    let namespace = ctx.namespace.as_ref().map_or(String::new(), |namespace| format!("{}:", namespace));
+    let mut renames = HashMap::new();
+    let mut moved = Vec::new();
+    for (&id, macro_val) in scope.iter() {
+        let name = ctx.symbols.name(id);
+        let unqualified = name.strip_prefix(&namespace).unwrap_or(name);
+        let is_public = unqualified.contains(':') || !macro_val.private && exports.iter().any(|e| e == unqualified);
+        if is_public {
+            moved.push((id, None));
+        } else {
+            let renamed = format!("{}0:{}:{}", namespace, scoped.id, unqualified);
+            renames.insert(unqualified.to_string(), renamed.clone());
+            moved.push((id, Some(renamed)));
+        }
+    }
+    for name in &exports {
+        if !scope.contains_key(&ctx.qualify(name)) {
+            warn!(opts, "{}: \\{} is exported but not defined by the scoped file", ctx.location(), name);
+            ctx.warnings += 1;
+        }
+    }
+    for (id, renamed) in moved {
+        let mut macro_val = scope.remove(&id).unwrap();
+        if !renames.is_empty() {
+            macro_val.body = qualify_calls(&macro_val.body, &renames);
+            macro_val.segments = compile_body(&macro_val.body);
+        }
+        match renamed {
+            Some(renamed) => {
+                let renamed = ctx.symbols.intern(&renamed);
+                ctx.scopes.last_mut().unwrap().insert(renamed, macro_val);
+            },
+            None => ctx.define(id, macro_val)?
+        }
+    }
+    Ok(())
+}
+
+// Text split at the commas outside braces, skipping escaped characters
+fn split_top_level(text: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => { chars.next(); },
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + 1;
+            },
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/*
+    \fileexists{path}{then}{else}
+    Takes the else branch for any path when files may not be read, e.g. while fuzzing
+*/
+fn builtin_fileexists(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let path = ctx.input_path(opts, &call.args[0]);
+    let exists = opts.allow_files && Path::new(&path).exists();
+    if let Some(reads) = ctx.reads.as_mut() {
+        reads.probed.push((path, exists));
+    }
+    push_taken_branch(opts, input, if exists { &call.args[1] } else { &call.args[2] });
+    Ok(())
+}
+
+/*
+    \sha256{text} and \crc32{text}
+    Lowercase hex digest of the UTF-8 bytes of the expanded text
+*/
+fn builtin_digest(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let text = expand_arg(ctx, opts, &call.args[0])?;
+    match call.name {
+        "sha256" => output.push_str(&sha256::hex(&sha256::digest(text.as_bytes()))),
+        _ => output.push_str(&format!("{:08x}", crc32::checksum(text.as_bytes())))
+    }
+    Ok(())
+}
+
+/*
+    \b64encode{text}, \b64decode{text}, \hexencode{text}, \hexdecode{text}, \urlencode{text},
+    and \urldecode{text}
+    The text is expanded and encoded as UTF-8. Decoded bytes must be UTF-8 and go straight to the
+    output, so they are not expanded.
+*/
+fn builtin_encode(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let text = expand_arg(ctx, opts, &call.args[0])?;
+    let decoded = match call.name {
+        "b64encode" => {
+            output.push_str(&encoding::base64_encode(text.as_bytes()));
+            return Ok(());
+        },
+        "hexencode" => {
+            output.push_str(&encoding::hex_encode(text.as_bytes()));
+            return Ok(());
+        },
+        "urlencode" => {
+            output.push_str(&encoding::url_encode(&text));
+            return Ok(());
+        },
+        "b64decode" => encoding::base64_decode(&text),
+        "hexdecode" => encoding::hex_decode(&text),
+        _ => encoding::url_decode(&text)
+    };
+    let Some(bytes) = decoded else { return Err(format!("Invalid input to {}.", call.name)) };
+    match String::from_utf8(bytes) {
+        Ok(decoded) => output.push_str(&decoded),
+        Err(_) => return Err(format!("Output of {} is not UTF-8.", call.name))
+    }
+    Ok(())
+}
+
+/*
+    \htmlescape{text}, \jsonescape{text}, and \shellquote{text}
+    The expanded text made safe to put in HTML text or attribute values, inside a JSON string, or
+    on a shell command line as one word
+*/
+fn builtin_escape(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let text = expand_arg(ctx, opts, &call.args[0])?;
+    output.push_str(&match call.name {
+        "htmlescape" => encoding::html_escape(&text),
+        "jsonescape" => encoding::json_escape(&text),
+        _ => encoding::shell_quote(&text)
+    });
+    Ok(())
+}
+
+/*
+    \uppercase{text}, \lowercase{text}, and {text}{locale}
+    The expanded text in uppercase or lowercase by the rules of the expanded locale, or of --locale
+    if there is none, see unicode::change_case
+*/
+fn builtin_case(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    if call.args.len() > 2 { return Err(format!("Too many arguments to {}.", call.name)) }
+    let text = expand_arg(ctx, opts, &call.args[0])?;
+    let language = match call.args.get(1) {
+        Some(locale) => unicode::language(expand_arg(ctx, opts, locale)?.trim())?,
+        None => unicode::language(&opts.locale)?
+    };
+    output.push_str(&unicode::change_case(&text, call.name == "uppercase", &language));
+    Ok(())
+}
+
+/*
+    \ascii{text} and \slugify{text}
+    The expanded text folded to ASCII, or made into a URL slug, see unicode::to_ascii and
+    unicode::slugify
+*/
+fn builtin_ascii(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let text = expand_arg(ctx, opts, &call.args[0])?;
+    output.push_str(&if call.name == "ascii" { unicode::to_ascii(&text) } else { unicode::slugify(&text) });
+    Ok(())
+}
+
+/*
+    \jsonget{json}{pointer} and \jsonfile{path}{pointer}
+    Both arguments of \jsonget are expanded. \jsonfile reads the file as it is, like \foreachrow,
+    so it is not preprocessed or expanded, and only its pointer is. A string expands to its
+    contents, any other value to compact JSON. The result is not expanded again.
+*/
+#[cfg(feature = "json")]
+fn builtin_jsonget(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let text = if call.name == "jsonfile" {
+        let path = ctx.input_path(opts, &call.args[0]);
+        String::from_utf8(read_included(ctx, opts, &path)?).map_err(|_| "Include error.".to_string())?
+    } else {
+        expand_arg(ctx, opts, &call.args[0])?
+    };
+    let pointer = expand_arg(ctx, opts, &call.args[1])?;
+    let value = json::parse(&text)?;
+    match json::lookup(&value, pointer.trim()) {
+        Some(found) => output.push_str(&json::to_text(found)),
+        None => return Err(format!("No value at {} in jsonget.", pointer.trim()))
+    }
+    Ok(())
+}
+
+/*
+    \format{spec}{value}
+    The expanded value formatted as a number by the expanded spec, see format.rs
+*/
+fn builtin_format(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let spec = expand_arg(ctx, opts, &call.args[0])?;
+    let value = expand_arg(ctx, opts, &call.args[1])?;
+    match format::format_number(&spec, &value) {
+        Some(formatted) => output.push_str(&formatted),
+        None => return Err(format!("Cannot format {:?} as {:?}.", value, spec))
+    }
+    Ok(())
+}
+
+/*
+    \padleft{width}{fill}{text}, \padright{width}{fill}{text}, and \center{width}{fill}{text}
+    The expanded text padded to width characters with fill, which must be one character, on the
+    left, on the right, or on both sides. Text that is already as wide is left as it is.
+*/
+fn builtin_pad(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let width = match expand_arg(ctx, opts, &call.args[0])?.trim().parse::<usize>() {
+        Ok(width) if width <= format::MAX_WIDTH => width,
+        _ => return Err(format!("Invalid width in {}.", call.name))
+    };
+    let fill = expand_arg(ctx, opts, &call.args[1])?;
+    let mut chars = fill.chars();
+    let (Some(fill), None) = (chars.next(), chars.next()) else {
+        return Err(format!("Fill of {} must be one character.", call.name));
+    };
+    let text = expand_arg(ctx, opts, &call.args[2])?;
+    let align = match call.name {
+        "padleft" => '>',
+        "padright" => '<',
+        _ => '^'
+    };
+    output.push_str(&format::pad(&text, width, fill, align));
+    Ok(())
+}
+
+/*
+    \wordcount{text} and \linecount{text}
+    Words are separated by whitespace. A final line break does not start another line, so empty
+    text has no lines.
+*/
+fn builtin_count(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let text = expand_arg(ctx, opts, &call.args[0])?;
+    let count = match call.name {
+        "wordcount" => text.split_whitespace().count(),
+        _ => text.lines().count()
+    };
+    output.push_str(&count.to_string());
+    Ok(())
+}
+
+/*
+    \hostname{}, \username{}, \os{}, and \arch{}
+    Where the processor runs, which is not part of the input, so these are refused with
+    --deterministic and when files may not be read, and the output is not cached. The operating
+    system and architecture are the ones the processor was built for, e.g. linux and x86_64.
+*/
+fn builtin_host(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    if opts.deterministic || !opts.allow_files {
+        return Err(format!("{} is not available{}.", call.name, if opts.deterministic { " with --deterministic" } else { "" }));
+    }
+    if let Some(reads) = ctx.reads.as_mut() { reads.unrepeatable = true }
+    let value = match call.name {
+        "hostname" => env::var("COMPUTERNAME").ok()
+            .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+            .or_else(|| fs::read_to_string("/etc/hostname").ok())
+            .or_else(|| env::var("HOSTNAME").ok()),
+        "username" => ["USER", "USERNAME", "LOGNAME"].iter().find_map(|var| env::var(var).ok()),
+        "os" => Some(env::consts::OS.to_string()),
+        _ => Some(env::consts::ARCH.to_string())
+    };
+    match value.map(|value| value.trim().to_string()).filter(|value| !value.is_empty()) {
+        Some(value) => output.push_str(&value),
+        None => return Err(format!("Cannot determine the {}.", call.name))
+    }
+    Ok(())
+}
+
+/*
+    \version{}
+    The version of the processor, so generated files can record what produced them
+*/
+fn builtin_version(_: &mut Context, _: &Options, _: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    output.push_str(env!("CARGO_PKG_VERSION"));
+    Ok(())
+}
+
+/*
+    \gitdescribe{} and \gitsha{}
+    git describe --always --dirty and the commit hash of HEAD, for the repository of the working
+    directory. Running git needs --allow-git, and the output is not cached since the repository
+    can change without any file the run read changing. Each command is run once per run, even with
+    --passes.
+*/
+fn builtin_git(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    if !opts.allow_git { return Err(format!("{} needs --allow-git.", call.name)) }
+    if let Some(reads) = ctx.reads.as_mut() { reads.unrepeatable = true }
+    if let Some(previous) = ctx.git.get(call.name) {
+        output.push_str(previous);
+        return Ok(());
+    }
+    let args: &[&str] = match call.name {
+        "gitdescribe" => &["describe", "--always", "--dirty"],
+        _ => &["rev-parse", "HEAD"]
+    };
+    let result = match Command::new("git").args(args).output() {
+        Ok(result) if result.status.success() => result,
+        _ => return Err(format!("git {} failed.", args[0]))
+    };
+    let git_output = String::from_utf8_lossy(&result.stdout).trim().to_string();
+    output.push_str(&git_output);
+    ctx.git.insert(call.name.to_string(), git_output);
+    Ok(())
+}
+
+/*
+    \random{min}{max}
+    A number from min to max, both included. Arguments are expanded. Without --seed every run gives
+    different numbers, so the output is not cached.
+*/
+fn builtin_random(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let mut bounds = [0i64; 2];
+    for (bound, arg) in bounds.iter_mut().zip(&call.args) {
+        *bound = match expand_arg(ctx, opts, arg)?.trim().parse() {
+            Ok(n) => n,
+            Err(_) => return Err("Non-numeric bound in random.".to_string())
+        };
+    }
+    let [min, max] = bounds;
+    if min > max { return Err("Empty range in random.".to_string()) }
+    // Scaled into the range, in 128 bits so that even the whole range of i64 fits
+    let range = (max as i128 - min as i128 + 1) as u128;
+    let offset = (draw_random(ctx, opts)? as u128 * range) >> 64;
+    output.push_str(&(min as i128 + offset as i128).to_string());
+    Ok(())
+}
+
+/*
+    Next number for \random and \uuid
+*/
+fn draw_random(ctx: &mut Context, opts: &Options) -> Result<u64, String> {
+    if opts.deterministic { return Err("Random numbers are not allowed with --deterministic.".to_string()) }
+    if opts.seed.is_none() {
+        if let Some(reads) = ctx.reads.as_mut() { reads.unrepeatable = true }
+    }
+    Ok(ctx.next_random())
+}
+
+// RFC 9562 namespace for URLs, under which \uuid{name} derives its UUIDs
+const UUID_NAMESPACE: [u8; 16] = [0x6b, 0xa7, 0xb8, 0x11, 0x9d, 0xad, 0x11, 0xd1, 0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30, 0xc8];
+
+/*
+    \uuid{} and \uuid{name}
+    A random UUID (version 4), or one derived from the expanded name (version 5), which is the same
+    on every run and so the only kind allowed with --deterministic.
+*/
+fn builtin_uuid(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let name = expand_arg(ctx, opts, &call.args[0])?;
+    let mut bytes = [0u8; 16];
+    if name.is_empty() {
+        bytes[..8].copy_from_slice(&draw_random(ctx, opts)?.to_le_bytes());
+        bytes[8..].copy_from_slice(&draw_random(ctx, opts)?.to_le_bytes());
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    } else {
+        let digest = sha1::digest(&[&UUID_NAMESPACE[..], name.as_bytes()].concat());
+        bytes.copy_from_slice(&digest[..16]);
+        bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    }
+    bytes[8] = (bytes[8] & 0x3f) | 0x80; // The RFC variant
+    for (i, byte) in bytes.iter().enumerate() {
+        if [4, 6, 8, 10].contains(&i) { output.push('-') }
+        output.push_str(&format!("{:02x}", byte));
+    }
+    Ok(())
+}
+
+/*
+    \basename{path}, \dirname{path}, and \joinpath{a}{b}...
+    Arguments are expanded and the result goes straight to the output, so it is not expanded again.
+    Paths are split and joined the way the platform does, so a component that is an absolute path
+    replaces everything before it in \joinpath.
+*/
+fn builtin_path(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let mut paths = Vec::new();
+    for arg in &call.args {
+        paths.push(expand_arg(ctx, opts, arg)?);
+    }
+    let path = Path::new(&paths[0]);
+    let result = match call.name {
+        "basename" => path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default().into_owned(),
+        "dirname" => match path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => ".".to_string(),
+            Some(parent) => parent.to_string_lossy().into_owned(),
+            None => paths[0].clone() // The root, or empty
+        },
+        _ => paths[1..].iter().fold(path.to_path_buf(), |joined, next| joined.join(next)).to_string_lossy().into_owned()
+    };
+    output.push_str(&result);
+    Ok(())
+}
+
+/*
+    \currentfile{} and \currentline{}: where the call, or the top-level call it came from, was made
+*/
+fn builtin_current(ctx: &mut Context, _: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let (name, line) = ctx.current_line().unwrap_or(("<input>", 1));
+    if call.name == "currentfile" { output.push_str(name) } else { output.push_str(&line.to_string()) }
+    Ok(())
+}
+
+/*
+    Rewrites the calls in a macro body that refer to renamed macros
+*/
+fn qualify_calls(body: &str, renames: &HashMap<String, String>) -> String {
+    let mut qualified = String::new();
+    let mut chars = body.char_indices();
+    let mut prev_is_escaping_backslash = false;
+    while let Some((i, c)) = chars.next() {
+        if prev_is_escaping_backslash {
+            let name: String = body[i..].chars().take_while(|c| c.is_alphanumeric()).collect();
+            if let Some(renamed) = renames.get(&name).filter(|_| body[i + name.len()..].starts_with('{')) {
+                qualified.push_str(renamed);
+                qualified.push('{');
+                chars.nth(name.chars().count() - 1);
+                prev_is_escaping_backslash = false;
+                continue;
+            }
+        }
+        prev_is_escaping_backslash = c == '\\' && !prev_is_escaping_backslash;
+        qualified.push(c);
+    }
+    qualified
+}
+
+/*
+    \import{file}{prefix}
+    Processes a file with its definitions registered as prefix:name. Calls between the imported
+    macros are qualified afterwards so they keep working when expanded from the importing document.
+    Macros the file keeps private, through \defp or by leaving them out of an \export{a,b,c} list,
+    are renamed to prefix:0:name so they stay usable by the public ones without surfacing.
+*/
+fn builtin_import(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let (file, prefix) = (&call.args[0], &call.args[1]);
+    if !prefix.starts_with(char::is_alphabetic) || !prefix.chars().all(|c| c.is_alphanumeric()) {
+        return Err("Non-alphanumeric in import prefix.".to_string());
+    }
+    let path = ctx.input_path(opts, file);
+    let (preprocessed, marks) = preproc_include(ctx, opts, &path)?;
+    let start = ctx.add_source(&path, preprocessed.len(), marks);
+
+    let prefix = ctx.qualified_name(prefix);
+    let outer_namespace = ctx.namespace.replace(prefix.clone());
+    let outer_exports = ctx.exports.take();
+    let cursor = ctx.cursor;
+    ctx.importing.push(path);
+    let file_output = process_str(ctx, opts, &mut Input::from_source(preprocessed.chars().rev().collect(), start));
+    ctx.importing.pop();
+    ctx.cursor = cursor;
+    ctx.namespace = outer_namespace;
+    let exports = std::mem::replace(&mut ctx.exports, outer_exports);
+    let file_output = file_output?;
+
+    let qualified_prefix = format!("{}:", prefix);
+    ctx.generation += 1;
+    for scope in ctx.scopes.iter_mut() {
+        let mut renames = HashMap::new();
+        let mut ids = Vec::new();
+        for (&id, macro_val) in scope.iter() {
+            let name = ctx.symbols.name(id);
+            let Some(unqualified) = name.strip_prefix(&qualified_prefix) else { continue };
+            if unqualified.contains(':') { continue } // From a nested import, already qualified
+            let is_private = macro_val.private || exports.as_ref().is_some_and(|exports| !exports.iter().any(|e| e == unqualified));
+            let renamed = if is_private { format!("{}0:{}", qualified_prefix, unqualified) } else { name.to_string() };
+            renames.insert(unqualified.to_string(), renamed.clone());
+            ids.push((id, renamed));
+        }
+        for (id, renamed) in ids {
+            let mut macro_val = scope.remove(&id).unwrap();
+            macro_val.body = qualify_calls(&macro_val.body, &renames);
+            macro_val.segments = compile_body(&macro_val.body);
+            scope.insert(ctx.symbols.intern(&renamed), macro_val);
+        }
+    }
+    output.push_str(&file_output);
+    Ok(())
+}
+
+/*
+    \export{a,b,c}
+    Declares the macros an imported file makes available, see builtin_import
+*/
+fn builtin_export(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let exports = ctx.exports.get_or_insert_with(Vec::new);
+    for name in call.args[0].split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+        if !name.chars().all(|c| c.is_alphanumeric()) {
+            return Err("Non-alphanumeric in export.".to_string());
+        }
+        exports.push(name.to_string());
+    }
+    chomp_newline(opts, input);
+    Ok(())
+}
+
+/*
+    \deprecate{name}{message}
+    Every later expansion of the macro warns with the (expanded) message
+*/
+fn builtin_deprecate(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let message = expand_arg(ctx, opts, &call.args[1])?;
+    let name = ctx.resolve(&call.args[0]);
+    ctx.deprecated.insert(name, message);
+    chomp_newline(opts, input);
+    Ok(())
+}
+
+/*
+    \stderr{text} and \stderr{text}{level}
+    Prints the expanded text as a line on stderr instead of putting it in the output, if the log
+    level asks for messages of the expanded level: warn, the default, info, or debug
+*/
+fn builtin_stderr(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, _: &mut String) -> Result<(), String> {
+    if call.args.len() > 2 { return Err(format!("Too many arguments to {}.", call.name)) }
+    let text = expand_arg(ctx, opts, &call.args[0])?;
+    let level = match call.args.get(1) {
+        Some(level) => expand_arg(ctx, opts, level)?,
+        None => String::new()
+    };
+    let needed = match level.trim() {
+        "" | "warn" => 1,
+        "info" => 2,
+        "debug" => 3,
+        level => return Err(format!("Unknown level {} in stderr, expected warn, info, or debug.", level))
+    };
+    if opts.log_level >= needed { eprintln!("{}", text) }
+    Ok(())
+}
+
+/*
+    \tostream{name}{content}
+    Adds the expanded content to the output stream of the expanded name instead of the output. It
+    is discarded if no --stream maps the stream to a file, or an error with --strict-streams, and
+    in the passes of --passes before the last.
+*/
+fn builtin_tostream(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, _: &mut String) -> Result<(), String> {
+    let name = expand_arg(ctx, opts, &call.args[0])?;
+    let name = name.trim();
+    let content = expand_arg(ctx, opts, &call.args[1])?;
+    if !opts.streams.iter().any(|(mapped, _)| mapped == name) {
+        if opts.strict_streams { return Err(format!("Stream {} is not mapped by --stream.", name)) }
+        return Ok(());
+    }
+    if opts.early_pass { return Ok(()) }
+    ctx.held_len += content.len();
+    ctx.streams.entry(name.to_string()).or_default().push_str(&content);
+    Ok(())
+}
+
+/*
+    \atend{text}
+    Holds the expanded text back until the rest of the document has been processed, see
+    eval::process_into. Texts held back by several calls are put out in the order of the calls.
+*/
+fn builtin_atend(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, _: &mut String) -> Result<(), String> {
+    let text = expand_arg(ctx, opts, &call.args[0])?;
+    ctx.held_len += text.len();
+    ctx.deferred.push(text);
+    Ok(())
+}
+
+/*
+    \label{name}
+    Records what \currentlabel{} expands to now, if it is defined, as the value of the label of the
+    expanded name. A label made twice is a warning, and the first value is kept.
+*/
+fn builtin_label(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, _: &mut String) -> Result<(), String> {
+    let name = expand_arg(ctx, opts, &call.args[0])?.trim().to_string();
+    let value = current_label(ctx, opts)?.unwrap_or_default();
+    let location = ctx.location();
+    if let Some((_, first)) = ctx.labels.get(&name) {
+        warn!(opts, "{}: label {} made again, first at {}", location, name, first);
+        ctx.warnings += 1;
+        return Ok(());
+    }
+    ctx.labels.insert(name, (value, location));
+    Ok(())
+}
+
+// Where a label or index entry is made: what \currentlabel{} expands to, or the location if it is not defined
+fn current_label(ctx: &mut Context, opts: &Options) -> Result<Option<String>, String> {
+    let current = ctx.resolve("currentlabel");
+    if !ctx.is_defined(current) { return Ok(None) }
+    expand_arg(ctx, opts, "\\currentlabel{}").map(Some)
+}
+
+/*
+    \indexentry{term}
+    Records the expanded term for \printindex, with where it occurs: what \currentlabel{} expands to
+    now, or the file and line if it is not defined
+*/
+fn builtin_indexentry(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, _: &mut String) -> Result<(), String> {
+    let term = expand_arg(ctx, opts, &call.args[0])?.trim().to_string();
+    let at = match current_label(ctx, opts)? {
+        Some(label) => label,
+        None => ctx.location()
+    };
+    ctx.index.push((term, at));
+    Ok(())
+}
+
+/*
+    \printindex{}
+    The entries recorded by \indexentry so far, or all of those of the pass before with --passes,
+    sorted by term regardless of case and accents. Each term comes with where it occurs, in order
+    and without repeats, as a "term, where, where" line, or as the expansion of
+    \indexline{term}{where, where} if that macro is defined.
+*/
+fn builtin_printindex(ctx: &mut Context, _: &Options, _: Call, input: &mut Input, output: &mut String) -> Result<(), String> {
+    let line = ctx.resolve("indexline");
+    let custom = ctx.is_defined(line);
+    let entries = ctx.carried_index.as_ref().unwrap_or(&ctx.index);
+    let mut terms: Vec<(&str, Vec<&str>)> = Vec::new();
+    for (term, at) in entries {
+        match terms.iter_mut().find(|(seen, _)| seen == term) {
+            Some((_, places)) => if !places.contains(&at.as_str()) { places.push(at) },
+            None => terms.push((term, vec![at]))
+        }
+    }
+    terms.sort_by_cached_key(|(term, _)| (unicode::to_ascii(term).to_lowercase(), term.to_string()));
+    if custom {
+        let calls: String = terms.iter()
+            .map(|(term, places)| format!("\\indexline{{{}}}{{{}}}", literal_body(term), literal_body(&places.join(", "))))
+            .collect();
+        input.push_text(&calls);
+    } else {
+        for (term, places) in terms {
+            output.push_str(&format!("{}, {}\n", term, places.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+/*
+    \ref{name}
+    The value of the label of the expanded name, made earlier or by the pass before with --passes.
+    An unknown label is a warning and expands to ??.
+*/
+fn builtin_ref(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let name = expand_arg(ctx, opts, &call.args[0])?;
+    let name = name.trim();
+    let value = ctx.labels.get(name).map(|(value, _)| value).or_else(|| ctx.carried_labels.get(name));
+    match value {
+        Some(value) => output.push_str(value),
+        None => {
+            let hint = if opts.passes == 1 { " (references to labels made later need --passes 2)" } else { "" };
+            warn!(opts, "{}: label {} is not defined{}", ctx.location(), name, hint);
+            ctx.warnings += 1;
+            output.push_str("??");
+        }
+    }
+    Ok(())
+}
+
+/*
+    \cite{key} or \cite{key1,key2}
+    The formatted entries of the expanded keys in the --refs databases, separated by "; ". A key
+    that is not there gives ??, with a warning at the end that gathers its citations.
+*/
+fn builtin_cite(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    let keys = expand_arg(ctx, opts, &call.args[0])?;
+    let mut entries = Vec::new();
+    for key in keys.split(',').map(str::trim) {
+        if key.is_empty() { return Err("cite expects reference keys separated by commas.".to_string()) }
+        match opts.references.get(key) {
+            Some(entry) => entries.push(entry.as_str()),
+            None => {
+                ctx.unknown_citations.push((key.to_string(), ctx.location()));
+                entries.push("??");
+            }
+        }
+    }
+    output.push_str(&entries.join("; "));
+    Ok(())
+}
+
+/*
+    \writefile{path}{content} and \appendfile{path}{content}
+    Writes the expanded content to the file at the expanded path, replacing it or adding to its end,
+    if --allow-write lets them, in the last of the passes of --passes. The output is not cached, since
+    the files would not be written again.
+*/
+fn builtin_writefile(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, _: &mut String) -> Result<(), String> {
+    if !opts.allow_write || !opts.allow_files { return Err(format!("{} needs --allow-write.", call.name)) }
+    if let Some(reads) = ctx.reads.as_mut() { reads.unrepeatable = true }
+    let path = expand_arg(ctx, opts, &call.args[0])?;
+    let path = ctx.file_path(opts, path.trim());
+    let content = expand_arg(ctx, opts, &call.args[1])?;
+    if opts.early_pass { return Ok(()) }
+    let result = if call.name == "writefile" {
+        fs::write(&path, content)
+    } else {
+        OpenOptions::new().append(true).create(true).open(&path).and_then(|mut file| file.write_all(content.as_bytes()))
+    };
+    result.map_err(|e| format!("Cannot write {}: {}.", path, e))
+}
+
+/*
+    \error{message}{code}
+    Fails with the expanded message and the location of the call. The run exits with the code if
+    one is given, so calling scripts can tell failures the document reports from other errors.
+*/
+fn builtin_error(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, _: &mut String) -> Result<(), String> {
+    let message = expand_arg(ctx, opts, &call.args[0])?;
+    let code = expand_arg(ctx, opts, &call.args[1])?;
+    if !code.trim().is_empty() {
+        match code.trim().parse::<i32>() {
+            Ok(code) if (1..=255).contains(&code) => ctx.exit_code = Some(code),
+            _ => return Err("Invalid exit code in error.".to_string())
+        }
+    }
+    Err(format!("{}: {}", ctx.location(), message))
+}
+
+/*
+    \expandafter{a}{b} and \expandafter{a}{b}{n}
+    Expands b, n times over if a depth is given, and then processes a followed by the result. Groups
+    after the call are commonly meant for a, so a third group is only the depth if it holds nothing
+    but a number. Every round after the first counts towards the expansion limit.
+*/
+fn builtin_expandafter(ctx: &mut Context, opts: &Options, _: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let first = read_arg(input)?;
+    let mut after = read_group(input)?;
+    let depth = match read_number_group(input) {
+        Some(depth) => match depth.trim().parse::<usize>() {
+            Ok(depth) if depth >= 1 => depth,
+            _ => return Err("Invalid depth in expandafter.".to_string())
+        },
+        None => 1
+    };
+    for round in 0..depth {
+        if round > 0 { count_expansion(ctx, opts)? }
+        after = expand_arg(ctx, opts, &after)?;
+    }
+    input.push_text(&after);
+    input.push_text(&first);
+    Ok(())
+}
+
+/*
+    \expand{text}
+    Expands text completely, right away, and puts the result in the output. Unlike \expandafter{}{text},
+    which processes the expansion once more, the result is not expanded again.
+*/
+fn builtin_expand(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    output.push_str(&expand_arg(ctx, opts, &call.args[0])?);
+    Ok(())
+}
+
+/*
+    \show{name}
+    Prints the definition of a macro and where it was made to stderr, for debugging macro libraries,
+    once with --passes
+*/
+fn builtin_show(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, _: &mut String) -> Result<(), String> {
+    if opts.early_pass { return Ok(()) }
+    let name = ctx.resolve(&call.args[0]);
+    let shown = match ctx.lookup(name) {
+        Some(macro_val) => {
+            let default = macro_val.default.as_ref().map_or(String::new(), |default| format!("[{}]", default));
+            format!("= {}{} (defined at {})", default, macro_val.body, macro_val.defined_at)
+        },
+        None if ctx.builtins.get(&call.args[0]).is_some() => "is a builtin".to_string(),
+        None => "is not defined".to_string()
+    };
+    eprintln!("{}: \\{} {}", ctx.location(), ctx.symbols.name(name), shown);
+    Ok(())
+}
+
+/*
+    \noexpand{text}
+    Puts text in the output as it is written, so a macro can produce a call as text. Wherever the
+    output is processed again, e.g. by \expandafter, the call is made then.
+*/
+fn builtin_noexpand(_: &mut Context, _: &Options, call: Call, _: &mut Input, output: &mut String) -> Result<(), String> {
+    output.push_str(&call.args[0]);
+    Ok(())
+}
+
+/*
+    \if{cond}{then}{else}
+    The condition is expanded and true iff the result is non-empty, as for \while, so \and, \or, and
+    \not can be used in it
+*/
+fn builtin_if(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let taken = if expand_arg(ctx, opts, &call.args[0])?.is_empty() { &call.args[2] } else { &call.args[1] };
+    push_taken_branch(opts, input, taken);
+    Ok(())
+}
+
+/*
+    \ifdef{name}{then}{else} and \ifndef{name}{then}{else}
+*/
+fn builtin_ifdef(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let name = ctx.resolve(&call.args[0]);
+    let taken = if ctx.is_defined(name) != (call.name == "ifndef") { &call.args[1] } else { &call.args[2] };
+    push_taken_branch(opts, input, taken);
+    Ok(())
+}
+
+/*
+    \ifflag{name}{then}{else}
+    Tests the --flag switches, which are separate from the definition table
+*/
+fn builtin_ifflag(_: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let taken = if opts.flags.contains(&call.args[0]) { &call.args[1] } else { &call.args[2] };
+    push_taken_branch(opts, input, taken);
+    Ok(())
+}
+
+/*
+    \ifcase{selector}{case0}{case1}...{default}
+    The selector is expanded and must be a whole number; out-of-range selectors, negative ones
+    included, take the last branch.
+*/
+fn builtin_ifcase(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let selector = expand_arg(ctx, opts, &call.args[0])?;
+    let branches = &call.args[1..];
+    let index: i64 = match selector.trim().parse() {
+        Ok(index) => index,
+        Err(_) => return Err("Non-numeric selector in ifcase.".to_string())
+    };
+    let branch = usize::try_from(index).ok().and_then(|index| branches.get(index)).unwrap_or(&branches[branches.len() - 1]);
+    push_taken_branch(opts, input, branch);
+    Ok(())
+}
+
+/*
+    \and{a}{b}..., \or{a}{b}..., and \not{a}
+    Each argument is expanded and treated as true iff non-empty, matching \if. The result is 1 for
+    true and empty for false. \and and \or stop expanding arguments once the result is known.
+*/
+fn builtin_bool(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    let result = match call.name {
+        "not" => expand_arg(ctx, opts, &call.args[0])?.is_empty(),
+        "and" => {
+            let mut result = true;
+            for arg in &call.args {
+                if expand_arg(ctx, opts, arg)?.is_empty() { result = false; break }
+            }
+            result
+        },
+        _ => {
+            let mut result = false;
+            for arg in &call.args {
+                if !expand_arg(ctx, opts, arg)?.is_empty() { result = true; break }
+            }
+            result
+        }
+    };
+    if result { input.push('1') }
+    Ok(())
+}
+
+/*
+    \while{cond}{body}
+    The condition is expanded before every iteration and the loop runs while it is non-empty, so the
+    body has to change the definition table for the loop to end. Each iteration counts towards the
+    expansion limit, and the output and memory limits are checked after each.
+*/
+fn builtin_while(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, output: &mut String) -> Result<(), String> {
+    let (cond, body) = (&call.args[0], &call.args[1]);
+    while !expand_arg(ctx, opts, cond)?.is_empty() {
+        count_expansion(ctx, opts)?;
+        output.push_str(&expand_arg(ctx, opts, body)?);
+        check_output(ctx, opts, output, input.len())?;
+    }
+    Ok(())
+}
+
+/*
+    \foreachrow{file}{body}
+    Expands the body once for every row of a CSV file after the header row, with #name standing for
+    the column headed name, #1 for the first column, and # for the number of the row, counting from
+    1. Fields are put in as text, so they are not expanded, and missing ones are empty. Each row
+    counts towards the expansion limit, like an iteration of \while.
+*/
+fn builtin_foreachrow(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, output: &mut String) -> Result<(), String> {
+    let path = ctx.input_path(opts, &call.args[0]);
+    let text = String::from_utf8(read_included(ctx, opts, &path)?).map_err(|_| "Include error.".to_string())?;
+    let mut rows = csv::parse(&text)?.into_iter();
+    let header = rows.next().unwrap_or_default();
+    let segments = compile_body(&call.args[1]);
+    for (number, row) in rows.enumerate() {
+        count_expansion(ctx, opts)?;
+        let mut body = String::new();
+        for segment in &segments {
+            match segment {
+                Segment::Literal(reversed) => body.extend(reversed.chars().rev()),
+                Segment::Arg => body.push_str(&(number + 1).to_string()),
+                Segment::Named(key) => {
+                    let column = match header.iter().position(|name| name.trim() == key) {
+                        Some(column) => column,
+                        None => match key.parse::<usize>() {
+                            Ok(column) if column >= 1 => column - 1,
+                            _ => return Err(format!("No column {} in foreachrow.", key))
+                        }
+                    };
+                    body.push_str(&literal_body(row.get(column).map_or("", |field| field)));
+                }
+            }
+        }
+        output.push_str(&expand_arg(ctx, opts, &body)?);
+        check_output(ctx, opts, output, input.len())?;
+    }
+    Ok(())
+}
+
+/*
+    \arg{i} and \argcount{} inside a variadic macro. Indices start at 1.
+*/
+fn builtin_variadic_arg(ctx: &mut Context, opts: &Options, call: Call, input: &mut Input, _: &mut String) -> Result<(), String> {
+    count_expansion(ctx, opts)?;
+    let index = expand_arg(ctx, opts, &call.args[0])?;
+    let args = match ctx.variadic_args.last() {
+        Some(args) => args,
+        None => return Err("Argument access outside a variadic macro.".to_string())
+    };
+    let value = if call.name == "argcount" {
+        args.len().to_string()
+    } else {
+        match index.trim().parse::<usize>() {
+            Ok(i) if i >= 1 && i <= args.len() => args[i - 1].clone(),
+            _ => return Err("Argument index out of range.".to_string())
+        }
+    };
+    input.push_text(&value);
+    Ok(())
+}
+
+/*
+    \begingroup{} and \endgroup{}
+*/
+fn builtin_group(ctx: &mut Context, opts: &Options, call: Call, _: &mut Input, _: &mut String) -> Result<(), String> {
+    ctx.generation += 1;
+    if call.name == "begingroup" { ctx.scopes.push(FxHashMap::default()) }
+    else if ctx.scopes.len() > 1 {
+        let scope = ctx.scopes.pop().unwrap();
+        if ctx.scoped_includes.last().is_some_and(|scoped| scoped.depth == ctx.scopes.len() + 1) {
+            let scoped = ctx.scoped_includes.pop().unwrap();
+            end_scoped_include(ctx, opts, scoped, scope)?;
+        }
+    }
+    else { return Err("Unmatched endgroup.".to_string()) }
+    Ok(())
+}
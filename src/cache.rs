@@ -0,0 +1,100 @@
+/*
+    Output cache for --cache. An entry is a file in the cache directory named by the hash of the
+    arguments and the input files. It lists the files the run included, one "hash path" line each,
+    and the paths \fileexists looked at, as "+ path" if they existed and "- path" if not, then an
+    empty line and the output. It is only used while all of those are unchanged.
+*/
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+
+use super::Definition;
+use super::sha256::{self, Digest, Sha256};
+
+/*
+    What a run looked at on the filesystem besides its input
+*/
+#[derive(Default)]
+pub(crate) struct Reads {
+    pub(crate) included: Vec<(String, Digest)>, // By \include, \import, and \foreachrow, with their hashes
+    pub(crate) probed: Vec<(String, bool)>, // By \fileexists, with whether they existed
+    pub(crate) unrepeatable: bool // Whether the output depends on more than that, e.g. on \random without --seed
+}
+
+// Length-prefixed so that no two different argument lists hash the same bytes
+fn hash_field(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update(&(bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+/*
+    Key of the entry for a run with the given arguments, input files and buffers, and definitions
+    from outside the document. The version is part of it so that an upgrade does not reuse output
+    an older version produced.
+*/
+pub(crate) fn key<'a>(args: &[String], inputs: impl Iterator<Item = &'a (String, Vec<u8>)>, definitions: &[Definition]) -> String {
+    let mut hasher = Sha256::default();
+    hash_field(&mut hasher, concat!("proj3 ", env!("CARGO_PKG_VERSION")).as_bytes());
+    for arg in args {
+        hash_field(&mut hasher, arg.as_bytes());
+    }
+    for (name, bytes) in inputs {
+        hash_field(&mut hasher, name.as_bytes());
+        hash_field(&mut hasher, bytes);
+    }
+    for definition in definitions {
+        hash_field(&mut hasher, definition.name.as_bytes());
+        hash_field(&mut hasher, definition.value.as_bytes());
+    }
+    sha256::hex(&hasher.finish())
+}
+
+/*
+    What a cached run read and its output, if there is an entry and none of what it read has changed
+*/
+pub(crate) fn lookup(dir: &str, key: &str) -> Option<(Reads, String)> {
+    let entry = fs::read_to_string(Path::new(dir).join(key)).ok()?;
+    let (included, output) = match entry.strip_prefix('\n') {
+        Some(output) => ("", output),
+        None => entry.split_once("\n\n")?
+    };
+    let mut reads = Reads::default();
+    for line in included.lines() {
+        match line.split_once(' ')? {
+            (exists @ ("+" | "-"), path) => {
+                if Path::new(path).exists() != (exists == "+") { return None }
+                reads.probed.push((path.to_string(), exists == "+"));
+            },
+            (hash, path) => {
+                let digest = sha256::digest(&fs::read(path).ok()?);
+                if sha256::hex(&digest) != hash { return None }
+                reads.included.push((path.to_string(), digest));
+            }
+        }
+    }
+    Some((reads, output.to_string()))
+}
+
+/*
+    Writes an entry, through a temporary file so that concurrent runs never see half of one
+*/
+pub(crate) fn store(dir: &str, key: &str, reads: &Reads, output: &str) -> io::Result<()> {
+    let mut paths = reads.included.iter().map(|(path, _)| path).chain(reads.probed.iter().map(|(path, _)| path));
+    if paths.any(|path| path.contains('\n')) { return Ok(()) } // Cannot be listed
+    if reads.unrepeatable { return Ok(()) }
+    let mut entry = String::new();
+    for (path, digest) in &reads.included {
+        entry.push_str(&format!("{} {}\n", sha256::hex(digest), path));
+    }
+    for (path, exists) in &reads.probed {
+        entry.push_str(&format!("{} {}\n", if *exists { '+' } else { '-' }, path));
+    }
+    entry.push('\n');
+    entry.push_str(output);
+    fs::create_dir_all(dir)?;
+    let temp = Path::new(dir).join(format!("{}.{}.tmp", key, process::id()));
+    fs::write(&temp, entry)?;
+    fs::rename(&temp, Path::new(dir).join(key))
+}
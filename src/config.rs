@@ -0,0 +1,155 @@
+/*
+    Project configuration for proj3.toml: defaults for the command line options, found in the
+    working directory or the nearest directory above it. Each top-level key names an option without
+    its dashes, e.g. chomp = true for --chomp, max-expansions = 5000 for --max-expansions 5000, or
+    flag = ["draft", "web"] for --flag draft --flag web, and the pairs under [define] become
+    --define name=value. Keys are checked against the options and what they take, so no value can
+    be taken for another option. Only this flat subset of TOML is understood. Relative paths in the values
+    of options that take files are relative to the directory of the file. Since the file may come from
+    any directory above, e.g. one of a checkout someone else controls, it cannot set the options
+    that run commands or write files.
+*/
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use super::frontmatter::unquote;
+
+pub(crate) const FILE_NAME: &str = "proj3.toml";
+
+// Options that run commands or write files, which only the command line and PROJ3_FLAGS may give
+const RESTRICTED_KEYS: &[&str] = &["allow-git", "allow-write", "cache", "post-filter", "source-map", "stream"];
+// Options that take no value, which a key can only turn on with true or leave off with false
+const SWITCHES: &[&str] = &[
+    "allow-shadow-builtins", "chomp", "coverage", "deterministic", "front-matter", "hash", "include-relative", "keep-comments",
+    "lenient-undef", "line-directives", "partial-output", "recover", "reindent", "squeeze-blank-lines", "strict-streams",
+    "strip-trailing-ws"
+];
+// Options that take one value, given once for each item of an array; --raw-delims takes its two as one
+const VALUED: &[&str] = &[
+    "append", "append-file", "buffer", "define", "dialect", "env-file", "env-prefix", "expand-tabs", "flag", "include-path",
+    "line-directive-template", "locale", "max-errors", "max-expansions", "max-memory", "max-output", "normalize", "passes",
+    "prepend", "prepend-file", "refs", "seed", "timeout", "verify-balanced"
+];
+// Options whose values are files to read, besides --buffer with its name=FILE
+const PATH_KEYS: &[&str] = &["append-file", "env-file", "include-path", "prepend-file", "refs"];
+
+/*
+    Path of the configuration file that applies to the working directory, if there is one, relative
+    to it, e.g. ../../proj3.toml
+*/
+pub(crate) fn find() -> Option<PathBuf> {
+    let mut dir = env::current_dir().ok()?;
+    let mut relative = PathBuf::new();
+    loop {
+        if dir.join(FILE_NAME).is_file() { return Some(relative.join(FILE_NAME)) }
+        if !dir.pop() { return None }
+        relative.push("..");
+    }
+}
+
+// A path given in the configuration file in dir, as the command line would give it
+fn rebase(dir: &Path, path: String) -> String {
+    if path == "-" || path.is_empty() || Path::new(&path).is_absolute() { return path }
+    dir.join(path).to_string_lossy().into_owned()
+}
+
+// Items of a one-line array such as ["a", 'b,c'], unquoted
+fn array_items(inner: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in inner.char_indices() {
+        match quote {
+            Some('"') if c == '\\' && !escaped => { escaped = true; continue },
+            Some(q) if c == q && !escaped => quote = None,
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ',' => {
+                items.push(inner[start..i].trim());
+                start = i + 1;
+            },
+            _ => {}
+        }
+        escaped = false;
+    }
+    items.push(inner[start..].trim());
+    // A trailing comma leaves an empty last item
+    if items.last().is_some_and(|item| item.is_empty()) { items.pop(); }
+    items.into_iter().map(unquote).collect()
+}
+
+/*
+    The command line arguments the configuration file in dir stands for, to be put before the real
+    ones so that those take precedence
+*/
+pub(crate) fn args(text: &str, dir: &Path) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut in_define = false;
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') { continue }
+        if let Some(section) = trimmed.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            if section.trim() != "define" {
+                return Err(format!("Unsupported section [{}] in {} on line {}.", section, FILE_NAME, i + 1));
+            }
+            in_define = true;
+            continue;
+        }
+        let Some((key, value)) = trimmed.split_once('=') else {
+            return Err(format!("Unsupported {} line {}.", FILE_NAME, i + 1));
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if key.is_empty() {
+            return Err(format!("Missing {} key on line {}.", FILE_NAME, i + 1));
+        }
+        if !in_define && RESTRICTED_KEYS.contains(&key) {
+            return Err(format!("{} cannot be set in {}, on line {}, since it runs commands or writes files; give --{} on the command line or in PROJ3_FLAGS.",
+                               key, FILE_NAME, i + 1, key));
+        }
+        if in_define {
+            args.push("--define".to_string());
+            args.push(format!("{}={}", key, unquote(value)));
+            continue;
+        }
+        let value_arg = |value: String| -> Result<String, String> {
+            // Which the option parser would take for an option, e.g. -q
+            if value.starts_with('-') {
+                return Err(format!("The value of {} in {} on line {} cannot start with -.", key, FILE_NAME, i + 1));
+            }
+            Ok(value)
+        };
+        let path_arg = |value: String| match key {
+            "buffer" => match value.split_once('=') {
+                Some((name, path)) => format!("{}={}", name, rebase(dir, path.to_string())),
+                None => value
+            },
+            _ if PATH_KEYS.contains(&key) => rebase(dir, value),
+            _ => value
+        };
+        let option = format!("--{}", key);
+        let items = match value.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            Some(inner) => array_items(inner),
+            None => vec![unquote(value)]
+        };
+        if SWITCHES.contains(&key) {
+            match value {
+                "true" => args.push(option),
+                "false" => {},
+                _ => return Err(format!("{} in {} on line {} expects true or false.", key, FILE_NAME, i + 1))
+            }
+        } else if key == "raw-delims" {
+            let [begin, end] = <[String; 2]>::try_from(items)
+                .map_err(|_| format!("raw-delims in {} on line {} expects an array of a begin and an end marker.", FILE_NAME, i + 1))?;
+            args.extend([option, value_arg(begin)?, value_arg(end)?]);
+        } else if VALUED.contains(&key) {
+            for item in items {
+                args.push(option.clone());
+                args.push(path_arg(value_arg(item)?));
+            }
+        } else {
+            return Err(format!("Unknown option {} in {} on line {}.", key, FILE_NAME, i + 1));
+        }
+    }
+    Ok(args)
+}
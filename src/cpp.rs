@@ -0,0 +1,232 @@
+/*
+    The C preprocessor dialect of --dialect cpp, translated into the native syntax before a file is
+    preprocessed: #define, #undef, #include, #ifdef, #ifndef, #else, #endif, and #error lines become
+    calls of the builtins, and in other lines every identifier that names a macro when it is reached
+    is expanded, outside string and character literals and comments. Directive lines are left out
+    of the output, but their line breaks stay in the text, in a branch never taken, so that lines
+    keep their numbers and locations stay right. Macro names are alphanumeric, so _ in a name
+    becomes ˍ (U+02CD), which no C identifier holds; that is how native text and \show refer to
+    such a macro. Macros with parameters, #if, and #elif are not supported.
+*/
+
+use super::{literal_body, push_literal};
+
+/*
+    The native form of a file written for the C preprocessor
+*/
+pub(crate) fn translate(text: &str) -> Result<String, String> {
+    let mut translated = String::with_capacity(text.len());
+    let mut conditionals = Vec::new(); // Whether each open #ifdef or #ifndef is past its #else, innermost last
+    let mut in_comment = false; // In a /* */ comment
+    let mut lines = text.split_inclusive('\n');
+    let mut number = 0;
+    while let Some(first) = lines.next() {
+        number += 1;
+        let start = number;
+        // A backslash at the end of a line joins it with the next
+        let mut line = String::new();
+        let mut breaks = 0;
+        let mut physical = first;
+        loop {
+            let content = physical.strip_suffix('\n');
+            if content.is_some() { breaks += 1 }
+            let content = content.unwrap_or(physical);
+            match (content.strip_suffix('\\'), physical.ends_with('\n')) {
+                (Some(joined), true) => {
+                    line.push_str(joined);
+                    let Some(next) = lines.next() else { break };
+                    number += 1;
+                    physical = next;
+                },
+                _ => {
+                    line.push_str(content);
+                    break;
+                }
+            }
+        }
+        let breaks = "\n".repeat(breaks);
+        if !in_comment && line.trim_start().starts_with('#') {
+            // Comments in a directive are left out, as if they were spaces
+            let mut after = false;
+            let code: Vec<&str> = split_comments(&line, &mut after).into_iter().filter(|&(comment, _)| !comment).map(|(_, code)| code).collect();
+            let directive = code.join(" ");
+            if translate_directive(directive.trim_start()[1..].trim(), start, &mut conditionals, &mut translated)? {
+                in_comment = after;
+                if !breaks.is_empty() { translated.push_str(&format!("\\if{{}}{{{}}}{{}}", breaks)) }
+                continue;
+            }
+        }
+        translate_text(&line, &mut in_comment, &mut translated);
+        translated.push_str(&breaks);
+    }
+    if !conditionals.is_empty() { return Err("Unterminated #ifdef or #ifndef.".to_string()) }
+    Ok(translated)
+}
+
+// The name a directive is about, which must be a macro name
+fn directive_name<'a>(rest: &'a str, directive: &str, line: usize) -> Result<(String, &'a str), String> {
+    let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+    let name = &rest[..end];
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return Err(format!("Missing macro name in #{} on line {}.", directive, line));
+    }
+    Ok((native_name(name), &rest[end..]))
+}
+
+// The macro name of a C identifier
+fn native_name(identifier: &str) -> String {
+    identifier.replace('_', "ˍ")
+}
+
+/*
+    Carries out a directive, returning false for those that are for the compiler, e.g. #pragma,
+    which stay in the text
+*/
+fn translate_directive(directive: &str, line: usize, conditionals: &mut Vec<bool>, translated: &mut String) -> Result<bool, String> {
+    let end = directive.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(directive.len());
+    let (keyword, rest) = (&directive[..end], directive[end..].trim_start());
+    match keyword {
+        "define" => {
+            let (name, value) = directive_name(rest, keyword, line)?;
+            if value.starts_with('(') {
+                return Err(format!("Macros with parameters are not supported by the cpp dialect, on line {}.", line));
+            }
+            translated.push_str(&format!("\\def{{{}}}{{", name));
+            translate_code(value.trim(), translated);
+            translated.push('}');
+        },
+        "undef" => {
+            let (name, _) = directive_name(rest, keyword, line)?;
+            translated.push_str(&format!("\\ifdef{{{0}}}{{\\undef{{{0}}}}}{{}}", name));
+        },
+        "include" => {
+            let path = rest.strip_prefix('"').and_then(|rest| rest.strip_suffix('"'))
+                .or_else(|| rest.strip_prefix('<').and_then(|rest| rest.strip_suffix('>')));
+            match path {
+                Some(path) => translated.push_str(&format!("\\include{{{}}}", literal_body(path))),
+                None => return Err(format!("Invalid #include on line {}.", line))
+            }
+        },
+        "ifdef" | "ifndef" => {
+            let (name, _) = directive_name(rest, keyword, line)?;
+            translated.push_str(&format!("\\{}{{{}}}{{", keyword, name));
+            conditionals.push(false);
+        },
+        "else" => match conditionals.last_mut() {
+            Some(past_else @ false) => {
+                *past_else = true;
+                translated.push_str("}{");
+            },
+            _ => return Err(format!("Unexpected #else on line {}.", line))
+        },
+        "endif" => match conditionals.pop() {
+            Some(true) => translated.push('}'),
+            Some(false) => translated.push_str("}{}"),
+            None => return Err(format!("Unexpected #endif on line {}.", line))
+        },
+        "error" => {
+            translated.push_str("\\error{");
+            translated.push_str(&literal_body(rest));
+            translated.push_str("}{}");
+        },
+        "if" | "elif" => return Err(format!("#{} is not supported by the cpp dialect, on line {}.", keyword, line)),
+        "" => {}, // The null directive
+        _ => return Ok(false)
+    }
+    Ok(true)
+}
+
+/*
+    A line split into code and comments, in order, with whether each part is a comment. A /* */
+    comment may go on past the line, as in_comment records for the next.
+*/
+fn split_comments<'a>(line: &'a str, in_comment: &mut bool) -> Vec<(bool, &'a str)> {
+    let mut parts = Vec::new();
+    let (mut start, mut quote) = (0, None);
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, next)| next);
+        if *in_comment {
+            if c == '*' && next == Some('/') {
+                chars.next();
+                parts.push((true, &line[start..i + 2]));
+                start = i + 2;
+                *in_comment = false;
+            }
+        } else if let Some(q) = quote {
+            if c == '\\' {
+                chars.next();
+            } else if c == q {
+                quote = None;
+            }
+        } else if c == '"' || c == '\'' {
+            quote = Some(c);
+        } else if c == '/' && next == Some('/') {
+            parts.push((false, &line[start..i]));
+            parts.push((true, &line[i..]));
+            return parts;
+        } else if c == '/' && next == Some('*') {
+            chars.next();
+            parts.push((false, &line[start..i]));
+            start = i;
+            *in_comment = true;
+        }
+    }
+    parts.push((*in_comment, &line[start..]));
+    parts
+}
+
+/*
+    A line of text, with its identifiers expanded if they name macros by the time they are
+    reached. Comments are copied as they are.
+*/
+fn translate_text(text: &str, in_comment: &mut bool, translated: &mut String) {
+    for (comment, part) in split_comments(text, in_comment) {
+        if comment {
+            part.chars().for_each(|c| push_literal(translated, c));
+        } else {
+            translate_code(part, translated);
+        }
+    }
+}
+
+// Code outside comments, with its identifiers expanded
+fn translate_code(text: &str, translated: &mut String) {
+    let mut chars = text.char_indices().peekable();
+    let mut quote = None;
+    while let Some((i, c)) = chars.next() {
+        if let Some(q) = quote {
+            if c == q { quote = None }
+            push_literal(translated, c);
+            if c == '\\' {
+                if let Some((_, escaped)) = chars.next() { push_literal(translated, escaped) }
+            }
+            continue;
+        }
+        if c.is_ascii_alphanumeric() || c == '_' {
+            // An identifier, or a number such as 1e5 or 0x1f
+            let mut end = i + c.len_utf8();
+            while let Some(&(j, next)) = chars.peek() {
+                if !(next.is_ascii_alphanumeric() || next == '_' || c.is_ascii_digit() && next == '.') { break }
+                end = j + next.len_utf8();
+                chars.next();
+            }
+            push_identifier(&text[i..end], translated);
+            continue;
+        }
+        if c == '"' || c == '\'' { quote = Some(c) }
+        push_literal(translated, c);
+    }
+}
+
+/*
+    A word, expanded if it names a macro when it is reached. Words that cannot be macro names, such
+    as numbers, are left alone.
+*/
+fn push_identifier(word: &str, translated: &mut String) {
+    if word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        translated.push_str(&format!("\\ifdef{{{0}}}{{\\{0}{{}}}}{{{1}}}", native_name(word), word));
+    } else {
+        translated.push_str(word);
+    }
+}
@@ -0,0 +1,14 @@
+/*
+    CRC-32 as in zip and gzip, for checking archive members and for \crc32
+*/
+
+pub(crate) fn checksum(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
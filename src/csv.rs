@@ -0,0 +1,53 @@
+/*
+    CSV for \foreachrow, as in RFC 4180: fields separated by commas, quoted with double quotes
+    when they contain commas, quotes, or line breaks, with "" for a quote inside quotes
+*/
+
+/*
+    The rows of a CSV text. Lines may end in CRLF or LF; a final line break does not start a row.
+*/
+pub(crate) fn parse(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if field.is_empty() => {
+                let start = line;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        },
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' { line += 1 }
+                            field.push(c);
+                        },
+                        None => return Err(format!("Unterminated quote in CSV from line {}.", start))
+                    }
+                }
+                if !matches!(chars.peek(), None | Some(',' | '\r' | '\n')) {
+                    return Err(format!("Text after a quoted field in CSV on line {}.", line));
+                }
+            },
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {},
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+                line += 1;
+            },
+            '"' => return Err(format!("Stray quote in CSV on line {}.", line)),
+            c => field.push(c)
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
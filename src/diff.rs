@@ -0,0 +1,107 @@
+/*
+    Line based unified diffs, as printed by `proj3 --test` for failing cases
+*/
+
+const CONTEXT: usize = 3;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert
+}
+
+/*
+    Edit script turning old into new, from the longest common subsequence of their lines
+*/
+fn edit_script(old: &[&str], new: &[&str]) -> Vec<Op> {
+    // lcs[i][j] is the LCS length of old[i..] and new[j..]
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push(Op::Equal);
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(Op::Delete);
+            i += 1;
+        } else {
+            ops.push(Op::Insert);
+            j += 1;
+        }
+    }
+    ops
+}
+
+fn push_line(diff: &mut String, prefix: char, line: &str) {
+    diff.push(prefix);
+    diff.push_str(line);
+    if !line.ends_with('\n') {
+        diff.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+// An empty range is numbered after the line it follows, as diff -u does
+fn hunk_range(start: usize, end: usize) -> String {
+    format!("{},{}", if end == start { start } else { start + 1 }, end - start)
+}
+
+/*
+    Unified diff from old to new with three lines of context, empty if they are equal
+*/
+pub fn unified_diff(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let ops = edit_script(&old_lines, &new_lines);
+    let mut diff = String::new();
+    if ops.iter().all(|&op| op == Op::Equal) {
+        return diff;
+    }
+    diff.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+
+    // Line numbers in old and new before each op
+    let mut positions = Vec::with_capacity(ops.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for &op in &ops {
+        positions.push((i, j));
+        if op != Op::Insert { i += 1 }
+        if op != Op::Delete { j += 1 }
+    }
+    positions.push((i, j));
+
+    let mut k = 0;
+    while k < ops.len() {
+        if ops[k] == Op::Equal {
+            k += 1;
+            continue;
+        }
+        // A hunk runs until more than twice the context of unchanged lines follow a change
+        let start = k.saturating_sub(CONTEXT);
+        let mut end = k;
+        let mut equal_run = 0;
+        while end < ops.len() && equal_run <= 2 * CONTEXT {
+            equal_run = if ops[end] == Op::Equal { equal_run + 1 } else { 0 };
+            end += 1;
+        }
+        end -= equal_run.saturating_sub(CONTEXT);
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        diff.push_str(&format!("@@ -{} +{} @@\n", hunk_range(old_start, old_end), hunk_range(new_start, new_end)));
+        for (&op, &(i, j)) in ops[start..end].iter().zip(&positions[start..end]) {
+            match op {
+                Op::Equal => push_line(&mut diff, ' ', old_lines[i]),
+                Op::Delete => push_line(&mut diff, '-', old_lines[i]),
+                Op::Insert => push_line(&mut diff, '+', new_lines[j])
+            }
+        }
+        k = end;
+    }
+    diff
+}
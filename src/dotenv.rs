@@ -0,0 +1,27 @@
+/*
+    Dotenv files for --env-file: KEY=VALUE lines, optionally starting with export and with the
+    value quoted as in front matter. Empty lines and lines starting with # are skipped.
+*/
+
+use super::frontmatter::unquote;
+
+/*
+    The pairs of a dotenv file, with their lines
+*/
+pub(crate) fn parse(text: &str) -> Result<Vec<(usize, String, String)>, String> {
+    let mut pairs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') { continue }
+        let trimmed = trimmed.strip_prefix("export ").unwrap_or(trimmed);
+        let Some((key, value)) = trimmed.split_once('=') else {
+            return Err(format!("Unsupported env file line {}.", i + 1));
+        };
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("Missing env file key on line {}.", i + 1));
+        }
+        pairs.push((i + 1, key.to_string(), unquote(value.trim())));
+    }
+    Ok(pairs)
+}
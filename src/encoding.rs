@@ -0,0 +1,181 @@
+/*
+    Encodings of the encoding builtins: base64 with the standard alphabet and padding, hex, and the
+    percent-encoding of URLs, and the escapes of the escaping builtins, as well as the splitting of
+    PROJ3_FLAGS into words
+*/
+
+pub(crate) const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn base64_encode(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| group | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(group >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+/*
+    Whitespace is skipped, so that wrapped lines decode. Otherwise the text must be as
+    base64_encode writes it: groups of four with the last one padded, and no bits set past the end
+    of the bytes.
+*/
+pub(crate) fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = text.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+    let padding = digits.iter().rev().take_while(|&&c| c == b'=').count();
+    if !digits.len().is_multiple_of(4) || padding > 2 { return None }
+    let mut bytes = Vec::with_capacity(digits.len() / 4 * 3);
+    let mut group = 0u32;
+    let mut bits = 0;
+    for &c in &digits[..digits.len() - padding] {
+        group = group << 6 | BASE64.iter().position(|&digit| digit == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((group >> bits) as u8);
+        }
+    }
+    (group & ((1 << bits) - 1) == 0).then_some(bytes)
+}
+
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/*
+    Either case is accepted, and whitespace between bytes is skipped
+*/
+pub(crate) fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    let digits: Vec<char> = text.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) { return None }
+    digits.chunks(2).map(|pair| Some((pair[0].to_digit(16)? << 4 | pair[1].to_digit(16)?) as u8)).collect()
+}
+
+/*
+    Every byte of the UTF-8 but the unreserved characters of RFC 3986 as %XX
+*/
+pub(crate) fn url_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte))
+        }
+    }
+    encoded
+}
+
+/*
+    + is left alone, as only form submissions use it for spaces
+*/
+pub(crate) fn url_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.bytes();
+    while let Some(byte) = chars.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let high = (chars.next()? as char).to_digit(16)?;
+        let low = (chars.next()? as char).to_digit(16)?;
+        bytes.push((high << 4 | low) as u8);
+    }
+    Some(bytes)
+}
+
+pub(crate) fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/*
+    Contents of a JSON string, without the quotes
+*/
+pub(crate) fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/*
+    A single word for POSIX shells: quoted in single quotes, inside which nothing is special, with
+    each single quote closing the quotes, escaped, and reopening them
+*/
+pub(crate) fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/*
+    Words of a command line as a POSIX shell splits them, without expansions: whitespace separates
+    words, nothing is special inside single quotes, and a backslash escapes the next character
+    outside quotes and ", \, $, and ` inside double quotes
+*/
+pub(crate) fn shell_split(text: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None; // None between words, so that '' is an empty word
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            _ if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated quote.".to_string())
+                    }
+                }
+            },
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some('\n') => {},
+                            Some(c) => { word.push('\\'); word.push(c) },
+                            None => return Err("Unterminated quote.".to_string())
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("Unterminated quote.".to_string())
+                    }
+                }
+            },
+            '\\' => match chars.next() {
+                Some('\n') => {},
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => word.get_or_insert_with(String::new).push('\\')
+            },
+            _ => word.get_or_insert_with(String::new).push(c)
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
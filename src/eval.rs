@@ -0,0 +1,456 @@
+/*
+    Evaluates tokens from the lexer. Text is copied to the output, and each \name{ is either handled
+    by the builtin registered under that name, see builtins::Registry, or expanded as a user macro by
+    pushing its body back onto the input. Builtins may likewise push text, write output, or change
+    the definition tables.
+*/
+
+use std::time::Instant;
+
+use super::{ActiveExpansion, Buffered, Context, Options, OutputSpan, Segment, Symbol, error_summary, literal_body};
+use super::builtins::{Call, read_args};
+use super::lexer::{self, Input, TokenKind, read_arg, read_trailing_args};
+
+const PLACEHOLDER: &str = "[?]"; // Output in place of a call that failed, with --recover
+
+/*
+    A call to a user macro: \name{arg}, more groups for a variadic macro, or named arguments
+*/
+fn call_macro(ctx: &mut Context, opts: &Options, macro_name: &str, input: &mut Input, output: &mut String) -> Result<(), String> {
+    let arg = read_arg(input)?;
+    let name = ctx.resolve(macro_name);
+    if ctx.lookup(name).is_some_and(|m| m.variadic) {
+        let mut args = vec![arg];
+        args.append(&mut read_trailing_args(input)?);
+        check_cycle(ctx, name, args.join("}{"), input.len())?;
+        output.push_str(&expand_variadic(ctx, opts, name, args)?);
+    } else {
+        // Only macros with #key placeholders take named arguments, so other calls keep their groups
+        let keys: Vec<String> = ctx.lookup(name).map_or_else(Vec::new, |m| m.named_keys().map(str::to_string).collect());
+        let named = if keys.is_empty() { None } else { read_named_args(input, &arg, &keys)? };
+        let call_args = match &named {
+            Some(named) => named.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join(","),
+            None => arg.clone()
+        };
+        check_cycle(ctx, name, call_args, input.len())?;
+        push_expansion(ctx, opts, name, &arg, named.as_deref(), input)?;
+    }
+    Ok(())
+}
+
+/*
+    Processes the whole input and returns the output. Called recursively on arguments that have to
+    be expanded before a builtin can use them, see expand_arg. With --partial-output, what the
+    top-level call produced before an error is kept in the context.
+*/
+pub(crate) fn process_str(ctx: &mut Context, opts: &Options, input: &mut Input) -> Result<String, String> {
+    let top_level = ctx.nesting == 0;
+    let mut output = String::new();
+    match process_into(ctx, opts, input, &mut output) {
+        Ok(()) => Ok(output),
+        Err(e) => {
+            if top_level && opts.partial_output { ctx.partial_output = Some(output) }
+            Err(e)
+        }
+    }
+}
+
+fn process_into(ctx: &mut Context, opts: &Options, input: &mut Input, output: &mut String) -> Result<(), String> {
+    let outer_output_len = ctx.output_len;
+    ctx.buffered.push(Buffered { base: outer_output_len, counted: 0, chars: 0 });
+    ctx.nesting += 1;
+    loop {
+        // Where the next token starts, since the error does not say
+        let next_source = if opts.recover && ctx.nesting == 1 { input.next_source() } else { None };
+        let token = match lexer::next_token(input) {
+            Err(e) if opts.recover && e == lexer::BAD_NAME => {
+                if let Some(source) = next_source { ctx.cursor = source }
+                recover(ctx, opts, input, output, &e, 0)?;
+                continue;
+            },
+            token => token?
+        };
+        let at = token.as_ref().map_or(input.len(), |token| token.span.start);
+        let source = token.as_ref().and_then(|token| token.span.source);
+        if let Some(source) = source {
+            ctx.cursor = source;
+        }
+        check_output(ctx, opts, output, at)?;
+        check_deadline(ctx, opts, at)?;
+        let Some(token) = token else {
+            if ctx.nesting == 1 && !ctx.deferred.is_empty() {
+                // What \atend held back comes after everything else
+                ctx.held_len -= ctx.deferred.iter().map(|text| text.len()).sum::<usize>();
+                let deferred: String = ctx.deferred.drain(..).map(|text| literal_body(&text)).collect();
+                input.push_text(&deferred);
+                continue;
+            }
+            break;
+        };
+        let output_start = output.len();
+        let verbatim = source.is_some() && matches!(token.kind, TokenKind::Text(_));
+        match token.kind {
+            TokenKind::Text(text) => output.push_str(&text),
+            TokenKind::Escape(c) => output.push(c),
+            TokenKind::Open => output.push('{'),
+            TokenKind::Close => output.push('}'),
+            TokenKind::Name(name) => match ctx.builtins.get(&name) {
+                Some(builtin) => {
+                    // Expansions finished before a builtin processes its arguments must not count
+                    // as still in progress there
+                    finish_expansions(ctx, input.len());
+                    let args = read_args(builtin.arity, input)?;
+                    (builtin.handler)(ctx, opts, Call { name: &name, args }, input, output)?;
+                },
+                None if opts.recover && !is_macro(ctx, &name) => recover(ctx, opts, input, output, "Macro not defined.", 1)?,
+                None => call_macro(ctx, opts, &name, input, output)?
+            }
+        }
+        if ctx.nesting == 1 && output.len() > output_start {
+            record_output_span(ctx, output_start, verbatim);
+        }
+    }
+    let nesting = ctx.nesting;
+    ctx.active.retain(|e| e.nesting < nesting);
+    ctx.nesting -= 1;
+    // The caller counts the output again as it adds it to its own
+    if let Some(buffered) = ctx.buffered.pop() { ctx.output_chars -= buffered.chars }
+    ctx.output_len = outer_output_len;
+    Ok(())
+}
+
+fn is_macro(ctx: &mut Context, name: &str) -> bool {
+    let name = ctx.resolve(name);
+    ctx.is_defined(name)
+}
+
+/*
+    --recover: records an error that processing can go on after and puts PLACEHOLDER in the output
+    in place of the call. Fails once there have been --max-errors errors.
+*/
+pub(crate) fn record_error(ctx: &mut Context, opts: &Options, output: &mut String, error: &str) -> Result<(), String> {
+    ctx.errors.push(format!("{}: {}", ctx.location(), error));
+    if opts.max_errors != 0 && ctx.errors.len() >= opts.max_errors {
+        return Err(format!("{}, stopping at the --max-errors limit.", error_summary(ctx)));
+    }
+    output.push_str(PLACEHOLDER);
+    Ok(())
+}
+
+/*
+    --recover: records the error of a call that could not be read, and skips the input up to the
+    next backslash outside any group, where the next call can start, but not past the end of the
+    macro body being expanded, if any, so that the text after the call of the macro is kept. depth
+    is that of the group being read when the error happened.
+*/
+fn recover(ctx: &mut Context, opts: &Options, input: &mut Input, output: &mut String, error: &str, mut depth: usize) -> Result<(), String> {
+    record_error(ctx, opts, output, error)?;
+    let (nesting, len) = (ctx.nesting, input.len());
+    let bottom = ctx.active.iter().filter(|e| e.nesting == nesting && e.base_len < len).map(|e| e.base_len).max().unwrap_or(0);
+    while input.len() > bottom && !(depth == 0 && input.ends_with('\\')) {
+        match input.pop() {
+            Some('\\') => { input.pop(); },
+            Some('{') => depth += 1,
+            Some('}') => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/*
+    Notes where top-level output starting at output comes from, for --source-map and --line-directives.
+    Output that just continues the previous span is left to it.
+*/
+fn record_output_span(ctx: &mut Context, output: usize, verbatim: bool) {
+    let source = ctx.cursor;
+    let Some(spans) = ctx.output_spans.as_mut() else { return };
+    if let Some(last) = spans.last() {
+        let continues = if verbatim { last.verbatim && last.source + (output - last.output) == source }
+                        else { !last.verbatim && last.source == source };
+        if continues { return }
+    }
+    spans.push(OutputSpan { output, source, verbatim });
+}
+
+/*
+    Records the expansion of a macro and fails if it can never terminate: the same macro is already
+    being expanded with the same arguments and no definition has changed since, so processing would
+    just repeat itself. An expansion is in progress until the input below its body is reached; a call
+    at the very end of a body counts as nested, which catches tail recursion too.
+*/
+fn check_cycle(ctx: &mut Context, name: Symbol, args: String, input_len: usize) -> Result<(), String> {
+    finish_expansions(ctx, input_len);
+    let generation = ctx.generation;
+    if let Some(start) = ctx.active.iter().position(|e| e.name == name && e.args == args && e.generation == generation) {
+        let mut chain: Vec<String> = ctx.active[start..].iter().map(|e| format!("\\{}", ctx.symbols.name(e.name))).collect();
+        chain.push(format!("\\{}", ctx.symbols.name(name)));
+        let mut sites: Vec<String> = Vec::new();
+        for e in &ctx.active[start..] {
+            let site = format!("\\{} defined at {}", ctx.symbols.name(e.name), ctx.lookup(e.name).map_or("?", |m| &m.defined_at));
+            if !sites.contains(&site) { sites.push(site) }
+        }
+        return Err(format!("Expansion cycle: {} ({}).", chain.join(" -> "), sites.join(", ")));
+    }
+    ctx.active.push(ActiveExpansion { name, args, generation, nesting: ctx.nesting, base_len: input_len });
+    Ok(())
+}
+
+/*
+    Drops the expansions of this process_str call whose bodies have been read completely
+*/
+fn finish_expansions(ctx: &mut Context, input_len: usize) {
+    let nesting = ctx.nesting;
+    while ctx.active.last().is_some_and(|e| e.nesting == nesting && e.base_len > input_len) {
+        ctx.active.pop();
+    }
+}
+
+/*
+    Counts one expansion step against --max-expansions
+*/
+pub(crate) fn count_expansion(ctx: &mut Context, opts: &Options) -> Result<(), String> {
+    ctx.expansions += 1;
+    if opts.max_expansions != 0 && ctx.expansions > opts.max_expansions {
+        return Err("Expansion limit exceeded.".to_string());
+    }
+    Ok(())
+}
+
+/*
+    Checks --max-output and --max-memory as output, the output of the innermost process_str call so
+    far, grows. Output being buffered for a builtin by an inner call counts as well, so a runaway
+    argument is stopped too. Builtins that add to output many times, such as loops, call this every
+    time; input_len is where processing is, for the error.
+*/
+pub(crate) fn check_output(ctx: &mut Context, opts: &Options, output: &str, input_len: usize) -> Result<(), String> {
+    let Some(buffered) = ctx.buffered.last_mut() else { return Ok(()) };
+    let base = buffered.base;
+    if opts.max_output != 0 && output.len() > buffered.counted {
+        let chars = output[buffered.counted..].chars().count();
+        buffered.counted = output.len();
+        buffered.chars += chars;
+        ctx.output_chars += chars;
+        if ctx.output_chars > opts.max_output {
+            return Err(format!("Output limit exceeded {}.", position(ctx, input_len)));
+        }
+    }
+    if opts.max_memory != 0 {
+        ctx.output_len = base + output.len();
+        check_memory(ctx, opts, input_len)?;
+    }
+    Ok(())
+}
+
+/*
+    Fails once the pending input, the buffered output, and the definition tables together hold more
+    than --max-memory bytes. The tables are only measured again after they change.
+*/
+fn check_memory(ctx: &mut Context, opts: &Options, input_len: usize) -> Result<(), String> {
+    let generation = ctx.generation;
+    let table_size = match ctx.table_size {
+        Some((measured, size)) if measured == generation => size,
+        _ => {
+            let size = ctx.scopes.iter().flat_map(|scope| scope.iter())
+                .map(|(name, macro_val)| ctx.symbols.name(*name).len() + macro_val.size()).sum();
+            ctx.table_size = Some((generation, size));
+            size
+        }
+    };
+    let output_len = ctx.output_len + ctx.held_len;
+    if input_len + output_len + table_size > opts.max_memory {
+        return Err(format!("Memory limit exceeded at {}: {} bytes of pending input, {} of output, {} of definitions.",
+                           ctx.location(), input_len, output_len, table_size));
+    }
+    Ok(())
+}
+
+const POSITION_CHAIN_LEN: usize = 8;
+
+/*
+    Where processing is for errors that abort it: the location and the expansions whose text is still
+    being processed. Repeated expansions of the same macro are collapsed and only the innermost
+    POSITION_CHAIN_LEN are listed, since runaway recursion makes the chain arbitrarily long.
+*/
+fn position(ctx: &Context, input_len: usize) -> String {
+    let mut runs: Vec<(Symbol, usize)> = Vec::new();
+    // Those of enclosing process_str calls are in progress however far the inner input has got
+    for e in ctx.active.iter().filter(|e| e.nesting < ctx.nesting || e.base_len <= input_len) {
+        match runs.last_mut() {
+            Some((name, count)) if *name == e.name => *count += 1,
+            _ => runs.push((e.name, 1))
+        }
+    }
+    let mut chain: Vec<String> = runs.iter().map(|&(name, count)| match count {
+        1 => format!("\\{}", ctx.symbols.name(name)),
+        _ => format!("\\{} ({} times)", ctx.symbols.name(name), count)
+    }).collect();
+    if chain.len() > POSITION_CHAIN_LEN {
+        chain.drain(..chain.len() - POSITION_CHAIN_LEN);
+        chain.insert(0, "...".to_string());
+    }
+    if chain.is_empty() {
+        format!("at {}", ctx.location())
+    } else {
+        format!("at {} while expanding {}", ctx.location(), chain.join(" -> "))
+    }
+}
+
+const TIMEOUT_CHECK_INTERVAL: usize = 4096;
+
+/*
+    Fails once the --timeout deadline has passed
+*/
+fn check_deadline(ctx: &mut Context, opts: &Options, input_len: usize) -> Result<(), String> {
+    ctx.steps += 1;
+    if let Some(deadline) = ctx.deadline {
+        if ctx.steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && Instant::now() >= deadline {
+            return Err(format!("Timed out after {} seconds {}.", opts.timeout, position(ctx, input_len)));
+        }
+    }
+    Ok(())
+}
+
+/*
+    Pushes the expansion of a call onto the input, last segment first since the input is reversed
+*/
+fn push_expansion(ctx: &mut Context, opts: &Options, macro_name: Symbol, arg: &str, named: Option<&[(String, String)]>,
+                  input: &mut Input) -> Result<(), String> {
+    count_expansion(ctx, opts)?;
+    if let Some(message) = ctx.deprecated.get(&macro_name) {
+        warn!(opts, "{}: \\{} is deprecated: {}", ctx.location(), ctx.symbols.name(macro_name), message);
+        ctx.warnings += 1;
+    }
+    let macro_val = match ctx.lookup(macro_name) {
+        Some(macro_val) => macro_val,
+        None => return Err("Macro not defined.".to_string())
+    };
+    let arg = match &macro_val.default {
+        Some(default) if arg.is_empty() => default,
+        _ => arg
+    };
+    for segment in macro_val.segments.iter().rev() {
+        match segment {
+            Segment::Literal(reversed) => input.push_str(reversed),
+            Segment::Arg => input.push_text(arg),
+            Segment::Named(key) => match named {
+                Some(named) => match named.iter().find(|(name, _)| name == key) {
+                    Some((_, value)) => input.push_text(value),
+                    None => return Err("Named argument not given.".to_string())
+                },
+                None => {
+                    input.push_text(key);
+                    input.push_text(arg);
+                }
+            }
+        }
+    }
+    if let (Some(site), Some(coverage)) = (macro_val.site, &mut ctx.coverage) {
+        coverage.sites[site].2 = true;
+    }
+    Ok(())
+}
+
+fn expand_macro(ctx: &mut Context, opts: &Options, macro_name: Symbol, arg: &str, named: Option<&[(String, String)]>) -> Result<String, String> {
+    let mut expansion = Input::new(String::new());
+    push_expansion(ctx, opts, macro_name, arg, named, &mut expansion)?;
+    Ok(expansion.into_reversed().chars().rev().collect())
+}
+
+/*
+    Expands a variadic macro, whose body can refer to its argument groups through \argcount{} and
+    \arg{i}. The body is expanded right away so the groups are available to it, and the result is
+    returned for output.
+*/
+fn expand_variadic(ctx: &mut Context, opts: &Options, macro_name: Symbol, args: Vec<String>) -> Result<String, String> {
+    let body = expand_macro(ctx, opts, macro_name, &args[0], None)?;
+    ctx.variadic_args.push(args);
+    let expanded = expand_arg(ctx, opts, &body);
+    ctx.variadic_args.pop();
+    expanded
+}
+
+/*
+    Parses a call argument of the form key=value[,key=value...] for named arguments. Returns None
+    if the argument is not of that form or gives a key that is not among the macro's placeholders,
+    in which case it is an ordinary positional argument.
+*/
+fn parse_named_args(arg: &str, keys: &[String]) -> Option<Vec<(String, String)>> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut prev_is_escaping_backslash = false;
+    for (i, c) in arg.char_indices() {
+        if !prev_is_escaping_backslash {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                ',' if depth == 0 => {
+                    parts.push(&arg[start..i]);
+                    start = i + 1;
+                },
+                _ => {}
+            }
+        }
+        prev_is_escaping_backslash = c == '\\' && !prev_is_escaping_backslash;
+    }
+    parts.push(&arg[start..]);
+
+    let mut named = Vec::new();
+    for part in parts {
+        let (key, value) = part.split_once('=')?;
+        let key = key.trim();
+        if !keys.iter().any(|known| known == key) { return None }
+        named.push((key.to_string(), value.trim().to_string()));
+    }
+    Some(named)
+}
+
+/*
+    Collects the named arguments of a call like \box{width=10}{height=4} to a macro with the given
+    placeholders. Following groups are consumed for as long as they are key=value groups for them too.
+*/
+fn read_named_args(input: &mut Input, arg: &str, keys: &[String]) -> Result<Option<Vec<(String, String)>>, String> {
+    let mut named = match parse_named_args(arg, keys) {
+        Some(named) => named,
+        None => return Ok(None)
+    };
+    while input.ends_with('{') {
+        input.pop();
+        let group = read_arg(input)?;
+        match parse_named_args(&group, keys) {
+            Some(mut more) => named.append(&mut more),
+            None => {
+                input.push_text(&format!("{{{}}}", group));
+                break;
+            }
+        }
+    }
+    Ok(Some(named))
+}
+
+/*
+    Drops a single newline at the front of the (reversed) input, used by --chomp
+*/
+pub(crate) fn chomp_newline(opts: &Options, input: &mut Input) {
+    if opts.chomp && input.ends_with('\n') {
+        input.pop();
+    }
+}
+
+/*
+    Re-feeds the taken branch of a conditional into the input. Conditionals are lazy: branches are
+    only captured as raw text, and the branch that is not taken is discarded without ever being
+    expanded, so it may call undefined macros or include missing files.
+*/
+pub(crate) fn push_taken_branch(opts: &Options, input: &mut Input, branch: &str) {
+    chomp_newline(opts, input);
+    input.push_text(branch);
+}
+
+/*
+    Fully expands a macro argument in the current definition table
+*/
+pub(crate) fn expand_arg(ctx: &mut Context, opts: &Options, arg: &str) -> Result<String, String> {
+    process_str(ctx, opts, &mut Input::new(arg.chars().rev().collect()))
+}
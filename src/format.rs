@@ -0,0 +1,106 @@
+/*
+    Number formatting for \format, and the padding it shares with \padleft, \padright, and \center. A spec is [align][0][width][,][.precision]: the alignment is < for
+    left, ^ for centered, or > for right, the default, 0 pads with zeros after the sign instead of
+    aligning, the comma groups the digits before the point in threes, and the precision is the
+    number of digits after the point, rounded. Widths count characters.
+*/
+
+pub(crate) const MAX_WIDTH: usize = 1024; // Of widths and precisions, so that a typo cannot ask for gigabytes
+
+struct Spec {
+    align: char,
+    zero: bool,
+    width: usize,
+    grouped: bool,
+    precision: Option<usize>
+}
+
+fn parse_spec(spec: &str) -> Option<Spec> {
+    let mut rest = spec.trim();
+    let align = match rest.chars().next() {
+        Some(c @ ('<' | '^' | '>')) => {
+            rest = &rest[1..];
+            c
+        },
+        _ => '>'
+    };
+    let zero = rest.starts_with('0');
+    if zero { rest = &rest[1..] }
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let width = if digits == 0 { 0 } else { rest[..digits].parse().ok()? };
+    rest = &rest[digits..];
+    let grouped = rest.starts_with(',');
+    if grouped { rest = &rest[1..] }
+    let precision = match rest.strip_prefix('.') {
+        Some(precision) => {
+            rest = "";
+            Some(precision.parse().ok()?)
+        },
+        None => None
+    };
+    if width > MAX_WIDTH || precision.is_some_and(|precision| precision > MAX_WIDTH) { return None }
+    rest.is_empty().then_some(Spec { align, zero, width, grouped, precision })
+}
+
+fn group_thousands(digits: &str) -> String {
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) { grouped.push(',') }
+        grouped.push(c);
+    }
+    grouped
+}
+
+/*
+    The value formatted by the spec, or None if either does not parse. Integers without a precision
+    are formatted exactly, anything else as a 64-bit float.
+*/
+pub(crate) fn format_number(spec: &str, value: &str) -> Option<String> {
+    let spec = parse_spec(spec)?;
+    let value = value.trim();
+    let number = match (value.parse::<i128>(), spec.precision) {
+        (Ok(n), None) => n.to_string(),
+        (Ok(n), Some(precision)) => format!("{:.*}", precision, n as f64),
+        (Err(_), precision) => {
+            let n: f64 = value.parse().ok().filter(|n: &f64| n.is_finite())?;
+            match precision {
+                Some(precision) => format!("{:.*}", precision, n),
+                None => n.to_string()
+            }
+        }
+    };
+    let (sign, unsigned) = match number.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", number.as_str())
+    };
+    let (int, frac) = match unsigned.split_once('.') {
+        Some((int, frac)) => (int, Some(frac)),
+        None => (unsigned, None)
+    };
+    let mut digits = if spec.grouped { group_thousands(int) } else { int.to_string() };
+    if let Some(frac) = frac {
+        digits.push('.');
+        digits.push_str(frac);
+    }
+    if spec.zero {
+        let zeros = spec.width.saturating_sub(sign.len() + digits.len());
+        return Some(format!("{}{}{}", sign, "0".repeat(zeros), digits));
+    }
+    Some(pad(&format!("{}{}", sign, digits), spec.width, ' ', spec.align))
+}
+
+/*
+    Text padded with fill to width characters, aligned by < for left, ^ for centered, or > for
+    right. Text that is already as wide is returned as is. Centered text gets the odd fill
+    character on the right.
+*/
+pub(crate) fn pad(text: &str, width: usize, fill: char, align: char) -> String {
+    let pad = width.saturating_sub(text.chars().count());
+    let (left, right) = match align {
+        '<' => (0, pad),
+        '^' => (pad / 2, pad - pad / 2),
+        _ => (pad, 0)
+    };
+    let fill = |n| std::iter::repeat_n(fill, n).collect::<String>();
+    format!("{}{}{}", fill(left), text, fill(right))
+}
@@ -0,0 +1,82 @@
+/*
+    Front matter for --front-matter: a block of metadata at the very top of an input file, YAML
+    between two --- lines or TOML between two +++ lines, whose pairs become predefined macros. Only
+    flat key: value (YAML) and key = value (TOML) pairs are understood, optionally quoted; nested
+    mappings and lists, indented in YAML or in braces or brackets, are errors rather than pairs.
+*/
+
+use std::io::BufRead;
+
+#[derive(Default)]
+pub(crate) struct FrontMatter {
+    pub(crate) pairs: Vec<(usize, String, String)>, // Line, key, and value
+    pub(crate) lines: usize, // Lines taken up by the block
+    pub(crate) rest: String // Text read past the block, or the whole first line if there is none
+}
+
+pub(crate) fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        return value[1..value.len() - 1].replace("''", "'");
+    }
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let mut unquoted = String::new();
+        let mut chars = value[1..value.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                unquoted.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => unquoted.push('\n'),
+                Some('t') => unquoted.push('\t'),
+                Some(c) => unquoted.push(c),
+                None => unquoted.push('\\')
+            }
+        }
+        return unquoted;
+    }
+    // A comment after an unquoted value
+    match value.find(" #") {
+        Some(end) => value[..end].trim_end().to_string(),
+        None => value.to_string()
+    }
+}
+
+/*
+    Reads the front matter block at the start of reader, if there is one
+*/
+pub(crate) fn read_front_matter(reader: &mut impl BufRead) -> Result<FrontMatter, String> {
+    let mut first = String::new();
+    reader.read_line(&mut first).map_err(|e| e.to_string())?;
+    let (delimiter, separator) = match first.trim_end() {
+        "---" => ("---", ':'),
+        "+++" => ("+++", '='),
+        _ => return Ok(FrontMatter { pairs: Vec::new(), lines: 0, rest: first })
+    };
+    let mut pairs = Vec::new();
+    let mut lines = 1;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Err("Unterminated front matter.".to_string());
+        }
+        lines += 1;
+        let trimmed = line.trim();
+        if trimmed == delimiter {
+            return Ok(FrontMatter { pairs, lines, rest: String::new() });
+        }
+        if trimmed.is_empty() || trimmed.starts_with('#') { continue }
+        let value_start = trimmed.split_once(separator).map_or("", |(_, value)| value.trim_start());
+        if separator == ':' && line.starts_with([' ', '\t']) || trimmed.starts_with("- ") || value_start.starts_with(['{', '[']) {
+            return Err(format!("Unsupported nested front matter on line {}.", lines));
+        }
+        let Some((key, value)) = trimmed.split_once(separator) else {
+            return Err(format!("Unsupported front matter on line {}.", lines));
+        };
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_alphanumeric()) {
+            return Err("Non-alphanumeric in front matter key.".to_string());
+        }
+        pairs.push((lines, key.to_string(), unquote(value.trim())));
+    }
+}
@@ -0,0 +1,57 @@
+/*
+    FxHash, the multiply-rotate hash used inside rustc. It is much cheaper than the default SipHash
+    for the small integer keys of the definition tables, but it is not resistant to collision
+    attacks, so it is only used for maps keyed by interned symbols, never by names from the input.
+    The siphash feature goes back to the default hasher, to compare them.
+*/
+
+#![cfg_attr(feature = "siphash", allow(dead_code, unused_imports))]
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+#[derive(Default, Clone, Copy)]
+pub struct FxHasher {
+    hash: u64
+}
+
+impl FxHasher {
+    fn add_to_hash(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+}
+
+impl Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.add_to_hash(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        for &byte in chunks.remainder() {
+            self.add_to_hash(byte as u64);
+        }
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.add_to_hash(n as u64);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.add_to_hash(n);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.add_to_hash(n as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+#[cfg(not(feature = "siphash"))]
+pub type FxHashMap<K, V> = HashMap<K, V, BuildHasherDefault<FxHasher>>;
+#[cfg(feature = "siphash")]
+pub type FxHashMap<K, V> = HashMap<K, V>;
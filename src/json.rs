@@ -0,0 +1,220 @@
+/*
+    JSON for \jsonget, with the json feature: a parser into Value and lookup by JSON Pointer
+    (RFC 6901). Numbers are kept as written, since they are only ever put back into text.
+*/
+
+use super::encoding;
+
+const MAX_DEPTH: usize = 256; // Of nested arrays and objects, so that parsing cannot overflow the stack
+
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>) // In the order written, a repeated key is found first
+}
+
+pub(crate) fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser { bytes: text.as_bytes(), at: 0 };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.at != parser.bytes.len() { return Err(parser.error()) }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    at: usize
+}
+
+impl Parser<'_> {
+    fn error(&self) -> String {
+        format!("Invalid JSON at byte {}.", self.at)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.bytes.get(self.at).is_some_and(|c| b" \t\r\n".contains(c)) { self.at += 1 }
+    }
+
+    fn eat(&mut self, expected: &[u8]) -> Result<(), String> {
+        if !self.bytes[self.at..].starts_with(expected) { return Err(self.error()) }
+        self.at += expected.len();
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH { return Err("JSON nested too deeply.".to_string()) }
+        self.skip_whitespace();
+        match self.bytes.get(self.at) {
+            Some(b'n') => self.eat(b"null").map(|_| Value::Null),
+            Some(b't') => self.eat(b"true").map(|_| Value::Bool(true)),
+            Some(b'f') => self.eat(b"false").map(|_| Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => {
+                self.at += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.eat(b"]").is_ok() { return Ok(Value::Array(items)) }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    self.skip_whitespace();
+                    if self.eat(b"]").is_ok() { return Ok(Value::Array(items)) }
+                    self.eat(b",")?;
+                }
+            },
+            Some(b'{') => {
+                self.at += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.eat(b"}").is_ok() { return Ok(Value::Object(members)) }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.eat(b":")?;
+                    members.push((key, self.value(depth + 1)?));
+                    self.skip_whitespace();
+                    if self.eat(b"}").is_ok() { return Ok(Value::Object(members)) }
+                    self.eat(b",")?;
+                }
+            },
+            Some(b'-' | b'0'..=b'9') => self.number().map(Value::Number),
+            _ => Err(self.error())
+        }
+    }
+
+    fn number(&mut self) -> Result<String, String> {
+        let start = self.at;
+        let digits = |parser: &mut Self| {
+            let from = parser.at;
+            while parser.bytes.get(parser.at).is_some_and(u8::is_ascii_digit) { parser.at += 1 }
+            parser.at > from
+        };
+        let _ = self.eat(b"-");
+        if self.eat(b"0").is_err() && !digits(self) { return Err(self.error()) }
+        if self.eat(b".").is_ok() && !digits(self) { return Err(self.error()) }
+        if matches!(self.bytes.get(self.at), Some(b'e' | b'E')) {
+            self.at += 1;
+            if matches!(self.bytes.get(self.at), Some(b'+' | b'-')) { self.at += 1 }
+            if !digits(self) { return Err(self.error()) }
+        }
+        Ok(String::from_utf8_lossy(&self.bytes[start..self.at]).into_owned())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.eat(b"\"")?;
+        let mut bytes = Vec::new();
+        loop {
+            let Some(&c) = self.bytes.get(self.at) else { return Err(self.error()) };
+            self.at += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&escape) = self.bytes.get(self.at) else { return Err(self.error()) };
+                    self.at += 1;
+                    let c = match escape {
+                        b'"' | b'\\' | b'/' => escape as char,
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => {
+                            self.at -= 1; // At the escape, for the error
+                            return Err(self.error());
+                        }
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                },
+                0..=0x1f => {
+                    self.at -= 1;
+                    return Err(self.error());
+                },
+                _ => bytes.push(c)
+            }
+        }
+        // Valid, as the input is a str and escapes are pushed as whole characters
+        String::from_utf8(bytes).map_err(|_| self.error())
+    }
+
+    // After \u, with surrogate pairs combined
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            self.eat(b"\\u")?;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) { return Err(self.error()) }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error())
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.bytes.get(self.at..self.at + 4).ok_or_else(|| self.error())?;
+        let code = digits.iter().try_fold(0, |code, &c| Some(code << 4 | (c as char).to_digit(16)?));
+        self.at += 4;
+        code.ok_or_else(|| self.error())
+    }
+}
+
+/*
+    Value at a JSON pointer such as /items/0/name, where ~1 stands for / and ~0 for ~ in a key
+*/
+pub(crate) fn lookup<'a>(value: &'a Value, pointer: &str) -> Option<&'a Value> {
+    if pointer.is_empty() { return Some(value) }
+    let mut value = value;
+    for token in pointer.strip_prefix('/')?.split('/') {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        value = match value {
+            Value::Object(members) => &members.iter().find(|(key, _)| *key == token)?.1,
+            Value::Array(items) if token == "0" || !token.starts_with('0') => items.get(token.parse::<usize>().ok()?)?,
+            _ => return None
+        };
+    }
+    Some(value)
+}
+
+/*
+    Text a value expands to: strings without their quotes, anything else as compact JSON
+*/
+pub(crate) fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        _ => {
+            let mut text = String::new();
+            write_json(value, &mut text);
+            text
+        }
+    }
+}
+
+fn write_json(value: &Value, text: &mut String) {
+    match value {
+        Value::Null => text.push_str("null"),
+        Value::Bool(b) => text.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => text.push_str(n),
+        Value::String(s) => text.push_str(&format!("\"{}\"", encoding::json_escape(s))),
+        Value::Array(items) => {
+            text.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 { text.push(',') }
+                write_json(item, text);
+            }
+            text.push(']');
+        },
+        Value::Object(members) => {
+            text.push('{');
+            for (i, (key, item)) in members.iter().enumerate() {
+                if i > 0 { text.push(',') }
+                text.push_str(&format!("\"{}\":", encoding::json_escape(key)));
+                write_json(item, text);
+            }
+            text.push('}');
+        }
+    }
+}
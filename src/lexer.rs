@@ -0,0 +1,291 @@
+/*
+    Splits the input into tokens for eval. The input is a stack, reversed so that the next character
+    is at the end, and expansions are pushed back onto it, so tokens are read one at a time as the
+    evaluator asks for them. Builtins read their arguments as raw text with the read_ functions.
+*/
+
+/*
+    A stretch of the input that still holds the text of a source file, see Input
+*/
+struct Region {
+    bottom: usize, // Stack positions bottom..top
+    top: usize,
+    origin: usize // The character at stack position p has source offset origin - p - 1
+}
+
+/*
+    The input stack. Besides the text it tracks which parts of it are still the unchanged text of a
+    source file, the document or an \include, so that tokens can be traced back to where they were
+    written. Text pushed by expansions has no source offset of its own.
+*/
+pub(crate) struct Input {
+    text: String, // Reversed, the next character is at the end
+    regions: Vec<Region> // Bottom first, cut back to the length of the input before anything is pushed
+}
+
+impl Input {
+    /*
+        Input with no source of its own, e.g. an argument expanded by a builtin
+    */
+    pub(crate) fn new(reversed: String) -> Self {
+        Input { text: reversed, regions: Vec::new() }
+    }
+
+    /*
+        Input holding the text of a source file that starts at the given source offset
+    */
+    pub(crate) fn from_source(reversed: String, start: usize) -> Self {
+        let mut input = Input::new(String::new());
+        input.push_source(&reversed, start);
+        input
+    }
+
+    pub(crate) fn push_source(&mut self, reversed: &str, start: usize) {
+        self.truncate_regions();
+        let bottom = self.text.len();
+        self.text.push_str(reversed);
+        self.regions.push(Region { bottom, top: self.text.len(), origin: start + self.text.len() });
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<char> {
+        self.text.pop()
+    }
+
+    /*
+        Drops the parts of the regions that have been read, before other text takes their place
+    */
+    fn truncate_regions(&mut self) {
+        let len = self.text.len();
+        while let Some(region) = self.regions.last_mut() {
+            if region.top <= len { break }
+            region.top = len;
+            if region.top > region.bottom { break }
+            self.regions.pop();
+        }
+    }
+
+    pub(crate) fn into_reversed(self) -> String {
+        self.text
+    }
+
+    pub(crate) fn push(&mut self, c: char) {
+        self.truncate_regions();
+        self.text.push(c);
+    }
+
+    /*
+        Pushes text that is already reversed
+    */
+    pub(crate) fn push_str(&mut self, reversed: &str) {
+        self.truncate_regions();
+        self.text.push_str(reversed);
+    }
+
+    /*
+        Pushes text so that it is read next, first character first
+    */
+    pub(crate) fn push_text(&mut self, text: &str) {
+        self.truncate_regions();
+        self.text.extend(text.chars().rev());
+    }
+
+    pub(crate) fn ends_with(&self, c: char) -> bool {
+        self.text.ends_with(c)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.text.len()
+    }
+
+    /*
+        Source offset of the next character to read, if it comes unchanged from a source file
+    */
+    pub(crate) fn next_source(&self) -> Option<usize> {
+        self.source_offset(self.len())
+    }
+
+    /*
+        Source offset of the next character to read when the input has the given length, if it
+        comes unchanged from a source file
+    */
+    fn source_offset(&self, len: usize) -> Option<usize> {
+        let p = len.checked_sub(1)?;
+        let region = self.regions.iter().rev().find(|region| region.bottom <= p)?;
+        if p < region.top { Some(region.origin - p - 1) } else { None }
+    }
+
+    /*
+        Length the input has once the characters that share the provenance of the next one have
+        been read, so text runs can be split where their source changes
+    */
+    fn run_end(&self) -> usize {
+        let p = self.text.len().saturating_sub(1);
+        match self.regions.iter().rev().find(|region| region.bottom <= p) {
+            Some(region) if p < region.top => region.bottom,
+            Some(region) => region.top,
+            None => 0
+        }
+    }
+}
+
+/*
+    Where a token starts, as the length of the input before it, and as a source offset if the token
+    is text from a source file that no expansion has touched
+*/
+#[derive(Copy, Clone)]
+pub(crate) struct Span {
+    pub(crate) start: usize,
+    pub(crate) source: Option<usize>
+}
+
+pub(crate) enum TokenKind {
+    Text(String), // Run of characters without backslashes or braces, or \ before a character that is not escapable
+    Escape(char), // \\, \#, \%, \{, or \}, standing for the character itself
+    Open, // Unescaped {
+    Close, // Unescaped }
+    Name(String) // \name{, a call; the { opening its first argument is part of the token
+}
+
+pub(crate) struct Token {
+    pub(crate) kind: TokenKind,
+    pub(crate) span: Span
+}
+
+pub(crate) const BAD_NAME: &str = "Non-alphanumeric in macro name"; // Recovered from with --recover
+
+/*
+    Reads the next token, or None at the end of the input. A name may contain letters, digits, and
+    colons for imported macros, but must start with a letter or digit and be followed by {.
+*/
+pub(crate) fn next_token(input: &mut Input) -> Result<Option<Token>, String> {
+    let start = input.len();
+    let source = input.source_offset(start);
+    let run_end = input.run_end();
+    let c = match input.pop() {
+        Some(c) => c,
+        None => return Ok(None)
+    };
+    let kind = match c {
+        '{' => TokenKind::Open,
+        '}' => TokenKind::Close,
+        '\\' => match input.pop() {
+            Some(u) if u == '\\' || u == '#' || u == '%' || u == '{' || u == '}' => TokenKind::Escape(u),
+            Some(u) if u.is_alphanumeric() => {
+                let mut name = u.to_string();
+                loop {
+                    match input.pop() {
+                        Some('{') => break,
+                        Some(u) if u.is_alphanumeric() || u == ':' => name.push(u),
+                        Some(_) => return Err(BAD_NAME.to_string()),
+                        None => return Err("Incomplete macro.".to_string())
+                    }
+                }
+                TokenKind::Name(name)
+            },
+            Some(u) => TokenKind::Text(format!("\\{}", u)),
+            None => TokenKind::Text("\\".to_string()) // Trailing backslash
+        },
+        _ => {
+            let mut text = c.to_string();
+            while input.len() > run_end && !input.ends_with('\\') && !input.ends_with('{') && !input.ends_with('}') {
+                text.push(input.pop().unwrap());
+            }
+            TokenKind::Text(text)
+        }
+    };
+    Ok(Some(Token { kind, span: Span { start, source } }))
+}
+
+/*
+    Reads the rest of a brace group whose opening brace has already been consumed, returning its
+    contents without the closing brace. Escaped braces do not count towards nesting.
+*/
+pub(crate) fn read_arg(input: &mut Input) -> Result<String, String> {
+    let mut arg = String::new();
+    let mut depth = 1;
+    let mut prev_is_escaping_backslash = false;
+    while let Some(c) = input.pop() {
+        if !prev_is_escaping_backslash {
+            if c == '{' {
+                depth += 1;
+            } else if c == '}' {
+                depth -= 1;
+                if depth == 0 { return Ok(arg) }
+            }
+        }
+        prev_is_escaping_backslash = c == '\\' && !prev_is_escaping_backslash;
+        arg.push(c);
+    }
+    Err("Incomplete macro.".to_string())
+}
+
+/*
+    Reads a brace group that must follow immediately, e.g. the branches of \if
+*/
+pub(crate) fn read_group(input: &mut Input) -> Result<String, String> {
+    if input.pop() != Some('{') {
+        return Err("Incomplete macro.".to_string());
+    }
+    read_arg(input)
+}
+
+/*
+    Reads a brace group that immediately follows if it holds nothing but a number, e.g. the depth
+    of \expandafter, leaving any other group in the input
+*/
+pub(crate) fn read_number_group(input: &mut Input) -> Option<String> {
+    let mut chars = input.text.chars().rev();
+    if chars.next() != Some('{') { return None }
+    let number: String = chars.by_ref().take_while(|&c| c != '}').collect();
+    if number.trim().is_empty() || !number.trim().chars().all(|c| c.is_ascii_digit()) { return None }
+    // Everything read is ASCII, one byte a character
+    for _ in 0..number.len() + 2 { input.pop(); }
+    Some(number)
+}
+
+/*
+    Reads every brace group that immediately follows in the input
+*/
+pub(crate) fn read_trailing_args(input: &mut Input) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    while input.ends_with('{') {
+        input.pop();
+        args.push(read_arg(input)?);
+    }
+    Ok(args)
+}
+
+/*
+    Reads the rest of a group holding a macro name, failing with the given error on any character
+    that cannot be part of one. Colons are only allowed when referring to imported macros.
+*/
+pub(crate) fn read_name(input: &mut Input, allow_colon: bool, error: &str) -> Result<String, String> {
+    let mut name = String::new();
+    while let Some(c) = input.pop() {
+        if c == '}' { return Ok(name) }
+        if !(c.is_alphanumeric() || allow_colon && c == ':') { return Err(error.to_string()) }
+        name.push(c);
+    }
+    Err("Incomplete macro.".to_string())
+}
+
+/*
+    Reads an optional [default] for the argument of a macro being defined, e.g. \def{greet}[World]{..}
+    A ] inside the default can be escaped as \].
+*/
+pub(crate) fn read_default(input: &mut Input) -> Result<Option<String>, String> {
+    if !input.ends_with('[') { return Ok(None) }
+    input.pop();
+    let mut default = String::new();
+    let mut prev_is_escaping_backslash = false;
+    while let Some(c) = input.pop() {
+        if c == ']' && prev_is_escaping_backslash {
+            default.pop();
+        } else if c == ']' {
+            return Ok(Some(default));
+        }
+        prev_is_escaping_backslash = c == '\\' && !prev_is_escaping_backslash;
+        default.push(c);
+    }
+    Err("Incomplete macro.".to_string())
+}
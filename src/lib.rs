@@ -0,0 +1,1958 @@
+#![deny(unsafe_code)]
+
+/*
+    The processor, run by the proj3 command through run_command_line, and the library for programs
+    that embed it and for fuzzing. Processor takes options as on the command line, and builtins and
+    output filters of the embedder's own:
+
+        let mut processor = proj3::Processor::new(&["--chomp"])?;
+        processor.builtin("shout", 1, |args| Ok(args[0].to_uppercase()))?;
+        processor.filter(|output: String| Ok(output.replace("\t", "    ")));
+        let output = processor.process("doc.txt", "\\shout{hello}")?;
+
+    With the fuzz feature it also exposes fuzz_process, so a cargo-fuzz target is just
+
+        fuzz_target!(|data: &[u8]| proj3::fuzz_process(data));
+*/
+
+use std::collections::HashMap;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, ErrorKind, Read, Write, stdin};
+use std::iter;
+use std::panic;
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+use std::str;
+use std::time::{Duration, Instant, SystemTime};
+
+
+
+macro_rules! die {
+    ($($arg:tt)*) => {
+        eprintln!("proj3: {}", format_args!($($arg)*));
+        panic!();
+    };
+}
+
+// Messages to stderr, shown depending on Options::log_level
+macro_rules! warn {
+    ($opts:expr, $($arg:tt)*) => {
+        if $opts.log_level >= 1 { eprintln!("proj3: warning: {}", format_args!($($arg)*)) }
+    };
+}
+
+macro_rules! info {
+    ($opts:expr, $($arg:tt)*) => {
+        if $opts.log_level >= 2 { eprintln!("proj3: info: {}", format_args!($($arg)*)) }
+    };
+}
+
+macro_rules! debug {
+    ($opts:expr, $($arg:tt)*) => {
+        if $opts.log_level >= 3 { eprintln!("proj3: debug: {}", format_args!($($arg)*)) }
+    };
+}
+
+#[cfg(feature = "archives")]
+mod archive;
+mod braces;
+mod builtins;
+mod cache;
+mod config;
+mod cpp;
+mod crc32;
+mod csv;
+mod diff;
+mod dotenv;
+mod encoding;
+mod eval;
+mod format;
+mod frontmatter;
+mod fxhash;
+#[cfg(feature = "json")]
+mod json;
+mod lexer;
+mod m4;
+mod postprocess;
+mod refs;
+mod sha1;
+mod sha256;
+mod sourcemap;
+mod unicode;
+mod unicode_tables;
+mod verify;
+use builtins::{Arity, Registry};
+use fxhash::FxHashMap;
+use cache::Reads;
+use frontmatter::FrontMatter;
+#[allow(unused_imports)] // Only used through the library
+pub use postprocess::OutputFilter;
+use lexer::Input;
+use sha256::Digest;
+
+enum PreprocState {
+    Plain,
+    CommentStart, // just after an unescaped %
+    CommentLine1,
+    CommentLine2, // after newline
+    BlockComment,
+    BlockCommentPercent // % inside a block comment
+}
+
+#[derive(Clone)]
+struct Options {
+    chomp: bool, // Swallow the newline following \def, \undef, and conditionals
+    keep_comments: bool, // Pass comments through to the output instead of stripping them
+    max_expansions: usize, // Limit on macro expansions and loop iterations, 0 for no limit
+    max_memory: usize, // Limit in bytes on buffered input, output, and definitions, 0 for no limit
+    max_output: usize, // Limit on characters of output, 0 for no limit
+    timeout: usize, // Limit in seconds on processing time, 0 for no limit
+    allow_files: bool, // Whether \include and \import may read files, off for fuzzing
+    source_map: Option<String>, // File to write a source map of the output to
+    line_directives: Option<String>, // Template of the line directives to put in the output
+    cache: Option<String>, // Directory to keep outputs in for reuse, see cache.rs
+    hash: bool, // Report the hashes of the output and the files read
+    include_relative: bool, // Resolve paths in \include and the like against the including file
+    include_paths: Vec<String>, // From --include-path, searched for files \include and the like do not find
+    buffers: Vec<(String, Vec<u8>)>, // From --buffer, included as @name
+    front_matter: bool, // Turn a metadata block at the top of each input file into definitions
+    flags: Vec<String>, // From --flag, tested by \ifflag
+    definitions: Vec<Definition>, // Made before processing starts, later ones replacing earlier ones
+    seed: Option<u64>, // Seed for \random, taken from the clock if not given
+    deterministic: bool, // Refuse builtins whose output is not determined by the input
+    allow_git: bool, // Whether \gitdescribe and \gitsha may run git
+    allow_write: bool, // Whether \writefile and \appendfile may write files
+    allow_shadow_builtins: bool, // Whether \def and the like may use builtin names, see builtin_def
+    lenient_undef: bool, // Whether \undef and \popdef of an undefined name are ignored rather than errors
+    verify_balanced: Option<verify::Syntax>, // Format whose brackets must pair up in the output
+    prologue: Vec<(String, Vec<u8>)>, // From --prepend and --prepend-file, processed before the input
+    epilogue: Vec<(String, Vec<u8>)>, // From --append and --append-file, processed after it
+    coverage: bool, // Report the macros that were defined but never expanded
+    partial_output: bool, // Write the output produced before an error
+    recover: bool, // Go on after undefined macros and bad names, see eval::recover
+    max_errors: usize, // Errors after which --recover gives up, 0 for no limit
+    log_level: u8, // 0 for errors only, 1 for warnings too (the default), 2 for info, 3 for debug messages
+    dialect: Dialect, // Syntax the files are written in
+    raw_delims: Option<(String, String)>, // Markers of regions passed through untouched, see preproc_raw
+    expand_tabs: usize, // Columns between tab stops to replace tabs in the output with spaces, 0 to keep them
+    reindent: bool, // Make the indentation of the output even, see postprocess.rs
+    strip_trailing_ws: bool, // Remove spaces and tabs at the ends of output lines
+    squeeze_blank_lines: bool, // Replace runs of blank output lines by one
+    post_filters: Vec<String>, // Commands the output is piped through in turn, see postprocess::CommandFilter
+    normalize: Option<unicode::Form>, // Unicode normalization form to put the output in
+    locale: String, // Of \uppercase and \lowercase without one of their own, empty for the default rules
+    streams: Vec<(String, String)>, // From --stream, names of \tostream streams and the files they go to
+    strict_streams: bool, // Whether \tostream to a stream no --stream maps is an error rather than discarded
+    passes: usize, // Times the document is processed, each seeing the definitions the one before left, see load_passes
+    early_pass: bool, // In the passes before the last, which leave writing files and the like to it
+    refs: Vec<(String, Vec<u8>)>, // From --refs, the reference databases as read
+    references: HashMap<String, String>, // Formatted entries of those by key, see refs.rs
+    embedded: Vec<(String, usize, Rc<EmbeddedBuiltin>)> // Names, group counts, and handlers of the builtins of an embedder, see Processor
+}
+
+/*
+    A builtin an embedder provides, called with its expanded arguments. What it returns is put out
+    as it is, like the output of the other builtins.
+*/
+type EmbeddedBuiltin = dyn Fn(&[String]) -> Result<String, String>;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Dialect {
+    Tex, // The native syntax
+    Cpp, // The C preprocessor's, see cpp.rs
+    M4, // m4's, see m4.rs
+    Braces // Mustache-like tags, see braces.rs
+}
+
+const PRAGMA_DIALECT: &str = "\\pragma{dialect=";
+
+impl Dialect {
+    fn from_name(name: &str) -> Option<Dialect> {
+        match name {
+            "tex" => Some(Dialect::Tex),
+            "cpp" => Some(Dialect::Cpp),
+            "m4" => Some(Dialect::M4),
+            "braces" => Some(Dialect::Braces),
+            _ => None
+        }
+    }
+
+    // The dialect a \pragma{dialect=name} switches to
+    fn from_pragma(name: &str) -> Result<Dialect, String> {
+        Dialect::from_name(name).ok_or_else(|| format!("Unknown dialect {} in \\pragma, expected tex, cpp, m4, or braces.", name))
+    }
+
+    // The native form of text written in this dialect
+    fn translate(self, text: &str) -> Result<String, String> {
+        match self {
+            Dialect::Tex => Ok(text.to_string()),
+            Dialect::Cpp => cpp::translate(text),
+            Dialect::M4 => m4::translate(text),
+            Dialect::Braces => braces::translate(text)
+        }
+    }
+}
+
+/*
+    A macro defined from outside the document, e.g. by --env-prefix. It expands to its value as is.
+*/
+#[derive(Clone)]
+struct Definition {
+    name: String,
+    value: String,
+    origin: String // Where the value comes from, see Macro::defined_at
+}
+
+const DEFAULT_LINE_DIRECTIVE: &str = "//line {file}:{line}"; // For --line-directives
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { chomp: false, keep_comments: false, max_expansions: 1_000_000, max_memory: 0, max_output: 0, timeout: 0, allow_files: true,
+                  source_map: None, line_directives: None, cache: None,
+                  hash: false, include_relative: false, include_paths: Vec::new(), buffers: Vec::new(),
+                  front_matter: false, flags: Vec::new(),
+                  definitions: Vec::new(), seed: None, deterministic: false, allow_git: false, allow_write: false,
+                  allow_shadow_builtins: false, lenient_undef: false, verify_balanced: None,
+                  prologue: Vec::new(), epilogue: Vec::new(), coverage: false,
+                  partial_output: false, recover: false, max_errors: 0, log_level: 1, dialect: Dialect::Tex,
+                  raw_delims: None, expand_tabs: 0, reindent: false,
+                  strip_trailing_ws: false, squeeze_blank_lines: false,
+                  post_filters: Vec::new(), normalize: None,
+                  locale: String::new(), streams: Vec::new(), strict_streams: false, passes: 1, early_pass: false,
+                  refs: Vec::new(), references: HashMap::new(), embedded: Vec::new() }
+    }
+}
+
+struct Macro {
+    body: String,
+    default: Option<String>, // Used when the macro is called with an empty argument
+    variadic: bool, // Body uses \argcount or \arg, so calls take any number of groups
+    previous: Option<Box<Macro>>, // Definition shadowed by \pushdef, restored by \popdef
+    private: bool, // Defined by \defp, hidden when the defining file is imported
+    defined_at: String, // Location of the definition, see Context::location
+    site: Option<usize>, // Index into Coverage::sites, with --coverage
+    carried: bool, // Left by an earlier pass of --passes, so defining the name again replaces it
+    segments: Vec<Segment> // Body compiled by compile_body
+}
+
+impl Macro {
+    // Names of the #name placeholders of the body, which calls may give as named arguments
+    fn named_keys(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Named(key) => Some(key.as_str()),
+            _ => None
+        })
+    }
+
+    /*
+        Bytes held by the definition, including the definitions it shadows
+    */
+    fn size(&self) -> usize {
+        let segments: usize = self.segments.iter().map(|segment| match segment {
+            Segment::Literal(text) | Segment::Named(text) => text.len(),
+            Segment::Arg => 0
+        }).sum();
+        self.body.len() + self.default.as_ref().map_or(0, |default| default.len()) + self.defined_at.len() + segments
+            + self.previous.as_ref().map_or(0, |previous| previous.size())
+    }
+}
+
+enum Segment {
+    Literal(String), // Already reversed for the input
+    Arg, // #
+    Named(String) // #name, which is # followed by name unless the call has named arguments
+}
+
+/*
+    Splits a macro body into literal text and placeholders once at definition time, so expanding it
+    is a few push_str calls. A body without placeholders is a single literal.
+*/
+fn compile_body(body: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut prev_is_escaping_backslash = false;
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '#' && !prev_is_escaping_backslash {
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(literal.chars().rev().collect()));
+                literal.clear();
+            }
+            let mut key = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_alphanumeric()) { key.push(c) }
+            segments.push(if key.is_empty() { Segment::Arg } else { Segment::Named(key) });
+        } else {
+            literal.push(c);
+        }
+        prev_is_escaping_backslash = c == '\\' && !prev_is_escaping_backslash;
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal.chars().rev().collect()));
+    }
+    segments
+}
+
+/*
+    Definitions made by \def and the like, for --coverage, and whether they were ever expanded. A
+    definition made again at the same place, e.g. by every call of a macro, is the same site.
+*/
+#[derive(Default)]
+struct Coverage {
+    sites: Vec<(Symbol, String, bool)>, // Name, location, and whether expanded, in the order first defined
+    indices: HashMap<(Symbol, String), usize> // Index into sites by name and location, which comes from the input
+}
+
+/*
+    Interned macro name, see Interner
+*/
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+struct Symbol(u32);
+
+/*
+    Symbol table for macro names, so the definition tables and the expansion bookkeeping work with
+    copyable ids instead of cloning names. The names come from the input, so this map keeps the
+    default collision-resistant hasher.
+*/
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<String, Symbol>,
+    names: Vec<String>
+}
+
+impl Interner {
+    fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(&id) = self.ids.get(name) { return id }
+        let id = Symbol(self.names.len() as u32);
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    fn name(&self, id: Symbol) -> &str {
+        &self.names[id.0 as usize]
+    }
+}
+
+/*
+    Output of a process_str call in progress, see eval::check_output
+*/
+struct Buffered {
+    base: usize, // Output buffered by the enclosing calls
+    counted: usize, // Bytes of it counted for --max-output
+    chars: usize // Characters in those
+}
+
+/*
+    A macro expansion that may still be in progress, see check_cycle
+*/
+struct ActiveExpansion {
+    name: Symbol,
+    args: String,
+    generation: usize,
+    nesting: usize, // process_str depth of the expansion
+    base_len: usize // Input length below the expanded body
+}
+
+/*
+    Macro body that expands to value as it is
+*/
+fn literal_body(value: &str) -> String {
+    let mut body = String::new();
+    for c in value.chars() {
+        push_literal(&mut body, c);
+    }
+    body
+}
+
+// Pushes c so that it stands for itself, escaped if it is special
+fn push_literal(body: &mut String, c: char) {
+    if c == '\\' || c == '#' || c == '%' || c == '{' || c == '}' { body.push('\\') }
+    body.push(c);
+}
+
+/*
+    Whether a macro body calls the given builtin, ignoring escaped backslashes
+*/
+fn calls_builtin(body: &str, name: &str) -> bool {
+    let call = format!("{}{{", name);
+    let mut prev_is_escaping_backslash = false;
+    for (i, c) in body.char_indices() {
+        if prev_is_escaping_backslash && body[i..].starts_with(&call) { return true }
+        prev_is_escaping_backslash = c == '\\' && !prev_is_escaping_backslash;
+    }
+    false
+}
+
+/*
+    Where the preprocessed text from offset on comes from, see Preprocessor. The following bytes
+    continue the same line of the original text until the next mark.
+*/
+struct SourceMark {
+    offset: usize,
+    line: usize, // From 1
+    col: usize // In bytes, from 0
+}
+
+struct SourceFile {
+    name: String,
+    start: usize, // Source offset of the file, see Context::sources
+    marks: Vec<SourceMark>
+}
+
+/*
+    A stretch of the output starting at output, produced at source offset source. Verbatim output
+    is the source text itself and follows it byte by byte; anything else was generated there.
+*/
+struct OutputSpan {
+    output: usize,
+    source: usize,
+    verbatim: bool
+}
+
+struct Context {
+    builtins: Registry,
+    symbols: Interner,
+    scopes: Vec<FxHashMap<Symbol, Macro>>, // Definition tables, outermost first
+    variadic_args: Vec<Vec<String>>, // Argument groups of the variadic macros being expanded
+    namespace: Option<String>, // Prefix for definitions while processing an \import
+    exports: Option<Vec<String>>, // Names listed by \export in the file being imported
+    scoped_includes: Vec<ScopedInclude>, // Open \includescoped files, innermost last
+    importing: Vec<String>, // Paths of the files being imported, innermost last
+    deprecated: FxHashMap<Symbol, String>, // Messages from \deprecate by macro name
+    sources: Vec<SourceFile>, // Input and included files, laid out one after another as source offsets
+    source_len: usize, // End of the last of them
+    cursor: usize, // Source offset of the last token read from a source file
+    output_spans: Option<Vec<OutputSpan>>, // Where the top-level output comes from, for --source-map, --line-directives, and --verify-balanced
+    reads: Option<Reads>, // What was read from the filesystem, for --cache and --hash
+    coverage: Option<Coverage>, // Definitions and their use, for --coverage
+    #[cfg(feature = "archives")]
+    archives: HashMap<String, archive::Archive>, // Archives included from so far, by path
+    nesting: usize, // Depth of process_str calls
+    active: Vec<ActiveExpansion>, // Chain of expansions in progress, outermost first
+    generation: usize, // Bumped on every change to the definition tables
+    expansions: usize, // Macro expansions and loop iterations so far, checked against max_expansions
+    output_len: usize, // Output buffered by the enclosing process_str calls
+    buffered: Vec<Buffered>, // Output of the process_str calls in progress, outermost first
+    output_chars: usize, // Characters of it counted for --max-output
+    table_size: Option<(usize, usize)>, // Generation and byte size of the definition tables when last measured
+    random: u64, // State of the generator behind \random
+    exit_code: Option<i32>, // Exit status requested by \error
+    partial_output: Option<String>, // Output produced before an error, with --partial-output
+    errors: Vec<String>, // Errors processing went on after, with --recover, as "file:line: message"
+    warnings: usize, // Warnings printed while processing
+    streams: HashMap<String, String>, // Text sent to the mapped streams by \tostream, by name
+    git: HashMap<String, String>, // Output of the git commands run so far, by builtin, kept over --passes
+    deferred: Vec<String>, // Text from \atend, to be processed once the input is, as it is
+    labels: HashMap<String, (String, String)>, // Values of the labels made by \label, and where they were made
+    carried_labels: HashMap<String, String>, // Values of the labels the pass before made, with --passes
+    index: Vec<(String, String)>, // Terms from \indexentry and where they occur, in the order of the calls
+    carried_index: Option<Vec<(String, String)>>, // What the pass before collected, with --passes
+    unknown_citations: Vec<(String, String)>, // Keys \cite did not find and where, reported at the end
+    held_len: usize, // Bytes of streams and deferred text, counted as output against --max-memory
+    deadline: Option<Instant>, // From --timeout
+    steps: usize // Tokens processed, the clock is only read every TIMEOUT_CHECK_INTERVAL of them
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context { builtins: Registry::default(), symbols: Interner::default(), scopes: vec![FxHashMap::default()], variadic_args: Vec::new(), namespace: None, exports: None, scoped_includes: Vec::new(), importing: Vec::new(), deprecated: FxHashMap::default(),
+            sources: Vec::new(), source_len: 0, cursor: 0, output_spans: None, reads: None, coverage: None,
+            #[cfg(feature = "archives")]
+            archives: HashMap::new(), nesting: 0, active: Vec::new(), generation: 0,
+            expansions: 0, output_len: 0, buffered: Vec::new(), output_chars: 0, table_size: None,
+            random: 0, exit_code: None, partial_output: None, errors: Vec::new(), warnings: 0,
+            streams: HashMap::new(), git: HashMap::new(), deferred: Vec::new(), held_len: 0,
+            labels: HashMap::new(), carried_labels: HashMap::new(), index: Vec::new(), carried_index: None, unknown_citations: Vec::new(), deadline: None, steps: 0 }
+    }
+}
+
+impl Context {
+    /*
+        file:line of the source currently being processed. Text produced by expansions is
+        attributed to the place in the source that produced it.
+    */
+    fn location(&self) -> String {
+        match self.current_line() {
+            Some((name, line)) => format!("{}:{}", name, line),
+            None => "<input>".to_string()
+        }
+    }
+
+    /*
+        Path of a file named by a builtin. With --include-relative a relative path is taken relative
+        to the directory of the file making the call, or of the file being imported, rather than the
+        working directory.
+    */
+    fn file_path(&self, opts: &Options, path: &str) -> String {
+        let resolved = self.relative_path(opts, path);
+        info!(opts, "{}: {} resolves to {}", self.location(), path, resolved);
+        resolved
+    }
+
+    /*
+        Path a file named by \include, \import, \fileexists, or \foreachrow is read from: that of
+        file_path if there is a file there, or else the first of the --include-path directories that
+        has it. The paths tried are recorded for --cache, as those \fileexists probes are.
+    */
+    fn input_path(&mut self, opts: &Options, path: &str) -> String {
+        let resolved = self.file_path(opts, path);
+        if opts.include_paths.is_empty() || !opts.allow_files || path.starts_with('@') || Path::new(path).is_absolute() {
+            return resolved;
+        }
+        let searched = opts.include_paths.iter().map(|dir| Path::new(dir).join(path).to_string_lossy().into_owned());
+        for candidate in iter::once(resolved.clone()).chain(searched) {
+            // A member of an archive is looked for as the archive
+            #[cfg(feature = "archives")]
+            let probed = archive::split(&candidate).map_or(candidate.as_str(), |(archive_path, _)| archive_path);
+            #[cfg(not(feature = "archives"))]
+            let probed = candidate.as_str();
+            let exists = Path::new(probed).exists();
+            if let Some(reads) = self.reads.as_mut() {
+                reads.probed.push((probed.to_string(), exists));
+            }
+            if exists {
+                if candidate != resolved { info!(opts, "{}: {} found as {}", self.location(), path, candidate) }
+                return candidate;
+            }
+        }
+        resolved
+    }
+
+    fn relative_path(&self, opts: &Options, path: &str) -> String {
+        if !opts.include_relative || path.starts_with('@') { return path.to_string() }
+        let current = match self.importing.last() {
+            Some(file) => file.as_str(),
+            None => match self.current_line() {
+                Some((name, _)) => name,
+                None => return path.to_string()
+            }
+        };
+        match Path::new(current).parent() {
+            Some(dir) => dir.join(path).to_string_lossy().into_owned(),
+            None => path.to_string()
+        }
+    }
+
+    /*
+        File name and line of location
+    */
+    fn current_line(&self) -> Option<(&str, usize)> {
+        let (file, line, _) = self.source_position(self.cursor)?;
+        Some((&self.sources[file].name, line))
+    }
+
+    /*
+        Registers a preprocessed file and returns its source offset
+    */
+    fn add_source(&mut self, name: &str, len: usize, marks: Vec<SourceMark>) -> usize {
+        let start = self.source_len;
+        self.sources.push(SourceFile { name: name.to_string(), start, marks });
+        self.source_len += len;
+        start
+    }
+
+    /*
+        File index, line, and column of a source offset
+    */
+    fn source_position(&self, offset: usize) -> Option<(usize, usize, usize)> {
+        let index = self.sources.iter().rposition(|file| file.start <= offset)?;
+        let offset = offset - self.sources[index].start;
+        let marks = &self.sources[index].marks;
+        match marks[..marks.partition_point(|mark| mark.offset <= offset)].last() {
+            Some(mark) => Some((index, mark.line, mark.col + offset - mark.offset)),
+            None => Some((index, 1, 0))
+        }
+    }
+
+    /*
+        Name under which a definition made now is registered
+    */
+    fn qualify(&mut self, name: &str) -> Symbol {
+        let qualified = self.qualified_name(name);
+        self.symbols.intern(&qualified)
+    }
+
+    fn qualified_name(&self, name: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}:{}", namespace, name),
+            None => name.to_string()
+        }
+    }
+
+    /*
+        Name a reference made now refers to: inside an \import, names defined by the imported file
+        take precedence over names from the importing document
+    */
+    fn resolve(&mut self, name: &str) -> Symbol {
+        if self.namespace.is_some() {
+            let qualified = self.qualify(name);
+            if self.is_defined(qualified) { return qualified }
+        }
+        self.symbols.intern(name)
+    }
+
+    fn lookup(&self, name: Symbol) -> Option<&Macro> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(&name))
+    }
+
+    fn is_defined(&self, name: Symbol) -> bool {
+        self.lookup(name).is_some()
+    }
+
+    /*
+        Defines a macro in the innermost scope. Shadowing a definition from an outer scope is fine,
+        defining a name twice in the same scope is not.
+    */
+    fn define(&mut self, name: Symbol, macro_val: Macro) -> Result<(), String> {
+        self.generation += 1;
+        let scope = self.scopes.last_mut().unwrap();
+        if let Some(old) = scope.get(&name).filter(|old| !old.carried) {
+            return Err(format!("Macro already defined: \\{} at {}, previously at {}. Use \\redef to replace it.",
+                               self.symbols.name(name), macro_val.defined_at, old.defined_at));
+        }
+        scope.insert(name, macro_val);
+        Ok(())
+    }
+
+    /*
+        Defines a macro before processing starts, replacing any earlier such definition
+    */
+    fn predefine(&mut self, name: &str, body: &str, defined_at: String) {
+        let name = self.symbols.intern(name);
+        let macro_val = Macro {
+            body: body.to_string(), segments: compile_body(body), default: None, variadic: false, previous: None,
+            private: false, defined_at, site: None, carried: false
+        };
+        self.define_global(name, macro_val);
+    }
+
+    /*
+        Makes what an earlier pass left visible: its labels to \ref, and its top-level definitions,
+        except where a name is defined already, e.g. by --define
+    */
+    fn carry(&mut self, carried: Carried) {
+        self.generation += 1;
+        for (name, mut macro_val) in carried.definitions {
+            let name = self.symbols.intern(&name);
+            macro_val.carried = true;
+            self.scopes[0].entry(name).or_insert(macro_val);
+        }
+        self.carried_labels = carried.labels;
+        self.carried_index = carried.index;
+        self.git = carried.git;
+    }
+
+    // What the next pass starts with
+    fn into_carried(mut self) -> Carried {
+        let scope = std::mem::take(&mut self.scopes[0]);
+        Carried {
+            definitions: scope.into_iter().map(|(name, macro_val)| (self.symbols.name(name).to_string(), macro_val)).collect(),
+            labels: self.labels.into_iter().map(|(name, (value, _))| (name, value)).collect(),
+            index: Some(self.index),
+            git: self.git
+        }
+    }
+
+    /*
+        Coverage site of a definition of name made at defined_at, if --coverage is given
+    */
+    fn coverage_site(&mut self, name: Symbol, defined_at: &str) -> Option<usize> {
+        let coverage = self.coverage.as_mut()?;
+        let sites = &mut coverage.sites;
+        Some(*coverage.indices.entry((name, defined_at.to_string())).or_insert_with(|| {
+            sites.push((name, defined_at.to_string(), false));
+            sites.len() - 1
+        }))
+    }
+
+    /*
+        Next number from the generator behind \random, SplitMix64
+    */
+    fn next_random(&mut self) -> u64 {
+        self.random = self.random.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.random;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /*
+        Defines a macro in the outermost scope, replacing any previous global definition
+    */
+    fn define_global(&mut self, name: Symbol, macro_val: Macro) {
+        self.generation += 1;
+        self.scopes[0].insert(name, macro_val);
+    }
+
+    /*
+        Replaces the innermost visible definition of a macro, which must exist. Definitions pushed
+        underneath it are kept.
+    */
+    fn redefine(&mut self, name: Symbol, mut macro_val: Macro) -> Result<(), String> {
+        self.generation += 1;
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some(old) = scope.get_mut(&name) {
+                macro_val.previous = old.previous.take();
+                *old = macro_val;
+                return Ok(());
+            }
+        }
+        Err("Macro not defined.".to_string())
+    }
+
+    /*
+        Defines a macro in the innermost scope, saving any definition it replaces there for \popdef
+    */
+    fn push_definition(&mut self, name: Symbol, mut macro_val: Macro) {
+        self.generation += 1;
+        let scope = self.scopes.last_mut().unwrap();
+        macro_val.previous = scope.remove(&name).map(Box::new);
+        scope.insert(name, macro_val);
+    }
+
+    /*
+        Removes the innermost visible definition of a macro, restoring the one it was pushed over
+    */
+    fn pop_definition(&mut self, name: Symbol) -> bool {
+        self.generation += 1;
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some(macro_val) = scope.remove(&name) {
+                if let Some(previous) = macro_val.previous {
+                    scope.insert(name, *previous);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /*
+        Removes the innermost visible definition of a macro, including any definitions pushed
+        underneath it
+    */
+    fn undefine(&mut self, name: Symbol) -> bool {
+        self.generation += 1;
+        self.scopes.iter_mut().rev().any(|scope| scope.remove(&name).is_some())
+    }
+}
+
+/*
+    Pushes a comment character so that it survives processing verbatim, used by --keep-comments
+*/
+fn push_comment_char(opts: &Options, preprocessed_str: &mut String, c: char) {
+    if opts.keep_comments {
+        if c == '\\' || c == '%' || c == '#' || c == '{' || c == '}' {
+            preprocessed_str.push('\\');
+        }
+        preprocessed_str.push(c);
+    }
+}
+
+/*
+    Strips comments. A line comment runs from % to the end of the line and swallows the leading
+    whitespace of the next line. A block comment runs from %{ to the matching %}; block comments
+    nest, so disabling a region that already contains a block comment works as expected.
+    With --keep-comments, comments are escaped instead so they reach the output untouched.
+    Input is fed incrementally, with the state carried over between chunks, and marks record where
+    in the original text the output comes from, so positions can be mapped back to lines and columns.
+*/
+struct Preprocessor<'a> {
+    opts: &'a Options,
+    state: PreprocState,
+    prev_is_escaped: bool, // Whether previous character is escaped
+    block_depth: usize,
+    output: String,
+    marks: Vec<SourceMark>,
+    line: usize, // Position of the character being fed
+    col: usize
+}
+
+const PREPROC_CHUNK_SIZE: usize = 64 * 1024;
+
+impl<'a> Preprocessor<'a> {
+    fn new(opts: &'a Options) -> Self {
+        Preprocessor {
+            opts, state: PreprocState::Plain, prev_is_escaped: false, block_depth: 0,
+            output: String::new(), marks: Vec::new(), line: 1, col: 0
+        }
+    }
+
+    fn feed(&mut self, chunk: &str) {
+        for c in chunk.chars() {
+            self.push(c);
+        }
+    }
+
+    /*
+        Feeds native text up to the end of the first \pragma{dialect=name} in it, outside comments
+        and escapes, if there is one. Returns where the text after it starts and the name. The
+        pragma produces no output.
+    */
+    fn feed_native(&mut self, chunk: &str) -> Option<(usize, String)> {
+        for (i, c) in chunk.char_indices() {
+            self.push(c);
+            if c != '}' || !matches!(self.state, PreprocState::Plain) { continue }
+            if let Some(name) = self.take_pragma() { return Some((i + 1, name)) }
+        }
+        None
+    }
+
+    // Removes a \pragma{dialect=name} the output ends with, returning the name
+    fn take_pragma(&mut self) -> Option<String> {
+        let body = self.output.strip_suffix('}')?;
+        let start = body.rfind('\\')?;
+        let name = body[start..].strip_prefix(PRAGMA_DIALECT)?.to_string();
+        let backslashes = body[..start].len() - body[..start].trim_end_matches('\\').len();
+        if !backslashes.is_multiple_of(2) { return None } // The backslash is escaped
+        self.output.truncate(start);
+        while self.marks.last().is_some_and(|mark| mark.offset >= start) { self.marks.pop(); }
+        Some(name)
+    }
+
+    fn push(&mut self, c: char) {
+        let output_len = self.output.len();
+        match self.state {
+            PreprocState::Plain => {
+                if c == '%' && !self.prev_is_escaped {
+                    push_comment_char(self.opts, &mut self.output, c);
+                    self.state = PreprocState::CommentStart;
+                } else if c == '\\' {
+                    self.prev_is_escaped = !self.prev_is_escaped; // Toggle escape state
+                    self.output.push(c);
+                } else {
+                    self.output.push(c);
+                    self.prev_is_escaped = false; // Reset escape state if not a backslash
+                }
+            },
+            PreprocState::CommentStart => {
+                if c == '\n' && self.opts.keep_comments {
+                    self.output.push(c);
+                    self.state = PreprocState::Plain;
+                } else if c == '{' {
+                    push_comment_char(self.opts, &mut self.output, c);
+                    self.block_depth = 1;
+                    self.state = PreprocState::BlockComment;
+                } else if c == '\n' {
+                    self.state = PreprocState::CommentLine2;
+                } else {
+                    push_comment_char(self.opts, &mut self.output, c);
+                    self.state = PreprocState::CommentLine1;
+                }
+            },
+            PreprocState::CommentLine1 => {
+                if c == '\n' && self.opts.keep_comments {
+                    self.output.push(c);
+                    self.state = PreprocState::Plain;
+                } else if c == '\n' {
+                    self.state = PreprocState::CommentLine2;
+                    self.prev_is_escaped = false; // Reset escape state at newline
+                } else {
+                    push_comment_char(self.opts, &mut self.output, c);
+                }
+            },
+            PreprocState::CommentLine2 => {
+                if c == '%' {
+                    push_comment_char(self.opts, &mut self.output, c);
+                    self.state = PreprocState::CommentStart;
+                } else if c != ' ' && c != '\t' {
+                    self.state = PreprocState::Plain;
+                    self.output.push(c);
+                    self.prev_is_escaped = c == '\\';
+                } else if c == '\n' {
+                    // Stay in CommentLine2 state but reset escape state
+                    self.prev_is_escaped = false;
+                }
+            },
+            PreprocState::BlockComment => {
+                push_comment_char(self.opts, &mut self.output, c);
+                if c == '%' && !self.prev_is_escaped {
+                    self.state = PreprocState::BlockCommentPercent;
+                } else if c == '\\' {
+                    self.prev_is_escaped = !self.prev_is_escaped;
+                }
+            },
+            PreprocState::BlockCommentPercent => {
+                push_comment_char(self.opts, &mut self.output, c);
+                if c == '{' {
+                    self.block_depth += 1;
+                    self.state = PreprocState::BlockComment;
+                } else if c == '}' {
+                    self.block_depth -= 1;
+                    self.state = if self.block_depth == 0 { PreprocState::Plain } else { PreprocState::BlockComment };
+                } else if c != '%' {
+                    self.state = PreprocState::BlockComment;
+                    self.prev_is_escaped = c == '\\';
+                }
+            }
+        }
+
+        if c != '\\' {
+            self.prev_is_escaped = false;
+        }
+        self.mark(output_len, c);
+    }
+
+    /*
+        Records the position of c for the output it produced, if any. A mark is only needed where
+        the output stops following the original text: after a removed comment, on a new line, or
+        when --keep-comments adds an escape.
+    */
+    fn mark(&mut self, output_len: usize, c: char) {
+        // With an escape added, the backslash and c both come from c
+        let escaped = self.output.len() - output_len > c.len_utf8();
+        for offset in [Some(output_len), escaped.then_some(output_len + 1)].into_iter().flatten() {
+            if offset < self.output.len() && !self.marks.last().is_some_and(|mark| mark.line == self.line && mark.col + offset - mark.offset == self.col) {
+                self.marks.push(SourceMark { offset, line: self.line, col: self.col });
+            }
+        }
+        if c == '\n' {
+            self.line += 1;
+            self.col = 0;
+        } else {
+            self.col += c.len_utf8();
+        }
+    }
+
+    // Whether text fed now is outside comments and escapes, where a raw region may start
+    fn at_text(&self) -> bool {
+        matches!(self.state, PreprocState::Plain | PreprocState::CommentLine2) && !self.prev_is_escaped
+    }
+
+    // Pushes c of a raw region, escaped so that it stands for itself
+    fn push_raw(&mut self, c: char) {
+        let output_len = self.output.len();
+        push_literal(&mut self.output, c);
+        self.state = PreprocState::Plain;
+        self.mark(output_len, c);
+    }
+
+    // Steps over the text of a marker, which produces no output
+    fn skip(&mut self, text: &str) {
+        for c in text.chars() {
+            self.mark(self.output.len(), c);
+        }
+    }
+
+    fn finish(self) -> Result<(String, Vec<SourceMark>), String> {
+        if self.block_depth != 0 {
+            return Err("Unterminated block comment.".to_string());
+        }
+        Ok((self.output, self.marks))
+    }
+}
+
+/*
+    Preprocesses everything a reader produces, reading it in chunks rather than into one buffer. In the
+    other dialects the text is translated first, so it is read whole, as is the rest of the file
+    after a \pragma switches to one of them.
+*/
+fn preproc_reader(opts: &Options, mut reader: impl Read) -> Result<(String, Vec<SourceMark>), String> {
+    let mut preprocessor = Preprocessor::new(opts);
+    if let Some((begin, end)) = &opts.raw_delims {
+        let text = read_whole(opts, reader, Vec::new())?;
+        preproc_raw(&mut preprocessor, &text, begin, end)?;
+        return preprocessor.finish();
+    }
+    if opts.dialect != Dialect::Tex {
+        let text = read_whole(opts, reader, Vec::new())?;
+        preproc_dialects(&mut preprocessor, &text, opts.dialect)?;
+        return preprocessor.finish();
+    }
+    let mut buf = vec![0; PREPROC_CHUNK_SIZE];
+    let mut pending = 0; // Bytes of a UTF-8 sequence split across chunks, kept at the start of buf
+    loop {
+        let n = match reader.read(&mut buf[pending..]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.to_string())
+        };
+        let filled = pending + n;
+        let valid = match str::from_utf8(&buf[..filled]) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return Err("stream did not contain valid UTF-8".to_string())
+        };
+        let text = str::from_utf8(&buf[..valid]).unwrap();
+        if let Some((after, name)) = preprocessor.feed_native(text) {
+            // The rest of the file is translated, so it is read whole
+            let rest = read_whole(opts, reader, buf[after..filled].to_vec())?;
+            preproc_dialects(&mut preprocessor, &rest, Dialect::from_pragma(&name)?)?;
+            return preprocessor.finish();
+        }
+        check_preprocessed(opts, preprocessor.output.len())?;
+        buf.copy_within(valid..filled, 0);
+        pending = filled - valid;
+    }
+    if pending != 0 {
+        return Err("stream did not contain valid UTF-8".to_string());
+    }
+    preprocessor.finish()
+}
+
+/*
+    The rest of what a reader produces after start, as text, read no further than --max-memory allows
+*/
+fn read_whole(opts: &Options, reader: impl Read, mut start: Vec<u8>) -> Result<String, String> {
+    let limit = if opts.max_memory == 0 { u64::MAX } else { opts.max_memory as u64 + 1 };
+    reader.take(limit).read_to_end(&mut start).map_err(|e| e.to_string())?;
+    check_preprocessed(opts, start.len())?;
+    String::from_utf8(start).map_err(|_| "stream did not contain valid UTF-8".to_string())
+}
+
+/*
+    --max-memory holds for the input as it is read and preprocessed, before any of it is processed
+*/
+fn check_preprocessed(opts: &Options, len: usize) -> Result<(), String> {
+    if opts.max_memory != 0 && len > opts.max_memory {
+        return Err(format!("Memory limit exceeded reading the input: more than {} bytes.", opts.max_memory));
+    }
+    Ok(())
+}
+
+/*
+    Fails for a file of size bytes that is to be read whole but is larger than --max-memory allows
+*/
+fn check_size(opts: &Options, path: &str, size: u64) -> Result<(), String> {
+    if opts.max_memory != 0 && size > opts.max_memory as u64 {
+        return Err(format!("Memory limit exceeded by {}, of {} bytes.", path, size));
+    }
+    Ok(())
+}
+
+/*
+    Preprocesses text that starts in the given dialect, switching at each \pragma{dialect=name}.
+    Outside native text, a pragma is recognized wherever it is written, and the text between two
+    of them is translated on its own.
+*/
+fn preproc_dialects(preprocessor: &mut Preprocessor, text: &str, mut dialect: Dialect) -> Result<(), String> {
+    let mut rest = text;
+    loop {
+        let (after, name) = if dialect == Dialect::Tex {
+            match preprocessor.feed_native(rest) {
+                Some(found) => found,
+                None => return Ok(())
+            }
+        } else {
+            let found = rest.match_indices(PRAGMA_DIALECT).find_map(|(start, _)| {
+                let name_start = start + PRAGMA_DIALECT.len();
+                let name_len = rest[name_start..].find(|c: char| !c.is_alphanumeric())?;
+                rest[name_start + name_len..].starts_with('}').then(|| (start, name_start + name_len + 1, &rest[name_start..name_start + name_len]))
+            });
+            let Some((start, after, name)) = found else {
+                preprocessor.feed(&dialect.translate(rest)?);
+                return Ok(());
+            };
+            preprocessor.feed(&dialect.translate(&rest[..start])?);
+            (after, name.to_string())
+        };
+        dialect = Dialect::from_pragma(&name)?;
+        rest = &rest[after..];
+    }
+}
+
+/*
+    Preprocesses text with the regions between the --raw-delims markers escaped, so that they come
+    out as written. The markers are dropped. A begin marker in a comment or right after a backslash
+    does not start a region.
+*/
+fn preproc_raw(preprocessor: &mut Preprocessor, text: &str, begin: &str, end: &str) -> Result<(), String> {
+    let mut rest = text;
+    while let Some(start) = rest.find(begin) {
+        if preprocessor.feed_native(&rest[..start]).is_some() {
+            return Err("\\pragma{dialect=...} cannot be combined with --raw-delims.".to_string());
+        }
+        rest = &rest[start + begin.len()..];
+        if !preprocessor.at_text() {
+            preprocessor.feed(begin);
+            continue;
+        }
+        let Some(stop) = rest.find(end) else {
+            let line = text[..text.len() - rest.len()].matches('\n').count() + 1;
+            return Err(format!("Unterminated raw region starting on line {}.", line));
+        };
+        preprocessor.skip(begin);
+        for c in rest[..stop].chars() { preprocessor.push_raw(c) }
+        preprocessor.skip(end);
+        rest = &rest[stop + end.len()..];
+    }
+    if preprocessor.feed_native(rest).is_some() {
+        return Err("\\pragma{dialect=...} cannot be combined with --raw-delims.".to_string());
+    }
+    Ok(())
+}
+
+/*
+    Opens and preprocesses a file named by \include or \import, see read_included. Plain files are
+    streamed unless --cache or --hash needs their contents whole.
+*/
+fn preproc_include(ctx: &mut Context, opts: &Options, path: &str) -> Result<(String, Vec<SourceMark>), String> {
+    #[cfg(feature = "archives")]
+    let in_archive = archive::split(path).is_some();
+    #[cfg(not(feature = "archives"))]
+    let in_archive = false;
+    if ctx.reads.is_none() && opts.allow_files && !path.starts_with('@') && !in_archive {
+        let file = fs::File::open(path).map_err(|_| "Include error.".to_string())?;
+        check_size(opts, path, file.metadata().map_or(0, |metadata| metadata.len()))?;
+        return preproc_reader(opts, file);
+    }
+    preproc_reader(opts, &read_included(ctx, opts, path)?[..])
+}
+
+/*
+    Contents of a file a builtin reads: a --buffer named @name, with the archives feature a member
+    of an archive named archive!member, or a file, recorded for --cache and --hash so that what
+    gets hashed is exactly what was read
+*/
+fn read_included(ctx: &mut Context, opts: &Options, path: &str) -> Result<Vec<u8>, String> {
+    if path.starts_with('@') {
+        return match opts.buffers.iter().find(|(name, _)| name == path) {
+            Some((_, bytes)) => Ok(bytes.clone()),
+            None => Err("Include error.".to_string())
+        };
+    }
+    if !opts.allow_files {
+        return Err("Include error.".to_string());
+    }
+    #[cfg(feature = "archives")]
+    if let Some((archive_path, member)) = archive::split(path) {
+        if !ctx.archives.contains_key(archive_path) {
+            check_size(opts, archive_path, fs::metadata(archive_path).map_or(0, |metadata| metadata.len()))?;
+            let bytes = fs::read(archive_path).map_err(|_| "Include error.".to_string())?;
+            if let Some(reads) = ctx.reads.as_mut() {
+                reads.included.push((archive_path.to_string(), sha256::digest(&bytes)));
+            }
+            ctx.archives.insert(archive_path.to_string(), archive::Archive::parse(archive_path, bytes, opts.max_memory)?);
+        }
+        return ctx.archives[archive_path].read(member, opts.max_memory);
+    }
+    check_size(opts, path, fs::metadata(path).map_or(0, |metadata| metadata.len()))?;
+    let bytes = fs::read(path).map_err(|_| "Include error.".to_string())?;
+    if let Some(reads) = ctx.reads.as_mut() {
+        reads.included.push((path.to_string(), sha256::digest(&bytes)));
+    }
+    Ok(bytes)
+}
+
+fn parse_number(option: &str, value: Option<String>) -> Result<usize, String> {
+    match value.map(|value| value.parse()) {
+        Some(Ok(n)) => Ok(n),
+        _ => Err(format!("{} expects a number.", option))
+    }
+}
+
+/*
+    Definitions for --env-prefix: every environment variable whose name starts with the prefix,
+    defined under the rest of its name. Variables whose rest is not a macro name are skipped.
+*/
+fn env_definitions(opts: &mut Options, prefix: &str) {
+    let mut vars: Vec<(String, String)> = env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, _)| name.starts_with(prefix))
+        .collect();
+    vars.sort();
+    for (var, value) in vars {
+        let name = &var[prefix.len()..];
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric()) {
+            warn!(opts, "skipping environment variable {}: {:?} is not a macro name", var, name);
+            continue;
+        }
+        opts.definitions.push(Definition { name: name.to_string(), value, origin: format!("environment variable {}", var) });
+    }
+}
+
+/*
+    Definitions for --env-file, one for each pair in the file. As with --env-prefix, keys that are
+    not macro names are skipped.
+*/
+fn env_file_definitions(opts: &mut Options, path: &str) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Cannot read env file {}: {}.", path, e))?;
+    for (line, name, value) in dotenv::parse(&text)? {
+        if !name.chars().all(|c| c.is_alphanumeric()) {
+            warn!(opts, "skipping {} in {}:{}: not a macro name", name, path, line);
+            continue;
+        }
+        opts.definitions.push(Definition { name, value, origin: format!("{}:{}", path, line) });
+    }
+    Ok(())
+}
+
+// Where the text of --prepend and the like goes
+fn spliced<'a>(opts: &'a mut Options, option: &str) -> &'a mut Vec<(String, Vec<u8>)> {
+    if option.starts_with("--prepend") { &mut opts.prologue } else { &mut opts.epilogue }
+}
+
+/*
+    The arguments with the defaults for them put before them, so that they take precedence: first
+    those of the proj3.toml that applies, unless --no-config is among the arguments, then those in
+    PROJ3_FLAGS
+*/
+fn with_defaults(args: Vec<String>) -> Result<Vec<String>, String> {
+    let mut with_defaults = Vec::new();
+    if !args.iter().any(|arg| arg == "--no-config") {
+        if let Some(path) = config::find() {
+            let text = fs::read_to_string(&path).map_err(|e| format!("Cannot read {}: {}.", path.display(), e))?;
+            with_defaults.extend(config::args(&text, path.parent().unwrap_or(Path::new("")))?);
+        }
+    }
+    if let Ok(flags) = env::var("PROJ3_FLAGS") {
+        with_defaults.extend(encoding::shell_split(&flags).map_err(|e| format!("In PROJ3_FLAGS: {}", e))?);
+    }
+    with_defaults.extend(args);
+    Ok(with_defaults)
+}
+
+/*
+    Level of the messages to show: from PROJ3_LOG (quiet, warn, info, or debug), overridden by -q,
+    -v, and -vv. Found before the other arguments are parsed so that it applies to their messages.
+*/
+fn log_level(args: &[String]) -> Result<u8, String> {
+    let mut level = match env::var("PROJ3_LOG").as_deref() {
+        Err(_) | Ok("") | Ok("warn") => 1,
+        Ok("quiet") => 0,
+        Ok("info") => 2,
+        Ok("debug") => 3,
+        Ok(_) => return Err("PROJ3_LOG must be quiet, warn, info, or debug.".to_string())
+    };
+    for arg in args {
+        match arg.as_str() {
+            "-q" => level = 0,
+            "-v" => level = 2,
+            "-vv" => level = 3,
+            _ => {}
+        }
+    }
+    Ok(level)
+}
+
+fn parse_args(args: Vec<String>) -> Result<(Options, Vec<String>), String> {
+    let mut opts = Options { log_level: log_level(&args)?, ..Options::default() };
+    let mut files = Vec::new();
+    let mut buffers = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--chomp" => opts.chomp = true,
+            "-q" | "-v" | "-vv" => {}, // See log_level
+            "--env-prefix" => match args.next() {
+                Some(prefix) => env_definitions(&mut opts, &prefix),
+                None => return Err("--env-prefix expects a prefix.".to_string())
+            },
+            "--env-file" => match args.next() {
+                Some(path) => env_file_definitions(&mut opts, &path)?,
+                None => return Err("--env-file expects a path.".to_string())
+            },
+            "--define" => match args.next().as_ref().and_then(|value| value.split_once('=')) {
+                Some((name, value)) if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric()) => {
+                    opts.definitions.push(Definition { name: name.to_string(), value: value.to_string(), origin: "--define".to_string() });
+                },
+                _ => return Err("--define expects NAME=VALUE with an alphanumeric name.".to_string())
+            },
+            "--no-config" => {}, // See with_defaults
+            "--dialect" => match args.next().as_deref().and_then(Dialect::from_name) {
+                Some(dialect) => opts.dialect = dialect,
+                None => return Err("--dialect expects tex, cpp, m4, or braces.".to_string())
+            },
+            _ if arg.starts_with("--dialect=") => match Dialect::from_name(&arg["--dialect=".len()..]) {
+                Some(dialect) => opts.dialect = dialect,
+                None => return Err("--dialect expects tex, cpp, m4, or braces.".to_string())
+            },
+            "--flag" => match args.next() {
+                Some(flag) => opts.flags.push(flag),
+                None => return Err("--flag expects a name.".to_string())
+            },
+            "--front-matter" => opts.front_matter = true,
+            "--hash" => opts.hash = true,
+            "--coverage" => opts.coverage = true,
+            "--partial-output" => opts.partial_output = true,
+            "--recover" => opts.recover = true,
+            "--max-errors" => opts.max_errors = parse_number(&arg, args.next())?,
+            "--include-relative" => opts.include_relative = true,
+            "--include-path" => match args.next() {
+                Some(dir) => opts.include_paths.push(dir),
+                None => return Err("--include-path expects a directory.".to_string())
+            },
+            "--keep-comments" => opts.keep_comments = true,
+            "--max-expansions" => opts.max_expansions = parse_number(&arg, args.next())?,
+            "--max-memory" => opts.max_memory = parse_number(&arg, args.next())?,
+            "--max-output" => opts.max_output = parse_number(&arg, args.next())?,
+            "--timeout" => opts.timeout = parse_number(&arg, args.next())?,
+            "--seed" => opts.seed = Some(parse_number(&arg, args.next())? as u64),
+            "--deterministic" => opts.deterministic = true,
+            "--allow-git" => opts.allow_git = true,
+            "--allow-write" => opts.allow_write = true,
+            "--allow-shadow-builtins" => opts.allow_shadow_builtins = true,
+            "--lenient-undef" => opts.lenient_undef = true,
+            "--verify-balanced" => match args.next().as_deref().map(verify::Syntax::from_name) {
+                Some(Some(syntax)) => opts.verify_balanced = Some(syntax),
+                _ => return Err("--verify-balanced expects tex or brackets.".to_string())
+            },
+            "--source-map" => match args.next() {
+                Some(path) => opts.source_map = Some(path),
+                None => return Err("--source-map expects a file.".to_string())
+            },
+            "--prepend" | "--append" => match args.next() {
+                Some(text) => spliced(&mut opts, &arg).push((format!("<{}>", &arg[2..]), text.into_bytes())),
+                None => return Err(format!("{} expects text.", arg))
+            },
+            "--prepend-file" | "--append-file" => match args.next() {
+                Some(path) => match fs::read(&path) {
+                    Ok(bytes) => spliced(&mut opts, &arg).push((path, bytes)),
+                    Err(e) => return Err(format!("Cannot read {}: {}.", path, e))
+                },
+                None => return Err(format!("{} expects a file.", arg))
+            },
+            "--buffer" => match args.next().as_ref().and_then(|value| value.split_once('=')) {
+                Some((name, path)) if !name.is_empty() => buffers.push((name.to_string(), path.to_string())),
+                _ => return Err("--buffer expects name=FILE.".to_string())
+            },
+            "--expand-tabs" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) if n > 0 => opts.expand_tabs = n,
+                _ => return Err("--expand-tabs expects a positive number.".to_string())
+            },
+            "--reindent" => opts.reindent = true,
+            "--strip-trailing-ws" => opts.strip_trailing_ws = true,
+            "--squeeze-blank-lines" => opts.squeeze_blank_lines = true,
+            "--normalize" => match args.next().as_deref().and_then(unicode::Form::from_name) {
+                Some(form) => opts.normalize = Some(form),
+                None => return Err("--normalize expects nfc or nfd.".to_string())
+            },
+            "--locale" => match args.next() {
+                Some(locale) => {
+                    unicode::language(&locale).map_err(|e| format!("--locale: {}", e))?;
+                    opts.locale = locale;
+                },
+                None => return Err("--locale expects a locale such as tr_TR.".to_string())
+            },
+            "--stream" => match args.next().as_ref().and_then(|value| value.split_once('=')) {
+                Some((name, _)) if opts.streams.iter().any(|(mapped, _)| mapped == name) => {
+                    return Err(format!("--stream maps {} more than once.", name));
+                },
+                Some((name, path)) if !name.is_empty() => opts.streams.push((name.to_string(), path.to_string())),
+                _ => return Err("--stream expects name=FILE.".to_string())
+            },
+            "--strict-streams" => opts.strict_streams = true,
+            "--passes" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) if n > 0 => opts.passes = n,
+                _ => return Err("--passes expects a positive number.".to_string())
+            },
+            "--refs" => match args.next() {
+                Some(path) => {
+                    let bytes = fs::read(&path).map_err(|e| format!("Cannot read references {}: {}.", path, e))?;
+                    opts.refs.push((path, bytes));
+                },
+                None => return Err("--refs expects a file.".to_string())
+            },
+            "--post-filter" => match args.next() {
+                Some(command) => opts.post_filters.push(command),
+                None => return Err("--post-filter expects a command.".to_string())
+            },
+            "--raw-delims" => match (args.next(), args.next()) {
+                (Some(begin), Some(end)) if !begin.is_empty() && !end.is_empty() => opts.raw_delims = Some((begin, end)),
+                _ => return Err("--raw-delims expects a begin and an end marker.".to_string())
+            },
+            "--cache" => match args.next() {
+                Some(dir) => opts.cache = Some(dir),
+                None => return Err("--cache expects a directory.".to_string())
+            },
+            "--line-directives" => { opts.line_directives.get_or_insert_with(|| DEFAULT_LINE_DIRECTIVE.to_string()); },
+            "--line-directive-template" => match args.next() {
+                Some(template) => opts.line_directives = Some(template),
+                None => return Err("--line-directive-template expects a template.".to_string())
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}.", arg)),
+            _ => files.push(arg)
+        }
+    }
+    if opts.source_map.is_some() && opts.line_directives.is_some() {
+        return Err("--source-map cannot be combined with --line-directives.".to_string());
+    }
+    // Rewriting the output would leave the positions in it wrong
+    let rewriting = [
+        ("--expand-tabs", opts.expand_tabs > 0), ("--reindent", opts.reindent), ("--strip-trailing-ws", opts.strip_trailing_ws),
+        ("--squeeze-blank-lines", opts.squeeze_blank_lines), ("--normalize", opts.normalize.is_some()),
+        ("--post-filter", !opts.post_filters.is_empty())
+    ];
+    if let Some((option, _)) = rewriting.iter().find(|(_, given)| *given) {
+        if opts.source_map.is_some() || opts.line_directives.is_some() {
+            return Err(format!("{} cannot be combined with --source-map or --line-directives.", option));
+        }
+    }
+    if opts.raw_delims.is_some() && opts.dialect != Dialect::Tex {
+        return Err("--raw-delims cannot be combined with --dialect.".to_string());
+    }
+    if opts.recover && (opts.cache.is_some() || opts.hash) {
+        return Err("--recover cannot be combined with --cache or --hash.".to_string());
+    }
+    if opts.coverage && opts.cache.is_some() {
+        return Err("--coverage cannot be combined with --cache.".to_string());
+    }
+    if !opts.streams.is_empty() && opts.cache.is_some() {
+        return Err("--stream cannot be combined with --cache.".to_string());
+    }
+    if opts.source_map.is_some() && opts.cache.is_some() {
+        return Err("--source-map cannot be combined with --cache.".to_string());
+    }
+    let mut origins: HashMap<String, String> = HashMap::new();
+    for (path, bytes) in &opts.refs {
+        let text = str::from_utf8(bytes).map_err(|_| format!("References {} are not valid UTF-8.", path))?;
+        for (line, key, entry) in refs::parse(text).map_err(|e| format!("In references {}: {}", path, e))? {
+            let origin = format!("{}:{}", path, line);
+            if let Some(first) = origins.get(&key) {
+                return Err(format!("Reference {} is in {} and {}.", key, first, origin));
+            }
+            origins.insert(key.clone(), origin);
+            opts.references.insert(key, entry);
+        }
+    }
+    for (name, path) in buffers {
+        let cannot_read = |e: io::Error| format!("Cannot read buffer {}: {}.", name, e);
+        let bytes = match path.as_str() {
+            "-" if files.is_empty() => return Err("--buffer cannot read stdin when it is the input.".to_string()),
+            "-" => read_stdin(&opts)?,
+            _ => {
+                check_size(&opts, &path, fs::metadata(&path).map_err(cannot_read)?.len())?;
+                fs::read(&path).map_err(cannot_read)?
+            }
+        };
+        opts.buffers.push((format!("@{}", name), bytes));
+    }
+    Ok((opts, files))
+}
+
+/*
+    Preprocesses the input files, or stdin if there are none, into a context that records where each
+    file starts. Returns the concatenated input, ready for process_str.
+*/
+fn load_input(opts: &Options, files: &[String]) -> Result<(Context, Input), Box<dyn error::Error>> {
+    if opts.passes > 1 { return load_passes(opts, &read_inputs(opts, files)?) }
+    let mut sources: Vec<(String, Box<dyn Read>)> = Vec::new();
+    if files.is_empty() {
+        sources.push(("<stdin>".to_string(), Box::new(stdin())));
+    } else {
+        for file in files {
+            sources.push((file.clone(), Box::new(fs::File::open(file)?)));
+        }
+    }
+    load_sources(opts, sources)
+}
+
+// A file \includescoped is processing
+struct ScopedInclude {
+    depth: usize, // Number of scopes while it is processed, including its own
+    id: usize, // Unique to the include, part of the names its private macros are renamed to
+    outer_exports: Option<Vec<String>> // What \export listed before it, restored after it
+}
+
+// What a pass of --passes leaves to the next
+#[derive(Default)]
+struct Carried {
+    definitions: Vec<(String, Macro)>, // Top-level definitions by name
+    labels: HashMap<String, String>, // Values of labels by name
+    index: Option<Vec<(String, String)>>, // Index entries
+    git: HashMap<String, String> // Output of git commands
+}
+
+/*
+    --passes: processes inputs from read_inputs all passes but the last, then loads them for the last
+    one. Each pass starts with the top-level definitions the pass before it left. Earlier passes
+    print nothing, put placeholders for undefined macros as --recover does, and stop quietly at
+    other errors, since they may come from references to definitions made further on; what is
+    wrong is reported by the last pass. Only the last pass writes files, sends text to streams, or
+    prints \show's definitions, and git is run once, in the first pass that needs it.
+*/
+fn load_passes(opts: &Options, inputs: &InputFiles) -> Result<(Context, Input), Box<dyn error::Error>> {
+    let load = |opts: &Options| load_sources(opts, inputs.iter().map(|(name, bytes)| (name.clone(), &bytes[..])).collect());
+    let mut early = opts.clone();
+    early.log_level = 0;
+    early.recover = true;
+    early.max_errors = 0;
+    early.partial_output = false;
+    early.coverage = false;
+    early.source_map = None;
+    early.line_directives = None;
+    early.verify_balanced = None;
+    early.early_pass = true;
+    let mut carried = Carried::default();
+    for _ in 1..opts.passes {
+        let (mut ctx, mut input) = load(&early)?;
+        ctx.carry(carried);
+        let _ = eval::process_str(&mut ctx, &early, &mut input);
+        carried = ctx.into_carried();
+    }
+    let (mut ctx, input) = load(opts)?;
+    ctx.carry(carried);
+    Ok((ctx, input))
+}
+
+fn load_sources(opts: &Options, sources: Vec<(String, impl Read)>) -> Result<(Context, Input), Box<dyn error::Error>> {
+    let mut ctx = Context::default();
+    for (name, groups, _) in &opts.embedded {
+        ctx.builtins.register(name, Arity::Exact(*groups), builtins::builtin_embedded);
+    }
+    if opts.timeout != 0 {
+        ctx.deadline = Some(Instant::now() + Duration::from_secs(opts.timeout as u64));
+    }
+    if opts.source_map.is_some() || opts.line_directives.is_some() || opts.verify_balanced.is_some() {
+        ctx.output_spans = Some(Vec::new());
+    }
+    if opts.coverage {
+        ctx.coverage = Some(Coverage::default());
+    }
+    ctx.random = opts.seed.unwrap_or_else(|| {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        now.as_nanos() as u64 ^ (process::id() as u64) << 32
+    });
+    for definition in &opts.definitions {
+        ctx.predefine(&definition.name, &literal_body(&definition.value), definition.origin.clone());
+    }
+    let mut preprocessed = String::new();
+    for spliced in &opts.prologue {
+        load_spliced(&mut ctx, opts, spliced, &mut preprocessed)?;
+    }
+    for (name, reader) in sources {
+        let mut reader = BufReader::new(reader);
+        let front_matter = if opts.front_matter { frontmatter::read_front_matter(&mut reader)? } else { FrontMatter::default() };
+        for (line, key, value) in &front_matter.pairs {
+            ctx.predefine(key, &literal_body(value), format!("{}:{}", name, line));
+        }
+        let (preprocessed_file, mut marks) = preproc_reader(opts, io::Cursor::new(front_matter.rest).chain(reader))?;
+        for mark in &mut marks {
+            mark.line += front_matter.lines;
+        }
+        ctx.add_source(&name, preprocessed_file.len(), marks);
+        preprocessed.push_str(&preprocessed_file);
+        check_preprocessed(opts, preprocessed.len())?;
+    }
+    for spliced in &opts.epilogue {
+        load_spliced(&mut ctx, opts, spliced, &mut preprocessed)?;
+    }
+    Ok((ctx, Input::from_source(preprocessed.chars().rev().collect(), 0)))
+}
+
+/*
+    Adds text from --prepend or --append to the input as a source of its own, without front matter
+*/
+fn load_spliced(ctx: &mut Context, opts: &Options, (name, bytes): &(String, Vec<u8>), preprocessed: &mut String) -> Result<(), String> {
+    let (preprocessed_text, marks) = preproc_reader(opts, &bytes[..])?;
+    ctx.add_source(name, preprocessed_text.len(), marks);
+    preprocessed.push_str(&preprocessed_text);
+    Ok(())
+}
+
+// Checks made once processing has finished
+fn check_output(ctx: &Context, opts: &Options, output: &str) -> Result<(), String> {
+    if ctx.scopes.len() > 1 { return Err("Unclosed group.".to_string()) }
+    if let Some(syntax) = opts.verify_balanced {
+        verify::verify_balanced(ctx, output, syntax)?;
+    }
+    Ok(())
+}
+
+/*
+    Warns once for each key \cite did not find, with where it was cited, rather than at every
+    citation, so that a missing database entry is reported together with its uses
+*/
+fn warn_unknown_citations(ctx: &mut Context, opts: &Options) {
+    let mut keys: Vec<(&str, Vec<&str>)> = Vec::new();
+    for (key, at) in &ctx.unknown_citations {
+        match keys.iter_mut().find(|(seen, _)| seen == key) {
+            Some((_, places)) => places.push(at),
+            None => keys.push((key, vec![at]))
+        }
+    }
+    for (key, places) in &keys {
+        warn!(opts, "citation {} is not in the references, cited at {}", key, places.join(", "));
+    }
+    ctx.warnings += keys.len();
+}
+
+fn process_input(ctx: &mut Context, opts: &Options, mut input: Input) -> Result<String, String> {
+    let output = eval::process_str(ctx, opts, &mut input)?;
+    warn_unknown_citations(ctx, opts);
+    if let Err(e) = check_output(ctx, opts, &output) {
+        if opts.partial_output { ctx.partial_output = Some(output) }
+        return Err(e);
+    }
+    match &opts.line_directives {
+        Some(template) => Ok(sourcemap::line_directives(ctx, &output, template)),
+        None => postprocess::apply(opts, output)
+    }
+}
+
+/*
+    Error that ends the run with an exit status of its own instead of the usual one, from \error
+*/
+#[derive(Debug)]
+struct ExitError {
+    message: String,
+    code: i32
+}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl error::Error for ExitError {}
+
+// Counts of errors and warnings for --recover, e.g. "7 errors, 3 warnings"
+fn error_summary(ctx: &Context) -> String {
+    let count = |n: usize, what: &str| format!("{} {}{}", n, what, if n == 1 { "" } else { "s" });
+    format!("{}, {}", count(ctx.errors.len(), "error"), count(ctx.warnings, "warning"))
+}
+
+// Prints the errors --recover went on after
+fn report_errors(ctx: &Context) {
+    for error in &ctx.errors {
+        eprintln!("proj3: {}", error);
+    }
+}
+
+/*
+    An error from processing, carrying the exit status \error asked for if it made the run fail.
+    With --partial-output, the output produced before it is written first, followed by a banner on
+    stderr so that it cannot be mistaken for a complete run.
+*/
+fn processing_error(ctx: &Context, message: String) -> Box<dyn error::Error> {
+    if let Some(output) = &ctx.partial_output {
+        print!("{}", output);
+        let _ = io::stdout().flush();
+        eprintln!("proj3: partial output: processing stopped after {} bytes because of the last error below", output.len());
+    }
+    report_errors(ctx);
+    match ctx.exit_code {
+        Some(code) => Box::new(ExitError { message, code }),
+        None => message.into()
+    }
+}
+
+type InputFiles = Vec<(String, Vec<u8>)>; // Names and contents
+type Processed = (Reads, String); // What was read besides the input, and the output
+
+/*
+    Reads the input files, or stdin if there are none, whole, for --cache and --hash
+*/
+fn read_inputs(opts: &Options, files: &[String]) -> Result<InputFiles, Box<dyn error::Error>> {
+    let mut inputs = Vec::new();
+    if files.is_empty() {
+        inputs.push(("<stdin>".to_string(), read_stdin(opts)?));
+    } else {
+        for file in files {
+            check_size(opts, file, fs::metadata(file)?.len())?;
+            inputs.push((file.clone(), fs::read(file)?));
+        }
+    }
+    Ok(inputs)
+}
+
+// All of stdin, unless it is more than --max-memory allows
+fn read_stdin(opts: &Options) -> Result<Vec<u8>, String> {
+    let limit = if opts.max_memory == 0 { u64::MAX } else { opts.max_memory as u64 + 1 };
+    let mut bytes = Vec::new();
+    stdin().take(limit).read_to_end(&mut bytes).map_err(|e| format!("Cannot read stdin: {}.", e))?;
+    check_size(opts, "<stdin>", bytes.len() as u64)?;
+    Ok(bytes)
+}
+
+/*
+    Processes inputs from read_inputs, returning what was read along the way with the output
+*/
+fn process_inputs(opts: &Options, inputs: &InputFiles) -> Result<Processed, Box<dyn error::Error>> {
+    let (mut ctx, input) = load_passes(opts, inputs)?;
+    ctx.reads = Some(Reads::default());
+    let output = process_input(&mut ctx, opts, input).map_err(|e| processing_error(&ctx, e))?;
+    write_source_map(&ctx, opts, &output)?;
+    write_streams(&ctx, opts)?;
+    print_coverage(&ctx);
+    Ok((ctx.reads.unwrap_or_default(), output))
+}
+
+/*
+    With --cache the output is looked up by the hash of the arguments and the input, and reused as
+    long as what the cached run read is unchanged
+*/
+fn process_cached(args: &[String], opts: &Options, inputs: &InputFiles, dir: &str) -> Result<Processed, Box<dyn error::Error>> {
+    let files = inputs.iter().chain(&opts.buffers).chain(&opts.prologue).chain(&opts.epilogue).chain(&opts.refs);
+    // How much is logged does not change the output
+    let args: Vec<String> = args.iter().filter(|arg| !matches!(arg.as_str(), "-q" | "-v" | "-vv")).cloned().collect();
+    let key = cache::key(&args, files, &opts.definitions);
+    if let Some(hit) = cache::lookup(dir, &key) {
+        info!(opts, "using cached output {}", key);
+        return Ok(hit);
+    }
+    info!(opts, "no usable cached output, processing");
+    let (reads, output) = process_inputs(opts, inputs)?;
+    if let Err(e) = cache::store(dir, &key, &reads, &output) {
+        warn!(opts, "cannot write to the cache: {}", e);
+    }
+    Ok((reads, output))
+}
+
+fn write_source_map(ctx: &Context, opts: &Options, output: &str) -> Result<(), Box<dyn error::Error>> {
+    if let Some(path) = &opts.source_map {
+        fs::write(path, sourcemap::source_map(ctx, output))?;
+    }
+    Ok(())
+}
+
+/*
+    Writes each stream --stream maps to its file, empty if nothing was sent to it
+*/
+fn write_streams(ctx: &Context, opts: &Options) -> Result<(), Box<dyn error::Error>> {
+    for (name, path) in &opts.streams {
+        let text = ctx.streams.get(name).map_or("", |text| text.as_str());
+        fs::write(path, text).map_err(|e| format!("Cannot write stream {} to {}: {}.", name, path, e))?;
+    }
+    Ok(())
+}
+
+/*
+    --coverage: prints every definition site whose macro was never expanded to stderr, one
+    "unused \name defined at file:line" line each, followed by a summary
+*/
+fn print_coverage(ctx: &Context) {
+    let Some(coverage) = &ctx.coverage else { return };
+    let mut unused = 0;
+    for (name, defined_at, used) in &coverage.sites {
+        if *used { continue }
+        eprintln!("unused \\{} defined at {}", ctx.symbols.name(*name), defined_at);
+        unused += 1;
+    }
+    eprintln!("coverage: {} of {} definitions unused", unused, coverage.sites.len());
+}
+
+/*
+    --hash: prints the SHA-256 of the output and of every file that went into it to stderr, so that
+    runs on different machines can be compared. Files included more than once are listed once.
+*/
+fn print_hashes(opts: &Options, inputs: &InputFiles, included: &[(String, Digest)], output: &str) {
+    eprintln!("sha256 output {}", sha256::hex(&sha256::digest(output.as_bytes())));
+    for (name, bytes) in inputs.iter().chain(&opts.buffers).chain(&opts.prologue).chain(&opts.epilogue).chain(&opts.refs) {
+        eprintln!("sha256 input {} {}", sha256::hex(&sha256::digest(bytes)), name);
+    }
+    for (i, (path, digest)) in included.iter().enumerate() {
+        if included[..i].iter().any(|(earlier, other)| earlier == path && other == digest) { continue }
+        eprintln!("sha256 include {} {}", sha256::hex(digest), path);
+    }
+}
+
+fn read_file(args: Vec<String>) -> Result<(), Box<dyn error::Error>> {
+    let args = with_defaults(args)?;
+    let (opts, files) = parse_args(args.clone())?;
+    if opts.cache.is_some() || opts.hash {
+        let inputs = read_inputs(&opts, &files)?;
+        let (reads, output) = match &opts.cache {
+            Some(dir) => process_cached(&args, &opts, &inputs, dir)?,
+            None => process_inputs(&opts, &inputs)?
+        };
+        print!("{}", output);
+        if opts.hash { print_hashes(&opts, &inputs, &reads.included, &output) }
+        return Ok(());
+    }
+    let (mut ctx, input) = load_input(&opts, &files)?;
+    let output = process_input(&mut ctx, &opts, input).map_err(|e| processing_error(&ctx, e))?;
+    write_source_map(&ctx, &opts, &output)?;
+    write_streams(&ctx, &opts)?;
+    print_coverage(&ctx);
+    print!("{}", output); // No newline
+    if !ctx.errors.is_empty() {
+        io::stdout().flush()?;
+        report_errors(&ctx);
+        return Err(format!("{}.", error_summary(&ctx)).into());
+    }
+    Ok(())
+}
+
+/*
+    proj3 --bench [--iterations N] [options] file...: processes the files N times from scratch and
+    reports the throughput of the fastest and the median run
+*/
+fn run_bench(args: Vec<String>) -> Result<(), Box<dyn error::Error>> {
+    let mut iterations = 10;
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--iterations" {
+            iterations = parse_number(&arg, args.next())?;
+        } else {
+            rest.push(arg);
+        }
+    }
+    let (opts, files) = parse_args(with_defaults(rest)?)?;
+    if files.is_empty() || iterations == 0 {
+        return Err("--bench expects a file and at least one iteration.".into());
+    }
+    let mut times = Vec::new();
+    let mut input_len = 0;
+    let mut output_len = 0;
+    let mut expansions = 0;
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let (mut ctx, input) = load_input(&opts, &files)?;
+        input_len = ctx.source_len;
+        output_len = process_input(&mut ctx, &opts, input)?.len();
+        times.push(start.elapsed());
+        expansions = ctx.expansions;
+    }
+    times.sort();
+    let throughput = |time: Duration| input_len as f64 / 1e6 / time.as_secs_f64().max(1e-9);
+    println!("{} iterations, {} bytes in, {} bytes out, {} expansions", iterations, input_len, output_len, expansions);
+    println!("min    {:>10.3?} {:>10.2} MB/s", times[0], throughput(times[0]));
+    println!("median {:>10.3?} {:>10.2} MB/s", times[times.len() / 2], throughput(times[times.len() / 2]));
+    Ok(())
+}
+
+/*
+    proj3 --test [options] dir: processes every .in file in dir and compares the result with the
+    matching .expected file. A case that fails to process is compared as the error message proj3 would print.
+*/
+fn run_tests(args: Vec<String>) -> Result<(), Box<dyn error::Error>> {
+    let (opts, dirs) = parse_args(with_defaults(args)?)?;
+    if dirs.len() != 1 {
+        return Err("--test expects one directory.".into());
+    }
+    let mut cases: Vec<_> = fs::read_dir(&dirs[0])?.map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    cases.retain(|path| path.extension().is_some_and(|ext| ext == "in"));
+    cases.sort();
+    let mut failed = 0;
+    for case in &cases {
+        let input_name = case.display().to_string();
+        let expected_path = case.with_extension("expected");
+        let expected_name = expected_path.display().to_string();
+        let expected = match fs::read_to_string(&expected_path) {
+            Ok(expected) => expected,
+            Err(e) => {
+                println!("FAIL {}: cannot read {}: {}", input_name, expected_name, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let actual = match load_input(&opts, std::slice::from_ref(&input_name)) {
+            Ok((mut ctx, input)) => process_input(&mut ctx, &opts, input).unwrap_or_else(|e| format!("proj3: {}\n", e)),
+            Err(e) => format!("proj3: {}\n", e)
+        };
+        if actual == expected {
+            println!("ok   {}", input_name);
+        } else {
+            println!("FAIL {}", input_name);
+            print!("{}", diff::unified_diff(&expected_name, &input_name, &expected, &actual));
+            failed += 1;
+        }
+    }
+    println!("{} passed, {} failed", cases.len() - failed, failed);
+    if failed != 0 {
+        return Err(format!("{} of {} tests failed.", failed, cases.len()).into());
+    }
+    Ok(())
+}
+
+/*
+    Entry point for fuzz targets, see above. Preprocesses and processes arbitrary bytes without
+    touching the file system and with small limits so each run stays fast. Errors are expected; only
+    panics indicate a bug, so warnings are not logged.
+*/
+#[cfg(feature = "fuzz")]
+pub fn fuzz_process(bytes: &[u8]) {
+    let opts = Options { max_expansions: 10_000, max_memory: 1 << 20, max_output: 1 << 16, allow_files: false, log_level: 0,
+                         ..Options::default() };
+    let mut ctx = Context::default();
+    let (preprocessed, marks) = match preproc_reader(&opts, bytes) {
+        Ok(preprocessed) => preprocessed,
+        Err(_) => return
+    };
+    ctx.add_source("<fuzz>", preprocessed.len(), marks);
+    let _ = process_input(&mut ctx, &opts, Input::from_source(preprocessed.chars().rev().collect(), 0));
+}
+
+/*
+    The processor for embedders, see above: options as on the command line, without the defaults of
+    proj3.toml and PROJ3_FLAGS, and builtins and output filters of the embedder's own
+*/
+pub struct Processor {
+    opts: Options,
+    filters: Vec<Box<dyn OutputFilter>> // Run after those of --post-filter, in the order added
+}
+
+impl Processor {
+    pub fn new(args: &[&str]) -> Result<Processor, String> {
+        let (opts, files) = parse_args(args.iter().map(|arg| arg.to_string()).collect())?;
+        if let Some(file) = files.first() {
+            return Err(format!("Processor::new expects options, not the input file {}.", file));
+        }
+        Ok(Processor { opts, filters: Vec::new() })
+    }
+
+    /*
+        Adds a builtin called as \name{a}{b} with the given number of argument groups, replacing
+        any builtin of the same name. With --passes the handler is called in every pass, since what
+        it returns may be needed by the definitions a pass leaves, so unlike \writefile, a handler
+        with side effects has them once per pass.
+    */
+    pub fn builtin(&mut self, name: &str, groups: usize, handler: impl Fn(&[String]) -> Result<String, String> + 'static) -> Result<(), String> {
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric()) {
+            return Err(format!("Invalid builtin name {:?}.", name));
+        }
+        if groups == 0 {
+            return Err(format!("Builtin {} expects at least one argument group.", name));
+        }
+        self.opts.embedded.retain(|(embedded, _, _)| embedded != name);
+        self.opts.embedded.push((name.to_string(), groups, Rc::new(handler)));
+        Ok(())
+    }
+
+    /*
+        Adds a step the output goes through after those of the options, e.g. a closure taking and
+        returning it
+    */
+    pub fn filter(&mut self, filter: impl OutputFilter + 'static) {
+        self.filters.push(Box::new(filter));
+    }
+
+    /*
+        The output for a text, named as a file for locations in messages. With --recover, the
+        errors it went on after make it fail once processing has finished.
+    */
+    pub fn process(&mut self, name: &str, text: &str) -> Result<String, String> {
+        let inputs = vec![(name.to_string(), text.as_bytes().to_vec())];
+        let (mut ctx, input) = load_passes(&self.opts, &inputs).map_err(|e| e.to_string())?;
+        let mut output = process_input(&mut ctx, &self.opts, input)?;
+        write_streams(&ctx, &self.opts).map_err(|e| e.to_string())?;
+        if !ctx.errors.is_empty() {
+            return Err(format!("{}: {}", error_summary(&ctx), ctx.errors.join("; ")));
+        }
+        for filter in &mut self.filters {
+            output = filter.filter(output)?;
+        }
+        Ok(output)
+    }
+}
+
+/*
+    The proj3 command, with the arguments it was run with
+*/
+pub fn run_command_line() {
+    panic::set_hook(Box::new(|_| { }));
+
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let result = if args.first().is_some_and(|arg| arg == "--bench") {
+        run_bench(args.split_off(1))
+    } else if args.first().is_some_and(|arg| arg == "--test") {
+        run_tests(args.split_off(1))
+    } else {
+        read_file(args)
+    };
+    if let Err(e) = result {
+        if let Some(e) = e.downcast_ref::<ExitError>() {
+            eprintln!("proj3: {}", e.message);
+            process::exit(e.code);
+        }
+        die!("{}", e);
+    }
+}
@@ -0,0 +1,188 @@
+/*
+    The m4 dialect of --dialect m4, translated into the native syntax before a file is preprocessed:
+    define, undefine, ifdef, include, and dnl are carried out with the builtins, other words are
+    calls if they name macros when they are reached, with the arguments in parentheses right after
+    them, and text in `quotes' is taken as it is, less one level of quotes. In bodies, $1 to $9
+    stand for the arguments. changequote, ifelse, $#, and the other m4 builtins are not supported,
+    nor, since macro names are alphanumeric, names containing _.
+*/
+
+use super::{literal_body, push_literal};
+
+const MAX_ARGS: usize = 9; // Calls pass this many groups, so that $1 to $9 are always there
+
+/*
+    The native form of a file written for m4
+*/
+pub(crate) fn translate(text: &str) -> Result<String, String> {
+    let mut translated = String::with_capacity(text.len());
+    translate_into(text, false, &mut translated)?;
+    Ok(translated)
+}
+
+// Text of a definition's body if in_body, where $n refers to the arguments
+fn translate_into(text: &str, in_body: bool, translated: &mut String) -> Result<(), String> {
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '`' {
+            let (quoted, after) = split_quoted(rest)?;
+            for c in quoted.chars() { push_literal(translated, c) }
+            rest = after;
+        } else if c == '#' {
+            // Comments are copied as they are, up to the end of the line
+            let end = rest.find('\n').unwrap_or(rest.len());
+            for c in rest[..end].chars() { push_literal(translated, c) }
+            rest = &rest[end..];
+        } else if c == '$' && in_body && rest[1..].starts_with(|d: char| ('1'..='9').contains(&d)) {
+            translated.push_str(&format!("\\arg{{{}}}", &rest[1..2]));
+            rest = &rest[2..];
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let (word, after) = rest.split_at(end);
+            rest = if c.is_ascii_digit() {
+                translated.push_str(word);
+                after
+            } else {
+                translate_word(word, after, in_body, translated)?
+            };
+        } else {
+            push_literal(translated, c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Ok(())
+}
+
+/*
+    A word and the arguments after it, if any. Returns the text after them.
+*/
+fn translate_word<'a>(word: &str, after: &'a str, in_body: bool, translated: &mut String) -> Result<&'a str, String> {
+    if word == "dnl" {
+        // Up to and including the end of the line, which stays in the text so that lines keep
+        // their numbers
+        return Ok(match after.find('\n') {
+            Some(end) => {
+                translated.push_str("\\if{}{\n}{}");
+                &after[end + 1..]
+            },
+            None => ""
+        });
+    }
+    let Some(inside) = after.strip_prefix('(') else {
+        if matches!(word, "define" | "undefine" | "ifdef" | "include") {
+            return Err(format!("{} expects arguments.", word));
+        }
+        if macro_name(word).is_ok() {
+            translated.push_str(&format!("\\ifdef{{{0}}}{{\\{0}{1}}}{{{0}}}", word, "{}".repeat(MAX_ARGS)));
+        } else {
+            translated.push_str(word);
+        }
+        return Ok(after);
+    };
+    let (args, rest) = split_args(inside)?;
+    let arg = |i: usize| args.get(i).map_or("", |arg| unquote(arg));
+    match word {
+        "define" => {
+            translated.push_str(&format!("\\def{{{}}}{{", macro_name(arg(0))?));
+            translate_into(arg(1), true, translated)?;
+            // Refers to \arg so that the definition takes all MAX_ARGS groups of a call
+            translated.push_str("\\if{}{\\arg{1}}{}}");
+        },
+        "undefine" => translated.push_str(&format!("\\ifdef{{{0}}}{{\\undef{{{0}}}}}{{}}", macro_name(arg(0))?)),
+        "ifdef" => {
+            translated.push_str(&format!("\\ifdef{{{}}}{{", macro_name(arg(0))?));
+            translate_into(arg(1), in_body, translated)?;
+            translated.push_str("}{");
+            translate_into(arg(2), in_body, translated)?;
+            translated.push('}');
+        },
+        "include" => translated.push_str(&format!("\\include{{{}}}", literal_body(arg(0)))),
+        _ if word.contains('_') || args.len() > MAX_ARGS => {
+            translated.push_str(word);
+            return Ok(after);
+        },
+        _ => {
+            // A call if the word names a macro, otherwise the word and the text in parentheses
+            translated.push_str(&format!("\\ifdef{{{0}}}{{\\{0}", word));
+            for i in 0..MAX_ARGS {
+                translated.push('{');
+                translate_into(args.get(i).map_or("", |arg| arg.trim_start()), in_body, translated)?;
+                translated.push('}');
+            }
+            translated.push_str(&format!("}}{{{}(", word));
+            translate_into(&inside[..inside.len() - rest.len() - 1], in_body, translated)?;
+            translated.push_str(")}");
+        }
+    }
+    Ok(rest)
+}
+
+fn macro_name(name: &str) -> Result<&str, String> {
+    if name.contains('_') {
+        return Err(format!("Macro names with _ are not supported by the m4 dialect, as in {}.", name));
+    }
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("Invalid macro name {:?}.", name));
+    }
+    Ok(name)
+}
+
+/*
+    The text in the quotes text starts with, which may be nested, and the text after them
+*/
+fn split_quoted(text: &str) -> Result<(&str, &str), String> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '`' => depth += 1,
+            '\'' => {
+                depth -= 1;
+                if depth == 0 { return Ok((&text[1..i], &text[i + 1..])) }
+            },
+            _ => {}
+        }
+    }
+    Err("Unterminated quote.".to_string())
+}
+
+/*
+    The arguments of a call, whose opening parenthesis has been read, as written, and the text after
+    the closing one. Commas and parentheses in quotes or nested parentheses do not count.
+*/
+fn split_args(text: &str) -> Result<(Vec<&str>, &str), String> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut at = 0;
+    while let Some(c) = text[at..].chars().next() {
+        match c {
+            '`' => {
+                let (_, after) = split_quoted(&text[at..])?;
+                at = text.len() - after.len();
+                continue;
+            },
+            '(' => depth += 1,
+            ')' if depth == 0 => {
+                args.push(&text[start..at]);
+                return Ok((args, &text[at + 1..]));
+            },
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(&text[start..at]);
+                start = at + 1;
+            },
+            _ => {}
+        }
+        at += c.len_utf8();
+    }
+    Err("Unterminated argument list.".to_string())
+}
+
+// An argument with the leading whitespace m4 skips and one level of quotes removed
+fn unquote(arg: &str) -> &str {
+    let arg = arg.trim_start();
+    match split_quoted(arg) {
+        Ok((quoted, after)) if arg.starts_with('`') && after.trim().is_empty() => quoted,
+        _ => arg.trim_end()
+    }
+}
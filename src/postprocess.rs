@@ -0,0 +1,158 @@
+/*
+    Rewriting of the finished output for --expand-tabs, --strip-trailing-ws, --reindent, and
+    --squeeze-blank-lines, for generated text whose whitespace came out uneven from macros written
+    at different depths and lines of definitions, then --normalize, and the filters of --post-filter
+    after them
+*/
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use super::Options;
+use super::encoding;
+use super::unicode;
+
+const DEFAULT_TAB_WIDTH: usize = 8; // Columns of a tab for --reindent without --expand-tabs
+const DEFAULT_INDENT: usize = 4; // Spaces per level for --reindent without --expand-tabs
+
+/*
+    A step the finished output goes through before it is written, e.g. a formatter: a command of
+    --post-filter, or a closure an embedder gives Processor::filter
+*/
+pub trait OutputFilter {
+    fn filter(&mut self, output: String) -> Result<String, String>;
+}
+
+impl<F: FnMut(String) -> Result<String, String>> OutputFilter for F {
+    fn filter(&mut self, output: String) -> Result<String, String> {
+        self(output)
+    }
+}
+
+/*
+    An external command from --post-filter, split into words like PROJ3_FLAGS rather than run by a
+    shell, that gets the output on its stdin and whose stdout replaces it
+*/
+pub(crate) struct CommandFilter<'a> {
+    pub(crate) command: &'a str
+}
+
+impl OutputFilter for CommandFilter<'_> {
+    fn filter(&mut self, output: String) -> Result<String, String> {
+        let words = encoding::shell_split(self.command)?;
+        let Some((program, args)) = words.split_first() else {
+            return Err("--post-filter expects a command.".to_string());
+        };
+        let mut child = Command::new(program).args(args).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()
+            .map_err(|e| format!("Cannot run --post-filter {}: {}.", self.command, e))?;
+        // Written from another thread so that a filter that answers before reading all of its input
+        // cannot deadlock with us
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = thread::spawn(move || stdin.write_all(output.as_bytes()));
+        let mut filtered = Vec::new();
+        child.stdout.take().unwrap().read_to_end(&mut filtered).map_err(|e| e.to_string())?;
+        let status = child.wait().map_err(|e| e.to_string())?;
+        // A filter may stop reading early on purpose, e.g. head, so a broken pipe is not an error
+        let _ = writer.join();
+        if !status.success() {
+            return Err(format!("--post-filter {} failed with {}.", self.command, status));
+        }
+        String::from_utf8(filtered).map_err(|_| format!("--post-filter {} did not output valid UTF-8.", self.command))
+    }
+}
+
+/*
+    The output with the rewriting the options ask for done, in the order above
+*/
+pub(crate) fn apply(opts: &Options, output: String) -> Result<String, String> {
+    let mut output = output;
+    if opts.expand_tabs > 0 { output = expand_tabs(&output, opts.expand_tabs) }
+    if opts.strip_trailing_ws { output = strip_trailing_ws(&output) }
+    if opts.reindent {
+        let (tab_width, indent) = match opts.expand_tabs {
+            0 => (DEFAULT_TAB_WIDTH, DEFAULT_INDENT),
+            n => (n, n)
+        };
+        output = reindent(&output, tab_width, indent);
+    }
+    if opts.squeeze_blank_lines { output = squeeze_blank_lines(&output) }
+    if let Some(form) = opts.normalize { output = unicode::normalize(&output, form) }
+    for command in &opts.post_filters {
+        output = CommandFilter { command }.filter(output)?;
+    }
+    Ok(output)
+}
+
+// Tabs replaced by spaces up to the next multiple of width columns, counting characters
+fn expand_tabs(text: &str, width: usize) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut col = 0;
+    for c in text.chars() {
+        match c {
+            '\t' => {
+                let spaces = width - col % width;
+                expanded.extend(std::iter::repeat_n(' ', spaces));
+                col += spaces;
+            },
+            '\n' => {
+                expanded.push(c);
+                col = 0;
+            },
+            _ => {
+                expanded.push(c);
+                col += 1;
+            }
+        }
+    }
+    expanded
+}
+
+/*
+    Lines indented by levels of indent spaces: a line indented deeper than the one before it starts
+    a level, and one indented less goes back to the level of the nearest line before it indented as
+    much or less. Lines of only whitespace become empty.
+*/
+fn reindent(text: &str, tab_width: usize, indent: usize) -> String {
+    let mut reindented = String::with_capacity(text.len());
+    let mut depths = vec![0]; // Widths of the open levels, outermost first
+    for line in text.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            reindented.push_str(&line[line.trim_end_matches(['\n', '\r']).len()..]);
+            continue;
+        }
+        let content = line.trim_start_matches([' ', '\t']);
+        let mut width = 0;
+        for c in line[..line.len() - content.len()].chars() {
+            width = if c == '\t' { width + tab_width - width % tab_width } else { width + 1 };
+        }
+        while depths.last().is_some_and(|&depth| depth > width) { depths.pop(); }
+        if depths.last().is_none_or(|&depth| depth < width) { depths.push(width) }
+        reindented.extend(std::iter::repeat_n(' ', (depths.len() - 1) * indent));
+        reindented.push_str(content);
+    }
+    reindented
+}
+
+// Spaces and tabs at the ends of lines removed
+fn strip_trailing_ws(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\n', '\r']);
+        stripped.push_str(content.trim_end_matches([' ', '\t']));
+        stripped.push_str(&line[content.len()..]);
+    }
+    stripped
+}
+
+// Runs of lines of only whitespace replaced by their first line
+fn squeeze_blank_lines(text: &str) -> String {
+    let mut squeezed = String::with_capacity(text.len());
+    let mut prev_is_blank = false;
+    for line in text.split_inclusive('\n') {
+        let is_blank = line.trim().is_empty();
+        if !(is_blank && prev_is_blank) { squeezed.push_str(line) }
+        prev_is_blank = is_blank;
+    }
+    squeezed
+}
@@ -0,0 +1,130 @@
+/*
+    Reference databases for --refs, which \cite looks keys up in. A file holds entries of two kinds,
+    which may be mixed: key = text lines, whose text is the formatted entry as it is, optionally
+    quoted as in front matter, and BibTeX entries such as
+        @book{knuth84, author = {Donald E. Knuth}, title = {The {TeX}book}, year = 1984}
+    with values in braces, in quotes, or bare, formatted as "author. title. venue, year." with the
+    parts that are missing left out, the venue being the journal, book title, or publisher. Braces
+    inside values are dropped, and @string macros, # concatenation, and entries in parentheses are
+    not supported. Empty lines and lines starting with # or % are skipped, as are @comment entries.
+*/
+
+use super::frontmatter::unquote;
+
+type Fields = Vec<(String, String)>; // Names and values of the fields of a BibTeX entry
+
+/*
+    The entries of a database, with their lines, as keys and formatted texts
+*/
+pub(crate) fn parse(text: &str) -> Result<Vec<(usize, String, String)>, String> {
+    let mut entries = Vec::new();
+    let mut rest = text;
+    loop {
+        rest = rest.trim_start();
+        let line = text[..text.len() - rest.len()].matches('\n').count() + 1;
+        if rest.is_empty() { return Ok(entries) }
+        if rest.starts_with(['#', '%']) {
+            rest = rest.split_once('\n').map_or("", |(_, after)| after);
+            continue;
+        }
+        let Some(entry) = rest.strip_prefix('@') else {
+            let (pair, after) = rest.split_once('\n').unwrap_or((rest, ""));
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(format!("Unsupported reference on line {}.", line));
+            };
+            entries.push((line, entry_key(key.trim(), line)?.to_string(), unquote(value.trim())));
+            rest = after;
+            continue;
+        };
+        let kind_end = entry.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(entry.len());
+        let kind = entry[..kind_end].to_ascii_lowercase();
+        let Some(body) = entry[kind_end..].trim_start().strip_prefix('{') else {
+            return Err(format!("Expected {{ after @{} on line {}.", kind, line));
+        };
+        if kind == "comment" {
+            rest = &body[braced_len(body).ok_or_else(|| format!("Unterminated @comment on line {}.", line))?..];
+            continue;
+        }
+        if kind == "string" || kind == "preamble" {
+            return Err(format!("@{} is not supported in references, on line {}.", kind, line));
+        }
+        let (key, fields, after) = parse_entry(body, line)?;
+        entries.push((line, key.to_string(), format_entry(&fields)));
+        rest = after;
+    }
+}
+
+fn entry_key(key: &str, line: usize) -> Result<&str, String> {
+    if key.is_empty() || key.contains(|c: char| c.is_whitespace() || c == ',' || c == '{' || c == '}') {
+        return Err(format!("Invalid reference key {:?} on line {}.", key, line));
+    }
+    Ok(key)
+}
+
+// Length of text up to and including the } closing a { before it, counting nested pairs
+fn braced_len(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i + 1),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/*
+    The key and the fields, with lowercase names, of a BibTeX entry whose opening brace has been
+    read, and the text after its closing one
+*/
+fn parse_entry(body: &str, line: usize) -> Result<(&str, Fields, &str), String> {
+    let unterminated = || format!("Unterminated reference entry starting on line {}.", line);
+    let key_end = body.find([',', '}']).ok_or_else(unterminated)?;
+    let key = entry_key(body[..key_end].trim(), line)?;
+    let mut fields = Vec::new();
+    let mut rest = &body[key_end..];
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix('}') { return Ok((key, fields, after)) }
+        rest = rest.strip_prefix(',').ok_or_else(unterminated)?.trim_start();
+        // A comma may follow the last field
+        if let Some(after) = rest.strip_prefix('}') { return Ok((key, fields, after)) }
+        let (name, value) = rest.split_once('=').ok_or_else(|| format!("Expected name = value in reference {}.", key))?;
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("Invalid field name {:?} in reference {}.", name, key));
+        }
+        let value = value.trim_start();
+        let (value, after) = if let Some(braced) = value.strip_prefix('{') {
+            let len = braced_len(braced).ok_or_else(unterminated)?;
+            (&braced[..len - 1], &braced[len..])
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            let len = quoted.find('"').ok_or_else(unterminated)?;
+            (&quoted[..len], &quoted[len + 1..])
+        } else {
+            let len = value.find([',', '}']).ok_or_else(unterminated)?;
+            (value[..len].trim_end(), &value[len..])
+        };
+        if after.trim_start().starts_with('#') {
+            return Err(format!("# concatenation is not supported in references, in {}.", key));
+        }
+        // Braces only protect case in BibTeX, and line breaks in values are spaces
+        let value: String = value.chars().filter(|&c| c != '{' && c != '}').collect();
+        fields.push((name.to_ascii_lowercase(), value.split_whitespace().collect::<Vec<_>>().join(" ")));
+        rest = after;
+    }
+}
+
+// "author. title. venue, year." with the parts that are missing left out
+fn format_entry(fields: &[(String, String)]) -> String {
+    let field = |names: &[&str]| names.iter()
+        .find_map(|name| fields.iter().find(|(field, value)| field == name && !value.is_empty()))
+        .map(|(_, value)| value.trim_end_matches('.'));
+    let last = [field(&["journal", "booktitle", "publisher"]), field(&["year"])];
+    let last: Vec<&str> = last.into_iter().flatten().collect();
+    let mut parts: Vec<String> = [field(&["author"]), field(&["title"])].into_iter().flatten().map(str::to_string).collect();
+    if !last.is_empty() { parts.push(last.join(", ")) }
+    parts.iter().map(|part| format!("{}.", part)).collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,143 @@
+/*
+    Maps the output back to the source: source maps for --source-map, in the JSON format of the
+    Source Map Revision 3 proposal that most compilers and editors understand, with columns counting
+    bytes on both sides, line directives for --line-directives, and origins for --verify-balanced.
+*/
+
+use super::Context;
+use super::encoding::{self, BASE64};
+
+/*
+    Writes the mappings string, one segment at a time. Fields are stored as differences to the
+    previous segment, the generated column only within the current line.
+*/
+struct Mappings {
+    text: String,
+    line_has_segment: bool,
+    col: usize, // Generated column of the previous segment on this line
+    source: usize, // Source index, line, and column of the previous segment
+    source_line: usize,
+    source_col: usize
+}
+
+impl Mappings {
+    fn new_line(&mut self) {
+        self.text.push(';');
+        self.line_has_segment = false;
+        self.col = 0;
+    }
+
+    // Lines are counted from 1 by Context::source_position but from 0 in source maps
+    fn segment(&mut self, col: usize, source: usize, line: usize, source_col: usize) {
+        if self.line_has_segment { self.text.push(',') }
+        self.line_has_segment = true;
+        push_vlq(&mut self.text, col as i64 - self.col as i64);
+        push_vlq(&mut self.text, source as i64 - self.source as i64);
+        push_vlq(&mut self.text, (line - 1) as i64 - self.source_line as i64);
+        push_vlq(&mut self.text, source_col as i64 - self.source_col as i64);
+        (self.col, self.source, self.source_line, self.source_col) = (col, source, line - 1, source_col);
+    }
+}
+
+/*
+    Base64 VLQ: five bits at a time, least significant first, with the sign in the lowest bit
+*/
+fn push_vlq(text: &mut String, value: i64) {
+    let mut rest = (value.unsigned_abs() << 1) | (value < 0) as u64;
+    loop {
+        let digit = (rest & 31) as usize;
+        rest >>= 5;
+        text.push(BASE64[digit | if rest != 0 { 32 } else { 0 }] as char);
+        if rest == 0 { break }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    format!("\"{}\"", encoding::json_escape(s))
+}
+
+/*
+    Source map of the output of the top-level process_str, from the spans it recorded. Verbatim
+    spans get a segment wherever the source they follow jumps, e.g. over a stripped comment or to
+    a new line; generated spans map every line they cover to where they were generated.
+*/
+pub(crate) fn source_map(ctx: &Context, output: &str) -> String {
+    // A file included more than once is listed once
+    let mut names: Vec<&str> = Vec::new();
+    let indices: Vec<usize> = ctx.sources.iter().map(|file| {
+        names.iter().position(|&name| name == file.name).unwrap_or_else(|| {
+            names.push(&file.name);
+            names.len() - 1
+        })
+    }).collect();
+
+    let mut mappings = Mappings { text: String::new(), line_has_segment: false, col: 0, source: 0, source_line: 0, source_col: 0 };
+    let spans = ctx.output_spans.as_deref().unwrap_or(&[]);
+    let mut line_start = 0; // Output offset of the current generated line
+    let mut expected = None; // Source position that continues the previous segment
+    for (i, span) in spans.iter().enumerate() {
+        let end = spans.get(i + 1).map_or(output.len(), |next| next.output);
+        let mut segment_start = true;
+        for (offset, c) in output[span.output..end].char_indices() {
+            let at = span.output + offset;
+            let source = if span.verbatim { span.source + offset } else { span.source };
+            if let Some((file, line, col)) = ctx.source_position(source) {
+                let position = (indices[file], line, col);
+                if segment_start || expected != Some(position) {
+                    mappings.segment(at - line_start, position.0, position.1, position.2);
+                }
+                expected = Some((position.0, position.1, if span.verbatim { col + c.len_utf8() } else { col }));
+            }
+            segment_start = false;
+            if c == '\n' {
+                mappings.new_line();
+                line_start = at + 1;
+                segment_start = true;
+            }
+        }
+    }
+
+    let sources: Vec<String> = names.iter().map(|name| json_string(name)).collect();
+    format!("{{\"version\":3,\"sources\":[{}],\"names\":[],\"mappings\":{}}}\n", sources.join(","), json_string(&mappings.text))
+}
+
+/*
+    File index, line, and column of where the output at offset comes from, see Context::source_position
+*/
+pub(crate) fn origin(ctx: &Context, offset: usize) -> Option<(usize, usize, usize)> {
+    let spans = ctx.output_spans.as_deref()?;
+    let span = &spans[spans.partition_point(|span| span.output <= offset).checked_sub(1)?];
+    ctx.source_position(if span.verbatim { span.source + offset - span.output } else { span.source })
+}
+
+/*
+    Puts a line directive from the template, with {file} and {line} filled in, before every line
+    of the output that does not come from the line after the previous one, e.g. the first line of
+    an included file or the lines a macro expands to. A line is attributed to where its first
+    character comes from.
+*/
+pub(crate) fn line_directives(ctx: &Context, output: &str, template: &str) -> String {
+    let spans = ctx.output_spans.as_deref().unwrap_or(&[]);
+    let mut result = String::with_capacity(output.len());
+    let mut expected = ctx.sources.first().map(|file| (file.name.as_str(), 1));
+    let mut offset = 0;
+    let mut i = 0;
+    for line in output.split_inclusive('\n') {
+        while spans.get(i + 1).is_some_and(|next| next.output <= offset) { i += 1 }
+        let position = spans.get(i).filter(|span| span.output <= offset).and_then(|span| {
+            ctx.source_position(if span.verbatim { span.source + offset - span.output } else { span.source })
+        });
+        if let Some((file, line_number, _)) = position {
+            let name = ctx.sources[file].name.as_str();
+            if expected != Some((name, line_number)) {
+                result.push_str(&template.replace("{file}", name).replace("{line}", &line_number.to_string()));
+                result.push('\n');
+            }
+            expected = Some((name, line_number));
+        }
+        expected = expected.map(|(name, line_number)| (name, line_number + 1));
+        result.push_str(line);
+        offset += line.len();
+    }
+    result
+}
@@ -0,0 +1,210 @@
+/*
+    Unicode normalization for --normalize: NFD takes every character apart into its canonical
+    decomposition, with combining marks in canonical order, and NFC puts the pieces back together
+    where the standard composes them, so that text assembled from differently written fragments
+    compares equal. See https://unicode.org/reports/tr15/. Also the case conversion of \uppercase
+    and \lowercase, and the folding to ASCII of \ascii and \slugify.
+*/
+
+use super::unicode_tables::{COMBINING_CLASSES, COMPOSITIONS, DECOMPOSITIONS};
+
+#[derive(Copy, Clone)]
+pub(crate) enum Form {
+    Nfc,
+    Nfd
+}
+
+impl Form {
+    pub(crate) fn from_name(name: &str) -> Option<Form> {
+        match name {
+            "nfc" => Some(Form::Nfc),
+            "nfd" => Some(Form::Nfd),
+            _ => None
+        }
+    }
+}
+
+// Hangul syllables are composed of leading consonants, vowels, and optional trailing consonants
+const HANGUL_BASE: u32 = 0xAC00;
+const LEADING_BASE: u32 = 0x1100;
+const VOWEL_BASE: u32 = 0x1161;
+const TRAILING_BASE: u32 = 0x11A7; // One before the first trailing consonant, standing for none
+const VOWELS: u32 = 21;
+const TRAILINGS: u32 = 28;
+const SYLLABLES: u32 = 19 * VOWELS * TRAILINGS;
+
+pub(crate) fn normalize(text: &str, form: Form) -> String {
+    let decomposed = decompose(text);
+    match form {
+        Form::Nfd => decomposed.into_iter().collect(),
+        Form::Nfc => compose(decomposed).into_iter().collect()
+    }
+}
+
+fn combining_class(c: char) -> u8 {
+    match COMBINING_CLASSES.binary_search_by(|&(start, end, _)| {
+        if end < c { std::cmp::Ordering::Less } else if start > c { std::cmp::Ordering::Greater } else { std::cmp::Ordering::Equal }
+    }) {
+        Ok(i) => COMBINING_CLASSES[i].2,
+        Err(_) => 0
+    }
+}
+
+/*
+    Pushes the full canonical decomposition of c
+*/
+pub(crate) fn push_decomposition(chars: &mut Vec<char>, c: char) {
+    let index = (c as u32).wrapping_sub(HANGUL_BASE);
+    if index < SYLLABLES {
+        chars.push(char::from_u32(LEADING_BASE + index / (VOWELS * TRAILINGS)).unwrap());
+        chars.push(char::from_u32(VOWEL_BASE + index % (VOWELS * TRAILINGS) / TRAILINGS).unwrap());
+        if !index.is_multiple_of(TRAILINGS) { chars.push(char::from_u32(TRAILING_BASE + index % TRAILINGS).unwrap()) }
+        return;
+    }
+    match DECOMPOSITIONS.binary_search_by_key(&c, |&(composed, _)| composed) {
+        Ok(i) => chars.extend(DECOMPOSITIONS[i].1.chars()),
+        Err(_) => chars.push(c)
+    }
+}
+
+// The characters of text decomposed, with each run of combining marks sorted by class
+fn decompose(text: &str) -> Vec<char> {
+    let mut chars = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        let start = chars.len();
+        push_decomposition(&mut chars, c);
+        // Moves each new mark back past the marks of higher classes before it, keeping the sort stable
+        for i in start..chars.len() {
+            let class = combining_class(chars[i]);
+            if class == 0 { continue }
+            let mut j = i;
+            while j > 0 && combining_class(chars[j - 1]) > class {
+                chars.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+    }
+    chars
+}
+
+// The character first and second compose into, if any
+fn compose_pair(first: char, second: char) -> Option<char> {
+    let (first_code, second_code) = (first as u32, second as u32);
+    if (LEADING_BASE..LEADING_BASE + 19).contains(&first_code) && (VOWEL_BASE..VOWEL_BASE + VOWELS).contains(&second_code) {
+        let index = (first_code - LEADING_BASE) * VOWELS * TRAILINGS + (second_code - VOWEL_BASE) * TRAILINGS;
+        return char::from_u32(HANGUL_BASE + index);
+    }
+    let index = first_code.wrapping_sub(HANGUL_BASE);
+    if index < SYLLABLES && index.is_multiple_of(TRAILINGS) && (TRAILING_BASE + 1..TRAILING_BASE + TRAILINGS).contains(&second_code) {
+        return char::from_u32(first_code + second_code - TRAILING_BASE);
+    }
+    COMPOSITIONS.binary_search_by_key(&(first, second), |&(a, b, _)| (a, b)).ok().map(|i| COMPOSITIONS[i].2)
+}
+
+/*
+    Decomposed characters with each mark composed into the last starter before it, unless a
+    character between them blocks it: one of class 0 or of a class no lower than the mark's
+*/
+fn compose(chars: Vec<char>) -> Vec<char> {
+    let mut composed: Vec<char> = Vec::with_capacity(chars.len());
+    let mut starter = None; // Index in composed of the last character of class 0
+    let mut last_class = 0; // Of the last character pushed after the starter
+    for c in chars {
+        let class = combining_class(c);
+        if let Some(i) = starter {
+            let adjacent = composed.len() == i + 1;
+            if adjacent || last_class != 0 && last_class < class {
+                if let Some(pair) = compose_pair(composed[i], c) {
+                    composed[i] = pair;
+                    continue;
+                }
+            }
+        }
+        if class == 0 { starter = Some(composed.len()) }
+        last_class = class;
+        composed.push(c);
+    }
+    composed
+}
+
+/*
+    The language of a locale such as tr, tr_TR, or tr-TR.UTF-8, in lowercase, for case conversion.
+    The empty locale has no language.
+*/
+pub(crate) fn language(locale: &str) -> Result<String, String> {
+    let language = locale.split(['_', '-', '.', '@']).next().unwrap_or("");
+    if !language.chars().all(|c| c.is_ascii_alphabetic()) || locale.is_empty() != language.is_empty() {
+        return Err(format!("Invalid locale {:?}.", locale));
+    }
+    Ok(language.to_ascii_lowercase())
+}
+
+/*
+    Text in uppercase or lowercase by the default Unicode rules, e.g. with ß becoming SS and a final
+    sigma lowercased as one, except that in Turkish and Azerbaijani the dotted i and the dotless ı
+    keep their dots: i and İ are one pair of cases, ı and I another
+*/
+pub(crate) fn change_case(text: &str, upper: bool, language: &str) -> String {
+    let turkic = language == "tr" || language == "az";
+    match (turkic, upper) {
+        (false, true) => text.to_uppercase(),
+        (false, false) => text.to_lowercase(),
+        (true, true) => text.replace('i', "\u{130}").to_uppercase(),
+        // I with a combining dot above is another way to write İ
+        (true, false) => text.replace("I\u{307}", "i").replace('\u{130}', "i").replace('I', "\u{131}").to_lowercase()
+    }
+}
+
+// ASCII approximations of letters and punctuation that do not decompose into ASCII and marks
+const ASCII_APPROXIMATIONS: &[(char, &str)] = &[
+    ('\u{A0}', " "), ('\u{AB}', "<<"), ('\u{BB}', ">>"), ('\u{C6}', "AE"), ('\u{D0}', "D"), ('\u{D7}', "x"),
+    ('\u{D8}', "O"), ('\u{DE}', "Th"), ('\u{DF}', "ss"), ('\u{E6}', "ae"), ('\u{F0}', "d"), ('\u{F8}', "o"),
+    ('\u{FE}', "th"), ('\u{110}', "D"), ('\u{111}', "d"), ('\u{126}', "H"), ('\u{127}', "h"), ('\u{131}', "i"),
+    ('\u{132}', "IJ"), ('\u{133}', "ij"), ('\u{138}', "q"), ('\u{13F}', "L"), ('\u{140}', "l"), ('\u{141}', "L"),
+    ('\u{142}', "l"), ('\u{149}', "'n"), ('\u{14A}', "NG"), ('\u{14B}', "ng"), ('\u{152}', "OE"), ('\u{153}', "oe"),
+    ('\u{166}', "T"), ('\u{167}', "t"), ('\u{17F}', "s"), ('\u{180}', "b"), ('\u{1E9E}', "SS"), ('\u{2010}', "-"),
+    ('\u{2011}', "-"), ('\u{2012}', "-"), ('\u{2013}', "-"), ('\u{2014}', "-"), ('\u{2018}', "'"), ('\u{2019}', "'"),
+    ('\u{201A}', ","), ('\u{201C}', "\""), ('\u{201D}', "\""), ('\u{201E}', ",,"), ('\u{2026}', "..."), ('\u{2032}', "'"),
+    ('\u{2033}', "\""), ('\u{2039}', "<"), ('\u{203A}', ">"), ('\u{20AC}', "EUR"), ('\u{2122}', "TM"), ('\u{FB00}', "ff"),
+    ('\u{FB01}', "fi"), ('\u{FB02}', "fl"), ('\u{FB03}', "ffi"), ('\u{FB04}', "ffl")
+];
+
+/*
+    Text folded to ASCII: accented letters lose their accents, letters such as ß and Ø and typographic
+    punctuation become their usual ASCII spellings, and anything else that is not ASCII is dropped
+*/
+pub(crate) fn to_ascii(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    let mut chars = Vec::new();
+    for c in text.chars() {
+        chars.clear();
+        push_decomposition(&mut chars, c);
+        for &piece in &chars {
+            if piece.is_ascii() {
+                folded.push(piece);
+            } else if let Ok(i) = ASCII_APPROXIMATIONS.binary_search_by_key(&piece, |&(c, _)| c) {
+                folded.push_str(ASCII_APPROXIMATIONS[i].1);
+            }
+        }
+    }
+    folded
+}
+
+/*
+    A URL slug of text: folded to ASCII and lowercased, with each run of spaces, hyphens, and
+    underscores becoming one hyphen, no hyphens at either end, and other punctuation dropped
+*/
+pub(crate) fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    let mut pending_hyphen = false;
+    for c in to_ascii(text).chars() {
+        if c.is_ascii_alphanumeric() {
+            if pending_hyphen && !slug.is_empty() { slug.push('-') }
+            pending_hyphen = false;
+            slug.push(c.to_ascii_lowercase());
+        } else if c.is_ascii_whitespace() || c == '-' || c == '_' {
+            pending_hyphen = true;
+        }
+    }
+    slug
+}
@@ -0,0 +1,862 @@
+/*
+    Unicode 14.0.0 character data for unicode.rs, generated from the Unicode Character Database:
+    canonical decompositions, applied fully, the canonical compositions that are not excluded, and
+    canonical combining classes. Hangul syllables are left out, since they are computed.
+*/
+
+pub(crate) const DECOMPOSITIONS: &[(char, &str)] = &[
+    ('\u{C0}', "A\u{300}"), ('\u{C1}', "A\u{301}"), ('\u{C2}', "A\u{302}"), ('\u{C3}', "A\u{303}"),
+    ('\u{C4}', "A\u{308}"), ('\u{C5}', "A\u{30A}"), ('\u{C7}', "C\u{327}"), ('\u{C8}', "E\u{300}"),
+    ('\u{C9}', "E\u{301}"), ('\u{CA}', "E\u{302}"), ('\u{CB}', "E\u{308}"), ('\u{CC}', "I\u{300}"),
+    ('\u{CD}', "I\u{301}"), ('\u{CE}', "I\u{302}"), ('\u{CF}', "I\u{308}"), ('\u{D1}', "N\u{303}"),
+    ('\u{D2}', "O\u{300}"), ('\u{D3}', "O\u{301}"), ('\u{D4}', "O\u{302}"), ('\u{D5}', "O\u{303}"),
+    ('\u{D6}', "O\u{308}"), ('\u{D9}', "U\u{300}"), ('\u{DA}', "U\u{301}"), ('\u{DB}', "U\u{302}"),
+    ('\u{DC}', "U\u{308}"), ('\u{DD}', "Y\u{301}"), ('\u{E0}', "a\u{300}"), ('\u{E1}', "a\u{301}"),
+    ('\u{E2}', "a\u{302}"), ('\u{E3}', "a\u{303}"), ('\u{E4}', "a\u{308}"), ('\u{E5}', "a\u{30A}"),
+    ('\u{E7}', "c\u{327}"), ('\u{E8}', "e\u{300}"), ('\u{E9}', "e\u{301}"), ('\u{EA}', "e\u{302}"),
+    ('\u{EB}', "e\u{308}"), ('\u{EC}', "i\u{300}"), ('\u{ED}', "i\u{301}"), ('\u{EE}', "i\u{302}"),
+    ('\u{EF}', "i\u{308}"), ('\u{F1}', "n\u{303}"), ('\u{F2}', "o\u{300}"), ('\u{F3}', "o\u{301}"),
+    ('\u{F4}', "o\u{302}"), ('\u{F5}', "o\u{303}"), ('\u{F6}', "o\u{308}"), ('\u{F9}', "u\u{300}"),
+    ('\u{FA}', "u\u{301}"), ('\u{FB}', "u\u{302}"), ('\u{FC}', "u\u{308}"), ('\u{FD}', "y\u{301}"),
+    ('\u{FF}', "y\u{308}"), ('\u{100}', "A\u{304}"), ('\u{101}', "a\u{304}"), ('\u{102}', "A\u{306}"),
+    ('\u{103}', "a\u{306}"), ('\u{104}', "A\u{328}"), ('\u{105}', "a\u{328}"), ('\u{106}', "C\u{301}"),
+    ('\u{107}', "c\u{301}"), ('\u{108}', "C\u{302}"), ('\u{109}', "c\u{302}"), ('\u{10A}', "C\u{307}"),
+    ('\u{10B}', "c\u{307}"), ('\u{10C}', "C\u{30C}"), ('\u{10D}', "c\u{30C}"), ('\u{10E}', "D\u{30C}"),
+    ('\u{10F}', "d\u{30C}"), ('\u{112}', "E\u{304}"), ('\u{113}', "e\u{304}"), ('\u{114}', "E\u{306}"),
+    ('\u{115}', "e\u{306}"), ('\u{116}', "E\u{307}"), ('\u{117}', "e\u{307}"), ('\u{118}', "E\u{328}"),
+    ('\u{119}', "e\u{328}"), ('\u{11A}', "E\u{30C}"), ('\u{11B}', "e\u{30C}"), ('\u{11C}', "G\u{302}"),
+    ('\u{11D}', "g\u{302}"), ('\u{11E}', "G\u{306}"), ('\u{11F}', "g\u{306}"), ('\u{120}', "G\u{307}"),
+    ('\u{121}', "g\u{307}"), ('\u{122}', "G\u{327}"), ('\u{123}', "g\u{327}"), ('\u{124}', "H\u{302}"),
+    ('\u{125}', "h\u{302}"), ('\u{128}', "I\u{303}"), ('\u{129}', "i\u{303}"), ('\u{12A}', "I\u{304}"),
+    ('\u{12B}', "i\u{304}"), ('\u{12C}', "I\u{306}"), ('\u{12D}', "i\u{306}"), ('\u{12E}', "I\u{328}"),
+    ('\u{12F}', "i\u{328}"), ('\u{130}', "I\u{307}"), ('\u{134}', "J\u{302}"), ('\u{135}', "j\u{302}"),
+    ('\u{136}', "K\u{327}"), ('\u{137}', "k\u{327}"), ('\u{139}', "L\u{301}"), ('\u{13A}', "l\u{301}"),
+    ('\u{13B}', "L\u{327}"), ('\u{13C}', "l\u{327}"), ('\u{13D}', "L\u{30C}"), ('\u{13E}', "l\u{30C}"),
+    ('\u{143}', "N\u{301}"), ('\u{144}', "n\u{301}"), ('\u{145}', "N\u{327}"), ('\u{146}', "n\u{327}"),
+    ('\u{147}', "N\u{30C}"), ('\u{148}', "n\u{30C}"), ('\u{14C}', "O\u{304}"), ('\u{14D}', "o\u{304}"),
+    ('\u{14E}', "O\u{306}"), ('\u{14F}', "o\u{306}"), ('\u{150}', "O\u{30B}"), ('\u{151}', "o\u{30B}"),
+    ('\u{154}', "R\u{301}"), ('\u{155}', "r\u{301}"), ('\u{156}', "R\u{327}"), ('\u{157}', "r\u{327}"),
+    ('\u{158}', "R\u{30C}"), ('\u{159}', "r\u{30C}"), ('\u{15A}', "S\u{301}"), ('\u{15B}', "s\u{301}"),
+    ('\u{15C}', "S\u{302}"), ('\u{15D}', "s\u{302}"), ('\u{15E}', "S\u{327}"), ('\u{15F}', "s\u{327}"),
+    ('\u{160}', "S\u{30C}"), ('\u{161}', "s\u{30C}"), ('\u{162}', "T\u{327}"), ('\u{163}', "t\u{327}"),
+    ('\u{164}', "T\u{30C}"), ('\u{165}', "t\u{30C}"), ('\u{168}', "U\u{303}"), ('\u{169}', "u\u{303}"),
+    ('\u{16A}', "U\u{304}"), ('\u{16B}', "u\u{304}"), ('\u{16C}', "U\u{306}"), ('\u{16D}', "u\u{306}"),
+    ('\u{16E}', "U\u{30A}"), ('\u{16F}', "u\u{30A}"), ('\u{170}', "U\u{30B}"), ('\u{171}', "u\u{30B}"),
+    ('\u{172}', "U\u{328}"), ('\u{173}', "u\u{328}"), ('\u{174}', "W\u{302}"), ('\u{175}', "w\u{302}"),
+    ('\u{176}', "Y\u{302}"), ('\u{177}', "y\u{302}"), ('\u{178}', "Y\u{308}"), ('\u{179}', "Z\u{301}"),
+    ('\u{17A}', "z\u{301}"), ('\u{17B}', "Z\u{307}"), ('\u{17C}', "z\u{307}"), ('\u{17D}', "Z\u{30C}"),
+    ('\u{17E}', "z\u{30C}"), ('\u{1A0}', "O\u{31B}"), ('\u{1A1}', "o\u{31B}"), ('\u{1AF}', "U\u{31B}"),
+    ('\u{1B0}', "u\u{31B}"), ('\u{1CD}', "A\u{30C}"), ('\u{1CE}', "a\u{30C}"), ('\u{1CF}', "I\u{30C}"),
+    ('\u{1D0}', "i\u{30C}"), ('\u{1D1}', "O\u{30C}"), ('\u{1D2}', "o\u{30C}"), ('\u{1D3}', "U\u{30C}"),
+    ('\u{1D4}', "u\u{30C}"), ('\u{1D5}', "U\u{308}\u{304}"), ('\u{1D6}', "u\u{308}\u{304}"), ('\u{1D7}', "U\u{308}\u{301}"),
+    ('\u{1D8}', "u\u{308}\u{301}"), ('\u{1D9}', "U\u{308}\u{30C}"), ('\u{1DA}', "u\u{308}\u{30C}"), ('\u{1DB}', "U\u{308}\u{300}"),
+    ('\u{1DC}', "u\u{308}\u{300}"), ('\u{1DE}', "A\u{308}\u{304}"), ('\u{1DF}', "a\u{308}\u{304}"), ('\u{1E0}', "A\u{307}\u{304}"),
+    ('\u{1E1}', "a\u{307}\u{304}"), ('\u{1E2}', "\u{C6}\u{304}"), ('\u{1E3}', "\u{E6}\u{304}"), ('\u{1E6}', "G\u{30C}"),
+    ('\u{1E7}', "g\u{30C}"), ('\u{1E8}', "K\u{30C}"), ('\u{1E9}', "k\u{30C}"), ('\u{1EA}', "O\u{328}"),
+    ('\u{1EB}', "o\u{328}"), ('\u{1EC}', "O\u{328}\u{304}"), ('\u{1ED}', "o\u{328}\u{304}"), ('\u{1EE}', "\u{1B7}\u{30C}"),
+    ('\u{1EF}', "\u{292}\u{30C}"), ('\u{1F0}', "j\u{30C}"), ('\u{1F4}', "G\u{301}"), ('\u{1F5}', "g\u{301}"),
+    ('\u{1F8}', "N\u{300}"), ('\u{1F9}', "n\u{300}"), ('\u{1FA}', "A\u{30A}\u{301}"), ('\u{1FB}', "a\u{30A}\u{301}"),
+    ('\u{1FC}', "\u{C6}\u{301}"), ('\u{1FD}', "\u{E6}\u{301}"), ('\u{1FE}', "\u{D8}\u{301}"), ('\u{1FF}', "\u{F8}\u{301}"),
+    ('\u{200}', "A\u{30F}"), ('\u{201}', "a\u{30F}"), ('\u{202}', "A\u{311}"), ('\u{203}', "a\u{311}"),
+    ('\u{204}', "E\u{30F}"), ('\u{205}', "e\u{30F}"), ('\u{206}', "E\u{311}"), ('\u{207}', "e\u{311}"),
+    ('\u{208}', "I\u{30F}"), ('\u{209}', "i\u{30F}"), ('\u{20A}', "I\u{311}"), ('\u{20B}', "i\u{311}"),
+    ('\u{20C}', "O\u{30F}"), ('\u{20D}', "o\u{30F}"), ('\u{20E}', "O\u{311}"), ('\u{20F}', "o\u{311}"),
+    ('\u{210}', "R\u{30F}"), ('\u{211}', "r\u{30F}"), ('\u{212}', "R\u{311}"), ('\u{213}', "r\u{311}"),
+    ('\u{214}', "U\u{30F}"), ('\u{215}', "u\u{30F}"), ('\u{216}', "U\u{311}"), ('\u{217}', "u\u{311}"),
+    ('\u{218}', "S\u{326}"), ('\u{219}', "s\u{326}"), ('\u{21A}', "T\u{326}"), ('\u{21B}', "t\u{326}"),
+    ('\u{21E}', "H\u{30C}"), ('\u{21F}', "h\u{30C}"), ('\u{226}', "A\u{307}"), ('\u{227}', "a\u{307}"),
+    ('\u{228}', "E\u{327}"), ('\u{229}', "e\u{327}"), ('\u{22A}', "O\u{308}\u{304}"), ('\u{22B}', "o\u{308}\u{304}"),
+    ('\u{22C}', "O\u{303}\u{304}"), ('\u{22D}', "o\u{303}\u{304}"), ('\u{22E}', "O\u{307}"), ('\u{22F}', "o\u{307}"),
+    ('\u{230}', "O\u{307}\u{304}"), ('\u{231}', "o\u{307}\u{304}"), ('\u{232}', "Y\u{304}"), ('\u{233}', "y\u{304}"),
+    ('\u{340}', "\u{300}"), ('\u{341}', "\u{301}"), ('\u{343}', "\u{313}"), ('\u{344}', "\u{308}\u{301}"),
+    ('\u{374}', "\u{2B9}"), ('\u{37E}', ";"), ('\u{385}', "\u{A8}\u{301}"), ('\u{386}', "\u{391}\u{301}"),
+    ('\u{387}', "\u{B7}"), ('\u{388}', "\u{395}\u{301}"), ('\u{389}', "\u{397}\u{301}"), ('\u{38A}', "\u{399}\u{301}"),
+    ('\u{38C}', "\u{39F}\u{301}"), ('\u{38E}', "\u{3A5}\u{301}"), ('\u{38F}', "\u{3A9}\u{301}"), ('\u{390}', "\u{3B9}\u{308}\u{301}"),
+    ('\u{3AA}', "\u{399}\u{308}"), ('\u{3AB}', "\u{3A5}\u{308}"), ('\u{3AC}', "\u{3B1}\u{301}"), ('\u{3AD}', "\u{3B5}\u{301}"),
+    ('\u{3AE}', "\u{3B7}\u{301}"), ('\u{3AF}', "\u{3B9}\u{301}"), ('\u{3B0}', "\u{3C5}\u{308}\u{301}"), ('\u{3CA}', "\u{3B9}\u{308}"),
+    ('\u{3CB}', "\u{3C5}\u{308}"), ('\u{3CC}', "\u{3BF}\u{301}"), ('\u{3CD}', "\u{3C5}\u{301}"), ('\u{3CE}', "\u{3C9}\u{301}"),
+    ('\u{3D3}', "\u{3D2}\u{301}"), ('\u{3D4}', "\u{3D2}\u{308}"), ('\u{400}', "\u{415}\u{300}"), ('\u{401}', "\u{415}\u{308}"),
+    ('\u{403}', "\u{413}\u{301}"), ('\u{407}', "\u{406}\u{308}"), ('\u{40C}', "\u{41A}\u{301}"), ('\u{40D}', "\u{418}\u{300}"),
+    ('\u{40E}', "\u{423}\u{306}"), ('\u{419}', "\u{418}\u{306}"), ('\u{439}', "\u{438}\u{306}"), ('\u{450}', "\u{435}\u{300}"),
+    ('\u{451}', "\u{435}\u{308}"), ('\u{453}', "\u{433}\u{301}"), ('\u{457}', "\u{456}\u{308}"), ('\u{45C}', "\u{43A}\u{301}"),
+    ('\u{45D}', "\u{438}\u{300}"), ('\u{45E}', "\u{443}\u{306}"), ('\u{476}', "\u{474}\u{30F}"), ('\u{477}', "\u{475}\u{30F}"),
+    ('\u{4C1}', "\u{416}\u{306}"), ('\u{4C2}', "\u{436}\u{306}"), ('\u{4D0}', "\u{410}\u{306}"), ('\u{4D1}', "\u{430}\u{306}"),
+    ('\u{4D2}', "\u{410}\u{308}"), ('\u{4D3}', "\u{430}\u{308}"), ('\u{4D6}', "\u{415}\u{306}"), ('\u{4D7}', "\u{435}\u{306}"),
+    ('\u{4DA}', "\u{4D8}\u{308}"), ('\u{4DB}', "\u{4D9}\u{308}"), ('\u{4DC}', "\u{416}\u{308}"), ('\u{4DD}', "\u{436}\u{308}"),
+    ('\u{4DE}', "\u{417}\u{308}"), ('\u{4DF}', "\u{437}\u{308}"), ('\u{4E2}', "\u{418}\u{304}"), ('\u{4E3}', "\u{438}\u{304}"),
+    ('\u{4E4}', "\u{418}\u{308}"), ('\u{4E5}', "\u{438}\u{308}"), ('\u{4E6}', "\u{41E}\u{308}"), ('\u{4E7}', "\u{43E}\u{308}"),
+    ('\u{4EA}', "\u{4E8}\u{308}"), ('\u{4EB}', "\u{4E9}\u{308}"), ('\u{4EC}', "\u{42D}\u{308}"), ('\u{4ED}', "\u{44D}\u{308}"),
+    ('\u{4EE}', "\u{423}\u{304}"), ('\u{4EF}', "\u{443}\u{304}"), ('\u{4F0}', "\u{423}\u{308}"), ('\u{4F1}', "\u{443}\u{308}"),
+    ('\u{4F2}', "\u{423}\u{30B}"), ('\u{4F3}', "\u{443}\u{30B}"), ('\u{4F4}', "\u{427}\u{308}"), ('\u{4F5}', "\u{447}\u{308}"),
+    ('\u{4F8}', "\u{42B}\u{308}"), ('\u{4F9}', "\u{44B}\u{308}"), ('\u{622}', "\u{627}\u{653}"), ('\u{623}', "\u{627}\u{654}"),
+    ('\u{624}', "\u{648}\u{654}"), ('\u{625}', "\u{627}\u{655}"), ('\u{626}', "\u{64A}\u{654}"), ('\u{6C0}', "\u{6D5}\u{654}"),
+    ('\u{6C2}', "\u{6C1}\u{654}"), ('\u{6D3}', "\u{6D2}\u{654}"), ('\u{929}', "\u{928}\u{93C}"), ('\u{931}', "\u{930}\u{93C}"),
+    ('\u{934}', "\u{933}\u{93C}"), ('\u{958}', "\u{915}\u{93C}"), ('\u{959}', "\u{916}\u{93C}"), ('\u{95A}', "\u{917}\u{93C}"),
+    ('\u{95B}', "\u{91C}\u{93C}"), ('\u{95C}', "\u{921}\u{93C}"), ('\u{95D}', "\u{922}\u{93C}"), ('\u{95E}', "\u{92B}\u{93C}"),
+    ('\u{95F}', "\u{92F}\u{93C}"), ('\u{9CB}', "\u{9C7}\u{9BE}"), ('\u{9CC}', "\u{9C7}\u{9D7}"), ('\u{9DC}', "\u{9A1}\u{9BC}"),
+    ('\u{9DD}', "\u{9A2}\u{9BC}"), ('\u{9DF}', "\u{9AF}\u{9BC}"), ('\u{A33}', "\u{A32}\u{A3C}"), ('\u{A36}', "\u{A38}\u{A3C}"),
+    ('\u{A59}', "\u{A16}\u{A3C}"), ('\u{A5A}', "\u{A17}\u{A3C}"), ('\u{A5B}', "\u{A1C}\u{A3C}"), ('\u{A5E}', "\u{A2B}\u{A3C}"),
+    ('\u{B48}', "\u{B47}\u{B56}"), ('\u{B4B}', "\u{B47}\u{B3E}"), ('\u{B4C}', "\u{B47}\u{B57}"), ('\u{B5C}', "\u{B21}\u{B3C}"),
+    ('\u{B5D}', "\u{B22}\u{B3C}"), ('\u{B94}', "\u{B92}\u{BD7}"), ('\u{BCA}', "\u{BC6}\u{BBE}"), ('\u{BCB}', "\u{BC7}\u{BBE}"),
+    ('\u{BCC}', "\u{BC6}\u{BD7}"), ('\u{C48}', "\u{C46}\u{C56}"), ('\u{CC0}', "\u{CBF}\u{CD5}"), ('\u{CC7}', "\u{CC6}\u{CD5}"),
+    ('\u{CC8}', "\u{CC6}\u{CD6}"), ('\u{CCA}', "\u{CC6}\u{CC2}"), ('\u{CCB}', "\u{CC6}\u{CC2}\u{CD5}"), ('\u{D4A}', "\u{D46}\u{D3E}"),
+    ('\u{D4B}', "\u{D47}\u{D3E}"), ('\u{D4C}', "\u{D46}\u{D57}"), ('\u{DDA}', "\u{DD9}\u{DCA}"), ('\u{DDC}', "\u{DD9}\u{DCF}"),
+    ('\u{DDD}', "\u{DD9}\u{DCF}\u{DCA}"), ('\u{DDE}', "\u{DD9}\u{DDF}"), ('\u{F43}', "\u{F42}\u{FB7}"), ('\u{F4D}', "\u{F4C}\u{FB7}"),
+    ('\u{F52}', "\u{F51}\u{FB7}"), ('\u{F57}', "\u{F56}\u{FB7}"), ('\u{F5C}', "\u{F5B}\u{FB7}"), ('\u{F69}', "\u{F40}\u{FB5}"),
+    ('\u{F73}', "\u{F71}\u{F72}"), ('\u{F75}', "\u{F71}\u{F74}"), ('\u{F76}', "\u{FB2}\u{F80}"), ('\u{F78}', "\u{FB3}\u{F80}"),
+    ('\u{F81}', "\u{F71}\u{F80}"), ('\u{F93}', "\u{F92}\u{FB7}"), ('\u{F9D}', "\u{F9C}\u{FB7}"), ('\u{FA2}', "\u{FA1}\u{FB7}"),
+    ('\u{FA7}', "\u{FA6}\u{FB7}"), ('\u{FAC}', "\u{FAB}\u{FB7}"), ('\u{FB9}', "\u{F90}\u{FB5}"), ('\u{1026}', "\u{1025}\u{102E}"),
+    ('\u{1B06}', "\u{1B05}\u{1B35}"), ('\u{1B08}', "\u{1B07}\u{1B35}"), ('\u{1B0A}', "\u{1B09}\u{1B35}"), ('\u{1B0C}', "\u{1B0B}\u{1B35}"),
+    ('\u{1B0E}', "\u{1B0D}\u{1B35}"), ('\u{1B12}', "\u{1B11}\u{1B35}"), ('\u{1B3B}', "\u{1B3A}\u{1B35}"), ('\u{1B3D}', "\u{1B3C}\u{1B35}"),
+    ('\u{1B40}', "\u{1B3E}\u{1B35}"), ('\u{1B41}', "\u{1B3F}\u{1B35}"), ('\u{1B43}', "\u{1B42}\u{1B35}"), ('\u{1E00}', "A\u{325}"),
+    ('\u{1E01}', "a\u{325}"), ('\u{1E02}', "B\u{307}"), ('\u{1E03}', "b\u{307}"), ('\u{1E04}', "B\u{323}"),
+    ('\u{1E05}', "b\u{323}"), ('\u{1E06}', "B\u{331}"), ('\u{1E07}', "b\u{331}"), ('\u{1E08}', "C\u{327}\u{301}"),
+    ('\u{1E09}', "c\u{327}\u{301}"), ('\u{1E0A}', "D\u{307}"), ('\u{1E0B}', "d\u{307}"), ('\u{1E0C}', "D\u{323}"),
+    ('\u{1E0D}', "d\u{323}"), ('\u{1E0E}', "D\u{331}"), ('\u{1E0F}', "d\u{331}"), ('\u{1E10}', "D\u{327}"),
+    ('\u{1E11}', "d\u{327}"), ('\u{1E12}', "D\u{32D}"), ('\u{1E13}', "d\u{32D}"), ('\u{1E14}', "E\u{304}\u{300}"),
+    ('\u{1E15}', "e\u{304}\u{300}"), ('\u{1E16}', "E\u{304}\u{301}"), ('\u{1E17}', "e\u{304}\u{301}"), ('\u{1E18}', "E\u{32D}"),
+    ('\u{1E19}', "e\u{32D}"), ('\u{1E1A}', "E\u{330}"), ('\u{1E1B}', "e\u{330}"), ('\u{1E1C}', "E\u{327}\u{306}"),
+    ('\u{1E1D}', "e\u{327}\u{306}"), ('\u{1E1E}', "F\u{307}"), ('\u{1E1F}', "f\u{307}"), ('\u{1E20}', "G\u{304}"),
+    ('\u{1E21}', "g\u{304}"), ('\u{1E22}', "H\u{307}"), ('\u{1E23}', "h\u{307}"), ('\u{1E24}', "H\u{323}"),
+    ('\u{1E25}', "h\u{323}"), ('\u{1E26}', "H\u{308}"), ('\u{1E27}', "h\u{308}"), ('\u{1E28}', "H\u{327}"),
+    ('\u{1E29}', "h\u{327}"), ('\u{1E2A}', "H\u{32E}"), ('\u{1E2B}', "h\u{32E}"), ('\u{1E2C}', "I\u{330}"),
+    ('\u{1E2D}', "i\u{330}"), ('\u{1E2E}', "I\u{308}\u{301}"), ('\u{1E2F}', "i\u{308}\u{301}"), ('\u{1E30}', "K\u{301}"),
+    ('\u{1E31}', "k\u{301}"), ('\u{1E32}', "K\u{323}"), ('\u{1E33}', "k\u{323}"), ('\u{1E34}', "K\u{331}"),
+    ('\u{1E35}', "k\u{331}"), ('\u{1E36}', "L\u{323}"), ('\u{1E37}', "l\u{323}"), ('\u{1E38}', "L\u{323}\u{304}"),
+    ('\u{1E39}', "l\u{323}\u{304}"), ('\u{1E3A}', "L\u{331}"), ('\u{1E3B}', "l\u{331}"), ('\u{1E3C}', "L\u{32D}"),
+    ('\u{1E3D}', "l\u{32D}"), ('\u{1E3E}', "M\u{301}"), ('\u{1E3F}', "m\u{301}"), ('\u{1E40}', "M\u{307}"),
+    ('\u{1E41}', "m\u{307}"), ('\u{1E42}', "M\u{323}"), ('\u{1E43}', "m\u{323}"), ('\u{1E44}', "N\u{307}"),
+    ('\u{1E45}', "n\u{307}"), ('\u{1E46}', "N\u{323}"), ('\u{1E47}', "n\u{323}"), ('\u{1E48}', "N\u{331}"),
+    ('\u{1E49}', "n\u{331}"), ('\u{1E4A}', "N\u{32D}"), ('\u{1E4B}', "n\u{32D}"), ('\u{1E4C}', "O\u{303}\u{301}"),
+    ('\u{1E4D}', "o\u{303}\u{301}"), ('\u{1E4E}', "O\u{303}\u{308}"), ('\u{1E4F}', "o\u{303}\u{308}"), ('\u{1E50}', "O\u{304}\u{300}"),
+    ('\u{1E51}', "o\u{304}\u{300}"), ('\u{1E52}', "O\u{304}\u{301}"), ('\u{1E53}', "o\u{304}\u{301}"), ('\u{1E54}', "P\u{301}"),
+    ('\u{1E55}', "p\u{301}"), ('\u{1E56}', "P\u{307}"), ('\u{1E57}', "p\u{307}"), ('\u{1E58}', "R\u{307}"),
+    ('\u{1E59}', "r\u{307}"), ('\u{1E5A}', "R\u{323}"), ('\u{1E5B}', "r\u{323}"), ('\u{1E5C}', "R\u{323}\u{304}"),
+    ('\u{1E5D}', "r\u{323}\u{304}"), ('\u{1E5E}', "R\u{331}"), ('\u{1E5F}', "r\u{331}"), ('\u{1E60}', "S\u{307}"),
+    ('\u{1E61}', "s\u{307}"), ('\u{1E62}', "S\u{323}"), ('\u{1E63}', "s\u{323}"), ('\u{1E64}', "S\u{301}\u{307}"),
+    ('\u{1E65}', "s\u{301}\u{307}"), ('\u{1E66}', "S\u{30C}\u{307}"), ('\u{1E67}', "s\u{30C}\u{307}"), ('\u{1E68}', "S\u{323}\u{307}"),
+    ('\u{1E69}', "s\u{323}\u{307}"), ('\u{1E6A}', "T\u{307}"), ('\u{1E6B}', "t\u{307}"), ('\u{1E6C}', "T\u{323}"),
+    ('\u{1E6D}', "t\u{323}"), ('\u{1E6E}', "T\u{331}"), ('\u{1E6F}', "t\u{331}"), ('\u{1E70}', "T\u{32D}"),
+    ('\u{1E71}', "t\u{32D}"), ('\u{1E72}', "U\u{324}"), ('\u{1E73}', "u\u{324}"), ('\u{1E74}', "U\u{330}"),
+    ('\u{1E75}', "u\u{330}"), ('\u{1E76}', "U\u{32D}"), ('\u{1E77}', "u\u{32D}"), ('\u{1E78}', "U\u{303}\u{301}"),
+    ('\u{1E79}', "u\u{303}\u{301}"), ('\u{1E7A}', "U\u{304}\u{308}"), ('\u{1E7B}', "u\u{304}\u{308}"), ('\u{1E7C}', "V\u{303}"),
+    ('\u{1E7D}', "v\u{303}"), ('\u{1E7E}', "V\u{323}"), ('\u{1E7F}', "v\u{323}"), ('\u{1E80}', "W\u{300}"),
+    ('\u{1E81}', "w\u{300}"), ('\u{1E82}', "W\u{301}"), ('\u{1E83}', "w\u{301}"), ('\u{1E84}', "W\u{308}"),
+    ('\u{1E85}', "w\u{308}"), ('\u{1E86}', "W\u{307}"), ('\u{1E87}', "w\u{307}"), ('\u{1E88}', "W\u{323}"),
+    ('\u{1E89}', "w\u{323}"), ('\u{1E8A}', "X\u{307}"), ('\u{1E8B}', "x\u{307}"), ('\u{1E8C}', "X\u{308}"),
+    ('\u{1E8D}', "x\u{308}"), ('\u{1E8E}', "Y\u{307}"), ('\u{1E8F}', "y\u{307}"), ('\u{1E90}', "Z\u{302}"),
+    ('\u{1E91}', "z\u{302}"), ('\u{1E92}', "Z\u{323}"), ('\u{1E93}', "z\u{323}"), ('\u{1E94}', "Z\u{331}"),
+    ('\u{1E95}', "z\u{331}"), ('\u{1E96}', "h\u{331}"), ('\u{1E97}', "t\u{308}"), ('\u{1E98}', "w\u{30A}"),
+    ('\u{1E99}', "y\u{30A}"), ('\u{1E9B}', "\u{17F}\u{307}"), ('\u{1EA0}', "A\u{323}"), ('\u{1EA1}', "a\u{323}"),
+    ('\u{1EA2}', "A\u{309}"), ('\u{1EA3}', "a\u{309}"), ('\u{1EA4}', "A\u{302}\u{301}"), ('\u{1EA5}', "a\u{302}\u{301}"),
+    ('\u{1EA6}', "A\u{302}\u{300}"), ('\u{1EA7}', "a\u{302}\u{300}"), ('\u{1EA8}', "A\u{302}\u{309}"), ('\u{1EA9}', "a\u{302}\u{309}"),
+    ('\u{1EAA}', "A\u{302}\u{303}"), ('\u{1EAB}', "a\u{302}\u{303}"), ('\u{1EAC}', "A\u{323}\u{302}"), ('\u{1EAD}', "a\u{323}\u{302}"),
+    ('\u{1EAE}', "A\u{306}\u{301}"), ('\u{1EAF}', "a\u{306}\u{301}"), ('\u{1EB0}', "A\u{306}\u{300}"), ('\u{1EB1}', "a\u{306}\u{300}"),
+    ('\u{1EB2}', "A\u{306}\u{309}"), ('\u{1EB3}', "a\u{306}\u{309}"), ('\u{1EB4}', "A\u{306}\u{303}"), ('\u{1EB5}', "a\u{306}\u{303}"),
+    ('\u{1EB6}', "A\u{323}\u{306}"), ('\u{1EB7}', "a\u{323}\u{306}"), ('\u{1EB8}', "E\u{323}"), ('\u{1EB9}', "e\u{323}"),
+    ('\u{1EBA}', "E\u{309}"), ('\u{1EBB}', "e\u{309}"), ('\u{1EBC}', "E\u{303}"), ('\u{1EBD}', "e\u{303}"),
+    ('\u{1EBE}', "E\u{302}\u{301}"), ('\u{1EBF}', "e\u{302}\u{301}"), ('\u{1EC0}', "E\u{302}\u{300}"), ('\u{1EC1}', "e\u{302}\u{300}"),
+    ('\u{1EC2}', "E\u{302}\u{309}"), ('\u{1EC3}', "e\u{302}\u{309}"), ('\u{1EC4}', "E\u{302}\u{303}"), ('\u{1EC5}', "e\u{302}\u{303}"),
+    ('\u{1EC6}', "E\u{323}\u{302}"), ('\u{1EC7}', "e\u{323}\u{302}"), ('\u{1EC8}', "I\u{309}"), ('\u{1EC9}', "i\u{309}"),
+    ('\u{1ECA}', "I\u{323}"), ('\u{1ECB}', "i\u{323}"), ('\u{1ECC}', "O\u{323}"), ('\u{1ECD}', "o\u{323}"),
+    ('\u{1ECE}', "O\u{309}"), ('\u{1ECF}', "o\u{309}"), ('\u{1ED0}', "O\u{302}\u{301}"), ('\u{1ED1}', "o\u{302}\u{301}"),
+    ('\u{1ED2}', "O\u{302}\u{300}"), ('\u{1ED3}', "o\u{302}\u{300}"), ('\u{1ED4}', "O\u{302}\u{309}"), ('\u{1ED5}', "o\u{302}\u{309}"),
+    ('\u{1ED6}', "O\u{302}\u{303}"), ('\u{1ED7}', "o\u{302}\u{303}"), ('\u{1ED8}', "O\u{323}\u{302}"), ('\u{1ED9}', "o\u{323}\u{302}"),
+    ('\u{1EDA}', "O\u{31B}\u{301}"), ('\u{1EDB}', "o\u{31B}\u{301}"), ('\u{1EDC}', "O\u{31B}\u{300}"), ('\u{1EDD}', "o\u{31B}\u{300}"),
+    ('\u{1EDE}', "O\u{31B}\u{309}"), ('\u{1EDF}', "o\u{31B}\u{309}"), ('\u{1EE0}', "O\u{31B}\u{303}"), ('\u{1EE1}', "o\u{31B}\u{303}"),
+    ('\u{1EE2}', "O\u{31B}\u{323}"), ('\u{1EE3}', "o\u{31B}\u{323}"), ('\u{1EE4}', "U\u{323}"), ('\u{1EE5}', "u\u{323}"),
+    ('\u{1EE6}', "U\u{309}"), ('\u{1EE7}', "u\u{309}"), ('\u{1EE8}', "U\u{31B}\u{301}"), ('\u{1EE9}', "u\u{31B}\u{301}"),
+    ('\u{1EEA}', "U\u{31B}\u{300}"), ('\u{1EEB}', "u\u{31B}\u{300}"), ('\u{1EEC}', "U\u{31B}\u{309}"), ('\u{1EED}', "u\u{31B}\u{309}"),
+    ('\u{1EEE}', "U\u{31B}\u{303}"), ('\u{1EEF}', "u\u{31B}\u{303}"), ('\u{1EF0}', "U\u{31B}\u{323}"), ('\u{1EF1}', "u\u{31B}\u{323}"),
+    ('\u{1EF2}', "Y\u{300}"), ('\u{1EF3}', "y\u{300}"), ('\u{1EF4}', "Y\u{323}"), ('\u{1EF5}', "y\u{323}"),
+    ('\u{1EF6}', "Y\u{309}"), ('\u{1EF7}', "y\u{309}"), ('\u{1EF8}', "Y\u{303}"), ('\u{1EF9}', "y\u{303}"),
+    ('\u{1F00}', "\u{3B1}\u{313}"), ('\u{1F01}', "\u{3B1}\u{314}"), ('\u{1F02}', "\u{3B1}\u{313}\u{300}"), ('\u{1F03}', "\u{3B1}\u{314}\u{300}"),
+    ('\u{1F04}', "\u{3B1}\u{313}\u{301}"), ('\u{1F05}', "\u{3B1}\u{314}\u{301}"), ('\u{1F06}', "\u{3B1}\u{313}\u{342}"), ('\u{1F07}', "\u{3B1}\u{314}\u{342}"),
+    ('\u{1F08}', "\u{391}\u{313}"), ('\u{1F09}', "\u{391}\u{314}"), ('\u{1F0A}', "\u{391}\u{313}\u{300}"), ('\u{1F0B}', "\u{391}\u{314}\u{300}"),
+    ('\u{1F0C}', "\u{391}\u{313}\u{301}"), ('\u{1F0D}', "\u{391}\u{314}\u{301}"), ('\u{1F0E}', "\u{391}\u{313}\u{342}"), ('\u{1F0F}', "\u{391}\u{314}\u{342}"),
+    ('\u{1F10}', "\u{3B5}\u{313}"), ('\u{1F11}', "\u{3B5}\u{314}"), ('\u{1F12}', "\u{3B5}\u{313}\u{300}"), ('\u{1F13}', "\u{3B5}\u{314}\u{300}"),
+    ('\u{1F14}', "\u{3B5}\u{313}\u{301}"), ('\u{1F15}', "\u{3B5}\u{314}\u{301}"), ('\u{1F18}', "\u{395}\u{313}"), ('\u{1F19}', "\u{395}\u{314}"),
+    ('\u{1F1A}', "\u{395}\u{313}\u{300}"), ('\u{1F1B}', "\u{395}\u{314}\u{300}"), ('\u{1F1C}', "\u{395}\u{313}\u{301}"), ('\u{1F1D}', "\u{395}\u{314}\u{301}"),
+    ('\u{1F20}', "\u{3B7}\u{313}"), ('\u{1F21}', "\u{3B7}\u{314}"), ('\u{1F22}', "\u{3B7}\u{313}\u{300}"), ('\u{1F23}', "\u{3B7}\u{314}\u{300}"),
+    ('\u{1F24}', "\u{3B7}\u{313}\u{301}"), ('\u{1F25}', "\u{3B7}\u{314}\u{301}"), ('\u{1F26}', "\u{3B7}\u{313}\u{342}"), ('\u{1F27}', "\u{3B7}\u{314}\u{342}"),
+    ('\u{1F28}', "\u{397}\u{313}"), ('\u{1F29}', "\u{397}\u{314}"), ('\u{1F2A}', "\u{397}\u{313}\u{300}"), ('\u{1F2B}', "\u{397}\u{314}\u{300}"),
+    ('\u{1F2C}', "\u{397}\u{313}\u{301}"), ('\u{1F2D}', "\u{397}\u{314}\u{301}"), ('\u{1F2E}', "\u{397}\u{313}\u{342}"), ('\u{1F2F}', "\u{397}\u{314}\u{342}"),
+    ('\u{1F30}', "\u{3B9}\u{313}"), ('\u{1F31}', "\u{3B9}\u{314}"), ('\u{1F32}', "\u{3B9}\u{313}\u{300}"), ('\u{1F33}', "\u{3B9}\u{314}\u{300}"),
+    ('\u{1F34}', "\u{3B9}\u{313}\u{301}"), ('\u{1F35}', "\u{3B9}\u{314}\u{301}"), ('\u{1F36}', "\u{3B9}\u{313}\u{342}"), ('\u{1F37}', "\u{3B9}\u{314}\u{342}"),
+    ('\u{1F38}', "\u{399}\u{313}"), ('\u{1F39}', "\u{399}\u{314}"), ('\u{1F3A}', "\u{399}\u{313}\u{300}"), ('\u{1F3B}', "\u{399}\u{314}\u{300}"),
+    ('\u{1F3C}', "\u{399}\u{313}\u{301}"), ('\u{1F3D}', "\u{399}\u{314}\u{301}"), ('\u{1F3E}', "\u{399}\u{313}\u{342}"), ('\u{1F3F}', "\u{399}\u{314}\u{342}"),
+    ('\u{1F40}', "\u{3BF}\u{313}"), ('\u{1F41}', "\u{3BF}\u{314}"), ('\u{1F42}', "\u{3BF}\u{313}\u{300}"), ('\u{1F43}', "\u{3BF}\u{314}\u{300}"),
+    ('\u{1F44}', "\u{3BF}\u{313}\u{301}"), ('\u{1F45}', "\u{3BF}\u{314}\u{301}"), ('\u{1F48}', "\u{39F}\u{313}"), ('\u{1F49}', "\u{39F}\u{314}"),
+    ('\u{1F4A}', "\u{39F}\u{313}\u{300}"), ('\u{1F4B}', "\u{39F}\u{314}\u{300}"), ('\u{1F4C}', "\u{39F}\u{313}\u{301}"), ('\u{1F4D}', "\u{39F}\u{314}\u{301}"),
+    ('\u{1F50}', "\u{3C5}\u{313}"), ('\u{1F51}', "\u{3C5}\u{314}"), ('\u{1F52}', "\u{3C5}\u{313}\u{300}"), ('\u{1F53}', "\u{3C5}\u{314}\u{300}"),
+    ('\u{1F54}', "\u{3C5}\u{313}\u{301}"), ('\u{1F55}', "\u{3C5}\u{314}\u{301}"), ('\u{1F56}', "\u{3C5}\u{313}\u{342}"), ('\u{1F57}', "\u{3C5}\u{314}\u{342}"),
+    ('\u{1F59}', "\u{3A5}\u{314}"), ('\u{1F5B}', "\u{3A5}\u{314}\u{300}"), ('\u{1F5D}', "\u{3A5}\u{314}\u{301}"), ('\u{1F5F}', "\u{3A5}\u{314}\u{342}"),
+    ('\u{1F60}', "\u{3C9}\u{313}"), ('\u{1F61}', "\u{3C9}\u{314}"), ('\u{1F62}', "\u{3C9}\u{313}\u{300}"), ('\u{1F63}', "\u{3C9}\u{314}\u{300}"),
+    ('\u{1F64}', "\u{3C9}\u{313}\u{301}"), ('\u{1F65}', "\u{3C9}\u{314}\u{301}"), ('\u{1F66}', "\u{3C9}\u{313}\u{342}"), ('\u{1F67}', "\u{3C9}\u{314}\u{342}"),
+    ('\u{1F68}', "\u{3A9}\u{313}"), ('\u{1F69}', "\u{3A9}\u{314}"), ('\u{1F6A}', "\u{3A9}\u{313}\u{300}"), ('\u{1F6B}', "\u{3A9}\u{314}\u{300}"),
+    ('\u{1F6C}', "\u{3A9}\u{313}\u{301}"), ('\u{1F6D}', "\u{3A9}\u{314}\u{301}"), ('\u{1F6E}', "\u{3A9}\u{313}\u{342}"), ('\u{1F6F}', "\u{3A9}\u{314}\u{342}"),
+    ('\u{1F70}', "\u{3B1}\u{300}"), ('\u{1F71}', "\u{3B1}\u{301}"), ('\u{1F72}', "\u{3B5}\u{300}"), ('\u{1F73}', "\u{3B5}\u{301}"),
+    ('\u{1F74}', "\u{3B7}\u{300}"), ('\u{1F75}', "\u{3B7}\u{301}"), ('\u{1F76}', "\u{3B9}\u{300}"), ('\u{1F77}', "\u{3B9}\u{301}"),
+    ('\u{1F78}', "\u{3BF}\u{300}"), ('\u{1F79}', "\u{3BF}\u{301}"), ('\u{1F7A}', "\u{3C5}\u{300}"), ('\u{1F7B}', "\u{3C5}\u{301}"),
+    ('\u{1F7C}', "\u{3C9}\u{300}"), ('\u{1F7D}', "\u{3C9}\u{301}"), ('\u{1F80}', "\u{3B1}\u{313}\u{345}"), ('\u{1F81}', "\u{3B1}\u{314}\u{345}"),
+    ('\u{1F82}', "\u{3B1}\u{313}\u{300}\u{345}"), ('\u{1F83}', "\u{3B1}\u{314}\u{300}\u{345}"), ('\u{1F84}', "\u{3B1}\u{313}\u{301}\u{345}"), ('\u{1F85}', "\u{3B1}\u{314}\u{301}\u{345}"),
+    ('\u{1F86}', "\u{3B1}\u{313}\u{342}\u{345}"), ('\u{1F87}', "\u{3B1}\u{314}\u{342}\u{345}"), ('\u{1F88}', "\u{391}\u{313}\u{345}"), ('\u{1F89}', "\u{391}\u{314}\u{345}"),
+    ('\u{1F8A}', "\u{391}\u{313}\u{300}\u{345}"), ('\u{1F8B}', "\u{391}\u{314}\u{300}\u{345}"), ('\u{1F8C}', "\u{391}\u{313}\u{301}\u{345}"), ('\u{1F8D}', "\u{391}\u{314}\u{301}\u{345}"),
+    ('\u{1F8E}', "\u{391}\u{313}\u{342}\u{345}"), ('\u{1F8F}', "\u{391}\u{314}\u{342}\u{345}"), ('\u{1F90}', "\u{3B7}\u{313}\u{345}"), ('\u{1F91}', "\u{3B7}\u{314}\u{345}"),
+    ('\u{1F92}', "\u{3B7}\u{313}\u{300}\u{345}"), ('\u{1F93}', "\u{3B7}\u{314}\u{300}\u{345}"), ('\u{1F94}', "\u{3B7}\u{313}\u{301}\u{345}"), ('\u{1F95}', "\u{3B7}\u{314}\u{301}\u{345}"),
+    ('\u{1F96}', "\u{3B7}\u{313}\u{342}\u{345}"), ('\u{1F97}', "\u{3B7}\u{314}\u{342}\u{345}"), ('\u{1F98}', "\u{397}\u{313}\u{345}"), ('\u{1F99}', "\u{397}\u{314}\u{345}"),
+    ('\u{1F9A}', "\u{397}\u{313}\u{300}\u{345}"), ('\u{1F9B}', "\u{397}\u{314}\u{300}\u{345}"), ('\u{1F9C}', "\u{397}\u{313}\u{301}\u{345}"), ('\u{1F9D}', "\u{397}\u{314}\u{301}\u{345}"),
+    ('\u{1F9E}', "\u{397}\u{313}\u{342}\u{345}"), ('\u{1F9F}', "\u{397}\u{314}\u{342}\u{345}"), ('\u{1FA0}', "\u{3C9}\u{313}\u{345}"), ('\u{1FA1}', "\u{3C9}\u{314}\u{345}"),
+    ('\u{1FA2}', "\u{3C9}\u{313}\u{300}\u{345}"), ('\u{1FA3}', "\u{3C9}\u{314}\u{300}\u{345}"), ('\u{1FA4}', "\u{3C9}\u{313}\u{301}\u{345}"), ('\u{1FA5}', "\u{3C9}\u{314}\u{301}\u{345}"),
+    ('\u{1FA6}', "\u{3C9}\u{313}\u{342}\u{345}"), ('\u{1FA7}', "\u{3C9}\u{314}\u{342}\u{345}"), ('\u{1FA8}', "\u{3A9}\u{313}\u{345}"), ('\u{1FA9}', "\u{3A9}\u{314}\u{345}"),
+    ('\u{1FAA}', "\u{3A9}\u{313}\u{300}\u{345}"), ('\u{1FAB}', "\u{3A9}\u{314}\u{300}\u{345}"), ('\u{1FAC}', "\u{3A9}\u{313}\u{301}\u{345}"), ('\u{1FAD}', "\u{3A9}\u{314}\u{301}\u{345}"),
+    ('\u{1FAE}', "\u{3A9}\u{313}\u{342}\u{345}"), ('\u{1FAF}', "\u{3A9}\u{314}\u{342}\u{345}"), ('\u{1FB0}', "\u{3B1}\u{306}"), ('\u{1FB1}', "\u{3B1}\u{304}"),
+    ('\u{1FB2}', "\u{3B1}\u{300}\u{345}"), ('\u{1FB3}', "\u{3B1}\u{345}"), ('\u{1FB4}', "\u{3B1}\u{301}\u{345}"), ('\u{1FB6}', "\u{3B1}\u{342}"),
+    ('\u{1FB7}', "\u{3B1}\u{342}\u{345}"), ('\u{1FB8}', "\u{391}\u{306}"), ('\u{1FB9}', "\u{391}\u{304}"), ('\u{1FBA}', "\u{391}\u{300}"),
+    ('\u{1FBB}', "\u{391}\u{301}"), ('\u{1FBC}', "\u{391}\u{345}"), ('\u{1FBE}', "\u{3B9}"), ('\u{1FC1}', "\u{A8}\u{342}"),
+    ('\u{1FC2}', "\u{3B7}\u{300}\u{345}"), ('\u{1FC3}', "\u{3B7}\u{345}"), ('\u{1FC4}', "\u{3B7}\u{301}\u{345}"), ('\u{1FC6}', "\u{3B7}\u{342}"),
+    ('\u{1FC7}', "\u{3B7}\u{342}\u{345}"), ('\u{1FC8}', "\u{395}\u{300}"), ('\u{1FC9}', "\u{395}\u{301}"), ('\u{1FCA}', "\u{397}\u{300}"),
+    ('\u{1FCB}', "\u{397}\u{301}"), ('\u{1FCC}', "\u{397}\u{345}"), ('\u{1FCD}', "\u{1FBF}\u{300}"), ('\u{1FCE}', "\u{1FBF}\u{301}"),
+    ('\u{1FCF}', "\u{1FBF}\u{342}"), ('\u{1FD0}', "\u{3B9}\u{306}"), ('\u{1FD1}', "\u{3B9}\u{304}"), ('\u{1FD2}', "\u{3B9}\u{308}\u{300}"),
+    ('\u{1FD3}', "\u{3B9}\u{308}\u{301}"), ('\u{1FD6}', "\u{3B9}\u{342}"), ('\u{1FD7}', "\u{3B9}\u{308}\u{342}"), ('\u{1FD8}', "\u{399}\u{306}"),
+    ('\u{1FD9}', "\u{399}\u{304}"), ('\u{1FDA}', "\u{399}\u{300}"), ('\u{1FDB}', "\u{399}\u{301}"), ('\u{1FDD}', "\u{1FFE}\u{300}"),
+    ('\u{1FDE}', "\u{1FFE}\u{301}"), ('\u{1FDF}', "\u{1FFE}\u{342}"), ('\u{1FE0}', "\u{3C5}\u{306}"), ('\u{1FE1}', "\u{3C5}\u{304}"),
+    ('\u{1FE2}', "\u{3C5}\u{308}\u{300}"), ('\u{1FE3}', "\u{3C5}\u{308}\u{301}"), ('\u{1FE4}', "\u{3C1}\u{313}"), ('\u{1FE5}', "\u{3C1}\u{314}"),
+    ('\u{1FE6}', "\u{3C5}\u{342}"), ('\u{1FE7}', "\u{3C5}\u{308}\u{342}"), ('\u{1FE8}', "\u{3A5}\u{306}"), ('\u{1FE9}', "\u{3A5}\u{304}"),
+    ('\u{1FEA}', "\u{3A5}\u{300}"), ('\u{1FEB}', "\u{3A5}\u{301}"), ('\u{1FEC}', "\u{3A1}\u{314}"), ('\u{1FED}', "\u{A8}\u{300}"),
+    ('\u{1FEE}', "\u{A8}\u{301}"), ('\u{1FEF}', "`"), ('\u{1FF2}', "\u{3C9}\u{300}\u{345}"), ('\u{1FF3}', "\u{3C9}\u{345}"),
+    ('\u{1FF4}', "\u{3C9}\u{301}\u{345}"), ('\u{1FF6}', "\u{3C9}\u{342}"), ('\u{1FF7}', "\u{3C9}\u{342}\u{345}"), ('\u{1FF8}', "\u{39F}\u{300}"),
+    ('\u{1FF9}', "\u{39F}\u{301}"), ('\u{1FFA}', "\u{3A9}\u{300}"), ('\u{1FFB}', "\u{3A9}\u{301}"), ('\u{1FFC}', "\u{3A9}\u{345}"),
+    ('\u{1FFD}', "\u{B4}"), ('\u{2000}', "\u{2002}"), ('\u{2001}', "\u{2003}"), ('\u{2126}', "\u{3A9}"),
+    ('\u{212A}', "K"), ('\u{212B}', "A\u{30A}"), ('\u{219A}', "\u{2190}\u{338}"), ('\u{219B}', "\u{2192}\u{338}"),
+    ('\u{21AE}', "\u{2194}\u{338}"), ('\u{21CD}', "\u{21D0}\u{338}"), ('\u{21CE}', "\u{21D4}\u{338}"), ('\u{21CF}', "\u{21D2}\u{338}"),
+    ('\u{2204}', "\u{2203}\u{338}"), ('\u{2209}', "\u{2208}\u{338}"), ('\u{220C}', "\u{220B}\u{338}"), ('\u{2224}', "\u{2223}\u{338}"),
+    ('\u{2226}', "\u{2225}\u{338}"), ('\u{2241}', "\u{223C}\u{338}"), ('\u{2244}', "\u{2243}\u{338}"), ('\u{2247}', "\u{2245}\u{338}"),
+    ('\u{2249}', "\u{2248}\u{338}"), ('\u{2260}', "=\u{338}"), ('\u{2262}', "\u{2261}\u{338}"), ('\u{226D}', "\u{224D}\u{338}"),
+    ('\u{226E}', "<\u{338}"), ('\u{226F}', ">\u{338}"), ('\u{2270}', "\u{2264}\u{338}"), ('\u{2271}', "\u{2265}\u{338}"),
+    ('\u{2274}', "\u{2272}\u{338}"), ('\u{2275}', "\u{2273}\u{338}"), ('\u{2278}', "\u{2276}\u{338}"), ('\u{2279}', "\u{2277}\u{338}"),
+    ('\u{2280}', "\u{227A}\u{338}"), ('\u{2281}', "\u{227B}\u{338}"), ('\u{2284}', "\u{2282}\u{338}"), ('\u{2285}', "\u{2283}\u{338}"),
+    ('\u{2288}', "\u{2286}\u{338}"), ('\u{2289}', "\u{2287}\u{338}"), ('\u{22AC}', "\u{22A2}\u{338}"), ('\u{22AD}', "\u{22A8}\u{338}"),
+    ('\u{22AE}', "\u{22A9}\u{338}"), ('\u{22AF}', "\u{22AB}\u{338}"), ('\u{22E0}', "\u{227C}\u{338}"), ('\u{22E1}', "\u{227D}\u{338}"),
+    ('\u{22E2}', "\u{2291}\u{338}"), ('\u{22E3}', "\u{2292}\u{338}"), ('\u{22EA}', "\u{22B2}\u{338}"), ('\u{22EB}', "\u{22B3}\u{338}"),
+    ('\u{22EC}', "\u{22B4}\u{338}"), ('\u{22ED}', "\u{22B5}\u{338}"), ('\u{2329}', "\u{3008}"), ('\u{232A}', "\u{3009}"),
+    ('\u{2ADC}', "\u{2ADD}\u{338}"), ('\u{304C}', "\u{304B}\u{3099}"), ('\u{304E}', "\u{304D}\u{3099}"), ('\u{3050}', "\u{304F}\u{3099}"),
+    ('\u{3052}', "\u{3051}\u{3099}"), ('\u{3054}', "\u{3053}\u{3099}"), ('\u{3056}', "\u{3055}\u{3099}"), ('\u{3058}', "\u{3057}\u{3099}"),
+    ('\u{305A}', "\u{3059}\u{3099}"), ('\u{305C}', "\u{305B}\u{3099}"), ('\u{305E}', "\u{305D}\u{3099}"), ('\u{3060}', "\u{305F}\u{3099}"),
+    ('\u{3062}', "\u{3061}\u{3099}"), ('\u{3065}', "\u{3064}\u{3099}"), ('\u{3067}', "\u{3066}\u{3099}"), ('\u{3069}', "\u{3068}\u{3099}"),
+    ('\u{3070}', "\u{306F}\u{3099}"), ('\u{3071}', "\u{306F}\u{309A}"), ('\u{3073}', "\u{3072}\u{3099}"), ('\u{3074}', "\u{3072}\u{309A}"),
+    ('\u{3076}', "\u{3075}\u{3099}"), ('\u{3077}', "\u{3075}\u{309A}"), ('\u{3079}', "\u{3078}\u{3099}"), ('\u{307A}', "\u{3078}\u{309A}"),
+    ('\u{307C}', "\u{307B}\u{3099}"), ('\u{307D}', "\u{307B}\u{309A}"), ('\u{3094}', "\u{3046}\u{3099}"), ('\u{309E}', "\u{309D}\u{3099}"),
+    ('\u{30AC}', "\u{30AB}\u{3099}"), ('\u{30AE}', "\u{30AD}\u{3099}"), ('\u{30B0}', "\u{30AF}\u{3099}"), ('\u{30B2}', "\u{30B1}\u{3099}"),
+    ('\u{30B4}', "\u{30B3}\u{3099}"), ('\u{30B6}', "\u{30B5}\u{3099}"), ('\u{30B8}', "\u{30B7}\u{3099}"), ('\u{30BA}', "\u{30B9}\u{3099}"),
+    ('\u{30BC}', "\u{30BB}\u{3099}"), ('\u{30BE}', "\u{30BD}\u{3099}"), ('\u{30C0}', "\u{30BF}\u{3099}"), ('\u{30C2}', "\u{30C1}\u{3099}"),
+    ('\u{30C5}', "\u{30C4}\u{3099}"), ('\u{30C7}', "\u{30C6}\u{3099}"), ('\u{30C9}', "\u{30C8}\u{3099}"), ('\u{30D0}', "\u{30CF}\u{3099}"),
+    ('\u{30D1}', "\u{30CF}\u{309A}"), ('\u{30D3}', "\u{30D2}\u{3099}"), ('\u{30D4}', "\u{30D2}\u{309A}"), ('\u{30D6}', "\u{30D5}\u{3099}"),
+    ('\u{30D7}', "\u{30D5}\u{309A}"), ('\u{30D9}', "\u{30D8}\u{3099}"), ('\u{30DA}', "\u{30D8}\u{309A}"), ('\u{30DC}', "\u{30DB}\u{3099}"),
+    ('\u{30DD}', "\u{30DB}\u{309A}"), ('\u{30F4}', "\u{30A6}\u{3099}"), ('\u{30F7}', "\u{30EF}\u{3099}"), ('\u{30F8}', "\u{30F0}\u{3099}"),
+    ('\u{30F9}', "\u{30F1}\u{3099}"), ('\u{30FA}', "\u{30F2}\u{3099}"), ('\u{30FE}', "\u{30FD}\u{3099}"), ('\u{F900}', "\u{8C48}"),
+    ('\u{F901}', "\u{66F4}"), ('\u{F902}', "\u{8ECA}"), ('\u{F903}', "\u{8CC8}"), ('\u{F904}', "\u{6ED1}"),
+    ('\u{F905}', "\u{4E32}"), ('\u{F906}', "\u{53E5}"), ('\u{F907}', "\u{9F9C}"), ('\u{F908}', "\u{9F9C}"),
+    ('\u{F909}', "\u{5951}"), ('\u{F90A}', "\u{91D1}"), ('\u{F90B}', "\u{5587}"), ('\u{F90C}', "\u{5948}"),
+    ('\u{F90D}', "\u{61F6}"), ('\u{F90E}', "\u{7669}"), ('\u{F90F}', "\u{7F85}"), ('\u{F910}', "\u{863F}"),
+    ('\u{F911}', "\u{87BA}"), ('\u{F912}', "\u{88F8}"), ('\u{F913}', "\u{908F}"), ('\u{F914}', "\u{6A02}"),
+    ('\u{F915}', "\u{6D1B}"), ('\u{F916}', "\u{70D9}"), ('\u{F917}', "\u{73DE}"), ('\u{F918}', "\u{843D}"),
+    ('\u{F919}', "\u{916A}"), ('\u{F91A}', "\u{99F1}"), ('\u{F91B}', "\u{4E82}"), ('\u{F91C}', "\u{5375}"),
+    ('\u{F91D}', "\u{6B04}"), ('\u{F91E}', "\u{721B}"), ('\u{F91F}', "\u{862D}"), ('\u{F920}', "\u{9E1E}"),
+    ('\u{F921}', "\u{5D50}"), ('\u{F922}', "\u{6FEB}"), ('\u{F923}', "\u{85CD}"), ('\u{F924}', "\u{8964}"),
+    ('\u{F925}', "\u{62C9}"), ('\u{F926}', "\u{81D8}"), ('\u{F927}', "\u{881F}"), ('\u{F928}', "\u{5ECA}"),
+    ('\u{F929}', "\u{6717}"), ('\u{F92A}', "\u{6D6A}"), ('\u{F92B}', "\u{72FC}"), ('\u{F92C}', "\u{90CE}"),
+    ('\u{F92D}', "\u{4F86}"), ('\u{F92E}', "\u{51B7}"), ('\u{F92F}', "\u{52DE}"), ('\u{F930}', "\u{64C4}"),
+    ('\u{F931}', "\u{6AD3}"), ('\u{F932}', "\u{7210}"), ('\u{F933}', "\u{76E7}"), ('\u{F934}', "\u{8001}"),
+    ('\u{F935}', "\u{8606}"), ('\u{F936}', "\u{865C}"), ('\u{F937}', "\u{8DEF}"), ('\u{F938}', "\u{9732}"),
+    ('\u{F939}', "\u{9B6F}"), ('\u{F93A}', "\u{9DFA}"), ('\u{F93B}', "\u{788C}"), ('\u{F93C}', "\u{797F}"),
+    ('\u{F93D}', "\u{7DA0}"), ('\u{F93E}', "\u{83C9}"), ('\u{F93F}', "\u{9304}"), ('\u{F940}', "\u{9E7F}"),
+    ('\u{F941}', "\u{8AD6}"), ('\u{F942}', "\u{58DF}"), ('\u{F943}', "\u{5F04}"), ('\u{F944}', "\u{7C60}"),
+    ('\u{F945}', "\u{807E}"), ('\u{F946}', "\u{7262}"), ('\u{F947}', "\u{78CA}"), ('\u{F948}', "\u{8CC2}"),
+    ('\u{F949}', "\u{96F7}"), ('\u{F94A}', "\u{58D8}"), ('\u{F94B}', "\u{5C62}"), ('\u{F94C}', "\u{6A13}"),
+    ('\u{F94D}', "\u{6DDA}"), ('\u{F94E}', "\u{6F0F}"), ('\u{F94F}', "\u{7D2F}"), ('\u{F950}', "\u{7E37}"),
+    ('\u{F951}', "\u{964B}"), ('\u{F952}', "\u{52D2}"), ('\u{F953}', "\u{808B}"), ('\u{F954}', "\u{51DC}"),
+    ('\u{F955}', "\u{51CC}"), ('\u{F956}', "\u{7A1C}"), ('\u{F957}', "\u{7DBE}"), ('\u{F958}', "\u{83F1}"),
+    ('\u{F959}', "\u{9675}"), ('\u{F95A}', "\u{8B80}"), ('\u{F95B}', "\u{62CF}"), ('\u{F95C}', "\u{6A02}"),
+    ('\u{F95D}', "\u{8AFE}"), ('\u{F95E}', "\u{4E39}"), ('\u{F95F}', "\u{5BE7}"), ('\u{F960}', "\u{6012}"),
+    ('\u{F961}', "\u{7387}"), ('\u{F962}', "\u{7570}"), ('\u{F963}', "\u{5317}"), ('\u{F964}', "\u{78FB}"),
+    ('\u{F965}', "\u{4FBF}"), ('\u{F966}', "\u{5FA9}"), ('\u{F967}', "\u{4E0D}"), ('\u{F968}', "\u{6CCC}"),
+    ('\u{F969}', "\u{6578}"), ('\u{F96A}', "\u{7D22}"), ('\u{F96B}', "\u{53C3}"), ('\u{F96C}', "\u{585E}"),
+    ('\u{F96D}', "\u{7701}"), ('\u{F96E}', "\u{8449}"), ('\u{F96F}', "\u{8AAA}"), ('\u{F970}', "\u{6BBA}"),
+    ('\u{F971}', "\u{8FB0}"), ('\u{F972}', "\u{6C88}"), ('\u{F973}', "\u{62FE}"), ('\u{F974}', "\u{82E5}"),
+    ('\u{F975}', "\u{63A0}"), ('\u{F976}', "\u{7565}"), ('\u{F977}', "\u{4EAE}"), ('\u{F978}', "\u{5169}"),
+    ('\u{F979}', "\u{51C9}"), ('\u{F97A}', "\u{6881}"), ('\u{F97B}', "\u{7CE7}"), ('\u{F97C}', "\u{826F}"),
+    ('\u{F97D}', "\u{8AD2}"), ('\u{F97E}', "\u{91CF}"), ('\u{F97F}', "\u{52F5}"), ('\u{F980}', "\u{5442}"),
+    ('\u{F981}', "\u{5973}"), ('\u{F982}', "\u{5EEC}"), ('\u{F983}', "\u{65C5}"), ('\u{F984}', "\u{6FFE}"),
+    ('\u{F985}', "\u{792A}"), ('\u{F986}', "\u{95AD}"), ('\u{F987}', "\u{9A6A}"), ('\u{F988}', "\u{9E97}"),
+    ('\u{F989}', "\u{9ECE}"), ('\u{F98A}', "\u{529B}"), ('\u{F98B}', "\u{66C6}"), ('\u{F98C}', "\u{6B77}"),
+    ('\u{F98D}', "\u{8F62}"), ('\u{F98E}', "\u{5E74}"), ('\u{F98F}', "\u{6190}"), ('\u{F990}', "\u{6200}"),
+    ('\u{F991}', "\u{649A}"), ('\u{F992}', "\u{6F23}"), ('\u{F993}', "\u{7149}"), ('\u{F994}', "\u{7489}"),
+    ('\u{F995}', "\u{79CA}"), ('\u{F996}', "\u{7DF4}"), ('\u{F997}', "\u{806F}"), ('\u{F998}', "\u{8F26}"),
+    ('\u{F999}', "\u{84EE}"), ('\u{F99A}', "\u{9023}"), ('\u{F99B}', "\u{934A}"), ('\u{F99C}', "\u{5217}"),
+    ('\u{F99D}', "\u{52A3}"), ('\u{F99E}', "\u{54BD}"), ('\u{F99F}', "\u{70C8}"), ('\u{F9A0}', "\u{88C2}"),
+    ('\u{F9A1}', "\u{8AAA}"), ('\u{F9A2}', "\u{5EC9}"), ('\u{F9A3}', "\u{5FF5}"), ('\u{F9A4}', "\u{637B}"),
+    ('\u{F9A5}', "\u{6BAE}"), ('\u{F9A6}', "\u{7C3E}"), ('\u{F9A7}', "\u{7375}"), ('\u{F9A8}', "\u{4EE4}"),
+    ('\u{F9A9}', "\u{56F9}"), ('\u{F9AA}', "\u{5BE7}"), ('\u{F9AB}', "\u{5DBA}"), ('\u{F9AC}', "\u{601C}"),
+    ('\u{F9AD}', "\u{73B2}"), ('\u{F9AE}', "\u{7469}"), ('\u{F9AF}', "\u{7F9A}"), ('\u{F9B0}', "\u{8046}"),
+    ('\u{F9B1}', "\u{9234}"), ('\u{F9B2}', "\u{96F6}"), ('\u{F9B3}', "\u{9748}"), ('\u{F9B4}', "\u{9818}"),
+    ('\u{F9B5}', "\u{4F8B}"), ('\u{F9B6}', "\u{79AE}"), ('\u{F9B7}', "\u{91B4}"), ('\u{F9B8}', "\u{96B8}"),
+    ('\u{F9B9}', "\u{60E1}"), ('\u{F9BA}', "\u{4E86}"), ('\u{F9BB}', "\u{50DA}"), ('\u{F9BC}', "\u{5BEE}"),
+    ('\u{F9BD}', "\u{5C3F}"), ('\u{F9BE}', "\u{6599}"), ('\u{F9BF}', "\u{6A02}"), ('\u{F9C0}', "\u{71CE}"),
+    ('\u{F9C1}', "\u{7642}"), ('\u{F9C2}', "\u{84FC}"), ('\u{F9C3}', "\u{907C}"), ('\u{F9C4}', "\u{9F8D}"),
+    ('\u{F9C5}', "\u{6688}"), ('\u{F9C6}', "\u{962E}"), ('\u{F9C7}', "\u{5289}"), ('\u{F9C8}', "\u{677B}"),
+    ('\u{F9C9}', "\u{67F3}"), ('\u{F9CA}', "\u{6D41}"), ('\u{F9CB}', "\u{6E9C}"), ('\u{F9CC}', "\u{7409}"),
+    ('\u{F9CD}', "\u{7559}"), ('\u{F9CE}', "\u{786B}"), ('\u{F9CF}', "\u{7D10}"), ('\u{F9D0}', "\u{985E}"),
+    ('\u{F9D1}', "\u{516D}"), ('\u{F9D2}', "\u{622E}"), ('\u{F9D3}', "\u{9678}"), ('\u{F9D4}', "\u{502B}"),
+    ('\u{F9D5}', "\u{5D19}"), ('\u{F9D6}', "\u{6DEA}"), ('\u{F9D7}', "\u{8F2A}"), ('\u{F9D8}', "\u{5F8B}"),
+    ('\u{F9D9}', "\u{6144}"), ('\u{F9DA}', "\u{6817}"), ('\u{F9DB}', "\u{7387}"), ('\u{F9DC}', "\u{9686}"),
+    ('\u{F9DD}', "\u{5229}"), ('\u{F9DE}', "\u{540F}"), ('\u{F9DF}', "\u{5C65}"), ('\u{F9E0}', "\u{6613}"),
+    ('\u{F9E1}', "\u{674E}"), ('\u{F9E2}', "\u{68A8}"), ('\u{F9E3}', "\u{6CE5}"), ('\u{F9E4}', "\u{7406}"),
+    ('\u{F9E5}', "\u{75E2}"), ('\u{F9E6}', "\u{7F79}"), ('\u{F9E7}', "\u{88CF}"), ('\u{F9E8}', "\u{88E1}"),
+    ('\u{F9E9}', "\u{91CC}"), ('\u{F9EA}', "\u{96E2}"), ('\u{F9EB}', "\u{533F}"), ('\u{F9EC}', "\u{6EBA}"),
+    ('\u{F9ED}', "\u{541D}"), ('\u{F9EE}', "\u{71D0}"), ('\u{F9EF}', "\u{7498}"), ('\u{F9F0}', "\u{85FA}"),
+    ('\u{F9F1}', "\u{96A3}"), ('\u{F9F2}', "\u{9C57}"), ('\u{F9F3}', "\u{9E9F}"), ('\u{F9F4}', "\u{6797}"),
+    ('\u{F9F5}', "\u{6DCB}"), ('\u{F9F6}', "\u{81E8}"), ('\u{F9F7}', "\u{7ACB}"), ('\u{F9F8}', "\u{7B20}"),
+    ('\u{F9F9}', "\u{7C92}"), ('\u{F9FA}', "\u{72C0}"), ('\u{F9FB}', "\u{7099}"), ('\u{F9FC}', "\u{8B58}"),
+    ('\u{F9FD}', "\u{4EC0}"), ('\u{F9FE}', "\u{8336}"), ('\u{F9FF}', "\u{523A}"), ('\u{FA00}', "\u{5207}"),
+    ('\u{FA01}', "\u{5EA6}"), ('\u{FA02}', "\u{62D3}"), ('\u{FA03}', "\u{7CD6}"), ('\u{FA04}', "\u{5B85}"),
+    ('\u{FA05}', "\u{6D1E}"), ('\u{FA06}', "\u{66B4}"), ('\u{FA07}', "\u{8F3B}"), ('\u{FA08}', "\u{884C}"),
+    ('\u{FA09}', "\u{964D}"), ('\u{FA0A}', "\u{898B}"), ('\u{FA0B}', "\u{5ED3}"), ('\u{FA0C}', "\u{5140}"),
+    ('\u{FA0D}', "\u{55C0}"), ('\u{FA10}', "\u{585A}"), ('\u{FA12}', "\u{6674}"), ('\u{FA15}', "\u{51DE}"),
+    ('\u{FA16}', "\u{732A}"), ('\u{FA17}', "\u{76CA}"), ('\u{FA18}', "\u{793C}"), ('\u{FA19}', "\u{795E}"),
+    ('\u{FA1A}', "\u{7965}"), ('\u{FA1B}', "\u{798F}"), ('\u{FA1C}', "\u{9756}"), ('\u{FA1D}', "\u{7CBE}"),
+    ('\u{FA1E}', "\u{7FBD}"), ('\u{FA20}', "\u{8612}"), ('\u{FA22}', "\u{8AF8}"), ('\u{FA25}', "\u{9038}"),
+    ('\u{FA26}', "\u{90FD}"), ('\u{FA2A}', "\u{98EF}"), ('\u{FA2B}', "\u{98FC}"), ('\u{FA2C}', "\u{9928}"),
+    ('\u{FA2D}', "\u{9DB4}"), ('\u{FA2E}', "\u{90DE}"), ('\u{FA2F}', "\u{96B7}"), ('\u{FA30}', "\u{4FAE}"),
+    ('\u{FA31}', "\u{50E7}"), ('\u{FA32}', "\u{514D}"), ('\u{FA33}', "\u{52C9}"), ('\u{FA34}', "\u{52E4}"),
+    ('\u{FA35}', "\u{5351}"), ('\u{FA36}', "\u{559D}"), ('\u{FA37}', "\u{5606}"), ('\u{FA38}', "\u{5668}"),
+    ('\u{FA39}', "\u{5840}"), ('\u{FA3A}', "\u{58A8}"), ('\u{FA3B}', "\u{5C64}"), ('\u{FA3C}', "\u{5C6E}"),
+    ('\u{FA3D}', "\u{6094}"), ('\u{FA3E}', "\u{6168}"), ('\u{FA3F}', "\u{618E}"), ('\u{FA40}', "\u{61F2}"),
+    ('\u{FA41}', "\u{654F}"), ('\u{FA42}', "\u{65E2}"), ('\u{FA43}', "\u{6691}"), ('\u{FA44}', "\u{6885}"),
+    ('\u{FA45}', "\u{6D77}"), ('\u{FA46}', "\u{6E1A}"), ('\u{FA47}', "\u{6F22}"), ('\u{FA48}', "\u{716E}"),
+    ('\u{FA49}', "\u{722B}"), ('\u{FA4A}', "\u{7422}"), ('\u{FA4B}', "\u{7891}"), ('\u{FA4C}', "\u{793E}"),
+    ('\u{FA4D}', "\u{7949}"), ('\u{FA4E}', "\u{7948}"), ('\u{FA4F}', "\u{7950}"), ('\u{FA50}', "\u{7956}"),
+    ('\u{FA51}', "\u{795D}"), ('\u{FA52}', "\u{798D}"), ('\u{FA53}', "\u{798E}"), ('\u{FA54}', "\u{7A40}"),
+    ('\u{FA55}', "\u{7A81}"), ('\u{FA56}', "\u{7BC0}"), ('\u{FA57}', "\u{7DF4}"), ('\u{FA58}', "\u{7E09}"),
+    ('\u{FA59}', "\u{7E41}"), ('\u{FA5A}', "\u{7F72}"), ('\u{FA5B}', "\u{8005}"), ('\u{FA5C}', "\u{81ED}"),
+    ('\u{FA5D}', "\u{8279}"), ('\u{FA5E}', "\u{8279}"), ('\u{FA5F}', "\u{8457}"), ('\u{FA60}', "\u{8910}"),
+    ('\u{FA61}', "\u{8996}"), ('\u{FA62}', "\u{8B01}"), ('\u{FA63}', "\u{8B39}"), ('\u{FA64}', "\u{8CD3}"),
+    ('\u{FA65}', "\u{8D08}"), ('\u{FA66}', "\u{8FB6}"), ('\u{FA67}', "\u{9038}"), ('\u{FA68}', "\u{96E3}"),
+    ('\u{FA69}', "\u{97FF}"), ('\u{FA6A}', "\u{983B}"), ('\u{FA6B}', "\u{6075}"), ('\u{FA6C}', "\u{242EE}"),
+    ('\u{FA6D}', "\u{8218}"), ('\u{FA70}', "\u{4E26}"), ('\u{FA71}', "\u{51B5}"), ('\u{FA72}', "\u{5168}"),
+    ('\u{FA73}', "\u{4F80}"), ('\u{FA74}', "\u{5145}"), ('\u{FA75}', "\u{5180}"), ('\u{FA76}', "\u{52C7}"),
+    ('\u{FA77}', "\u{52FA}"), ('\u{FA78}', "\u{559D}"), ('\u{FA79}', "\u{5555}"), ('\u{FA7A}', "\u{5599}"),
+    ('\u{FA7B}', "\u{55E2}"), ('\u{FA7C}', "\u{585A}"), ('\u{FA7D}', "\u{58B3}"), ('\u{FA7E}', "\u{5944}"),
+    ('\u{FA7F}', "\u{5954}"), ('\u{FA80}', "\u{5A62}"), ('\u{FA81}', "\u{5B28}"), ('\u{FA82}', "\u{5ED2}"),
+    ('\u{FA83}', "\u{5ED9}"), ('\u{FA84}', "\u{5F69}"), ('\u{FA85}', "\u{5FAD}"), ('\u{FA86}', "\u{60D8}"),
+    ('\u{FA87}', "\u{614E}"), ('\u{FA88}', "\u{6108}"), ('\u{FA89}', "\u{618E}"), ('\u{FA8A}', "\u{6160}"),
+    ('\u{FA8B}', "\u{61F2}"), ('\u{FA8C}', "\u{6234}"), ('\u{FA8D}', "\u{63C4}"), ('\u{FA8E}', "\u{641C}"),
+    ('\u{FA8F}', "\u{6452}"), ('\u{FA90}', "\u{6556}"), ('\u{FA91}', "\u{6674}"), ('\u{FA92}', "\u{6717}"),
+    ('\u{FA93}', "\u{671B}"), ('\u{FA94}', "\u{6756}"), ('\u{FA95}', "\u{6B79}"), ('\u{FA96}', "\u{6BBA}"),
+    ('\u{FA97}', "\u{6D41}"), ('\u{FA98}', "\u{6EDB}"), ('\u{FA99}', "\u{6ECB}"), ('\u{FA9A}', "\u{6F22}"),
+    ('\u{FA9B}', "\u{701E}"), ('\u{FA9C}', "\u{716E}"), ('\u{FA9D}', "\u{77A7}"), ('\u{FA9E}', "\u{7235}"),
+    ('\u{FA9F}', "\u{72AF}"), ('\u{FAA0}', "\u{732A}"), ('\u{FAA1}', "\u{7471}"), ('\u{FAA2}', "\u{7506}"),
+    ('\u{FAA3}', "\u{753B}"), ('\u{FAA4}', "\u{761D}"), ('\u{FAA5}', "\u{761F}"), ('\u{FAA6}', "\u{76CA}"),
+    ('\u{FAA7}', "\u{76DB}"), ('\u{FAA8}', "\u{76F4}"), ('\u{FAA9}', "\u{774A}"), ('\u{FAAA}', "\u{7740}"),
+    ('\u{FAAB}', "\u{78CC}"), ('\u{FAAC}', "\u{7AB1}"), ('\u{FAAD}', "\u{7BC0}"), ('\u{FAAE}', "\u{7C7B}"),
+    ('\u{FAAF}', "\u{7D5B}"), ('\u{FAB0}', "\u{7DF4}"), ('\u{FAB1}', "\u{7F3E}"), ('\u{FAB2}', "\u{8005}"),
+    ('\u{FAB3}', "\u{8352}"), ('\u{FAB4}', "\u{83EF}"), ('\u{FAB5}', "\u{8779}"), ('\u{FAB6}', "\u{8941}"),
+    ('\u{FAB7}', "\u{8986}"), ('\u{FAB8}', "\u{8996}"), ('\u{FAB9}', "\u{8ABF}"), ('\u{FABA}', "\u{8AF8}"),
+    ('\u{FABB}', "\u{8ACB}"), ('\u{FABC}', "\u{8B01}"), ('\u{FABD}', "\u{8AFE}"), ('\u{FABE}', "\u{8AED}"),
+    ('\u{FABF}', "\u{8B39}"), ('\u{FAC0}', "\u{8B8A}"), ('\u{FAC1}', "\u{8D08}"), ('\u{FAC2}', "\u{8F38}"),
+    ('\u{FAC3}', "\u{9072}"), ('\u{FAC4}', "\u{9199}"), ('\u{FAC5}', "\u{9276}"), ('\u{FAC6}', "\u{967C}"),
+    ('\u{FAC7}', "\u{96E3}"), ('\u{FAC8}', "\u{9756}"), ('\u{FAC9}', "\u{97DB}"), ('\u{FACA}', "\u{97FF}"),
+    ('\u{FACB}', "\u{980B}"), ('\u{FACC}', "\u{983B}"), ('\u{FACD}', "\u{9B12}"), ('\u{FACE}', "\u{9F9C}"),
+    ('\u{FACF}', "\u{2284A}"), ('\u{FAD0}', "\u{22844}"), ('\u{FAD1}', "\u{233D5}"), ('\u{FAD2}', "\u{3B9D}"),
+    ('\u{FAD3}', "\u{4018}"), ('\u{FAD4}', "\u{4039}"), ('\u{FAD5}', "\u{25249}"), ('\u{FAD6}', "\u{25CD0}"),
+    ('\u{FAD7}', "\u{27ED3}"), ('\u{FAD8}', "\u{9F43}"), ('\u{FAD9}', "\u{9F8E}"), ('\u{FB1D}', "\u{5D9}\u{5B4}"),
+    ('\u{FB1F}', "\u{5F2}\u{5B7}"), ('\u{FB2A}', "\u{5E9}\u{5C1}"), ('\u{FB2B}', "\u{5E9}\u{5C2}"), ('\u{FB2C}', "\u{5E9}\u{5BC}\u{5C1}"),
+    ('\u{FB2D}', "\u{5E9}\u{5BC}\u{5C2}"), ('\u{FB2E}', "\u{5D0}\u{5B7}"), ('\u{FB2F}', "\u{5D0}\u{5B8}"), ('\u{FB30}', "\u{5D0}\u{5BC}"),
+    ('\u{FB31}', "\u{5D1}\u{5BC}"), ('\u{FB32}', "\u{5D2}\u{5BC}"), ('\u{FB33}', "\u{5D3}\u{5BC}"), ('\u{FB34}', "\u{5D4}\u{5BC}"),
+    ('\u{FB35}', "\u{5D5}\u{5BC}"), ('\u{FB36}', "\u{5D6}\u{5BC}"), ('\u{FB38}', "\u{5D8}\u{5BC}"), ('\u{FB39}', "\u{5D9}\u{5BC}"),
+    ('\u{FB3A}', "\u{5DA}\u{5BC}"), ('\u{FB3B}', "\u{5DB}\u{5BC}"), ('\u{FB3C}', "\u{5DC}\u{5BC}"), ('\u{FB3E}', "\u{5DE}\u{5BC}"),
+    ('\u{FB40}', "\u{5E0}\u{5BC}"), ('\u{FB41}', "\u{5E1}\u{5BC}"), ('\u{FB43}', "\u{5E3}\u{5BC}"), ('\u{FB44}', "\u{5E4}\u{5BC}"),
+    ('\u{FB46}', "\u{5E6}\u{5BC}"), ('\u{FB47}', "\u{5E7}\u{5BC}"), ('\u{FB48}', "\u{5E8}\u{5BC}"), ('\u{FB49}', "\u{5E9}\u{5BC}"),
+    ('\u{FB4A}', "\u{5EA}\u{5BC}"), ('\u{FB4B}', "\u{5D5}\u{5B9}"), ('\u{FB4C}', "\u{5D1}\u{5BF}"), ('\u{FB4D}', "\u{5DB}\u{5BF}"),
+    ('\u{FB4E}', "\u{5E4}\u{5BF}"), ('\u{1109A}', "\u{11099}\u{110BA}"), ('\u{1109C}', "\u{1109B}\u{110BA}"), ('\u{110AB}', "\u{110A5}\u{110BA}"),
+    ('\u{1112E}', "\u{11131}\u{11127}"), ('\u{1112F}', "\u{11132}\u{11127}"), ('\u{1134B}', "\u{11347}\u{1133E}"), ('\u{1134C}', "\u{11347}\u{11357}"),
+    ('\u{114BB}', "\u{114B9}\u{114BA}"), ('\u{114BC}', "\u{114B9}\u{114B0}"), ('\u{114BE}', "\u{114B9}\u{114BD}"), ('\u{115BA}', "\u{115B8}\u{115AF}"),
+    ('\u{115BB}', "\u{115B9}\u{115AF}"), ('\u{11938}', "\u{11935}\u{11930}"), ('\u{1D15E}', "\u{1D157}\u{1D165}"), ('\u{1D15F}', "\u{1D158}\u{1D165}"),
+    ('\u{1D160}', "\u{1D158}\u{1D165}\u{1D16E}"), ('\u{1D161}', "\u{1D158}\u{1D165}\u{1D16F}"), ('\u{1D162}', "\u{1D158}\u{1D165}\u{1D170}"), ('\u{1D163}', "\u{1D158}\u{1D165}\u{1D171}"),
+    ('\u{1D164}', "\u{1D158}\u{1D165}\u{1D172}"), ('\u{1D1BB}', "\u{1D1B9}\u{1D165}"), ('\u{1D1BC}', "\u{1D1BA}\u{1D165}"), ('\u{1D1BD}', "\u{1D1B9}\u{1D165}\u{1D16E}"),
+    ('\u{1D1BE}', "\u{1D1BA}\u{1D165}\u{1D16E}"), ('\u{1D1BF}', "\u{1D1B9}\u{1D165}\u{1D16F}"), ('\u{1D1C0}', "\u{1D1BA}\u{1D165}\u{1D16F}"), ('\u{2F800}', "\u{4E3D}"),
+    ('\u{2F801}', "\u{4E38}"), ('\u{2F802}', "\u{4E41}"), ('\u{2F803}', "\u{20122}"), ('\u{2F804}', "\u{4F60}"),
+    ('\u{2F805}', "\u{4FAE}"), ('\u{2F806}', "\u{4FBB}"), ('\u{2F807}', "\u{5002}"), ('\u{2F808}', "\u{507A}"),
+    ('\u{2F809}', "\u{5099}"), ('\u{2F80A}', "\u{50E7}"), ('\u{2F80B}', "\u{50CF}"), ('\u{2F80C}', "\u{349E}"),
+    ('\u{2F80D}', "\u{2063A}"), ('\u{2F80E}', "\u{514D}"), ('\u{2F80F}', "\u{5154}"), ('\u{2F810}', "\u{5164}"),
+    ('\u{2F811}', "\u{5177}"), ('\u{2F812}', "\u{2051C}"), ('\u{2F813}', "\u{34B9}"), ('\u{2F814}', "\u{5167}"),
+    ('\u{2F815}', "\u{518D}"), ('\u{2F816}', "\u{2054B}"), ('\u{2F817}', "\u{5197}"), ('\u{2F818}', "\u{51A4}"),
+    ('\u{2F819}', "\u{4ECC}"), ('\u{2F81A}', "\u{51AC}"), ('\u{2F81B}', "\u{51B5}"), ('\u{2F81C}', "\u{291DF}"),
+    ('\u{2F81D}', "\u{51F5}"), ('\u{2F81E}', "\u{5203}"), ('\u{2F81F}', "\u{34DF}"), ('\u{2F820}', "\u{523B}"),
+    ('\u{2F821}', "\u{5246}"), ('\u{2F822}', "\u{5272}"), ('\u{2F823}', "\u{5277}"), ('\u{2F824}', "\u{3515}"),
+    ('\u{2F825}', "\u{52C7}"), ('\u{2F826}', "\u{52C9}"), ('\u{2F827}', "\u{52E4}"), ('\u{2F828}', "\u{52FA}"),
+    ('\u{2F829}', "\u{5305}"), ('\u{2F82A}', "\u{5306}"), ('\u{2F82B}', "\u{5317}"), ('\u{2F82C}', "\u{5349}"),
+    ('\u{2F82D}', "\u{5351}"), ('\u{2F82E}', "\u{535A}"), ('\u{2F82F}', "\u{5373}"), ('\u{2F830}', "\u{537D}"),
+    ('\u{2F831}', "\u{537F}"), ('\u{2F832}', "\u{537F}"), ('\u{2F833}', "\u{537F}"), ('\u{2F834}', "\u{20A2C}"),
+    ('\u{2F835}', "\u{7070}"), ('\u{2F836}', "\u{53CA}"), ('\u{2F837}', "\u{53DF}"), ('\u{2F838}', "\u{20B63}"),
+    ('\u{2F839}', "\u{53EB}"), ('\u{2F83A}', "\u{53F1}"), ('\u{2F83B}', "\u{5406}"), ('\u{2F83C}', "\u{549E}"),
+    ('\u{2F83D}', "\u{5438}"), ('\u{2F83E}', "\u{5448}"), ('\u{2F83F}', "\u{5468}"), ('\u{2F840}', "\u{54A2}"),
+    ('\u{2F841}', "\u{54F6}"), ('\u{2F842}', "\u{5510}"), ('\u{2F843}', "\u{5553}"), ('\u{2F844}', "\u{5563}"),
+    ('\u{2F845}', "\u{5584}"), ('\u{2F846}', "\u{5584}"), ('\u{2F847}', "\u{5599}"), ('\u{2F848}', "\u{55AB}"),
+    ('\u{2F849}', "\u{55B3}"), ('\u{2F84A}', "\u{55C2}"), ('\u{2F84B}', "\u{5716}"), ('\u{2F84C}', "\u{5606}"),
+    ('\u{2F84D}', "\u{5717}"), ('\u{2F84E}', "\u{5651}"), ('\u{2F84F}', "\u{5674}"), ('\u{2F850}', "\u{5207}"),
+    ('\u{2F851}', "\u{58EE}"), ('\u{2F852}', "\u{57CE}"), ('\u{2F853}', "\u{57F4}"), ('\u{2F854}', "\u{580D}"),
+    ('\u{2F855}', "\u{578B}"), ('\u{2F856}', "\u{5832}"), ('\u{2F857}', "\u{5831}"), ('\u{2F858}', "\u{58AC}"),
+    ('\u{2F859}', "\u{214E4}"), ('\u{2F85A}', "\u{58F2}"), ('\u{2F85B}', "\u{58F7}"), ('\u{2F85C}', "\u{5906}"),
+    ('\u{2F85D}', "\u{591A}"), ('\u{2F85E}', "\u{5922}"), ('\u{2F85F}', "\u{5962}"), ('\u{2F860}', "\u{216A8}"),
+    ('\u{2F861}', "\u{216EA}"), ('\u{2F862}', "\u{59EC}"), ('\u{2F863}', "\u{5A1B}"), ('\u{2F864}', "\u{5A27}"),
+    ('\u{2F865}', "\u{59D8}"), ('\u{2F866}', "\u{5A66}"), ('\u{2F867}', "\u{36EE}"), ('\u{2F868}', "\u{36FC}"),
+    ('\u{2F869}', "\u{5B08}"), ('\u{2F86A}', "\u{5B3E}"), ('\u{2F86B}', "\u{5B3E}"), ('\u{2F86C}', "\u{219C8}"),
+    ('\u{2F86D}', "\u{5BC3}"), ('\u{2F86E}', "\u{5BD8}"), ('\u{2F86F}', "\u{5BE7}"), ('\u{2F870}', "\u{5BF3}"),
+    ('\u{2F871}', "\u{21B18}"), ('\u{2F872}', "\u{5BFF}"), ('\u{2F873}', "\u{5C06}"), ('\u{2F874}', "\u{5F53}"),
+    ('\u{2F875}', "\u{5C22}"), ('\u{2F876}', "\u{3781}"), ('\u{2F877}', "\u{5C60}"), ('\u{2F878}', "\u{5C6E}"),
+    ('\u{2F879}', "\u{5CC0}"), ('\u{2F87A}', "\u{5C8D}"), ('\u{2F87B}', "\u{21DE4}"), ('\u{2F87C}', "\u{5D43}"),
+    ('\u{2F87D}', "\u{21DE6}"), ('\u{2F87E}', "\u{5D6E}"), ('\u{2F87F}', "\u{5D6B}"), ('\u{2F880}', "\u{5D7C}"),
+    ('\u{2F881}', "\u{5DE1}"), ('\u{2F882}', "\u{5DE2}"), ('\u{2F883}', "\u{382F}"), ('\u{2F884}', "\u{5DFD}"),
+    ('\u{2F885}', "\u{5E28}"), ('\u{2F886}', "\u{5E3D}"), ('\u{2F887}', "\u{5E69}"), ('\u{2F888}', "\u{3862}"),
+    ('\u{2F889}', "\u{22183}"), ('\u{2F88A}', "\u{387C}"), ('\u{2F88B}', "\u{5EB0}"), ('\u{2F88C}', "\u{5EB3}"),
+    ('\u{2F88D}', "\u{5EB6}"), ('\u{2F88E}', "\u{5ECA}"), ('\u{2F88F}', "\u{2A392}"), ('\u{2F890}', "\u{5EFE}"),
+    ('\u{2F891}', "\u{22331}"), ('\u{2F892}', "\u{22331}"), ('\u{2F893}', "\u{8201}"), ('\u{2F894}', "\u{5F22}"),
+    ('\u{2F895}', "\u{5F22}"), ('\u{2F896}', "\u{38C7}"), ('\u{2F897}', "\u{232B8}"), ('\u{2F898}', "\u{261DA}"),
+    ('\u{2F899}', "\u{5F62}"), ('\u{2F89A}', "\u{5F6B}"), ('\u{2F89B}', "\u{38E3}"), ('\u{2F89C}', "\u{5F9A}"),
+    ('\u{2F89D}', "\u{5FCD}"), ('\u{2F89E}', "\u{5FD7}"), ('\u{2F89F}', "\u{5FF9}"), ('\u{2F8A0}', "\u{6081}"),
+    ('\u{2F8A1}', "\u{393A}"), ('\u{2F8A2}', "\u{391C}"), ('\u{2F8A3}', "\u{6094}"), ('\u{2F8A4}', "\u{226D4}"),
+    ('\u{2F8A5}', "\u{60C7}"), ('\u{2F8A6}', "\u{6148}"), ('\u{2F8A7}', "\u{614C}"), ('\u{2F8A8}', "\u{614E}"),
+    ('\u{2F8A9}', "\u{614C}"), ('\u{2F8AA}', "\u{617A}"), ('\u{2F8AB}', "\u{618E}"), ('\u{2F8AC}', "\u{61B2}"),
+    ('\u{2F8AD}', "\u{61A4}"), ('\u{2F8AE}', "\u{61AF}"), ('\u{2F8AF}', "\u{61DE}"), ('\u{2F8B0}', "\u{61F2}"),
+    ('\u{2F8B1}', "\u{61F6}"), ('\u{2F8B2}', "\u{6210}"), ('\u{2F8B3}', "\u{621B}"), ('\u{2F8B4}', "\u{625D}"),
+    ('\u{2F8B5}', "\u{62B1}"), ('\u{2F8B6}', "\u{62D4}"), ('\u{2F8B7}', "\u{6350}"), ('\u{2F8B8}', "\u{22B0C}"),
+    ('\u{2F8B9}', "\u{633D}"), ('\u{2F8BA}', "\u{62FC}"), ('\u{2F8BB}', "\u{6368}"), ('\u{2F8BC}', "\u{6383}"),
+    ('\u{2F8BD}', "\u{63E4}"), ('\u{2F8BE}', "\u{22BF1}"), ('\u{2F8BF}', "\u{6422}"), ('\u{2F8C0}', "\u{63C5}"),
+    ('\u{2F8C1}', "\u{63A9}"), ('\u{2F8C2}', "\u{3A2E}"), ('\u{2F8C3}', "\u{6469}"), ('\u{2F8C4}', "\u{647E}"),
+    ('\u{2F8C5}', "\u{649D}"), ('\u{2F8C6}', "\u{6477}"), ('\u{2F8C7}', "\u{3A6C}"), ('\u{2F8C8}', "\u{654F}"),
+    ('\u{2F8C9}', "\u{656C}"), ('\u{2F8CA}', "\u{2300A}"), ('\u{2F8CB}', "\u{65E3}"), ('\u{2F8CC}', "\u{66F8}"),
+    ('\u{2F8CD}', "\u{6649}"), ('\u{2F8CE}', "\u{3B19}"), ('\u{2F8CF}', "\u{6691}"), ('\u{2F8D0}', "\u{3B08}"),
+    ('\u{2F8D1}', "\u{3AE4}"), ('\u{2F8D2}', "\u{5192}"), ('\u{2F8D3}', "\u{5195}"), ('\u{2F8D4}', "\u{6700}"),
+    ('\u{2F8D5}', "\u{669C}"), ('\u{2F8D6}', "\u{80AD}"), ('\u{2F8D7}', "\u{43D9}"), ('\u{2F8D8}', "\u{6717}"),
+    ('\u{2F8D9}', "\u{671B}"), ('\u{2F8DA}', "\u{6721}"), ('\u{2F8DB}', "\u{675E}"), ('\u{2F8DC}', "\u{6753}"),
+    ('\u{2F8DD}', "\u{233C3}"), ('\u{2F8DE}', "\u{3B49}"), ('\u{2F8DF}', "\u{67FA}"), ('\u{2F8E0}', "\u{6785}"),
+    ('\u{2F8E1}', "\u{6852}"), ('\u{2F8E2}', "\u{6885}"), ('\u{2F8E3}', "\u{2346D}"), ('\u{2F8E4}', "\u{688E}"),
+    ('\u{2F8E5}', "\u{681F}"), ('\u{2F8E6}', "\u{6914}"), ('\u{2F8E7}', "\u{3B9D}"), ('\u{2F8E8}', "\u{6942}"),
+    ('\u{2F8E9}', "\u{69A3}"), ('\u{2F8EA}', "\u{69EA}"), ('\u{2F8EB}', "\u{6AA8}"), ('\u{2F8EC}', "\u{236A3}"),
+    ('\u{2F8ED}', "\u{6ADB}"), ('\u{2F8EE}', "\u{3C18}"), ('\u{2F8EF}', "\u{6B21}"), ('\u{2F8F0}', "\u{238A7}"),
+    ('\u{2F8F1}', "\u{6B54}"), ('\u{2F8F2}', "\u{3C4E}"), ('\u{2F8F3}', "\u{6B72}"), ('\u{2F8F4}', "\u{6B9F}"),
+    ('\u{2F8F5}', "\u{6BBA}"), ('\u{2F8F6}', "\u{6BBB}"), ('\u{2F8F7}', "\u{23A8D}"), ('\u{2F8F8}', "\u{21D0B}"),
+    ('\u{2F8F9}', "\u{23AFA}"), ('\u{2F8FA}', "\u{6C4E}"), ('\u{2F8FB}', "\u{23CBC}"), ('\u{2F8FC}', "\u{6CBF}"),
+    ('\u{2F8FD}', "\u{6CCD}"), ('\u{2F8FE}', "\u{6C67}"), ('\u{2F8FF}', "\u{6D16}"), ('\u{2F900}', "\u{6D3E}"),
+    ('\u{2F901}', "\u{6D77}"), ('\u{2F902}', "\u{6D41}"), ('\u{2F903}', "\u{6D69}"), ('\u{2F904}', "\u{6D78}"),
+    ('\u{2F905}', "\u{6D85}"), ('\u{2F906}', "\u{23D1E}"), ('\u{2F907}', "\u{6D34}"), ('\u{2F908}', "\u{6E2F}"),
+    ('\u{2F909}', "\u{6E6E}"), ('\u{2F90A}', "\u{3D33}"), ('\u{2F90B}', "\u{6ECB}"), ('\u{2F90C}', "\u{6EC7}"),
+    ('\u{2F90D}', "\u{23ED1}"), ('\u{2F90E}', "\u{6DF9}"), ('\u{2F90F}', "\u{6F6E}"), ('\u{2F910}', "\u{23F5E}"),
+    ('\u{2F911}', "\u{23F8E}"), ('\u{2F912}', "\u{6FC6}"), ('\u{2F913}', "\u{7039}"), ('\u{2F914}', "\u{701E}"),
+    ('\u{2F915}', "\u{701B}"), ('\u{2F916}', "\u{3D96}"), ('\u{2F917}', "\u{704A}"), ('\u{2F918}', "\u{707D}"),
+    ('\u{2F919}', "\u{7077}"), ('\u{2F91A}', "\u{70AD}"), ('\u{2F91B}', "\u{20525}"), ('\u{2F91C}', "\u{7145}"),
+    ('\u{2F91D}', "\u{24263}"), ('\u{2F91E}', "\u{719C}"), ('\u{2F91F}', "\u{243AB}"), ('\u{2F920}', "\u{7228}"),
+    ('\u{2F921}', "\u{7235}"), ('\u{2F922}', "\u{7250}"), ('\u{2F923}', "\u{24608}"), ('\u{2F924}', "\u{7280}"),
+    ('\u{2F925}', "\u{7295}"), ('\u{2F926}', "\u{24735}"), ('\u{2F927}', "\u{24814}"), ('\u{2F928}', "\u{737A}"),
+    ('\u{2F929}', "\u{738B}"), ('\u{2F92A}', "\u{3EAC}"), ('\u{2F92B}', "\u{73A5}"), ('\u{2F92C}', "\u{3EB8}"),
+    ('\u{2F92D}', "\u{3EB8}"), ('\u{2F92E}', "\u{7447}"), ('\u{2F92F}', "\u{745C}"), ('\u{2F930}', "\u{7471}"),
+    ('\u{2F931}', "\u{7485}"), ('\u{2F932}', "\u{74CA}"), ('\u{2F933}', "\u{3F1B}"), ('\u{2F934}', "\u{7524}"),
+    ('\u{2F935}', "\u{24C36}"), ('\u{2F936}', "\u{753E}"), ('\u{2F937}', "\u{24C92}"), ('\u{2F938}', "\u{7570}"),
+    ('\u{2F939}', "\u{2219F}"), ('\u{2F93A}', "\u{7610}"), ('\u{2F93B}', "\u{24FA1}"), ('\u{2F93C}', "\u{24FB8}"),
+    ('\u{2F93D}', "\u{25044}"), ('\u{2F93E}', "\u{3FFC}"), ('\u{2F93F}', "\u{4008}"), ('\u{2F940}', "\u{76F4}"),
+    ('\u{2F941}', "\u{250F3}"), ('\u{2F942}', "\u{250F2}"), ('\u{2F943}', "\u{25119}"), ('\u{2F944}', "\u{25133}"),
+    ('\u{2F945}', "\u{771E}"), ('\u{2F946}', "\u{771F}"), ('\u{2F947}', "\u{771F}"), ('\u{2F948}', "\u{774A}"),
+    ('\u{2F949}', "\u{4039}"), ('\u{2F94A}', "\u{778B}"), ('\u{2F94B}', "\u{4046}"), ('\u{2F94C}', "\u{4096}"),
+    ('\u{2F94D}', "\u{2541D}"), ('\u{2F94E}', "\u{784E}"), ('\u{2F94F}', "\u{788C}"), ('\u{2F950}', "\u{78CC}"),
+    ('\u{2F951}', "\u{40E3}"), ('\u{2F952}', "\u{25626}"), ('\u{2F953}', "\u{7956}"), ('\u{2F954}', "\u{2569A}"),
+    ('\u{2F955}', "\u{256C5}"), ('\u{2F956}', "\u{798F}"), ('\u{2F957}', "\u{79EB}"), ('\u{2F958}', "\u{412F}"),
+    ('\u{2F959}', "\u{7A40}"), ('\u{2F95A}', "\u{7A4A}"), ('\u{2F95B}', "\u{7A4F}"), ('\u{2F95C}', "\u{2597C}"),
+    ('\u{2F95D}', "\u{25AA7}"), ('\u{2F95E}', "\u{25AA7}"), ('\u{2F95F}', "\u{7AEE}"), ('\u{2F960}', "\u{4202}"),
+    ('\u{2F961}', "\u{25BAB}"), ('\u{2F962}', "\u{7BC6}"), ('\u{2F963}', "\u{7BC9}"), ('\u{2F964}', "\u{4227}"),
+    ('\u{2F965}', "\u{25C80}"), ('\u{2F966}', "\u{7CD2}"), ('\u{2F967}', "\u{42A0}"), ('\u{2F968}', "\u{7CE8}"),
+    ('\u{2F969}', "\u{7CE3}"), ('\u{2F96A}', "\u{7D00}"), ('\u{2F96B}', "\u{25F86}"), ('\u{2F96C}', "\u{7D63}"),
+    ('\u{2F96D}', "\u{4301}"), ('\u{2F96E}', "\u{7DC7}"), ('\u{2F96F}', "\u{7E02}"), ('\u{2F970}', "\u{7E45}"),
+    ('\u{2F971}', "\u{4334}"), ('\u{2F972}', "\u{26228}"), ('\u{2F973}', "\u{26247}"), ('\u{2F974}', "\u{4359}"),
+    ('\u{2F975}', "\u{262D9}"), ('\u{2F976}', "\u{7F7A}"), ('\u{2F977}', "\u{2633E}"), ('\u{2F978}', "\u{7F95}"),
+    ('\u{2F979}', "\u{7FFA}"), ('\u{2F97A}', "\u{8005}"), ('\u{2F97B}', "\u{264DA}"), ('\u{2F97C}', "\u{26523}"),
+    ('\u{2F97D}', "\u{8060}"), ('\u{2F97E}', "\u{265A8}"), ('\u{2F97F}', "\u{8070}"), ('\u{2F980}', "\u{2335F}"),
+    ('\u{2F981}', "\u{43D5}"), ('\u{2F982}', "\u{80B2}"), ('\u{2F983}', "\u{8103}"), ('\u{2F984}', "\u{440B}"),
+    ('\u{2F985}', "\u{813E}"), ('\u{2F986}', "\u{5AB5}"), ('\u{2F987}', "\u{267A7}"), ('\u{2F988}', "\u{267B5}"),
+    ('\u{2F989}', "\u{23393}"), ('\u{2F98A}', "\u{2339C}"), ('\u{2F98B}', "\u{8201}"), ('\u{2F98C}', "\u{8204}"),
+    ('\u{2F98D}', "\u{8F9E}"), ('\u{2F98E}', "\u{446B}"), ('\u{2F98F}', "\u{8291}"), ('\u{2F990}', "\u{828B}"),
+    ('\u{2F991}', "\u{829D}"), ('\u{2F992}', "\u{52B3}"), ('\u{2F993}', "\u{82B1}"), ('\u{2F994}', "\u{82B3}"),
+    ('\u{2F995}', "\u{82BD}"), ('\u{2F996}', "\u{82E6}"), ('\u{2F997}', "\u{26B3C}"), ('\u{2F998}', "\u{82E5}"),
+    ('\u{2F999}', "\u{831D}"), ('\u{2F99A}', "\u{8363}"), ('\u{2F99B}', "\u{83AD}"), ('\u{2F99C}', "\u{8323}"),
+    ('\u{2F99D}', "\u{83BD}"), ('\u{2F99E}', "\u{83E7}"), ('\u{2F99F}', "\u{8457}"), ('\u{2F9A0}', "\u{8353}"),
+    ('\u{2F9A1}', "\u{83CA}"), ('\u{2F9A2}', "\u{83CC}"), ('\u{2F9A3}', "\u{83DC}"), ('\u{2F9A4}', "\u{26C36}"),
+    ('\u{2F9A5}', "\u{26D6B}"), ('\u{2F9A6}', "\u{26CD5}"), ('\u{2F9A7}', "\u{452B}"), ('\u{2F9A8}', "\u{84F1}"),
+    ('\u{2F9A9}', "\u{84F3}"), ('\u{2F9AA}', "\u{8516}"), ('\u{2F9AB}', "\u{273CA}"), ('\u{2F9AC}', "\u{8564}"),
+    ('\u{2F9AD}', "\u{26F2C}"), ('\u{2F9AE}', "\u{455D}"), ('\u{2F9AF}', "\u{4561}"), ('\u{2F9B0}', "\u{26FB1}"),
+    ('\u{2F9B1}', "\u{270D2}"), ('\u{2F9B2}', "\u{456B}"), ('\u{2F9B3}', "\u{8650}"), ('\u{2F9B4}', "\u{865C}"),
+    ('\u{2F9B5}', "\u{8667}"), ('\u{2F9B6}', "\u{8669}"), ('\u{2F9B7}', "\u{86A9}"), ('\u{2F9B8}', "\u{8688}"),
+    ('\u{2F9B9}', "\u{870E}"), ('\u{2F9BA}', "\u{86E2}"), ('\u{2F9BB}', "\u{8779}"), ('\u{2F9BC}', "\u{8728}"),
+    ('\u{2F9BD}', "\u{876B}"), ('\u{2F9BE}', "\u{8786}"), ('\u{2F9BF}', "\u{45D7}"), ('\u{2F9C0}', "\u{87E1}"),
+    ('\u{2F9C1}', "\u{8801}"), ('\u{2F9C2}', "\u{45F9}"), ('\u{2F9C3}', "\u{8860}"), ('\u{2F9C4}', "\u{8863}"),
+    ('\u{2F9C5}', "\u{27667}"), ('\u{2F9C6}', "\u{88D7}"), ('\u{2F9C7}', "\u{88DE}"), ('\u{2F9C8}', "\u{4635}"),
+    ('\u{2F9C9}', "\u{88FA}"), ('\u{2F9CA}', "\u{34BB}"), ('\u{2F9CB}', "\u{278AE}"), ('\u{2F9CC}', "\u{27966}"),
+    ('\u{2F9CD}', "\u{46BE}"), ('\u{2F9CE}', "\u{46C7}"), ('\u{2F9CF}', "\u{8AA0}"), ('\u{2F9D0}', "\u{8AED}"),
+    ('\u{2F9D1}', "\u{8B8A}"), ('\u{2F9D2}', "\u{8C55}"), ('\u{2F9D3}', "\u{27CA8}"), ('\u{2F9D4}', "\u{8CAB}"),
+    ('\u{2F9D5}', "\u{8CC1}"), ('\u{2F9D6}', "\u{8D1B}"), ('\u{2F9D7}', "\u{8D77}"), ('\u{2F9D8}', "\u{27F2F}"),
+    ('\u{2F9D9}', "\u{20804}"), ('\u{2F9DA}', "\u{8DCB}"), ('\u{2F9DB}', "\u{8DBC}"), ('\u{2F9DC}', "\u{8DF0}"),
+    ('\u{2F9DD}', "\u{208DE}"), ('\u{2F9DE}', "\u{8ED4}"), ('\u{2F9DF}', "\u{8F38}"), ('\u{2F9E0}', "\u{285D2}"),
+    ('\u{2F9E1}', "\u{285ED}"), ('\u{2F9E2}', "\u{9094}"), ('\u{2F9E3}', "\u{90F1}"), ('\u{2F9E4}', "\u{9111}"),
+    ('\u{2F9E5}', "\u{2872E}"), ('\u{2F9E6}', "\u{911B}"), ('\u{2F9E7}', "\u{9238}"), ('\u{2F9E8}', "\u{92D7}"),
+    ('\u{2F9E9}', "\u{92D8}"), ('\u{2F9EA}', "\u{927C}"), ('\u{2F9EB}', "\u{93F9}"), ('\u{2F9EC}', "\u{9415}"),
+    ('\u{2F9ED}', "\u{28BFA}"), ('\u{2F9EE}', "\u{958B}"), ('\u{2F9EF}', "\u{4995}"), ('\u{2F9F0}', "\u{95B7}"),
+    ('\u{2F9F1}', "\u{28D77}"), ('\u{2F9F2}', "\u{49E6}"), ('\u{2F9F3}', "\u{96C3}"), ('\u{2F9F4}', "\u{5DB2}"),
+    ('\u{2F9F5}', "\u{9723}"), ('\u{2F9F6}', "\u{29145}"), ('\u{2F9F7}', "\u{2921A}"), ('\u{2F9F8}', "\u{4A6E}"),
+    ('\u{2F9F9}', "\u{4A76}"), ('\u{2F9FA}', "\u{97E0}"), ('\u{2F9FB}', "\u{2940A}"), ('\u{2F9FC}', "\u{4AB2}"),
+    ('\u{2F9FD}', "\u{29496}"), ('\u{2F9FE}', "\u{980B}"), ('\u{2F9FF}', "\u{980B}"), ('\u{2FA00}', "\u{9829}"),
+    ('\u{2FA01}', "\u{295B6}"), ('\u{2FA02}', "\u{98E2}"), ('\u{2FA03}', "\u{4B33}"), ('\u{2FA04}', "\u{9929}"),
+    ('\u{2FA05}', "\u{99A7}"), ('\u{2FA06}', "\u{99C2}"), ('\u{2FA07}', "\u{99FE}"), ('\u{2FA08}', "\u{4BCE}"),
+    ('\u{2FA09}', "\u{29B30}"), ('\u{2FA0A}', "\u{9B12}"), ('\u{2FA0B}', "\u{9C40}"), ('\u{2FA0C}', "\u{9CFD}"),
+    ('\u{2FA0D}', "\u{4CCE}"), ('\u{2FA0E}', "\u{4CED}"), ('\u{2FA0F}', "\u{9D67}"), ('\u{2FA10}', "\u{2A0CE}"),
+    ('\u{2FA11}', "\u{4CF8}"), ('\u{2FA12}', "\u{2A105}"), ('\u{2FA13}', "\u{2A20E}"), ('\u{2FA14}', "\u{2A291}"),
+    ('\u{2FA15}', "\u{9EBB}"), ('\u{2FA16}', "\u{4D56}"), ('\u{2FA17}', "\u{9EF9}"), ('\u{2FA18}', "\u{9EFE}"),
+    ('\u{2FA19}', "\u{9F05}"), ('\u{2FA1A}', "\u{9F0F}"), ('\u{2FA1B}', "\u{9F16}"), ('\u{2FA1C}', "\u{9F3B}"),
+    ('\u{2FA1D}', "\u{2A600}"),
+];
+
+pub(crate) const COMPOSITIONS: &[(char, char, char)] = &[
+    ('<', '\u{338}', '\u{226E}'), ('=', '\u{338}', '\u{2260}'), ('>', '\u{338}', '\u{226F}'), ('A', '\u{300}', '\u{C0}'),
+    ('A', '\u{301}', '\u{C1}'), ('A', '\u{302}', '\u{C2}'), ('A', '\u{303}', '\u{C3}'), ('A', '\u{304}', '\u{100}'),
+    ('A', '\u{306}', '\u{102}'), ('A', '\u{307}', '\u{226}'), ('A', '\u{308}', '\u{C4}'), ('A', '\u{309}', '\u{1EA2}'),
+    ('A', '\u{30A}', '\u{C5}'), ('A', '\u{30C}', '\u{1CD}'), ('A', '\u{30F}', '\u{200}'), ('A', '\u{311}', '\u{202}'),
+    ('A', '\u{323}', '\u{1EA0}'), ('A', '\u{325}', '\u{1E00}'), ('A', '\u{328}', '\u{104}'), ('B', '\u{307}', '\u{1E02}'),
+    ('B', '\u{323}', '\u{1E04}'), ('B', '\u{331}', '\u{1E06}'), ('C', '\u{301}', '\u{106}'), ('C', '\u{302}', '\u{108}'),
+    ('C', '\u{307}', '\u{10A}'), ('C', '\u{30C}', '\u{10C}'), ('C', '\u{327}', '\u{C7}'), ('D', '\u{307}', '\u{1E0A}'),
+    ('D', '\u{30C}', '\u{10E}'), ('D', '\u{323}', '\u{1E0C}'), ('D', '\u{327}', '\u{1E10}'), ('D', '\u{32D}', '\u{1E12}'),
+    ('D', '\u{331}', '\u{1E0E}'), ('E', '\u{300}', '\u{C8}'), ('E', '\u{301}', '\u{C9}'), ('E', '\u{302}', '\u{CA}'),
+    ('E', '\u{303}', '\u{1EBC}'), ('E', '\u{304}', '\u{112}'), ('E', '\u{306}', '\u{114}'), ('E', '\u{307}', '\u{116}'),
+    ('E', '\u{308}', '\u{CB}'), ('E', '\u{309}', '\u{1EBA}'), ('E', '\u{30C}', '\u{11A}'), ('E', '\u{30F}', '\u{204}'),
+    ('E', '\u{311}', '\u{206}'), ('E', '\u{323}', '\u{1EB8}'), ('E', '\u{327}', '\u{228}'), ('E', '\u{328}', '\u{118}'),
+    ('E', '\u{32D}', '\u{1E18}'), ('E', '\u{330}', '\u{1E1A}'), ('F', '\u{307}', '\u{1E1E}'), ('G', '\u{301}', '\u{1F4}'),
+    ('G', '\u{302}', '\u{11C}'), ('G', '\u{304}', '\u{1E20}'), ('G', '\u{306}', '\u{11E}'), ('G', '\u{307}', '\u{120}'),
+    ('G', '\u{30C}', '\u{1E6}'), ('G', '\u{327}', '\u{122}'), ('H', '\u{302}', '\u{124}'), ('H', '\u{307}', '\u{1E22}'),
+    ('H', '\u{308}', '\u{1E26}'), ('H', '\u{30C}', '\u{21E}'), ('H', '\u{323}', '\u{1E24}'), ('H', '\u{327}', '\u{1E28}'),
+    ('H', '\u{32E}', '\u{1E2A}'), ('I', '\u{300}', '\u{CC}'), ('I', '\u{301}', '\u{CD}'), ('I', '\u{302}', '\u{CE}'),
+    ('I', '\u{303}', '\u{128}'), ('I', '\u{304}', '\u{12A}'), ('I', '\u{306}', '\u{12C}'), ('I', '\u{307}', '\u{130}'),
+    ('I', '\u{308}', '\u{CF}'), ('I', '\u{309}', '\u{1EC8}'), ('I', '\u{30C}', '\u{1CF}'), ('I', '\u{30F}', '\u{208}'),
+    ('I', '\u{311}', '\u{20A}'), ('I', '\u{323}', '\u{1ECA}'), ('I', '\u{328}', '\u{12E}'), ('I', '\u{330}', '\u{1E2C}'),
+    ('J', '\u{302}', '\u{134}'), ('K', '\u{301}', '\u{1E30}'), ('K', '\u{30C}', '\u{1E8}'), ('K', '\u{323}', '\u{1E32}'),
+    ('K', '\u{327}', '\u{136}'), ('K', '\u{331}', '\u{1E34}'), ('L', '\u{301}', '\u{139}'), ('L', '\u{30C}', '\u{13D}'),
+    ('L', '\u{323}', '\u{1E36}'), ('L', '\u{327}', '\u{13B}'), ('L', '\u{32D}', '\u{1E3C}'), ('L', '\u{331}', '\u{1E3A}'),
+    ('M', '\u{301}', '\u{1E3E}'), ('M', '\u{307}', '\u{1E40}'), ('M', '\u{323}', '\u{1E42}'), ('N', '\u{300}', '\u{1F8}'),
+    ('N', '\u{301}', '\u{143}'), ('N', '\u{303}', '\u{D1}'), ('N', '\u{307}', '\u{1E44}'), ('N', '\u{30C}', '\u{147}'),
+    ('N', '\u{323}', '\u{1E46}'), ('N', '\u{327}', '\u{145}'), ('N', '\u{32D}', '\u{1E4A}'), ('N', '\u{331}', '\u{1E48}'),
+    ('O', '\u{300}', '\u{D2}'), ('O', '\u{301}', '\u{D3}'), ('O', '\u{302}', '\u{D4}'), ('O', '\u{303}', '\u{D5}'),
+    ('O', '\u{304}', '\u{14C}'), ('O', '\u{306}', '\u{14E}'), ('O', '\u{307}', '\u{22E}'), ('O', '\u{308}', '\u{D6}'),
+    ('O', '\u{309}', '\u{1ECE}'), ('O', '\u{30B}', '\u{150}'), ('O', '\u{30C}', '\u{1D1}'), ('O', '\u{30F}', '\u{20C}'),
+    ('O', '\u{311}', '\u{20E}'), ('O', '\u{31B}', '\u{1A0}'), ('O', '\u{323}', '\u{1ECC}'), ('O', '\u{328}', '\u{1EA}'),
+    ('P', '\u{301}', '\u{1E54}'), ('P', '\u{307}', '\u{1E56}'), ('R', '\u{301}', '\u{154}'), ('R', '\u{307}', '\u{1E58}'),
+    ('R', '\u{30C}', '\u{158}'), ('R', '\u{30F}', '\u{210}'), ('R', '\u{311}', '\u{212}'), ('R', '\u{323}', '\u{1E5A}'),
+    ('R', '\u{327}', '\u{156}'), ('R', '\u{331}', '\u{1E5E}'), ('S', '\u{301}', '\u{15A}'), ('S', '\u{302}', '\u{15C}'),
+    ('S', '\u{307}', '\u{1E60}'), ('S', '\u{30C}', '\u{160}'), ('S', '\u{323}', '\u{1E62}'), ('S', '\u{326}', '\u{218}'),
+    ('S', '\u{327}', '\u{15E}'), ('T', '\u{307}', '\u{1E6A}'), ('T', '\u{30C}', '\u{164}'), ('T', '\u{323}', '\u{1E6C}'),
+    ('T', '\u{326}', '\u{21A}'), ('T', '\u{327}', '\u{162}'), ('T', '\u{32D}', '\u{1E70}'), ('T', '\u{331}', '\u{1E6E}'),
+    ('U', '\u{300}', '\u{D9}'), ('U', '\u{301}', '\u{DA}'), ('U', '\u{302}', '\u{DB}'), ('U', '\u{303}', '\u{168}'),
+    ('U', '\u{304}', '\u{16A}'), ('U', '\u{306}', '\u{16C}'), ('U', '\u{308}', '\u{DC}'), ('U', '\u{309}', '\u{1EE6}'),
+    ('U', '\u{30A}', '\u{16E}'), ('U', '\u{30B}', '\u{170}'), ('U', '\u{30C}', '\u{1D3}'), ('U', '\u{30F}', '\u{214}'),
+    ('U', '\u{311}', '\u{216}'), ('U', '\u{31B}', '\u{1AF}'), ('U', '\u{323}', '\u{1EE4}'), ('U', '\u{324}', '\u{1E72}'),
+    ('U', '\u{328}', '\u{172}'), ('U', '\u{32D}', '\u{1E76}'), ('U', '\u{330}', '\u{1E74}'), ('V', '\u{303}', '\u{1E7C}'),
+    ('V', '\u{323}', '\u{1E7E}'), ('W', '\u{300}', '\u{1E80}'), ('W', '\u{301}', '\u{1E82}'), ('W', '\u{302}', '\u{174}'),
+    ('W', '\u{307}', '\u{1E86}'), ('W', '\u{308}', '\u{1E84}'), ('W', '\u{323}', '\u{1E88}'), ('X', '\u{307}', '\u{1E8A}'),
+    ('X', '\u{308}', '\u{1E8C}'), ('Y', '\u{300}', '\u{1EF2}'), ('Y', '\u{301}', '\u{DD}'), ('Y', '\u{302}', '\u{176}'),
+    ('Y', '\u{303}', '\u{1EF8}'), ('Y', '\u{304}', '\u{232}'), ('Y', '\u{307}', '\u{1E8E}'), ('Y', '\u{308}', '\u{178}'),
+    ('Y', '\u{309}', '\u{1EF6}'), ('Y', '\u{323}', '\u{1EF4}'), ('Z', '\u{301}', '\u{179}'), ('Z', '\u{302}', '\u{1E90}'),
+    ('Z', '\u{307}', '\u{17B}'), ('Z', '\u{30C}', '\u{17D}'), ('Z', '\u{323}', '\u{1E92}'), ('Z', '\u{331}', '\u{1E94}'),
+    ('a', '\u{300}', '\u{E0}'), ('a', '\u{301}', '\u{E1}'), ('a', '\u{302}', '\u{E2}'), ('a', '\u{303}', '\u{E3}'),
+    ('a', '\u{304}', '\u{101}'), ('a', '\u{306}', '\u{103}'), ('a', '\u{307}', '\u{227}'), ('a', '\u{308}', '\u{E4}'),
+    ('a', '\u{309}', '\u{1EA3}'), ('a', '\u{30A}', '\u{E5}'), ('a', '\u{30C}', '\u{1CE}'), ('a', '\u{30F}', '\u{201}'),
+    ('a', '\u{311}', '\u{203}'), ('a', '\u{323}', '\u{1EA1}'), ('a', '\u{325}', '\u{1E01}'), ('a', '\u{328}', '\u{105}'),
+    ('b', '\u{307}', '\u{1E03}'), ('b', '\u{323}', '\u{1E05}'), ('b', '\u{331}', '\u{1E07}'), ('c', '\u{301}', '\u{107}'),
+    ('c', '\u{302}', '\u{109}'), ('c', '\u{307}', '\u{10B}'), ('c', '\u{30C}', '\u{10D}'), ('c', '\u{327}', '\u{E7}'),
+    ('d', '\u{307}', '\u{1E0B}'), ('d', '\u{30C}', '\u{10F}'), ('d', '\u{323}', '\u{1E0D}'), ('d', '\u{327}', '\u{1E11}'),
+    ('d', '\u{32D}', '\u{1E13}'), ('d', '\u{331}', '\u{1E0F}'), ('e', '\u{300}', '\u{E8}'), ('e', '\u{301}', '\u{E9}'),
+    ('e', '\u{302}', '\u{EA}'), ('e', '\u{303}', '\u{1EBD}'), ('e', '\u{304}', '\u{113}'), ('e', '\u{306}', '\u{115}'),
+    ('e', '\u{307}', '\u{117}'), ('e', '\u{308}', '\u{EB}'), ('e', '\u{309}', '\u{1EBB}'), ('e', '\u{30C}', '\u{11B}'),
+    ('e', '\u{30F}', '\u{205}'), ('e', '\u{311}', '\u{207}'), ('e', '\u{323}', '\u{1EB9}'), ('e', '\u{327}', '\u{229}'),
+    ('e', '\u{328}', '\u{119}'), ('e', '\u{32D}', '\u{1E19}'), ('e', '\u{330}', '\u{1E1B}'), ('f', '\u{307}', '\u{1E1F}'),
+    ('g', '\u{301}', '\u{1F5}'), ('g', '\u{302}', '\u{11D}'), ('g', '\u{304}', '\u{1E21}'), ('g', '\u{306}', '\u{11F}'),
+    ('g', '\u{307}', '\u{121}'), ('g', '\u{30C}', '\u{1E7}'), ('g', '\u{327}', '\u{123}'), ('h', '\u{302}', '\u{125}'),
+    ('h', '\u{307}', '\u{1E23}'), ('h', '\u{308}', '\u{1E27}'), ('h', '\u{30C}', '\u{21F}'), ('h', '\u{323}', '\u{1E25}'),
+    ('h', '\u{327}', '\u{1E29}'), ('h', '\u{32E}', '\u{1E2B}'), ('h', '\u{331}', '\u{1E96}'), ('i', '\u{300}', '\u{EC}'),
+    ('i', '\u{301}', '\u{ED}'), ('i', '\u{302}', '\u{EE}'), ('i', '\u{303}', '\u{129}'), ('i', '\u{304}', '\u{12B}'),
+    ('i', '\u{306}', '\u{12D}'), ('i', '\u{308}', '\u{EF}'), ('i', '\u{309}', '\u{1EC9}'), ('i', '\u{30C}', '\u{1D0}'),
+    ('i', '\u{30F}', '\u{209}'), ('i', '\u{311}', '\u{20B}'), ('i', '\u{323}', '\u{1ECB}'), ('i', '\u{328}', '\u{12F}'),
+    ('i', '\u{330}', '\u{1E2D}'), ('j', '\u{302}', '\u{135}'), ('j', '\u{30C}', '\u{1F0}'), ('k', '\u{301}', '\u{1E31}'),
+    ('k', '\u{30C}', '\u{1E9}'), ('k', '\u{323}', '\u{1E33}'), ('k', '\u{327}', '\u{137}'), ('k', '\u{331}', '\u{1E35}'),
+    ('l', '\u{301}', '\u{13A}'), ('l', '\u{30C}', '\u{13E}'), ('l', '\u{323}', '\u{1E37}'), ('l', '\u{327}', '\u{13C}'),
+    ('l', '\u{32D}', '\u{1E3D}'), ('l', '\u{331}', '\u{1E3B}'), ('m', '\u{301}', '\u{1E3F}'), ('m', '\u{307}', '\u{1E41}'),
+    ('m', '\u{323}', '\u{1E43}'), ('n', '\u{300}', '\u{1F9}'), ('n', '\u{301}', '\u{144}'), ('n', '\u{303}', '\u{F1}'),
+    ('n', '\u{307}', '\u{1E45}'), ('n', '\u{30C}', '\u{148}'), ('n', '\u{323}', '\u{1E47}'), ('n', '\u{327}', '\u{146}'),
+    ('n', '\u{32D}', '\u{1E4B}'), ('n', '\u{331}', '\u{1E49}'), ('o', '\u{300}', '\u{F2}'), ('o', '\u{301}', '\u{F3}'),
+    ('o', '\u{302}', '\u{F4}'), ('o', '\u{303}', '\u{F5}'), ('o', '\u{304}', '\u{14D}'), ('o', '\u{306}', '\u{14F}'),
+    ('o', '\u{307}', '\u{22F}'), ('o', '\u{308}', '\u{F6}'), ('o', '\u{309}', '\u{1ECF}'), ('o', '\u{30B}', '\u{151}'),
+    ('o', '\u{30C}', '\u{1D2}'), ('o', '\u{30F}', '\u{20D}'), ('o', '\u{311}', '\u{20F}'), ('o', '\u{31B}', '\u{1A1}'),
+    ('o', '\u{323}', '\u{1ECD}'), ('o', '\u{328}', '\u{1EB}'), ('p', '\u{301}', '\u{1E55}'), ('p', '\u{307}', '\u{1E57}'),
+    ('r', '\u{301}', '\u{155}'), ('r', '\u{307}', '\u{1E59}'), ('r', '\u{30C}', '\u{159}'), ('r', '\u{30F}', '\u{211}'),
+    ('r', '\u{311}', '\u{213}'), ('r', '\u{323}', '\u{1E5B}'), ('r', '\u{327}', '\u{157}'), ('r', '\u{331}', '\u{1E5F}'),
+    ('s', '\u{301}', '\u{15B}'), ('s', '\u{302}', '\u{15D}'), ('s', '\u{307}', '\u{1E61}'), ('s', '\u{30C}', '\u{161}'),
+    ('s', '\u{323}', '\u{1E63}'), ('s', '\u{326}', '\u{219}'), ('s', '\u{327}', '\u{15F}'), ('t', '\u{307}', '\u{1E6B}'),
+    ('t', '\u{308}', '\u{1E97}'), ('t', '\u{30C}', '\u{165}'), ('t', '\u{323}', '\u{1E6D}'), ('t', '\u{326}', '\u{21B}'),
+    ('t', '\u{327}', '\u{163}'), ('t', '\u{32D}', '\u{1E71}'), ('t', '\u{331}', '\u{1E6F}'), ('u', '\u{300}', '\u{F9}'),
+    ('u', '\u{301}', '\u{FA}'), ('u', '\u{302}', '\u{FB}'), ('u', '\u{303}', '\u{169}'), ('u', '\u{304}', '\u{16B}'),
+    ('u', '\u{306}', '\u{16D}'), ('u', '\u{308}', '\u{FC}'), ('u', '\u{309}', '\u{1EE7}'), ('u', '\u{30A}', '\u{16F}'),
+    ('u', '\u{30B}', '\u{171}'), ('u', '\u{30C}', '\u{1D4}'), ('u', '\u{30F}', '\u{215}'), ('u', '\u{311}', '\u{217}'),
+    ('u', '\u{31B}', '\u{1B0}'), ('u', '\u{323}', '\u{1EE5}'), ('u', '\u{324}', '\u{1E73}'), ('u', '\u{328}', '\u{173}'),
+    ('u', '\u{32D}', '\u{1E77}'), ('u', '\u{330}', '\u{1E75}'), ('v', '\u{303}', '\u{1E7D}'), ('v', '\u{323}', '\u{1E7F}'),
+    ('w', '\u{300}', '\u{1E81}'), ('w', '\u{301}', '\u{1E83}'), ('w', '\u{302}', '\u{175}'), ('w', '\u{307}', '\u{1E87}'),
+    ('w', '\u{308}', '\u{1E85}'), ('w', '\u{30A}', '\u{1E98}'), ('w', '\u{323}', '\u{1E89}'), ('x', '\u{307}', '\u{1E8B}'),
+    ('x', '\u{308}', '\u{1E8D}'), ('y', '\u{300}', '\u{1EF3}'), ('y', '\u{301}', '\u{FD}'), ('y', '\u{302}', '\u{177}'),
+    ('y', '\u{303}', '\u{1EF9}'), ('y', '\u{304}', '\u{233}'), ('y', '\u{307}', '\u{1E8F}'), ('y', '\u{308}', '\u{FF}'),
+    ('y', '\u{309}', '\u{1EF7}'), ('y', '\u{30A}', '\u{1E99}'), ('y', '\u{323}', '\u{1EF5}'), ('z', '\u{301}', '\u{17A}'),
+    ('z', '\u{302}', '\u{1E91}'), ('z', '\u{307}', '\u{17C}'), ('z', '\u{30C}', '\u{17E}'), ('z', '\u{323}', '\u{1E93}'),
+    ('z', '\u{331}', '\u{1E95}'), ('\u{A8}', '\u{300}', '\u{1FED}'), ('\u{A8}', '\u{301}', '\u{385}'), ('\u{A8}', '\u{342}', '\u{1FC1}'),
+    ('\u{C2}', '\u{300}', '\u{1EA6}'), ('\u{C2}', '\u{301}', '\u{1EA4}'), ('\u{C2}', '\u{303}', '\u{1EAA}'), ('\u{C2}', '\u{309}', '\u{1EA8}'),
+    ('\u{C4}', '\u{304}', '\u{1DE}'), ('\u{C5}', '\u{301}', '\u{1FA}'), ('\u{C6}', '\u{301}', '\u{1FC}'), ('\u{C6}', '\u{304}', '\u{1E2}'),
+    ('\u{C7}', '\u{301}', '\u{1E08}'), ('\u{CA}', '\u{300}', '\u{1EC0}'), ('\u{CA}', '\u{301}', '\u{1EBE}'), ('\u{CA}', '\u{303}', '\u{1EC4}'),
+    ('\u{CA}', '\u{309}', '\u{1EC2}'), ('\u{CF}', '\u{301}', '\u{1E2E}'), ('\u{D4}', '\u{300}', '\u{1ED2}'), ('\u{D4}', '\u{301}', '\u{1ED0}'),
+    ('\u{D4}', '\u{303}', '\u{1ED6}'), ('\u{D4}', '\u{309}', '\u{1ED4}'), ('\u{D5}', '\u{301}', '\u{1E4C}'), ('\u{D5}', '\u{304}', '\u{22C}'),
+    ('\u{D5}', '\u{308}', '\u{1E4E}'), ('\u{D6}', '\u{304}', '\u{22A}'), ('\u{D8}', '\u{301}', '\u{1FE}'), ('\u{DC}', '\u{300}', '\u{1DB}'),
+    ('\u{DC}', '\u{301}', '\u{1D7}'), ('\u{DC}', '\u{304}', '\u{1D5}'), ('\u{DC}', '\u{30C}', '\u{1D9}'), ('\u{E2}', '\u{300}', '\u{1EA7}'),
+    ('\u{E2}', '\u{301}', '\u{1EA5}'), ('\u{E2}', '\u{303}', '\u{1EAB}'), ('\u{E2}', '\u{309}', '\u{1EA9}'), ('\u{E4}', '\u{304}', '\u{1DF}'),
+    ('\u{E5}', '\u{301}', '\u{1FB}'), ('\u{E6}', '\u{301}', '\u{1FD}'), ('\u{E6}', '\u{304}', '\u{1E3}'), ('\u{E7}', '\u{301}', '\u{1E09}'),
+    ('\u{EA}', '\u{300}', '\u{1EC1}'), ('\u{EA}', '\u{301}', '\u{1EBF}'), ('\u{EA}', '\u{303}', '\u{1EC5}'), ('\u{EA}', '\u{309}', '\u{1EC3}'),
+    ('\u{EF}', '\u{301}', '\u{1E2F}'), ('\u{F4}', '\u{300}', '\u{1ED3}'), ('\u{F4}', '\u{301}', '\u{1ED1}'), ('\u{F4}', '\u{303}', '\u{1ED7}'),
+    ('\u{F4}', '\u{309}', '\u{1ED5}'), ('\u{F5}', '\u{301}', '\u{1E4D}'), ('\u{F5}', '\u{304}', '\u{22D}'), ('\u{F5}', '\u{308}', '\u{1E4F}'),
+    ('\u{F6}', '\u{304}', '\u{22B}'), ('\u{F8}', '\u{301}', '\u{1FF}'), ('\u{FC}', '\u{300}', '\u{1DC}'), ('\u{FC}', '\u{301}', '\u{1D8}'),
+    ('\u{FC}', '\u{304}', '\u{1D6}'), ('\u{FC}', '\u{30C}', '\u{1DA}'), ('\u{102}', '\u{300}', '\u{1EB0}'), ('\u{102}', '\u{301}', '\u{1EAE}'),
+    ('\u{102}', '\u{303}', '\u{1EB4}'), ('\u{102}', '\u{309}', '\u{1EB2}'), ('\u{103}', '\u{300}', '\u{1EB1}'), ('\u{103}', '\u{301}', '\u{1EAF}'),
+    ('\u{103}', '\u{303}', '\u{1EB5}'), ('\u{103}', '\u{309}', '\u{1EB3}'), ('\u{112}', '\u{300}', '\u{1E14}'), ('\u{112}', '\u{301}', '\u{1E16}'),
+    ('\u{113}', '\u{300}', '\u{1E15}'), ('\u{113}', '\u{301}', '\u{1E17}'), ('\u{14C}', '\u{300}', '\u{1E50}'), ('\u{14C}', '\u{301}', '\u{1E52}'),
+    ('\u{14D}', '\u{300}', '\u{1E51}'), ('\u{14D}', '\u{301}', '\u{1E53}'), ('\u{15A}', '\u{307}', '\u{1E64}'), ('\u{15B}', '\u{307}', '\u{1E65}'),
+    ('\u{160}', '\u{307}', '\u{1E66}'), ('\u{161}', '\u{307}', '\u{1E67}'), ('\u{168}', '\u{301}', '\u{1E78}'), ('\u{169}', '\u{301}', '\u{1E79}'),
+    ('\u{16A}', '\u{308}', '\u{1E7A}'), ('\u{16B}', '\u{308}', '\u{1E7B}'), ('\u{17F}', '\u{307}', '\u{1E9B}'), ('\u{1A0}', '\u{300}', '\u{1EDC}'),
+    ('\u{1A0}', '\u{301}', '\u{1EDA}'), ('\u{1A0}', '\u{303}', '\u{1EE0}'), ('\u{1A0}', '\u{309}', '\u{1EDE}'), ('\u{1A0}', '\u{323}', '\u{1EE2}'),
+    ('\u{1A1}', '\u{300}', '\u{1EDD}'), ('\u{1A1}', '\u{301}', '\u{1EDB}'), ('\u{1A1}', '\u{303}', '\u{1EE1}'), ('\u{1A1}', '\u{309}', '\u{1EDF}'),
+    ('\u{1A1}', '\u{323}', '\u{1EE3}'), ('\u{1AF}', '\u{300}', '\u{1EEA}'), ('\u{1AF}', '\u{301}', '\u{1EE8}'), ('\u{1AF}', '\u{303}', '\u{1EEE}'),
+    ('\u{1AF}', '\u{309}', '\u{1EEC}'), ('\u{1AF}', '\u{323}', '\u{1EF0}'), ('\u{1B0}', '\u{300}', '\u{1EEB}'), ('\u{1B0}', '\u{301}', '\u{1EE9}'),
+    ('\u{1B0}', '\u{303}', '\u{1EEF}'), ('\u{1B0}', '\u{309}', '\u{1EED}'), ('\u{1B0}', '\u{323}', '\u{1EF1}'), ('\u{1B7}', '\u{30C}', '\u{1EE}'),
+    ('\u{1EA}', '\u{304}', '\u{1EC}'), ('\u{1EB}', '\u{304}', '\u{1ED}'), ('\u{226}', '\u{304}', '\u{1E0}'), ('\u{227}', '\u{304}', '\u{1E1}'),
+    ('\u{228}', '\u{306}', '\u{1E1C}'), ('\u{229}', '\u{306}', '\u{1E1D}'), ('\u{22E}', '\u{304}', '\u{230}'), ('\u{22F}', '\u{304}', '\u{231}'),
+    ('\u{292}', '\u{30C}', '\u{1EF}'), ('\u{391}', '\u{300}', '\u{1FBA}'), ('\u{391}', '\u{301}', '\u{386}'), ('\u{391}', '\u{304}', '\u{1FB9}'),
+    ('\u{391}', '\u{306}', '\u{1FB8}'), ('\u{391}', '\u{313}', '\u{1F08}'), ('\u{391}', '\u{314}', '\u{1F09}'), ('\u{391}', '\u{345}', '\u{1FBC}'),
+    ('\u{395}', '\u{300}', '\u{1FC8}'), ('\u{395}', '\u{301}', '\u{388}'), ('\u{395}', '\u{313}', '\u{1F18}'), ('\u{395}', '\u{314}', '\u{1F19}'),
+    ('\u{397}', '\u{300}', '\u{1FCA}'), ('\u{397}', '\u{301}', '\u{389}'), ('\u{397}', '\u{313}', '\u{1F28}'), ('\u{397}', '\u{314}', '\u{1F29}'),
+    ('\u{397}', '\u{345}', '\u{1FCC}'), ('\u{399}', '\u{300}', '\u{1FDA}'), ('\u{399}', '\u{301}', '\u{38A}'), ('\u{399}', '\u{304}', '\u{1FD9}'),
+    ('\u{399}', '\u{306}', '\u{1FD8}'), ('\u{399}', '\u{308}', '\u{3AA}'), ('\u{399}', '\u{313}', '\u{1F38}'), ('\u{399}', '\u{314}', '\u{1F39}'),
+    ('\u{39F}', '\u{300}', '\u{1FF8}'), ('\u{39F}', '\u{301}', '\u{38C}'), ('\u{39F}', '\u{313}', '\u{1F48}'), ('\u{39F}', '\u{314}', '\u{1F49}'),
+    ('\u{3A1}', '\u{314}', '\u{1FEC}'), ('\u{3A5}', '\u{300}', '\u{1FEA}'), ('\u{3A5}', '\u{301}', '\u{38E}'), ('\u{3A5}', '\u{304}', '\u{1FE9}'),
+    ('\u{3A5}', '\u{306}', '\u{1FE8}'), ('\u{3A5}', '\u{308}', '\u{3AB}'), ('\u{3A5}', '\u{314}', '\u{1F59}'), ('\u{3A9}', '\u{300}', '\u{1FFA}'),
+    ('\u{3A9}', '\u{301}', '\u{38F}'), ('\u{3A9}', '\u{313}', '\u{1F68}'), ('\u{3A9}', '\u{314}', '\u{1F69}'), ('\u{3A9}', '\u{345}', '\u{1FFC}'),
+    ('\u{3AC}', '\u{345}', '\u{1FB4}'), ('\u{3AE}', '\u{345}', '\u{1FC4}'), ('\u{3B1}', '\u{300}', '\u{1F70}'), ('\u{3B1}', '\u{301}', '\u{3AC}'),
+    ('\u{3B1}', '\u{304}', '\u{1FB1}'), ('\u{3B1}', '\u{306}', '\u{1FB0}'), ('\u{3B1}', '\u{313}', '\u{1F00}'), ('\u{3B1}', '\u{314}', '\u{1F01}'),
+    ('\u{3B1}', '\u{342}', '\u{1FB6}'), ('\u{3B1}', '\u{345}', '\u{1FB3}'), ('\u{3B5}', '\u{300}', '\u{1F72}'), ('\u{3B5}', '\u{301}', '\u{3AD}'),
+    ('\u{3B5}', '\u{313}', '\u{1F10}'), ('\u{3B5}', '\u{314}', '\u{1F11}'), ('\u{3B7}', '\u{300}', '\u{1F74}'), ('\u{3B7}', '\u{301}', '\u{3AE}'),
+    ('\u{3B7}', '\u{313}', '\u{1F20}'), ('\u{3B7}', '\u{314}', '\u{1F21}'), ('\u{3B7}', '\u{342}', '\u{1FC6}'), ('\u{3B7}', '\u{345}', '\u{1FC3}'),
+    ('\u{3B9}', '\u{300}', '\u{1F76}'), ('\u{3B9}', '\u{301}', '\u{3AF}'), ('\u{3B9}', '\u{304}', '\u{1FD1}'), ('\u{3B9}', '\u{306}', '\u{1FD0}'),
+    ('\u{3B9}', '\u{308}', '\u{3CA}'), ('\u{3B9}', '\u{313}', '\u{1F30}'), ('\u{3B9}', '\u{314}', '\u{1F31}'), ('\u{3B9}', '\u{342}', '\u{1FD6}'),
+    ('\u{3BF}', '\u{300}', '\u{1F78}'), ('\u{3BF}', '\u{301}', '\u{3CC}'), ('\u{3BF}', '\u{313}', '\u{1F40}'), ('\u{3BF}', '\u{314}', '\u{1F41}'),
+    ('\u{3C1}', '\u{313}', '\u{1FE4}'), ('\u{3C1}', '\u{314}', '\u{1FE5}'), ('\u{3C5}', '\u{300}', '\u{1F7A}'), ('\u{3C5}', '\u{301}', '\u{3CD}'),
+    ('\u{3C5}', '\u{304}', '\u{1FE1}'), ('\u{3C5}', '\u{306}', '\u{1FE0}'), ('\u{3C5}', '\u{308}', '\u{3CB}'), ('\u{3C5}', '\u{313}', '\u{1F50}'),
+    ('\u{3C5}', '\u{314}', '\u{1F51}'), ('\u{3C5}', '\u{342}', '\u{1FE6}'), ('\u{3C9}', '\u{300}', '\u{1F7C}'), ('\u{3C9}', '\u{301}', '\u{3CE}'),
+    ('\u{3C9}', '\u{313}', '\u{1F60}'), ('\u{3C9}', '\u{314}', '\u{1F61}'), ('\u{3C9}', '\u{342}', '\u{1FF6}'), ('\u{3C9}', '\u{345}', '\u{1FF3}'),
+    ('\u{3CA}', '\u{300}', '\u{1FD2}'), ('\u{3CA}', '\u{301}', '\u{390}'), ('\u{3CA}', '\u{342}', '\u{1FD7}'), ('\u{3CB}', '\u{300}', '\u{1FE2}'),
+    ('\u{3CB}', '\u{301}', '\u{3B0}'), ('\u{3CB}', '\u{342}', '\u{1FE7}'), ('\u{3CE}', '\u{345}', '\u{1FF4}'), ('\u{3D2}', '\u{301}', '\u{3D3}'),
+    ('\u{3D2}', '\u{308}', '\u{3D4}'), ('\u{406}', '\u{308}', '\u{407}'), ('\u{410}', '\u{306}', '\u{4D0}'), ('\u{410}', '\u{308}', '\u{4D2}'),
+    ('\u{413}', '\u{301}', '\u{403}'), ('\u{415}', '\u{300}', '\u{400}'), ('\u{415}', '\u{306}', '\u{4D6}'), ('\u{415}', '\u{308}', '\u{401}'),
+    ('\u{416}', '\u{306}', '\u{4C1}'), ('\u{416}', '\u{308}', '\u{4DC}'), ('\u{417}', '\u{308}', '\u{4DE}'), ('\u{418}', '\u{300}', '\u{40D}'),
+    ('\u{418}', '\u{304}', '\u{4E2}'), ('\u{418}', '\u{306}', '\u{419}'), ('\u{418}', '\u{308}', '\u{4E4}'), ('\u{41A}', '\u{301}', '\u{40C}'),
+    ('\u{41E}', '\u{308}', '\u{4E6}'), ('\u{423}', '\u{304}', '\u{4EE}'), ('\u{423}', '\u{306}', '\u{40E}'), ('\u{423}', '\u{308}', '\u{4F0}'),
+    ('\u{423}', '\u{30B}', '\u{4F2}'), ('\u{427}', '\u{308}', '\u{4F4}'), ('\u{42B}', '\u{308}', '\u{4F8}'), ('\u{42D}', '\u{308}', '\u{4EC}'),
+    ('\u{430}', '\u{306}', '\u{4D1}'), ('\u{430}', '\u{308}', '\u{4D3}'), ('\u{433}', '\u{301}', '\u{453}'), ('\u{435}', '\u{300}', '\u{450}'),
+    ('\u{435}', '\u{306}', '\u{4D7}'), ('\u{435}', '\u{308}', '\u{451}'), ('\u{436}', '\u{306}', '\u{4C2}'), ('\u{436}', '\u{308}', '\u{4DD}'),
+    ('\u{437}', '\u{308}', '\u{4DF}'), ('\u{438}', '\u{300}', '\u{45D}'), ('\u{438}', '\u{304}', '\u{4E3}'), ('\u{438}', '\u{306}', '\u{439}'),
+    ('\u{438}', '\u{308}', '\u{4E5}'), ('\u{43A}', '\u{301}', '\u{45C}'), ('\u{43E}', '\u{308}', '\u{4E7}'), ('\u{443}', '\u{304}', '\u{4EF}'),
+    ('\u{443}', '\u{306}', '\u{45E}'), ('\u{443}', '\u{308}', '\u{4F1}'), ('\u{443}', '\u{30B}', '\u{4F3}'), ('\u{447}', '\u{308}', '\u{4F5}'),
+    ('\u{44B}', '\u{308}', '\u{4F9}'), ('\u{44D}', '\u{308}', '\u{4ED}'), ('\u{456}', '\u{308}', '\u{457}'), ('\u{474}', '\u{30F}', '\u{476}'),
+    ('\u{475}', '\u{30F}', '\u{477}'), ('\u{4D8}', '\u{308}', '\u{4DA}'), ('\u{4D9}', '\u{308}', '\u{4DB}'), ('\u{4E8}', '\u{308}', '\u{4EA}'),
+    ('\u{4E9}', '\u{308}', '\u{4EB}'), ('\u{627}', '\u{653}', '\u{622}'), ('\u{627}', '\u{654}', '\u{623}'), ('\u{627}', '\u{655}', '\u{625}'),
+    ('\u{648}', '\u{654}', '\u{624}'), ('\u{64A}', '\u{654}', '\u{626}'), ('\u{6C1}', '\u{654}', '\u{6C2}'), ('\u{6D2}', '\u{654}', '\u{6D3}'),
+    ('\u{6D5}', '\u{654}', '\u{6C0}'), ('\u{928}', '\u{93C}', '\u{929}'), ('\u{930}', '\u{93C}', '\u{931}'), ('\u{933}', '\u{93C}', '\u{934}'),
+    ('\u{9C7}', '\u{9BE}', '\u{9CB}'), ('\u{9C7}', '\u{9D7}', '\u{9CC}'), ('\u{B47}', '\u{B3E}', '\u{B4B}'), ('\u{B47}', '\u{B56}', '\u{B48}'),
+    ('\u{B47}', '\u{B57}', '\u{B4C}'), ('\u{B92}', '\u{BD7}', '\u{B94}'), ('\u{BC6}', '\u{BBE}', '\u{BCA}'), ('\u{BC6}', '\u{BD7}', '\u{BCC}'),
+    ('\u{BC7}', '\u{BBE}', '\u{BCB}'), ('\u{C46}', '\u{C56}', '\u{C48}'), ('\u{CBF}', '\u{CD5}', '\u{CC0}'), ('\u{CC6}', '\u{CC2}', '\u{CCA}'),
+    ('\u{CC6}', '\u{CD5}', '\u{CC7}'), ('\u{CC6}', '\u{CD6}', '\u{CC8}'), ('\u{CCA}', '\u{CD5}', '\u{CCB}'), ('\u{D46}', '\u{D3E}', '\u{D4A}'),
+    ('\u{D46}', '\u{D57}', '\u{D4C}'), ('\u{D47}', '\u{D3E}', '\u{D4B}'), ('\u{DD9}', '\u{DCA}', '\u{DDA}'), ('\u{DD9}', '\u{DCF}', '\u{DDC}'),
+    ('\u{DD9}', '\u{DDF}', '\u{DDE}'), ('\u{DDC}', '\u{DCA}', '\u{DDD}'), ('\u{1025}', '\u{102E}', '\u{1026}'), ('\u{1B05}', '\u{1B35}', '\u{1B06}'),
+    ('\u{1B07}', '\u{1B35}', '\u{1B08}'), ('\u{1B09}', '\u{1B35}', '\u{1B0A}'), ('\u{1B0B}', '\u{1B35}', '\u{1B0C}'), ('\u{1B0D}', '\u{1B35}', '\u{1B0E}'),
+    ('\u{1B11}', '\u{1B35}', '\u{1B12}'), ('\u{1B3A}', '\u{1B35}', '\u{1B3B}'), ('\u{1B3C}', '\u{1B35}', '\u{1B3D}'), ('\u{1B3E}', '\u{1B35}', '\u{1B40}'),
+    ('\u{1B3F}', '\u{1B35}', '\u{1B41}'), ('\u{1B42}', '\u{1B35}', '\u{1B43}'), ('\u{1E36}', '\u{304}', '\u{1E38}'), ('\u{1E37}', '\u{304}', '\u{1E39}'),
+    ('\u{1E5A}', '\u{304}', '\u{1E5C}'), ('\u{1E5B}', '\u{304}', '\u{1E5D}'), ('\u{1E62}', '\u{307}', '\u{1E68}'), ('\u{1E63}', '\u{307}', '\u{1E69}'),
+    ('\u{1EA0}', '\u{302}', '\u{1EAC}'), ('\u{1EA0}', '\u{306}', '\u{1EB6}'), ('\u{1EA1}', '\u{302}', '\u{1EAD}'), ('\u{1EA1}', '\u{306}', '\u{1EB7}'),
+    ('\u{1EB8}', '\u{302}', '\u{1EC6}'), ('\u{1EB9}', '\u{302}', '\u{1EC7}'), ('\u{1ECC}', '\u{302}', '\u{1ED8}'), ('\u{1ECD}', '\u{302}', '\u{1ED9}'),
+    ('\u{1F00}', '\u{300}', '\u{1F02}'), ('\u{1F00}', '\u{301}', '\u{1F04}'), ('\u{1F00}', '\u{342}', '\u{1F06}'), ('\u{1F00}', '\u{345}', '\u{1F80}'),
+    ('\u{1F01}', '\u{300}', '\u{1F03}'), ('\u{1F01}', '\u{301}', '\u{1F05}'), ('\u{1F01}', '\u{342}', '\u{1F07}'), ('\u{1F01}', '\u{345}', '\u{1F81}'),
+    ('\u{1F02}', '\u{345}', '\u{1F82}'), ('\u{1F03}', '\u{345}', '\u{1F83}'), ('\u{1F04}', '\u{345}', '\u{1F84}'), ('\u{1F05}', '\u{345}', '\u{1F85}'),
+    ('\u{1F06}', '\u{345}', '\u{1F86}'), ('\u{1F07}', '\u{345}', '\u{1F87}'), ('\u{1F08}', '\u{300}', '\u{1F0A}'), ('\u{1F08}', '\u{301}', '\u{1F0C}'),
+    ('\u{1F08}', '\u{342}', '\u{1F0E}'), ('\u{1F08}', '\u{345}', '\u{1F88}'), ('\u{1F09}', '\u{300}', '\u{1F0B}'), ('\u{1F09}', '\u{301}', '\u{1F0D}'),
+    ('\u{1F09}', '\u{342}', '\u{1F0F}'), ('\u{1F09}', '\u{345}', '\u{1F89}'), ('\u{1F0A}', '\u{345}', '\u{1F8A}'), ('\u{1F0B}', '\u{345}', '\u{1F8B}'),
+    ('\u{1F0C}', '\u{345}', '\u{1F8C}'), ('\u{1F0D}', '\u{345}', '\u{1F8D}'), ('\u{1F0E}', '\u{345}', '\u{1F8E}'), ('\u{1F0F}', '\u{345}', '\u{1F8F}'),
+    ('\u{1F10}', '\u{300}', '\u{1F12}'), ('\u{1F10}', '\u{301}', '\u{1F14}'), ('\u{1F11}', '\u{300}', '\u{1F13}'), ('\u{1F11}', '\u{301}', '\u{1F15}'),
+    ('\u{1F18}', '\u{300}', '\u{1F1A}'), ('\u{1F18}', '\u{301}', '\u{1F1C}'), ('\u{1F19}', '\u{300}', '\u{1F1B}'), ('\u{1F19}', '\u{301}', '\u{1F1D}'),
+    ('\u{1F20}', '\u{300}', '\u{1F22}'), ('\u{1F20}', '\u{301}', '\u{1F24}'), ('\u{1F20}', '\u{342}', '\u{1F26}'), ('\u{1F20}', '\u{345}', '\u{1F90}'),
+    ('\u{1F21}', '\u{300}', '\u{1F23}'), ('\u{1F21}', '\u{301}', '\u{1F25}'), ('\u{1F21}', '\u{342}', '\u{1F27}'), ('\u{1F21}', '\u{345}', '\u{1F91}'),
+    ('\u{1F22}', '\u{345}', '\u{1F92}'), ('\u{1F23}', '\u{345}', '\u{1F93}'), ('\u{1F24}', '\u{345}', '\u{1F94}'), ('\u{1F25}', '\u{345}', '\u{1F95}'),
+    ('\u{1F26}', '\u{345}', '\u{1F96}'), ('\u{1F27}', '\u{345}', '\u{1F97}'), ('\u{1F28}', '\u{300}', '\u{1F2A}'), ('\u{1F28}', '\u{301}', '\u{1F2C}'),
+    ('\u{1F28}', '\u{342}', '\u{1F2E}'), ('\u{1F28}', '\u{345}', '\u{1F98}'), ('\u{1F29}', '\u{300}', '\u{1F2B}'), ('\u{1F29}', '\u{301}', '\u{1F2D}'),
+    ('\u{1F29}', '\u{342}', '\u{1F2F}'), ('\u{1F29}', '\u{345}', '\u{1F99}'), ('\u{1F2A}', '\u{345}', '\u{1F9A}'), ('\u{1F2B}', '\u{345}', '\u{1F9B}'),
+    ('\u{1F2C}', '\u{345}', '\u{1F9C}'), ('\u{1F2D}', '\u{345}', '\u{1F9D}'), ('\u{1F2E}', '\u{345}', '\u{1F9E}'), ('\u{1F2F}', '\u{345}', '\u{1F9F}'),
+    ('\u{1F30}', '\u{300}', '\u{1F32}'), ('\u{1F30}', '\u{301}', '\u{1F34}'), ('\u{1F30}', '\u{342}', '\u{1F36}'), ('\u{1F31}', '\u{300}', '\u{1F33}'),
+    ('\u{1F31}', '\u{301}', '\u{1F35}'), ('\u{1F31}', '\u{342}', '\u{1F37}'), ('\u{1F38}', '\u{300}', '\u{1F3A}'), ('\u{1F38}', '\u{301}', '\u{1F3C}'),
+    ('\u{1F38}', '\u{342}', '\u{1F3E}'), ('\u{1F39}', '\u{300}', '\u{1F3B}'), ('\u{1F39}', '\u{301}', '\u{1F3D}'), ('\u{1F39}', '\u{342}', '\u{1F3F}'),
+    ('\u{1F40}', '\u{300}', '\u{1F42}'), ('\u{1F40}', '\u{301}', '\u{1F44}'), ('\u{1F41}', '\u{300}', '\u{1F43}'), ('\u{1F41}', '\u{301}', '\u{1F45}'),
+    ('\u{1F48}', '\u{300}', '\u{1F4A}'), ('\u{1F48}', '\u{301}', '\u{1F4C}'), ('\u{1F49}', '\u{300}', '\u{1F4B}'), ('\u{1F49}', '\u{301}', '\u{1F4D}'),
+    ('\u{1F50}', '\u{300}', '\u{1F52}'), ('\u{1F50}', '\u{301}', '\u{1F54}'), ('\u{1F50}', '\u{342}', '\u{1F56}'), ('\u{1F51}', '\u{300}', '\u{1F53}'),
+    ('\u{1F51}', '\u{301}', '\u{1F55}'), ('\u{1F51}', '\u{342}', '\u{1F57}'), ('\u{1F59}', '\u{300}', '\u{1F5B}'), ('\u{1F59}', '\u{301}', '\u{1F5D}'),
+    ('\u{1F59}', '\u{342}', '\u{1F5F}'), ('\u{1F60}', '\u{300}', '\u{1F62}'), ('\u{1F60}', '\u{301}', '\u{1F64}'), ('\u{1F60}', '\u{342}', '\u{1F66}'),
+    ('\u{1F60}', '\u{345}', '\u{1FA0}'), ('\u{1F61}', '\u{300}', '\u{1F63}'), ('\u{1F61}', '\u{301}', '\u{1F65}'), ('\u{1F61}', '\u{342}', '\u{1F67}'),
+    ('\u{1F61}', '\u{345}', '\u{1FA1}'), ('\u{1F62}', '\u{345}', '\u{1FA2}'), ('\u{1F63}', '\u{345}', '\u{1FA3}'), ('\u{1F64}', '\u{345}', '\u{1FA4}'),
+    ('\u{1F65}', '\u{345}', '\u{1FA5}'), ('\u{1F66}', '\u{345}', '\u{1FA6}'), ('\u{1F67}', '\u{345}', '\u{1FA7}'), ('\u{1F68}', '\u{300}', '\u{1F6A}'),
+    ('\u{1F68}', '\u{301}', '\u{1F6C}'), ('\u{1F68}', '\u{342}', '\u{1F6E}'), ('\u{1F68}', '\u{345}', '\u{1FA8}'), ('\u{1F69}', '\u{300}', '\u{1F6B}'),
+    ('\u{1F69}', '\u{301}', '\u{1F6D}'), ('\u{1F69}', '\u{342}', '\u{1F6F}'), ('\u{1F69}', '\u{345}', '\u{1FA9}'), ('\u{1F6A}', '\u{345}', '\u{1FAA}'),
+    ('\u{1F6B}', '\u{345}', '\u{1FAB}'), ('\u{1F6C}', '\u{345}', '\u{1FAC}'), ('\u{1F6D}', '\u{345}', '\u{1FAD}'), ('\u{1F6E}', '\u{345}', '\u{1FAE}'),
+    ('\u{1F6F}', '\u{345}', '\u{1FAF}'), ('\u{1F70}', '\u{345}', '\u{1FB2}'), ('\u{1F74}', '\u{345}', '\u{1FC2}'), ('\u{1F7C}', '\u{345}', '\u{1FF2}'),
+    ('\u{1FB6}', '\u{345}', '\u{1FB7}'), ('\u{1FBF}', '\u{300}', '\u{1FCD}'), ('\u{1FBF}', '\u{301}', '\u{1FCE}'), ('\u{1FBF}', '\u{342}', '\u{1FCF}'),
+    ('\u{1FC6}', '\u{345}', '\u{1FC7}'), ('\u{1FF6}', '\u{345}', '\u{1FF7}'), ('\u{1FFE}', '\u{300}', '\u{1FDD}'), ('\u{1FFE}', '\u{301}', '\u{1FDE}'),
+    ('\u{1FFE}', '\u{342}', '\u{1FDF}'), ('\u{2190}', '\u{338}', '\u{219A}'), ('\u{2192}', '\u{338}', '\u{219B}'), ('\u{2194}', '\u{338}', '\u{21AE}'),
+    ('\u{21D0}', '\u{338}', '\u{21CD}'), ('\u{21D2}', '\u{338}', '\u{21CF}'), ('\u{21D4}', '\u{338}', '\u{21CE}'), ('\u{2203}', '\u{338}', '\u{2204}'),
+    ('\u{2208}', '\u{338}', '\u{2209}'), ('\u{220B}', '\u{338}', '\u{220C}'), ('\u{2223}', '\u{338}', '\u{2224}'), ('\u{2225}', '\u{338}', '\u{2226}'),
+    ('\u{223C}', '\u{338}', '\u{2241}'), ('\u{2243}', '\u{338}', '\u{2244}'), ('\u{2245}', '\u{338}', '\u{2247}'), ('\u{2248}', '\u{338}', '\u{2249}'),
+    ('\u{224D}', '\u{338}', '\u{226D}'), ('\u{2261}', '\u{338}', '\u{2262}'), ('\u{2264}', '\u{338}', '\u{2270}'), ('\u{2265}', '\u{338}', '\u{2271}'),
+    ('\u{2272}', '\u{338}', '\u{2274}'), ('\u{2273}', '\u{338}', '\u{2275}'), ('\u{2276}', '\u{338}', '\u{2278}'), ('\u{2277}', '\u{338}', '\u{2279}'),
+    ('\u{227A}', '\u{338}', '\u{2280}'), ('\u{227B}', '\u{338}', '\u{2281}'), ('\u{227C}', '\u{338}', '\u{22E0}'), ('\u{227D}', '\u{338}', '\u{22E1}'),
+    ('\u{2282}', '\u{338}', '\u{2284}'), ('\u{2283}', '\u{338}', '\u{2285}'), ('\u{2286}', '\u{338}', '\u{2288}'), ('\u{2287}', '\u{338}', '\u{2289}'),
+    ('\u{2291}', '\u{338}', '\u{22E2}'), ('\u{2292}', '\u{338}', '\u{22E3}'), ('\u{22A2}', '\u{338}', '\u{22AC}'), ('\u{22A8}', '\u{338}', '\u{22AD}'),
+    ('\u{22A9}', '\u{338}', '\u{22AE}'), ('\u{22AB}', '\u{338}', '\u{22AF}'), ('\u{22B2}', '\u{338}', '\u{22EA}'), ('\u{22B3}', '\u{338}', '\u{22EB}'),
+    ('\u{22B4}', '\u{338}', '\u{22EC}'), ('\u{22B5}', '\u{338}', '\u{22ED}'), ('\u{3046}', '\u{3099}', '\u{3094}'), ('\u{304B}', '\u{3099}', '\u{304C}'),
+    ('\u{304D}', '\u{3099}', '\u{304E}'), ('\u{304F}', '\u{3099}', '\u{3050}'), ('\u{3051}', '\u{3099}', '\u{3052}'), ('\u{3053}', '\u{3099}', '\u{3054}'),
+    ('\u{3055}', '\u{3099}', '\u{3056}'), ('\u{3057}', '\u{3099}', '\u{3058}'), ('\u{3059}', '\u{3099}', '\u{305A}'), ('\u{305B}', '\u{3099}', '\u{305C}'),
+    ('\u{305D}', '\u{3099}', '\u{305E}'), ('\u{305F}', '\u{3099}', '\u{3060}'), ('\u{3061}', '\u{3099}', '\u{3062}'), ('\u{3064}', '\u{3099}', '\u{3065}'),
+    ('\u{3066}', '\u{3099}', '\u{3067}'), ('\u{3068}', '\u{3099}', '\u{3069}'), ('\u{306F}', '\u{3099}', '\u{3070}'), ('\u{306F}', '\u{309A}', '\u{3071}'),
+    ('\u{3072}', '\u{3099}', '\u{3073}'), ('\u{3072}', '\u{309A}', '\u{3074}'), ('\u{3075}', '\u{3099}', '\u{3076}'), ('\u{3075}', '\u{309A}', '\u{3077}'),
+    ('\u{3078}', '\u{3099}', '\u{3079}'), ('\u{3078}', '\u{309A}', '\u{307A}'), ('\u{307B}', '\u{3099}', '\u{307C}'), ('\u{307B}', '\u{309A}', '\u{307D}'),
+    ('\u{309D}', '\u{3099}', '\u{309E}'), ('\u{30A6}', '\u{3099}', '\u{30F4}'), ('\u{30AB}', '\u{3099}', '\u{30AC}'), ('\u{30AD}', '\u{3099}', '\u{30AE}'),
+    ('\u{30AF}', '\u{3099}', '\u{30B0}'), ('\u{30B1}', '\u{3099}', '\u{30B2}'), ('\u{30B3}', '\u{3099}', '\u{30B4}'), ('\u{30B5}', '\u{3099}', '\u{30B6}'),
+    ('\u{30B7}', '\u{3099}', '\u{30B8}'), ('\u{30B9}', '\u{3099}', '\u{30BA}'), ('\u{30BB}', '\u{3099}', '\u{30BC}'), ('\u{30BD}', '\u{3099}', '\u{30BE}'),
+    ('\u{30BF}', '\u{3099}', '\u{30C0}'), ('\u{30C1}', '\u{3099}', '\u{30C2}'), ('\u{30C4}', '\u{3099}', '\u{30C5}'), ('\u{30C6}', '\u{3099}', '\u{30C7}'),
+    ('\u{30C8}', '\u{3099}', '\u{30C9}'), ('\u{30CF}', '\u{3099}', '\u{30D0}'), ('\u{30CF}', '\u{309A}', '\u{30D1}'), ('\u{30D2}', '\u{3099}', '\u{30D3}'),
+    ('\u{30D2}', '\u{309A}', '\u{30D4}'), ('\u{30D5}', '\u{3099}', '\u{30D6}'), ('\u{30D5}', '\u{309A}', '\u{30D7}'), ('\u{30D8}', '\u{3099}', '\u{30D9}'),
+    ('\u{30D8}', '\u{309A}', '\u{30DA}'), ('\u{30DB}', '\u{3099}', '\u{30DC}'), ('\u{30DB}', '\u{309A}', '\u{30DD}'), ('\u{30EF}', '\u{3099}', '\u{30F7}'),
+    ('\u{30F0}', '\u{3099}', '\u{30F8}'), ('\u{30F1}', '\u{3099}', '\u{30F9}'), ('\u{30F2}', '\u{3099}', '\u{30FA}'), ('\u{30FD}', '\u{3099}', '\u{30FE}'),
+    ('\u{11099}', '\u{110BA}', '\u{1109A}'), ('\u{1109B}', '\u{110BA}', '\u{1109C}'), ('\u{110A5}', '\u{110BA}', '\u{110AB}'), ('\u{11131}', '\u{11127}', '\u{1112E}'),
+    ('\u{11132}', '\u{11127}', '\u{1112F}'), ('\u{11347}', '\u{1133E}', '\u{1134B}'), ('\u{11347}', '\u{11357}', '\u{1134C}'), ('\u{114B9}', '\u{114B0}', '\u{114BC}'),
+    ('\u{114B9}', '\u{114BA}', '\u{114BB}'), ('\u{114B9}', '\u{114BD}', '\u{114BE}'), ('\u{115B8}', '\u{115AF}', '\u{115BA}'), ('\u{115B9}', '\u{115AF}', '\u{115BB}'),
+    ('\u{11935}', '\u{11930}', '\u{11938}'),
+];
+
+pub(crate) const COMBINING_CLASSES: &[(char, char, u8)] = &[
+    ('\u{300}', '\u{314}', 230), ('\u{315}', '\u{315}', 232), ('\u{316}', '\u{319}', 220), ('\u{31A}', '\u{31A}', 232),
+    ('\u{31B}', '\u{31B}', 216), ('\u{31C}', '\u{320}', 220), ('\u{321}', '\u{322}', 202), ('\u{323}', '\u{326}', 220),
+    ('\u{327}', '\u{328}', 202), ('\u{329}', '\u{333}', 220), ('\u{334}', '\u{338}', 1), ('\u{339}', '\u{33C}', 220),
+    ('\u{33D}', '\u{344}', 230), ('\u{345}', '\u{345}', 240), ('\u{346}', '\u{346}', 230), ('\u{347}', '\u{349}', 220),
+    ('\u{34A}', '\u{34C}', 230), ('\u{34D}', '\u{34E}', 220), ('\u{350}', '\u{352}', 230), ('\u{353}', '\u{356}', 220),
+    ('\u{357}', '\u{357}', 230), ('\u{358}', '\u{358}', 232), ('\u{359}', '\u{35A}', 220), ('\u{35B}', '\u{35B}', 230),
+    ('\u{35C}', '\u{35C}', 233), ('\u{35D}', '\u{35E}', 234), ('\u{35F}', '\u{35F}', 233), ('\u{360}', '\u{361}', 234),
+    ('\u{362}', '\u{362}', 233), ('\u{363}', '\u{36F}', 230), ('\u{483}', '\u{487}', 230), ('\u{591}', '\u{591}', 220),
+    ('\u{592}', '\u{595}', 230), ('\u{596}', '\u{596}', 220), ('\u{597}', '\u{599}', 230), ('\u{59A}', '\u{59A}', 222),
+    ('\u{59B}', '\u{59B}', 220), ('\u{59C}', '\u{5A1}', 230), ('\u{5A2}', '\u{5A7}', 220), ('\u{5A8}', '\u{5A9}', 230),
+    ('\u{5AA}', '\u{5AA}', 220), ('\u{5AB}', '\u{5AC}', 230), ('\u{5AD}', '\u{5AD}', 222), ('\u{5AE}', '\u{5AE}', 228),
+    ('\u{5AF}', '\u{5AF}', 230), ('\u{5B0}', '\u{5B0}', 10), ('\u{5B1}', '\u{5B1}', 11), ('\u{5B2}', '\u{5B2}', 12),
+    ('\u{5B3}', '\u{5B3}', 13), ('\u{5B4}', '\u{5B4}', 14), ('\u{5B5}', '\u{5B5}', 15), ('\u{5B6}', '\u{5B6}', 16),
+    ('\u{5B7}', '\u{5B7}', 17), ('\u{5B8}', '\u{5B8}', 18), ('\u{5B9}', '\u{5BA}', 19), ('\u{5BB}', '\u{5BB}', 20),
+    ('\u{5BC}', '\u{5BC}', 21), ('\u{5BD}', '\u{5BD}', 22), ('\u{5BF}', '\u{5BF}', 23), ('\u{5C1}', '\u{5C1}', 24),
+    ('\u{5C2}', '\u{5C2}', 25), ('\u{5C4}', '\u{5C4}', 230), ('\u{5C5}', '\u{5C5}', 220), ('\u{5C7}', '\u{5C7}', 18),
+    ('\u{610}', '\u{617}', 230), ('\u{618}', '\u{618}', 30), ('\u{619}', '\u{619}', 31), ('\u{61A}', '\u{61A}', 32),
+    ('\u{64B}', '\u{64B}', 27), ('\u{64C}', '\u{64C}', 28), ('\u{64D}', '\u{64D}', 29), ('\u{64E}', '\u{64E}', 30),
+    ('\u{64F}', '\u{64F}', 31), ('\u{650}', '\u{650}', 32), ('\u{651}', '\u{651}', 33), ('\u{652}', '\u{652}', 34),
+    ('\u{653}', '\u{654}', 230), ('\u{655}', '\u{656}', 220), ('\u{657}', '\u{65B}', 230), ('\u{65C}', '\u{65C}', 220),
+    ('\u{65D}', '\u{65E}', 230), ('\u{65F}', '\u{65F}', 220), ('\u{670}', '\u{670}', 35), ('\u{6D6}', '\u{6DC}', 230),
+    ('\u{6DF}', '\u{6E2}', 230), ('\u{6E3}', '\u{6E3}', 220), ('\u{6E4}', '\u{6E4}', 230), ('\u{6E7}', '\u{6E8}', 230),
+    ('\u{6EA}', '\u{6EA}', 220), ('\u{6EB}', '\u{6EC}', 230), ('\u{6ED}', '\u{6ED}', 220), ('\u{711}', '\u{711}', 36),
+    ('\u{730}', '\u{730}', 230), ('\u{731}', '\u{731}', 220), ('\u{732}', '\u{733}', 230), ('\u{734}', '\u{734}', 220),
+    ('\u{735}', '\u{736}', 230), ('\u{737}', '\u{739}', 220), ('\u{73A}', '\u{73A}', 230), ('\u{73B}', '\u{73C}', 220),
+    ('\u{73D}', '\u{73D}', 230), ('\u{73E}', '\u{73E}', 220), ('\u{73F}', '\u{741}', 230), ('\u{742}', '\u{742}', 220),
+    ('\u{743}', '\u{743}', 230), ('\u{744}', '\u{744}', 220), ('\u{745}', '\u{745}', 230), ('\u{746}', '\u{746}', 220),
+    ('\u{747}', '\u{747}', 230), ('\u{748}', '\u{748}', 220), ('\u{749}', '\u{74A}', 230), ('\u{7EB}', '\u{7F1}', 230),
+    ('\u{7F2}', '\u{7F2}', 220), ('\u{7F3}', '\u{7F3}', 230), ('\u{7FD}', '\u{7FD}', 220), ('\u{816}', '\u{819}', 230),
+    ('\u{81B}', '\u{823}', 230), ('\u{825}', '\u{827}', 230), ('\u{829}', '\u{82D}', 230), ('\u{859}', '\u{85B}', 220),
+    ('\u{898}', '\u{898}', 230), ('\u{899}', '\u{89B}', 220), ('\u{89C}', '\u{89F}', 230), ('\u{8CA}', '\u{8CE}', 230),
+    ('\u{8CF}', '\u{8D3}', 220), ('\u{8D4}', '\u{8E1}', 230), ('\u{8E3}', '\u{8E3}', 220), ('\u{8E4}', '\u{8E5}', 230),
+    ('\u{8E6}', '\u{8E6}', 220), ('\u{8E7}', '\u{8E8}', 230), ('\u{8E9}', '\u{8E9}', 220), ('\u{8EA}', '\u{8EC}', 230),
+    ('\u{8ED}', '\u{8EF}', 220), ('\u{8F0}', '\u{8F0}', 27), ('\u{8F1}', '\u{8F1}', 28), ('\u{8F2}', '\u{8F2}', 29),
+    ('\u{8F3}', '\u{8F5}', 230), ('\u{8F6}', '\u{8F6}', 220), ('\u{8F7}', '\u{8F8}', 230), ('\u{8F9}', '\u{8FA}', 220),
+    ('\u{8FB}', '\u{8FF}', 230), ('\u{93C}', '\u{93C}', 7), ('\u{94D}', '\u{94D}', 9), ('\u{951}', '\u{951}', 230),
+    ('\u{952}', '\u{952}', 220), ('\u{953}', '\u{954}', 230), ('\u{9BC}', '\u{9BC}', 7), ('\u{9CD}', '\u{9CD}', 9),
+    ('\u{9FE}', '\u{9FE}', 230), ('\u{A3C}', '\u{A3C}', 7), ('\u{A4D}', '\u{A4D}', 9), ('\u{ABC}', '\u{ABC}', 7),
+    ('\u{ACD}', '\u{ACD}', 9), ('\u{B3C}', '\u{B3C}', 7), ('\u{B4D}', '\u{B4D}', 9), ('\u{BCD}', '\u{BCD}', 9),
+    ('\u{C3C}', '\u{C3C}', 7), ('\u{C4D}', '\u{C4D}', 9), ('\u{C55}', '\u{C55}', 84), ('\u{C56}', '\u{C56}', 91),
+    ('\u{CBC}', '\u{CBC}', 7), ('\u{CCD}', '\u{CCD}', 9), ('\u{D3B}', '\u{D3C}', 9), ('\u{D4D}', '\u{D4D}', 9),
+    ('\u{DCA}', '\u{DCA}', 9), ('\u{E38}', '\u{E39}', 103), ('\u{E3A}', '\u{E3A}', 9), ('\u{E48}', '\u{E4B}', 107),
+    ('\u{EB8}', '\u{EB9}', 118), ('\u{EBA}', '\u{EBA}', 9), ('\u{EC8}', '\u{ECB}', 122), ('\u{F18}', '\u{F19}', 220),
+    ('\u{F35}', '\u{F35}', 220), ('\u{F37}', '\u{F37}', 220), ('\u{F39}', '\u{F39}', 216), ('\u{F71}', '\u{F71}', 129),
+    ('\u{F72}', '\u{F72}', 130), ('\u{F74}', '\u{F74}', 132), ('\u{F7A}', '\u{F7D}', 130), ('\u{F80}', '\u{F80}', 130),
+    ('\u{F82}', '\u{F83}', 230), ('\u{F84}', '\u{F84}', 9), ('\u{F86}', '\u{F87}', 230), ('\u{FC6}', '\u{FC6}', 220),
+    ('\u{1037}', '\u{1037}', 7), ('\u{1039}', '\u{103A}', 9), ('\u{108D}', '\u{108D}', 220), ('\u{135D}', '\u{135F}', 230),
+    ('\u{1714}', '\u{1715}', 9), ('\u{1734}', '\u{1734}', 9), ('\u{17D2}', '\u{17D2}', 9), ('\u{17DD}', '\u{17DD}', 230),
+    ('\u{18A9}', '\u{18A9}', 228), ('\u{1939}', '\u{1939}', 222), ('\u{193A}', '\u{193A}', 230), ('\u{193B}', '\u{193B}', 220),
+    ('\u{1A17}', '\u{1A17}', 230), ('\u{1A18}', '\u{1A18}', 220), ('\u{1A60}', '\u{1A60}', 9), ('\u{1A75}', '\u{1A7C}', 230),
+    ('\u{1A7F}', '\u{1A7F}', 220), ('\u{1AB0}', '\u{1AB4}', 230), ('\u{1AB5}', '\u{1ABA}', 220), ('\u{1ABB}', '\u{1ABC}', 230),
+    ('\u{1ABD}', '\u{1ABD}', 220), ('\u{1ABF}', '\u{1AC0}', 220), ('\u{1AC1}', '\u{1AC2}', 230), ('\u{1AC3}', '\u{1AC4}', 220),
+    ('\u{1AC5}', '\u{1AC9}', 230), ('\u{1ACA}', '\u{1ACA}', 220), ('\u{1ACB}', '\u{1ACE}', 230), ('\u{1B34}', '\u{1B34}', 7),
+    ('\u{1B44}', '\u{1B44}', 9), ('\u{1B6B}', '\u{1B6B}', 230), ('\u{1B6C}', '\u{1B6C}', 220), ('\u{1B6D}', '\u{1B73}', 230),
+    ('\u{1BAA}', '\u{1BAB}', 9), ('\u{1BE6}', '\u{1BE6}', 7), ('\u{1BF2}', '\u{1BF3}', 9), ('\u{1C37}', '\u{1C37}', 7),
+    ('\u{1CD0}', '\u{1CD2}', 230), ('\u{1CD4}', '\u{1CD4}', 1), ('\u{1CD5}', '\u{1CD9}', 220), ('\u{1CDA}', '\u{1CDB}', 230),
+    ('\u{1CDC}', '\u{1CDF}', 220), ('\u{1CE0}', '\u{1CE0}', 230), ('\u{1CE2}', '\u{1CE8}', 1), ('\u{1CED}', '\u{1CED}', 220),
+    ('\u{1CF4}', '\u{1CF4}', 230), ('\u{1CF8}', '\u{1CF9}', 230), ('\u{1DC0}', '\u{1DC1}', 230), ('\u{1DC2}', '\u{1DC2}', 220),
+    ('\u{1DC3}', '\u{1DC9}', 230), ('\u{1DCA}', '\u{1DCA}', 220), ('\u{1DCB}', '\u{1DCC}', 230), ('\u{1DCD}', '\u{1DCD}', 234),
+    ('\u{1DCE}', '\u{1DCE}', 214), ('\u{1DCF}', '\u{1DCF}', 220), ('\u{1DD0}', '\u{1DD0}', 202), ('\u{1DD1}', '\u{1DF5}', 230),
+    ('\u{1DF6}', '\u{1DF6}', 232), ('\u{1DF7}', '\u{1DF8}', 228), ('\u{1DF9}', '\u{1DF9}', 220), ('\u{1DFA}', '\u{1DFA}', 218),
+    ('\u{1DFB}', '\u{1DFB}', 230), ('\u{1DFC}', '\u{1DFC}', 233), ('\u{1DFD}', '\u{1DFD}', 220), ('\u{1DFE}', '\u{1DFE}', 230),
+    ('\u{1DFF}', '\u{1DFF}', 220), ('\u{20D0}', '\u{20D1}', 230), ('\u{20D2}', '\u{20D3}', 1), ('\u{20D4}', '\u{20D7}', 230),
+    ('\u{20D8}', '\u{20DA}', 1), ('\u{20DB}', '\u{20DC}', 230), ('\u{20E1}', '\u{20E1}', 230), ('\u{20E5}', '\u{20E6}', 1),
+    ('\u{20E7}', '\u{20E7}', 230), ('\u{20E8}', '\u{20E8}', 220), ('\u{20E9}', '\u{20E9}', 230), ('\u{20EA}', '\u{20EB}', 1),
+    ('\u{20EC}', '\u{20EF}', 220), ('\u{20F0}', '\u{20F0}', 230), ('\u{2CEF}', '\u{2CF1}', 230), ('\u{2D7F}', '\u{2D7F}', 9),
+    ('\u{2DE0}', '\u{2DFF}', 230), ('\u{302A}', '\u{302A}', 218), ('\u{302B}', '\u{302B}', 228), ('\u{302C}', '\u{302C}', 232),
+    ('\u{302D}', '\u{302D}', 222), ('\u{302E}', '\u{302F}', 224), ('\u{3099}', '\u{309A}', 8), ('\u{A66F}', '\u{A66F}', 230),
+    ('\u{A674}', '\u{A67D}', 230), ('\u{A69E}', '\u{A69F}', 230), ('\u{A6F0}', '\u{A6F1}', 230), ('\u{A806}', '\u{A806}', 9),
+    ('\u{A82C}', '\u{A82C}', 9), ('\u{A8C4}', '\u{A8C4}', 9), ('\u{A8E0}', '\u{A8F1}', 230), ('\u{A92B}', '\u{A92D}', 220),
+    ('\u{A953}', '\u{A953}', 9), ('\u{A9B3}', '\u{A9B3}', 7), ('\u{A9C0}', '\u{A9C0}', 9), ('\u{AAB0}', '\u{AAB0}', 230),
+    ('\u{AAB2}', '\u{AAB3}', 230), ('\u{AAB4}', '\u{AAB4}', 220), ('\u{AAB7}', '\u{AAB8}', 230), ('\u{AABE}', '\u{AABF}', 230),
+    ('\u{AAC1}', '\u{AAC1}', 230), ('\u{AAF6}', '\u{AAF6}', 9), ('\u{ABED}', '\u{ABED}', 9), ('\u{FB1E}', '\u{FB1E}', 26),
+    ('\u{FE20}', '\u{FE26}', 230), ('\u{FE27}', '\u{FE2D}', 220), ('\u{FE2E}', '\u{FE2F}', 230), ('\u{101FD}', '\u{101FD}', 220),
+    ('\u{102E0}', '\u{102E0}', 220), ('\u{10376}', '\u{1037A}', 230), ('\u{10A0D}', '\u{10A0D}', 220), ('\u{10A0F}', '\u{10A0F}', 230),
+    ('\u{10A38}', '\u{10A38}', 230), ('\u{10A39}', '\u{10A39}', 1), ('\u{10A3A}', '\u{10A3A}', 220), ('\u{10A3F}', '\u{10A3F}', 9),
+    ('\u{10AE5}', '\u{10AE5}', 230), ('\u{10AE6}', '\u{10AE6}', 220), ('\u{10D24}', '\u{10D27}', 230), ('\u{10EAB}', '\u{10EAC}', 230),
+    ('\u{10F46}', '\u{10F47}', 220), ('\u{10F48}', '\u{10F4A}', 230), ('\u{10F4B}', '\u{10F4B}', 220), ('\u{10F4C}', '\u{10F4C}', 230),
+    ('\u{10F4D}', '\u{10F50}', 220), ('\u{10F82}', '\u{10F82}', 230), ('\u{10F83}', '\u{10F83}', 220), ('\u{10F84}', '\u{10F84}', 230),
+    ('\u{10F85}', '\u{10F85}', 220), ('\u{11046}', '\u{11046}', 9), ('\u{11070}', '\u{11070}', 9), ('\u{1107F}', '\u{1107F}', 9),
+    ('\u{110B9}', '\u{110B9}', 9), ('\u{110BA}', '\u{110BA}', 7), ('\u{11100}', '\u{11102}', 230), ('\u{11133}', '\u{11134}', 9),
+    ('\u{11173}', '\u{11173}', 7), ('\u{111C0}', '\u{111C0}', 9), ('\u{111CA}', '\u{111CA}', 7), ('\u{11235}', '\u{11235}', 9),
+    ('\u{11236}', '\u{11236}', 7), ('\u{112E9}', '\u{112E9}', 7), ('\u{112EA}', '\u{112EA}', 9), ('\u{1133B}', '\u{1133C}', 7),
+    ('\u{1134D}', '\u{1134D}', 9), ('\u{11366}', '\u{1136C}', 230), ('\u{11370}', '\u{11374}', 230), ('\u{11442}', '\u{11442}', 9),
+    ('\u{11446}', '\u{11446}', 7), ('\u{1145E}', '\u{1145E}', 230), ('\u{114C2}', '\u{114C2}', 9), ('\u{114C3}', '\u{114C3}', 7),
+    ('\u{115BF}', '\u{115BF}', 9), ('\u{115C0}', '\u{115C0}', 7), ('\u{1163F}', '\u{1163F}', 9), ('\u{116B6}', '\u{116B6}', 9),
+    ('\u{116B7}', '\u{116B7}', 7), ('\u{1172B}', '\u{1172B}', 9), ('\u{11839}', '\u{11839}', 9), ('\u{1183A}', '\u{1183A}', 7),
+    ('\u{1193D}', '\u{1193E}', 9), ('\u{11943}', '\u{11943}', 7), ('\u{119E0}', '\u{119E0}', 9), ('\u{11A34}', '\u{11A34}', 9),
+    ('\u{11A47}', '\u{11A47}', 9), ('\u{11A99}', '\u{11A99}', 9), ('\u{11C3F}', '\u{11C3F}', 9), ('\u{11D42}', '\u{11D42}', 7),
+    ('\u{11D44}', '\u{11D45}', 9), ('\u{11D97}', '\u{11D97}', 9), ('\u{16AF0}', '\u{16AF4}', 1), ('\u{16B30}', '\u{16B36}', 230),
+    ('\u{16FF0}', '\u{16FF1}', 6), ('\u{1BC9E}', '\u{1BC9E}', 1), ('\u{1D165}', '\u{1D166}', 216), ('\u{1D167}', '\u{1D169}', 1),
+    ('\u{1D16D}', '\u{1D16D}', 226), ('\u{1D16E}', '\u{1D172}', 216), ('\u{1D17B}', '\u{1D182}', 220), ('\u{1D185}', '\u{1D189}', 230),
+    ('\u{1D18A}', '\u{1D18B}', 220), ('\u{1D1AA}', '\u{1D1AD}', 230), ('\u{1D242}', '\u{1D244}', 230), ('\u{1E000}', '\u{1E006}', 230),
+    ('\u{1E008}', '\u{1E018}', 230), ('\u{1E01B}', '\u{1E021}', 230), ('\u{1E023}', '\u{1E024}', 230), ('\u{1E026}', '\u{1E02A}', 230),
+    ('\u{1E130}', '\u{1E136}', 230), ('\u{1E2AE}', '\u{1E2AE}', 230), ('\u{1E2EC}', '\u{1E2EF}', 230), ('\u{1E8D0}', '\u{1E8D6}', 220),
+    ('\u{1E944}', '\u{1E949}', 230), ('\u{1E94A}', '\u{1E94A}', 7),
+];
@@ -0,0 +1,67 @@
+/*
+    Output verification for --verify-balanced: checks that the brackets in the output pair up by the
+    rules of the format it is meant for, and reports where the first one that does not was produced.
+*/
+
+use super::Context;
+use super::sourcemap;
+
+#[derive(Copy, Clone)]
+pub(crate) enum Syntax {
+    Tex, // Braces, with \ escaping the next character and % starting a comment to the end of the line
+    Brackets // Parentheses, square brackets, and braces, with nothing escaped
+}
+
+impl Syntax {
+    pub(crate) fn from_name(name: &str) -> Option<Syntax> {
+        match name {
+            "tex" => Some(Syntax::Tex),
+            "brackets" => Some(Syntax::Brackets),
+            _ => None
+        }
+    }
+}
+
+/*
+    Offset of the first bracket that does not pair up, and what is wrong with it. An opening bracket
+    that is never closed is reported only once everything else pairs up, the innermost first.
+*/
+fn first_imbalance(output: &str, syntax: Syntax) -> Option<(usize, String)> {
+    let mut open: Vec<(usize, char)> = Vec::new();
+    let mut chars = output.char_indices();
+    while let Some((offset, c)) = chars.next() {
+        match (syntax, c) {
+            (Syntax::Tex, '\\') => { chars.next(); },
+            (Syntax::Tex, '%') => { chars.by_ref().find(|&(_, c)| c == '\n'); },
+            (Syntax::Tex, '{') | (Syntax::Brackets, '(' | '[' | '{') => open.push((offset, c)),
+            (Syntax::Tex, '}') | (Syntax::Brackets, ')' | ']' | '}') => {
+                let expected = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{'
+                };
+                match open.pop() {
+                    Some((_, opener)) if opener == expected => {},
+                    Some((_, opener)) => return Some((offset, format!("{} closes {}", c, opener))),
+                    None => return Some((offset, format!("unmatched {}", c)))
+                }
+            },
+            _ => {}
+        }
+    }
+    open.pop().map(|(offset, c)| (offset, format!("unclosed {}", c)))
+}
+
+/*
+    The error for the first imbalance in the output of the top-level process_str, with the output
+    line and the place in the source that produced the bracket
+*/
+pub(crate) fn verify_balanced(ctx: &Context, output: &str, syntax: Syntax) -> Result<(), String> {
+    let Some((offset, problem)) = first_imbalance(output, syntax) else { return Ok(()) };
+    let line = output[..offset].matches('\n').count() + 1;
+    let origin = match sourcemap::origin(ctx, offset) {
+        Some((file, source_line, _)) => format!(", produced at {}:{}", ctx.sources[file].name, source_line),
+        None => String::new()
+    };
+    Err(format!("Unbalanced output: {} on output line {}{}.", problem, line, origin))
+}
@@ -0,0 +1,219 @@
+/*
+    Tests of \include from archives, with the archives feature. The archives are made here, with
+    deflate streams written by hand, so that malformed ones can be too.
+*/
+
+#![cfg(feature = "archives")]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn scratch_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("proj3-test-{}-{}", std::process::id(), test));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// proj3 on a document of text in dir
+fn run(dir: &Path, text: &str, args: &[&str]) -> Output {
+    fs::write(dir.join("doc.txt"), text).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_proj3")).current_dir(dir).env_remove("PROJ3_FLAGS").env_remove("PROJ3_LOG")
+        .arg("--no-config").args(args).arg("doc.txt").output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 { crc = if crc & 1 != 0 { crc >> 1 ^ 0xedb88320 } else { crc >> 1 } }
+    }
+    !crc
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u32 // Bits of the last byte written
+}
+
+impl BitWriter {
+    // Least significant bit first, as deflate packs numbers
+    fn bits(&mut self, value: u32, n: u32) {
+        for i in 0..n {
+            if self.used == 0 { self.bytes.push(0) }
+            *self.bytes.last_mut().unwrap() |= ((value >> i & 1) as u8) << self.used;
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    // Most significant bit first, as deflate packs Huffman codes
+    fn code(&mut self, code: u32, len: u32) {
+        for i in (0..len).rev() { self.bits(code >> i & 1, 1) }
+    }
+}
+
+// A deflate stream of one stored block
+fn deflate_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![1];
+    stream.extend((data.len() as u16).to_le_bytes());
+    stream.extend((!(data.len() as u16)).to_le_bytes());
+    stream.extend(data);
+    stream
+}
+
+// A deflate stream of a fixed Huffman block inflating to 1 + 258 * copies zero bytes
+fn deflate_zeros(copies: usize) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    writer.bits(1, 1);
+    writer.bits(1, 2);
+    writer.code(0x30, 8); // Literal 0
+    for _ in 0..copies {
+        writer.code(0xc5, 8); // Length 258
+        writer.code(0, 5); // Distance 1
+    }
+    writer.code(0, 7); // End of block
+    writer.bytes
+}
+
+struct Entry {
+    name: &'static str,
+    method: u16,
+    data: Vec<u8>, // As stored
+    size: usize, // As declared
+    crc: u32
+}
+
+impl Entry {
+    fn stored(name: &'static str, contents: &[u8]) -> Entry {
+        Entry { name, method: 0, data: contents.to_vec(), size: contents.len(), crc: crc32(contents) }
+    }
+
+    fn deflated(name: &'static str, contents: &[u8]) -> Entry {
+        Entry { method: 8, data: deflate_stored(contents), ..Entry::stored(name, contents) }
+    }
+}
+
+fn zip(entries: &[Entry]) -> Vec<u8> {
+    let (mut data, mut directory) = (Vec::new(), Vec::new());
+    for entry in entries {
+        let fields = |data: &mut Vec<u8>| {
+            data.extend([20, 0, 0, 0]);
+            data.extend(entry.method.to_le_bytes());
+            data.extend([0; 4]);
+            data.extend(entry.crc.to_le_bytes());
+            data.extend((entry.data.len() as u32).to_le_bytes());
+            data.extend((entry.size as u32).to_le_bytes());
+            data.extend((entry.name.len() as u16).to_le_bytes());
+            data.extend([0; 2]);
+        };
+        directory.extend(0x02014b50u32.to_le_bytes());
+        directory.extend([20, 0]);
+        fields(&mut directory);
+        directory.extend([0; 10]);
+        directory.extend((data.len() as u32).to_le_bytes());
+        directory.extend(entry.name.as_bytes());
+        data.extend(0x04034b50u32.to_le_bytes());
+        fields(&mut data);
+        data.extend(entry.name.as_bytes());
+        data.extend(&entry.data);
+    }
+    let offset = data.len() as u32;
+    let size = directory.len() as u32;
+    data.extend(directory);
+    data.extend(0x06054b50u32.to_le_bytes());
+    data.extend([0; 4]);
+    data.extend((entries.len() as u16).to_le_bytes());
+    data.extend((entries.len() as u16).to_le_bytes());
+    data.extend(size.to_le_bytes());
+    data.extend(offset.to_le_bytes());
+    data.extend([0; 2]);
+    data
+}
+
+fn tar(members: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (name, contents) in members {
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", contents.len()).as_bytes());
+        header[156] = b'0';
+        data.extend(header);
+        data.extend(*contents);
+        data.resize(data.len().div_ceil(512) * 512, 0);
+    }
+    data.extend([0; 1024]);
+    data
+}
+
+// A gzip file of a deflate stream, with the CRC and size of contents
+fn gzip(stream: &[u8], crc: u32, size: usize) -> Vec<u8> {
+    let mut data = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3];
+    data.extend(stream);
+    data.extend(crc.to_le_bytes());
+    data.extend((size as u32).to_le_bytes());
+    data
+}
+
+#[test]
+fn members() {
+    let dir = scratch_dir("archive_members");
+    fs::write(dir.join("bundle.zip"), zip(&[Entry::stored("a.mac", b"\\def{a}{A}"), Entry::deflated("lib/b.mac", b"\\def{b}{B}")])).unwrap();
+    let tarred = tar(&[("c.mac", b"\\def{c}{C}"), ("lib/d.mac", b"\\def{d}{D}")]);
+    fs::write(dir.join("bundle.tar"), &tarred).unwrap();
+    fs::write(dir.join("bundle.tar.gz"), gzip(&deflate_stored(&tarred), crc32(&tarred), tarred.len())).unwrap();
+    let text = "\\include{bundle.zip!a.mac}\\include{bundle.zip!lib/b.mac}\\include{bundle.tar!c.mac}\\include{bundle.tar.gz!lib/d.mac}\
+                \\a{}\\b{}\\c{}\\d{}\\fileexists{bundle.zip!none}{}{ none}\n";
+    let output = run(&dir, text, &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ABCD none\n", "{}", stderr(&output));
+    let output = run(&dir, "\\include{bundle.zip!none.mac}", &[]);
+    assert_eq!(stderr(&output), "proj3: Include error.\n");
+}
+
+#[test]
+fn malformed_archives() {
+    let dir = scratch_dir("malformed_archives");
+    let contents: &[u8] = b"\\def{a}{A}";
+    let cases = [
+        zip(&[Entry { crc: 1, ..Entry::deflated("a.mac", contents) }]),
+        zip(&[Entry { size: 3, ..Entry::deflated("a.mac", contents) }]),
+        zip(&[Entry { size: 100, ..Entry::stored("a.mac", contents) }]),
+        zip(&[Entry { method: 12, ..Entry::stored("a.mac", contents) }]),
+        zip(&[Entry { data: vec![0xff; 8], ..Entry::deflated("a.mac", contents) }]),
+        zip(&[Entry { data: deflate_stored(contents)[..6].to_vec(), ..Entry::deflated("a.mac", contents) }]),
+        zip(&[Entry::stored("a.mac", contents)])[..40].to_vec(),
+        b"not a zip file at all".to_vec()
+    ];
+    for (i, data) in cases.iter().enumerate() {
+        fs::write(dir.join("bundle.zip"), data).unwrap();
+        let output = run(&dir, "\\include{bundle.zip!a.mac}\\a{}", &[]);
+        assert_eq!(stderr(&output), "proj3: Include error.\n", "case {}", i);
+    }
+    for data in [gzip(&deflate_stored(b"x"), 0, 1), vec![0x1f, 0x8b, 8, 0], tar(&[("a.mac", contents)])[..600].to_vec()] {
+        fs::write(dir.join("bundle.tgz"), data).unwrap();
+        assert_eq!(stderr(&run(&dir, "\\include{bundle.tgz!a.mac}", &[])), "proj3: Include error.\n");
+    }
+}
+
+#[test]
+fn decompression_bombs() {
+    let dir = scratch_dir("decompression_bombs");
+    // 50 MB of zeros in about 400 KB
+    let (copies, size) = (200_000, 1 + 258 * 200_000);
+    let bomb = deflate_zeros(copies);
+    let limit = ["--max-memory", "1000000"];
+    fs::write(dir.join("bomb.zip"), zip(&[Entry { method: 8, data: bomb.clone(), size, crc: 0, name: "a.mac" }])).unwrap();
+    assert_eq!(stderr(&run(&dir, "\\include{bomb.zip!a.mac}", &limit)), "proj3: Memory limit exceeded by archive member a.mac.\n");
+    // Declaring less than it inflates to stops it there
+    fs::write(dir.join("bomb.zip"), zip(&[Entry { method: 8, data: bomb.clone(), size: 1000, crc: 0, name: "a.mac" }])).unwrap();
+    assert_eq!(stderr(&run(&dir, "\\include{bomb.zip!a.mac}", &limit)), "proj3: Include error.\n");
+    fs::write(dir.join("bomb.tgz"), gzip(&bomb, 0, size)).unwrap();
+    assert_eq!(stderr(&run(&dir, "\\include{bomb.tgz!a.mac}", &limit)), "proj3: Memory limit exceeded by bomb.tgz.\n");
+    fs::write(dir.join("bomb.tgz"), gzip(&bomb, 0, 1000)).unwrap();
+    assert_eq!(stderr(&run(&dir, "\\include{bomb.tgz!a.mac}", &limit)), "proj3: Include error.\n");
+}
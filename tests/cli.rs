@@ -0,0 +1,542 @@
+/*
+    Tests of the proj3 binary as a build would run it. The cases in tests/cases go through
+    proj3 --test, which compares each name.in with name.expected; what needs files, the environment,
+    or several runs is tested here.
+*/
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+// proj3 without the caller's PROJ3_FLAGS and PROJ3_LOG, run in dir
+fn proj3(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_proj3"));
+    command.current_dir(dir).env_remove("PROJ3_FLAGS").env_remove("PROJ3_LOG");
+    command
+}
+
+// An empty directory of the test's own
+fn scratch_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("proj3-test-{}-{}", std::process::id(), test));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn cases() {
+    let cases = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cases");
+    let output = proj3(&cases).args(["--test", "--no-config", "."]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn modes_are_flags() {
+    let dir = scratch_dir("modes_are_flags");
+    fs::write(dir.join("test"), "\\def{a}{A}\\a{} test\n").unwrap();
+    fs::write(dir.join("bench"), "bench\n").unwrap();
+    assert_eq!(stdout(&proj3(&dir).args(["--no-config", "test"]).output().unwrap()), "A test\n");
+    assert_eq!(stdout(&proj3(&dir).args(["bench"]).output().unwrap()), "bench\n");
+    let output = proj3(&dir).args(["--bench", "--iterations", "2", "--no-config", "test"]).output().unwrap();
+    assert!(stdout(&output).starts_with("2 iterations, 20 bytes in, 7 bytes out, 1 expansions\n"), "{}", stdout(&output));
+    let output = proj3(&dir).args(["--bench", "--no-config"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: --bench expects a file and at least one iteration.\n");
+    fs::create_dir_all(dir.join("cases")).unwrap();
+    fs::write(dir.join("cases/a.in"), "\\def{a}{A}\\a{}\n").unwrap();
+    fs::write(dir.join("cases/a.expected"), "B\n").unwrap();
+    let output = proj3(&dir).args(["--test", "--no-config", "cases"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(stdout(&output).contains("-B\n+A\n"), "{}", stdout(&output));
+    let output = proj3(&dir).args(["--test", "--no-config"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: --test expects one directory.\n");
+}
+
+#[test]
+fn config_cannot_run_commands() {
+    let dir = scratch_dir("config_cannot_run_commands");
+    fs::write(dir.join("proj3.toml"), "chomp = true\npost-filter = \"touch ran; cat\"\n").unwrap();
+    fs::create_dir(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/doc.txt"), "text\n").unwrap();
+    let output = proj3(&dir.join("sub")).arg("doc.txt").output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("post-filter cannot be set in proj3.toml"));
+    assert!(!dir.join("ran").exists() && !dir.join("sub/ran").exists());
+    let output = proj3(&dir.join("sub")).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "text\n");
+}
+
+#[test]
+fn config_paths_are_relative_to_it() {
+    let dir = scratch_dir("config_paths_are_relative_to_it");
+    fs::write(dir.join("proj3.toml"), "env-file = \"vars.env\"\nbuffer = [\"note=note.txt\"]\n").unwrap();
+    fs::write(dir.join("vars.env"), "WHO=world\n").unwrap();
+    fs::write(dir.join("note.txt"), "noted").unwrap();
+    fs::create_dir_all(dir.join("cfg/deep")).unwrap();
+    fs::write(dir.join("cfg/deep/doc.txt"), "hello \\WHO{}, \\include{@note}\n").unwrap();
+    let output = proj3(&dir.join("cfg/deep")).arg("doc.txt").output().unwrap();
+    assert_eq!(stdout(&output), "hello world, noted\n", "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn include_paths() {
+    let dir = scratch_dir("include_paths");
+    fs::write(dir.join("proj3.toml"), "include-path = [\"macros\", \"vendor\"]\n").unwrap();
+    fs::create_dir_all(dir.join("macros")).unwrap();
+    fs::create_dir_all(dir.join("vendor")).unwrap();
+    fs::create_dir_all(dir.join("docs")).unwrap();
+    fs::write(dir.join("macros/greet.mac"), "\\def{greet}{hello}").unwrap();
+    fs::write(dir.join("vendor/greet.mac"), "\\def{greet}{shadowed}").unwrap();
+    fs::write(dir.join("vendor/name.mac"), "\\def{name}{world}").unwrap();
+    fs::write(dir.join("docs/name.mac"), "\\def{name}{local}").unwrap();
+    fs::write(dir.join("docs/doc.txt"), "\\include{greet.mac}\\include{name.mac}\\greet{} \\name{}\\fileexists{none.mac}{}{ none}\n").unwrap();
+    let output = proj3(&dir.join("docs")).arg("doc.txt").output().unwrap();
+    assert_eq!(stdout(&output), "hello local none\n", "{}", String::from_utf8_lossy(&output.stderr));
+    fs::remove_file(dir.join("docs/name.mac")).unwrap();
+    let output = proj3(&dir.join("docs")).arg("doc.txt").output().unwrap();
+    assert_eq!(stdout(&output), "hello world none\n", "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn option_precedence() {
+    let dir = scratch_dir("option_precedence");
+    fs::write(dir.join("proj3.toml"), "expand-tabs = 2\nflag = [\"a\"]\n\n[define]\nwho = \"config\"\nwhere = \"config\"\n").unwrap();
+    fs::write(dir.join("doc.txt"), "\tx \\ifflag{a}{A}{}\\ifflag{b}{B}{}\\ifflag{c}{C}{} \\who{} \\where{}\n").unwrap();
+    let output = proj3(&dir).arg("doc.txt").output().unwrap();
+    assert_eq!(stdout(&output), "  x A config config\n");
+    // Scalars from PROJ3_FLAGS replace those of the file, and list options add to them
+    let output = proj3(&dir).env("PROJ3_FLAGS", "--expand-tabs 4 --flag b --define who=env").arg("doc.txt").output().unwrap();
+    assert_eq!(stdout(&output), "    x AB env config\n");
+    // The command line comes after both
+    let output = proj3(&dir).env("PROJ3_FLAGS", "--expand-tabs 4 --flag b --define who=env")
+        .args(["--expand-tabs", "8", "--flag", "c", "--define", "where=cli", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "        x ABC env cli\n");
+    // --no-config leaves out the file, and with it the definition of who, but not PROJ3_FLAGS
+    let output = proj3(&dir).env("PROJ3_FLAGS", "--flag b").args(["--no-config", "doc.txt"]).output().unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn builtins_take_precedence() {
+    let dir = scratch_dir("builtins_take_precedence");
+    fs::write(dir.join("lib.mac"), "\\def{if}{library}").unwrap();
+    fs::write(dir.join("doc.txt"), "\\def{if}{mine}\\if{1}{builtin}{} \\import{lib.mac}{lib}\\lib:if{}\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: Macro name is taken by a builtin.\n");
+    let output = proj3(&dir).args(["--no-config", "--allow-shadow-builtins", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "builtin library\n");
+}
+
+#[test]
+fn passes_write_once() {
+    let dir = scratch_dir("passes_write_once");
+    fs::write(dir.join("doc.txt"), "\\appendfile{log.txt}{line \\total{}\n}\\writefile{out.txt}{\\total{}}done\n\\def{total}{3}").unwrap();
+    let output = proj3(&dir).args(["--no-config", "--allow-write", "--passes", "3", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "done\n", "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(dir.join("log.txt")).unwrap(), "line 3\n");
+    assert_eq!(fs::read_to_string(dir.join("out.txt")).unwrap(), "3");
+}
+
+#[test]
+fn config_values_are_not_options() {
+    let dir = scratch_dir("config_values_are_not_options");
+    fs::write(dir.join("doc.txt"), "\\writefile{written.txt}{x}text\n").unwrap();
+    for config in ["chomp = \"--allow-write\"\n", "flag = [\"--allow-write\"]\n", "locale = \"--post-filter\"\nchomp = \"touch ran\"\n",
+                   "chomp = \"doc.txt\"\n", "allow-write = false\n", "raw-delims = [\"<<\"]\n", "no-such-option = 1\n"] {
+        fs::write(dir.join("proj3.toml"), config).unwrap();
+        let output = proj3(&dir).arg("doc.txt").output().unwrap();
+        assert!(!output.status.success(), "{}", config);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("proj3.toml"), "{}", config);
+    }
+    assert!(!dir.join("written.txt").exists() && !dir.join("ran").exists());
+    fs::write(dir.join("proj3.toml"), "chomp = true\nlenient-undef = false\nraw-delims = [\"<<\", \">>\"]\n").unwrap();
+    fs::write(dir.join("doc.txt"), "<<\\x>> text\n").unwrap();
+    assert_eq!(stdout(&proj3(&dir).arg("doc.txt").output().unwrap()), "\\x text\n");
+}
+
+#[test]
+fn loops_stop_at_limits() {
+    let dir = scratch_dir("loops_stop_at_limits");
+    fs::write(dir.join("loop.txt"), "\\def{x}{x}\\while{1}{\\x{}}").unwrap();
+    fs::write(dir.join("nested.txt"), "\\uppercase{\\while{1}{x}}").unwrap();
+    fs::write(dir.join("rows.csv"), format!("n\n{}", "1\n".repeat(1000))).unwrap();
+    fs::write(dir.join("rows.txt"), "\\foreachrow{rows.csv}{row #n }").unwrap();
+    for (file, args, error) in [
+        ("loop.txt", &["--max-expansions", "0", "--max-output", "100"][..], "proj3: Output limit exceeded at loop.txt:1.\n"),
+        ("loop.txt", &["--max-output", "100"], "proj3: Output limit exceeded at loop.txt:1.\n"),
+        ("nested.txt", &["--max-expansions", "0", "--max-output", "100"], "proj3: Output limit exceeded at nested.txt:1.\n"),
+        ("rows.txt", &["--max-output", "100"], "proj3: Output limit exceeded at rows.txt:1.\n"),
+        ("loop.txt", &["--max-expansions", "0", "--max-memory", "100000"], "proj3: Memory limit exceeded at loop.txt:1:")
+    ] {
+        let output = proj3(&dir).arg("--no-config").args(args).arg(file).output().unwrap();
+        assert!(String::from_utf8_lossy(&output.stderr).starts_with(error), "{} {:?}: {}", file, args, String::from_utf8_lossy(&output.stderr));
+    }
+    fs::write(dir.join("short.txt"), "\\def{x}{xy}\\x{}\\x{}").unwrap();
+    assert_eq!(stdout(&proj3(&dir).args(["--no-config", "--max-output", "4", "short.txt"]).output().unwrap()), "xyxy");
+}
+
+#[test]
+fn memory_limit_holds_while_reading() {
+    let dir = scratch_dir("memory_limit_holds_while_reading");
+    fs::write(dir.join("big.txt"), "abc % comment\n".repeat(100_000)).unwrap();
+    fs::write(dir.join("doc.txt"), "\\include{big.txt}").unwrap();
+    let limit = ["--no-config", "--max-memory", "100000"];
+    for (args, error) in [
+        (&["doc.txt"][..], "proj3: Memory limit exceeded by big.txt, of 1400000 bytes.\n"),
+        (&["--passes", "2", "big.txt"], "proj3: Memory limit exceeded by big.txt, of 1400000 bytes.\n"),
+        (&["--buffer", "b=big.txt", "doc.txt"], "proj3: Memory limit exceeded by big.txt, of 1400000 bytes.\n"),
+        (&["big.txt"], "proj3: Memory limit exceeded reading the input: more than 100000 bytes.\n"),
+        (&["--dialect", "m4", "big.txt"], "proj3: Memory limit exceeded reading the input: more than 100000 bytes.\n")
+    ] {
+        let output = proj3(&dir).args(limit).args(args).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), error, "{:?}", args);
+    }
+    let mut child = proj3(&dir).args(limit).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::piped()).spawn().unwrap();
+    let _ = child.stdin.take().unwrap().write_all(&fs::read(dir.join("big.txt")).unwrap());
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: Memory limit exceeded reading the input: more than 100000 bytes.\n");
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_files() {
+    let dir = scratch_dir("json_files");
+    fs::write(dir.join("data.json"), r#"{"items": [{"name": "50% off \"now\" \\ \u00e9 \ud83d\ude00"}], "n": [1, -2.5e3, null, true, {}], "a/b": {"~": 0}}"#).unwrap();
+    fs::write(dir.join("doc.txt"), "\\jsonfile{data.json}{/items/0/name}|\\jsonfile{data.json}{/n}|\\jsonfile{data.json}{/a~1b/~0}|\
+                                     \\def{settings}{{\"debug\": true}}\\jsonget{\\settings{}}{/debug}\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "50% off \"now\" \\ é 😀|[1,-2.5e3,null,true,{}]|0|true\n", "{}", String::from_utf8_lossy(&output.stderr));
+    let cases = [
+        (r#"{"a": 1"#, "proj3: Invalid JSON at byte 7.\n"),
+        (r#"{"a": 1,}"#, "proj3: Invalid JSON at byte 8.\n"),
+        (r#"{"a": "\x"}"#, "proj3: Invalid JSON at byte 8.\n"),
+        (r#"{"a": "\ud800"}"#, "proj3: Invalid JSON at byte 13.\n"),
+        ("{\"a\": \"tab\there\"}", "proj3: Invalid JSON at byte 10.\n"),
+        ("[01]", "proj3: Invalid JSON at byte 2.\n"),
+        ("[1] [2]", "proj3: Invalid JSON at byte 4.\n"),
+        (&"[".repeat(10_000), "proj3: JSON nested too deeply.\n"),
+        (r#"{"a": 1}"#, "proj3: No value at /b in jsonget.\n")
+    ];
+    for (json, error) in cases {
+        fs::write(dir.join("bad.json"), json).unwrap();
+        fs::write(dir.join("doc.txt"), "\\jsonfile{bad.json}{/b}").unwrap();
+        let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), error, "{}", json);
+    }
+    fs::write(dir.join("doc.txt"), "\\jsonfile{none.json}{}").unwrap();
+    assert_eq!(String::from_utf8_lossy(&proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap().stderr), "proj3: Include error.\n");
+}
+
+#[test]
+fn cpp_dialect() {
+    let dir = scratch_dir("cpp_dialect");
+    fs::write(dir.join("sizes.h"), "#define MAX_SIZE 10 // the most\n#define _LIMIT MAX_SIZE\n").unwrap();
+    fs::write(dir.join("doc.h"), "#include \"sizes.h\"\n#ifdef MAX_SIZE\nmax MAX_SIZE, limit _LIMIT, unknown MIN_SIZE\n#else\nno\n#endif\n\
+                                  #ifndef MAX_SIZE\nskipped\n#endif\n#undef _LIMIT\n_LIMIT // MAX_SIZE /* MAX_SIZE */\n\
+                                  /* MAX_SIZE\n#define MAX_SIZE 20\n*/ MAX_SIZE \"MAX_SIZE\" 'M'\n#pragma once /* MAX_SIZE */\n  #  define LONG \\\n  1\nLONG\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "--dialect", "cpp", "doc.h"]).output().unwrap();
+    assert_eq!(stdout(&output), "max 10, limit 10, unknown MIN_SIZE\n_LIMIT // MAX_SIZE /* MAX_SIZE */\n\
+                                 /* MAX_SIZE\n#define MAX_SIZE 20\n*/ 10 \"MAX_SIZE\" 'M'\n#pragma once /* MAX_SIZE */\n1\n",
+               "{}", String::from_utf8_lossy(&output.stderr));
+    let cases = [
+        ("#define A 1\n#ifdef A\n#else\n#endif\n\n#error stop\n", "proj3: doc.h:6: stop\n"),
+        ("#ifdef A\nx\n", "proj3: Unterminated #ifdef or #ifndef.\n"),
+        ("x\n#else\n", "proj3: Unexpected #else on line 2.\n"),
+        ("#ifdef A\n#else\n#else\n#endif\n", "proj3: Unexpected #else on line 3.\n"),
+        ("#endif\n", "proj3: Unexpected #endif on line 1.\n"),
+        ("#define SQUARE(x) x*x\n", "proj3: Macros with parameters are not supported by the cpp dialect, on line 1.\n"),
+        ("#if 1\n#endif\n", "proj3: #if is not supported by the cpp dialect, on line 1.\n"),
+        ("#define 9\n", "proj3: Missing macro name in #define on line 1.\n"),
+        ("#include sizes.h\n", "proj3: Invalid #include on line 1.\n")
+    ];
+    for (text, error) in cases {
+        fs::write(dir.join("doc.h"), text).unwrap();
+        let output = proj3(&dir).args(["--no-config", "--dialect", "cpp", "doc.h"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), error, "{}", text);
+    }
+}
+
+#[test]
+fn definitions_record_their_file() {
+    let dir = scratch_dir("definitions_record_their_file");
+    fs::write(dir.join("a.mac"), "x\n\n\\def{api}{A}\n").unwrap();
+    fs::write(dir.join("b.mac"), "\n\\def{inc}{I}\n").unwrap();
+    fs::write(dir.join("c.mac"), "\\def{with}{\\val{}}\n").unwrap();
+    fs::write(dir.join("doc.txt"), "\\import{a.mac}{m}\\show{m:api}\n\\include{b.mac}\\show{inc}\n\\includewith{c.mac}{val=V}\\show{with}\n\
+                                    \\def{here}{H}\\show{here}\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "doc.txt:1: \\m:api = A (defined at a.mac:3)\ndoc.txt:2: \\inc = I (defined at b.mac:2)\n\
+                                                          doc.txt:3: \\with = \\val{} (defined at c.mac:1)\ndoc.txt:4: \\here = H (defined at doc.txt:4)\n");
+}
+
+#[test]
+fn base64() {
+    let dir = scratch_dir("base64");
+    fs::write(dir.join("doc.txt"), "\\b64encode{}|\\b64encode{a}|\\b64encode{ab}|\\b64encode{abc}|\\b64encode{é}|\
+                                    \\b64decode{YQ==}|\\b64decode{YWI=}|\\b64decode{YWJj}|\\b64decode{YW\nJj YQ==}|\\b64decode{}\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "|YQ==|YWI=|YWJj|w6k=|a|ab|abc|abca|\n", "{}", String::from_utf8_lossy(&output.stderr));
+    for text in ["YQ", "YQ=", "YWI", "YWJj=", "YR==", "YWJ=", "Y===", "====", "YQ==YQ==", "YQ=a", "YW*j"] {
+        fs::write(dir.join("doc.txt"), format!("\\b64decode{{{}}}", text)).unwrap();
+        let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: Invalid input to b64decode.\n", "{}", text);
+    }
+    fs::write(dir.join("doc.txt"), "\\b64decode{/w==}").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: Output of b64decode is not UTF-8.\n");
+}
+
+#[test]
+fn recover() {
+    let dir = scratch_dir("recover");
+    fs::write(dir.join("doc.txt"), "\\def{m}{a \\nosuch{x} b}\nbefore \\m{} after\n\\undef{zz}tail \\popdef{yy}end\n\\b@d{} skipped \\m{}!\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "--recover", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "\nbefore a [?] after\n[?]tail [?]end\n[?]a [?]!\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: doc.txt:2: Macro not defined.\nproj3: doc.txt:3: Macro not defined.\n\
+                                                          proj3: doc.txt:3: Macro not defined.\nproj3: doc.txt:4: Non-alphanumeric in macro name\n\
+                                                          proj3: doc.txt:4: Macro not defined.\nproj3: 5 errors, 0 warnings.\n");
+    assert!(!output.status.success());
+    let output = proj3(&dir).args(["--no-config", "--recover", "--lenient-undef", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "\nbefore a [?] after\ntail end\n[?]a [?]!\n");
+    let output = proj3(&dir).args(["--no-config", "--recover", "--max-errors", "2", "doc.txt"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: doc.txt:2: Macro not defined.\nproj3: doc.txt:3: Macro not defined.\n\
+                                                          proj3: 2 errors, 0 warnings, stopping at the --max-errors limit.\n");
+    // Other errors still stop processing
+    fs::write(dir.join("doc.txt"), "\\nosuch{} \\def{a}\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "--recover", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn front_matter() {
+    let dir = scratch_dir("front_matter");
+    fs::write(dir.join("doc.txt"), "---\ntitle: \"A: \\\"B\\\"\"\n# comment\n\nnote: it's # not this\nquoted: 'it''s'\n---\n\\title{}|\\note{}|\\quoted{}\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "--front-matter", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "A: \"B\"|it's|it's\n", "{}", String::from_utf8_lossy(&output.stderr));
+    fs::write(dir.join("doc.txt"), "+++\n  title = 'T'\n+++\n\\title{}\n").unwrap();
+    assert_eq!(stdout(&proj3(&dir).args(["--no-config", "--front-matter", "doc.txt"]).output().unwrap()), "T\n");
+    let cases = [
+        ("---\nauthor:\n  name: X\n---\n", "proj3: Unsupported nested front matter on line 3.\n"),
+        ("---\ntags:\n- a\n---\n", "proj3: Unsupported nested front matter on line 3.\n"),
+        ("---\nauthor: {name: X}\n---\n", "proj3: Unsupported nested front matter on line 2.\n"),
+        ("+++\ntags = [1, 2]\n+++\n", "proj3: Unsupported nested front matter on line 2.\n"),
+        ("+++\n[author]\n+++\n", "proj3: Unsupported front matter on line 2.\n"),
+        ("---\ntitle T\n---\n", "proj3: Unsupported front matter on line 2.\n"),
+        ("---\nmy-title: T\n---\n", "proj3: Non-alphanumeric in front matter key.\n"),
+        ("---\ntitle: T\n", "proj3: Unterminated front matter.\n")
+    ];
+    for (text, error) in cases {
+        fs::write(dir.join("doc.txt"), text).unwrap();
+        let output = proj3(&dir).args(["--no-config", "--front-matter", "doc.txt"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), error, "{}", text);
+    }
+}
+
+#[test]
+fn normalize() {
+    let dir = scratch_dir("normalize");
+    // Composed and decomposed forms, a singleton, marks out of canonical order, a composition
+    // exclusion, Hangul syllables, and marks that only compose in order
+    let text = "\u{e9}|e\u{301}|\u{212b}|\u{1e0b}\u{323}|\u{958}|\u{ac01}|\u{1100}\u{1161}\u{11a8}|q\u{307}\u{323}|\u{1fa}|";
+    fs::write(dir.join("doc.txt"), format!("{}\\def{{acute}}{{\u{301}}}e\\acute{{}}\n", text)).unwrap();
+    let output = proj3(&dir).args(["--no-config", "--normalize", "nfc", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "\u{e9}|\u{e9}|\u{c5}|\u{1e0d}\u{307}|\u{915}\u{93c}|\u{ac01}|\u{ac01}|q\u{323}\u{307}|\u{1fa}|\u{e9}\n");
+    let output = proj3(&dir).args(["--no-config", "--normalize", "nfd", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "e\u{301}|e\u{301}|A\u{30a}|d\u{323}\u{307}|\u{915}\u{93c}|\u{1100}\u{1161}\u{11a8}|\u{1100}\u{1161}\u{11a8}|\
+                                 q\u{323}\u{307}|A\u{30a}\u{301}|e\u{301}\n");
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), format!("{}e\u{301}\n", text));
+    let output = proj3(&dir).args(["--no-config", "--normalize", "nfkc", "doc.txt"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: --normalize expects nfc or nfd.\n");
+}
+
+#[test]
+fn m4_dialect() {
+    let dir = scratch_dir("m4_dialect");
+    fs::write(dir.join("inc.m4"), "define(`inc', `INC')dnl\n").unwrap();
+    fs::write(dir.join("doc.m4"), "define(`greet', `Hello, $1!')dnl\ngreet(`world') greet(`a, b')\ndefine(`two', `[$1|$2]')two(x, y) two(x)\n\
+                                   ifdef(`greet', `yes', `no') ifdef(`nope', `yes', `no')\nundefine(`greet')greet(z) `greet' ``nested'' # greet\n\
+                                   include(`inc.m4')inc dnl gone\nundefine(`nope')end\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "--dialect", "m4", "doc.m4"]).output().unwrap();
+    assert_eq!(stdout(&output), "Hello, world! Hello, a, b!\n[x|y] [x|]\nyes no\ngreet(z) greet `nested' # greet\nINC end\n",
+               "{}", String::from_utf8_lossy(&output.stderr));
+    let cases = [
+        ("`open", "proj3: Unterminated quote.\n"),
+        ("define(`a', `b'", "proj3: Unterminated argument list.\n"),
+        ("define", "proj3: define expects arguments.\n"),
+        ("define(`a_b', x)", "proj3: Macro names with _ are not supported by the m4 dialect, as in a_b.\n"),
+        ("define(`9', x)", "proj3: Invalid macro name \"9\".\n"),
+        ("include(`none.m4')", "proj3: Include error.\n")
+    ];
+    for (text, error) in cases {
+        fs::write(dir.join("doc.m4"), text).unwrap();
+        let output = proj3(&dir).args(["--no-config", "--dialect", "m4", "doc.m4"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), error, "{}", text);
+    }
+}
+
+#[test]
+fn braces_dialect() {
+    let dir = scratch_dir("braces_dialect");
+    fs::write(dir.join("part.html"), "{{#def part}}P{{/def}}{{part}}").unwrap();
+    fs::write(dir.join("doc.html"), "{{#def title}}A & <B>{{/def}}<h1>{{title}}</h1> {{{title}}} [{{missing}}]\n\
+                                     {{#if title}}yes{{else}}no{{/if}} {{#unless title}}un{{else}}less{{/unless}} {{#if none}}x{{/if}}\n\
+                                     {{! a\ncomment }}{{> part.html}} \\ { } % # {{ title }}\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "--dialect", "braces", "doc.html"]).output().unwrap();
+    assert_eq!(stdout(&output), "<h1>A &amp; &lt;B&gt;</h1> A & <B> []\nyes less \nP \\ { } % # A &amp; &lt;B&gt;\n",
+               "{}", String::from_utf8_lossy(&output.stderr));
+    let cases = [
+        ("\n{{title", "proj3: Unterminated tag on line 2.\n"),
+        ("{{#if a}}x", "proj3: Unterminated {{#if}} section.\n"),
+        ("\n\n{{else}}", "proj3: Unexpected {{else}} on line 3.\n"),
+        ("{{#if a}}{{else}}{{else}}{{/if}}", "proj3: Unexpected {{else}} on line 1.\n"),
+        ("{{/if}}", "proj3: Unexpected {{/if}} on line 1.\n"),
+        ("{{#if a}}{{/unless}}", "proj3: Unexpected {{/unless}} on line 1.\n"),
+        ("{{#each a}}{{/each}}", "proj3: Unknown section {{#each}} on line 1.\n"),
+        ("{{a b}}", "proj3: Invalid name \"a b\" in tag on line 1.\n"),
+        ("{{> none.html}}", "proj3: Include error.\n")
+    ];
+    for (text, error) in cases {
+        fs::write(dir.join("doc.html"), text).unwrap();
+        let output = proj3(&dir).args(["--no-config", "--dialect", "braces", "doc.html"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), error, "{}", text);
+    }
+}
+
+#[test]
+fn digests() {
+    let dir = scratch_dir("digests");
+    // The standard test vectors, including one of two blocks and one of many
+    fs::write(dir.join("doc.txt"), format!("\\sha256{{}}\n\\sha256{{abc}}\n\\sha256{{abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq}}\n\\sha256{{{}}}\n\
+                                            \\def{{x}}{{é}}\\sha256{{\\x{{}}}}\n\\crc32{{}}|\\crc32{{123456789}}|\\crc32{{\\x{{}}}}\n", "a".repeat(1_000_000))).unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\n\
+                                 ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\n\
+                                 248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1\n\
+                                 cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0\n\
+                                 4a99557e4033c3539de2eb65472017cad5f9557f7a0625a09f1c3f6e2ba69c4c\n00000000|cbf43926|0e048d3e\n",
+               "{}", String::from_utf8_lossy(&output.stderr));
+    fs::write(dir.join("doc.txt"), "\\crc32{\\nosuch{}}").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: Macro not defined.\n");
+    fs::write(dir.join("doc.txt"), "\\sha256{abc").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: Incomplete macro.\n");
+}
+
+#[test]
+fn escapes() {
+    let dir = scratch_dir("escapes");
+    fs::write(dir.join("doc.txt"), "\\def{v}{<a href=\"x\">Tom & Jerry's</a>}\\htmlescape{\\v{}}|\\shellquote{\\v{}}|\\shellquote{}|\
+                                    \\jsonescape{q\"b\\\\s\ttab\r\u{1}é\n}|\\shellquote{\\\\nosuch\\{\\}}|\\htmlescape{\\\\x}\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;|'<a href=\"x\">Tom & Jerry'\\''s</a>'|''|\
+                                 q\\\"b\\\\s\\ttab\\u000d\\u0001é\\n|'\\nosuch{}'|\\x\n", "{}", String::from_utf8_lossy(&output.stderr));
+    fs::write(dir.join("doc.txt"), "\\jsonescape{\\nosuch{}}").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: Macro not defined.\n");
+}
+
+#[test]
+fn hex() {
+    let dir = scratch_dir("hex");
+    fs::write(dir.join("doc.txt"), "\\hexencode{}|\\hexencode{Hi é}|\\hexdecode{48 69\nc3A9}|\\hexdecode{}\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "|486920c3a9|Hié|\n", "{}", String::from_utf8_lossy(&output.stderr));
+    for (text, error) in [("486", "proj3: Invalid input to hexdecode.\n"), ("4g", "proj3: Invalid input to hexdecode.\n"),
+                          ("c3", "proj3: Output of hexdecode is not UTF-8.\n")] {
+        fs::write(dir.join("doc.txt"), format!("\\hexdecode{{{}}}", text)).unwrap();
+        let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), error, "{}", text);
+    }
+}
+
+#[test]
+fn url_encoding() {
+    let dir = scratch_dir("url_encoding");
+    fs::write(dir.join("doc.txt"), "\\urlencode{a b/ü-._~!*+\\%}|\\urlencode{}|\\urldecode{a\\%20b\\%2f\\%C3\\%BC+c}|\\urldecode{\\urlencode{x&y=z \\\\}}\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "a%20b%2F%C3%BC-._~%21%2A%2B%25||a b/ü+c|x&y=z \\\n", "{}", String::from_utf8_lossy(&output.stderr));
+    for (text, error) in [("\\%", "proj3: Invalid input to urldecode.\n"), ("\\%2", "proj3: Invalid input to urldecode.\n"),
+                          ("\\%zz", "proj3: Invalid input to urldecode.\n"), ("\\%C3", "proj3: Output of urldecode is not UTF-8.\n")] {
+        fs::write(dir.join("doc.txt"), format!("\\urldecode{{{}}}", text)).unwrap();
+        let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), error, "{}", text);
+    }
+}
+
+#[test]
+fn foreachrow() {
+    let dir = scratch_dir("foreachrow");
+    fs::write(dir.join("prices.csv"), "item, price\npen,\"1,50\"\n\"say \"\"hi\"\"\",2\r\nshort\n\"two\nlines\",\\x{}%\n").unwrap();
+    fs::write(dir.join("doc.txt"), "\\foreachrow{prices.csv}{#:#item=#price/#2;}\n\\foreachrow{prices.csv}{\\ifdef{none}{}{.}}\n").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(stdout(&output), "1:pen=1,50/1,50;2:say \"hi\"=2/2;3:short=/;4:two\nlines=\\x{}%/\\x{}%;\n....\n", "{}", String::from_utf8_lossy(&output.stderr));
+    let cases = [
+        ("item\n", "\\foreachrow{prices.csv}{#item}", ""),
+        ("", "\\foreachrow{prices.csv}{#item}", ""),
+        ("item\npen\n", "\\foreachrow{prices.csv}{#price}", "proj3: No column price in foreachrow.\n"),
+        ("item\npen\n", "\\foreachrow{prices.csv}{#0}", "proj3: No column 0 in foreachrow.\n"),
+        ("item\n\"pen\n\n", "\\foreachrow{prices.csv}{}", "proj3: Unterminated quote in CSV from line 2.\n"),
+        ("item\n\"a\nb\"c\n", "\\foreachrow{prices.csv}{}", "proj3: Text after a quoted field in CSV on line 3.\n"),
+        ("item\npe\"n\n", "\\foreachrow{prices.csv}{}", "proj3: Stray quote in CSV on line 2.\n"),
+        ("item\n", "\\foreachrow{none.csv}{}", "proj3: Include error.\n")
+    ];
+    for (csv, text, error) in cases {
+        fs::write(dir.join("prices.csv"), csv).unwrap();
+        fs::write(dir.join("doc.txt"), text).unwrap();
+        let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), error, "{}", csv);
+    }
+    fs::write(dir.join("prices.csv"), b"item\n\xff\n").unwrap();
+    fs::write(dir.join("doc.txt"), "\\foreachrow{prices.csv}{}").unwrap();
+    let output = proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: Include error.\n");
+}
+
+#[test]
+fn timeout() {
+    let dir = scratch_dir("timeout");
+    fs::write(dir.join("doc.txt"), "\\def{go}{1}\\def{a}{\\b{}}\\def{b}{\\while{\\go{}}{}}\nline 2 \\a{}\n").unwrap();
+    let start = std::time::Instant::now();
+    let output = proj3(&dir).args(["--no-config", "--max-expansions", "0", "--timeout", "1", "doc.txt"]).output().unwrap();
+    assert!(start.elapsed().as_secs() < 10);
+    let error = String::from_utf8_lossy(&output.stderr);
+    assert!(error.starts_with("proj3: Timed out after 1 seconds at doc.txt:2 while expanding \\a -> \\b"), "{}", error);
+    assert!(!output.status.success());
+    fs::write(dir.join("doc.txt"), "\\def{a}{A}\\a{}").unwrap();
+    assert_eq!(stdout(&proj3(&dir).args(["--no-config", "--timeout", "1", "doc.txt"]).output().unwrap()), "A");
+    let output = proj3(&dir).args(["--no-config", "--timeout", "soon", "doc.txt"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "proj3: --timeout expects a number.\n");
+}
+
+#[test]
+fn expansion_cycles() {
+    let dir = scratch_dir("expansion_cycles");
+    fs::write(dir.join("lib.mac"), "\n\\def{r}{\\uppercase{\\r{}}}\n").unwrap();
+    let cases = [
+        ("\\def{a}{\\a{}}\\a{}", "proj3: Expansion cycle: \\a -> \\a (\\a defined at doc.txt:1).\n"),
+        ("\\def{a}{<\\b{}>}\n\\def{b}{[\\a{}]}\n\\a{}", "proj3: Expansion cycle: \\a -> \\b -> \\a (\\a defined at doc.txt:1, \\b defined at doc.txt:2).\n"),
+        ("\\def{a}{\\b{#}}\\def{b}{\\c{#}}\\def{c}{\\a{#}}\\a{x}",
+         "proj3: Expansion cycle: \\a -> \\b -> \\c -> \\a (\\a defined at doc.txt:1, \\b defined at doc.txt:1, \\c defined at doc.txt:1).\n"),
+        ("\\def{a}{\\a{}}\\def{b}{\\a{}}\n\\b{}", "proj3: Expansion cycle: \\a -> \\a (\\a defined at doc.txt:1).\n"),
+        ("\\def{a}{\\uppercase{\\a{}}}\\a{}", "proj3: Expansion cycle: \\a -> \\a (\\a defined at doc.txt:1).\n"),
+        ("\\import{lib.mac}{m}\\m:r{}", "proj3: Expansion cycle: \\m:r -> \\m:r (\\m:r defined at lib.mac:2).\n"),
+        // Arguments that change every time are left to the expansion limit
+        ("\\def{a}{#\\a{#x}}\\a{}", "proj3: Expansion limit exceeded.\n")
+    ];
+    for (text, error) in cases {
+        fs::write(dir.join("doc.txt"), text).unwrap();
+        let output = proj3(&dir).args(["--no-config", "--max-expansions", "1000", "doc.txt"]).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stderr), error, "{}", text);
+    }
+    // Recursion that changes a definition on the way ends, and repeated calls are not cycles
+    fs::write(dir.join("doc.txt"), "\\def{n}{}\\def{a}{\\ifdef{n}{\\undef{n}\\a{}}{done}}\\a{} \\def{x}{x}\\def{b}{\\x{}\\x{}}\\b{}\\b{}").unwrap();
+    assert_eq!(stdout(&proj3(&dir).args(["--no-config", "doc.txt"]).output().unwrap()), "done xxxx");
+}
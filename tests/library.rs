@@ -0,0 +1,40 @@
+/*
+    Tests of the library API embedders use, see src/lib.rs
+*/
+
+use proj3::Processor;
+
+#[test]
+fn embedded_builtins() {
+    let mut processor = Processor::new(&["--chomp"]).unwrap();
+    processor.builtin("shout", 1, |args| Ok(args[0].to_uppercase())).unwrap();
+    processor.builtin("pair", 2, |args| Ok(format!("{}={}", args[0], args[1]))).unwrap();
+    let output = processor.process("doc.txt", "\\def{who}{world}\n\\shout{hello \\who{}} \\pair{a}{\\who{}}").unwrap();
+    assert_eq!(output, "HELLO WORLD a=world");
+}
+
+#[test]
+fn embedded_builtin_errors() {
+    let mut processor = Processor::new(&[]).unwrap();
+    processor.builtin("fail", 1, |args| Err(format!("cannot {}", args[0]))).unwrap();
+    assert_eq!(processor.process("doc.txt", "\\fail{go}").unwrap_err(), "cannot go");
+    assert!(processor.builtin("no-dashes", 1, |_| Ok(String::new())).is_err());
+    assert!(processor.builtin("none", 0, |_| Ok(String::new())).is_err());
+    assert!(Processor::new(&["input.txt"]).is_err());
+}
+
+#[test]
+fn output_filters() {
+    struct Banner;
+    impl proj3::OutputFilter for Banner {
+        fn filter(&mut self, output: String) -> Result<String, String> {
+            Ok(format!("// generated\n{}", output))
+        }
+    }
+    let mut processor = Processor::new(&["--strip-trailing-ws"]).unwrap();
+    processor.filter(|output: String| Ok(output.replace('\t', "  ")));
+    processor.filter(Banner);
+    assert_eq!(processor.process("doc.txt", "\ta  \n").unwrap(), "// generated\n  a\n");
+    processor.filter(|_: String| Err("rejected".to_string()));
+    assert_eq!(processor.process("doc.txt", "a").unwrap_err(), "rejected");
+}